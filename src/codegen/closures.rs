@@ -0,0 +1,409 @@
+//! Function literals inside other functions are closures. Each is lifted into a function of
+//! its own, which takes the closure's environment as an extra first parameter: a block on
+//! the heap holding the variables it captures from the functions around it, one per word.
+//!
+//! Variables that are never assigned once captured are copied into the environment. The rest
+//! are shared with the closure, stored in a heap cell that both the function's local and the
+//! environment point to, so every function sees the same variable.
+//!
+//! Function values are the address of a closure record, the function's slot in the function
+//! table followed by its environment. Top level functions used as values are put in the table
+//! as an adapter that drops the environment, with their record stored in a data segment.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    lexer::span::{Span, Spanned},
+    parser::{
+        expressions::{Expression, ExpressionKind},
+        statements::{Block, ElseStatement, Statement, StatementKind},
+        types::Type,
+    },
+    type_checker::types::check_type,
+    wasm::{self, ControlOp, FunctionType, Instruction, MemArg, MemoryOp, NumType, VariableOp},
+};
+
+use super::{
+    expressions::allocate,
+    layout::{is_reference, Layout},
+    statements::lower_block,
+    types::wasm_type,
+    unsupported, Codegen, CodegenError, FunctionContext, FunctionSignature, GeneratedFunction,
+};
+
+/// The offset of the environment in a closure record, after the table slot.
+pub const ENVIRONMENT: u32 = 4;
+
+/// A variable a function literal uses from the functions around it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Capture {
+    pub name: String,
+    // Whether the literal, or one nested in it, assigns to the variable
+    pub mutated: bool,
+}
+
+/// The variables the function literal with `args` and `body` captures, in the order they are
+/// first used. Names that aren't variables of the functions around it are included too, as
+/// top level functions and globals are only told apart when the literal is lowered.
+pub fn captures(args: &[(String, Spanned<Type>)], body: &[Statement]) -> Vec<Capture> {
+    Walker::function(args, body).free
+}
+
+/// The variables of the function with `args` and `body` that a function literal in it
+/// captures and that are assigned after being defined, either by the function or by a
+/// literal. These are stored in heap cells rather than copied into environments.
+pub fn shared_variables(args: &[(String, Spanned<Type>)], body: &[Statement]) -> HashSet<String> {
+    let walker = Walker::function(args, body);
+    (walker.captured.into_iter())
+        .filter(|(name, mutated)| *mutated || walker.assigned.contains(name))
+        .map(|(name, _)| name)
+        .collect()
+}
+
+// Finds the variables a function uses without defining them
+#[derive(Default)]
+struct Walker {
+    // The names defined in each block around the code being walked, innermost last
+    bound: Vec<HashSet<String>>,
+    free: Vec<Capture>,
+    // The variables assigned by the function itself
+    assigned: HashSet<String>,
+    // The variables captured by literals in the function, and whether any of them assign it
+    captured: HashMap<String, bool>,
+}
+
+impl Walker {
+    fn function(args: &[(String, Spanned<Type>)], body: &[Statement]) -> Self {
+        let mut walker = Walker {
+            bound: vec![args.iter().map(|(name, _)| name.clone()).collect()],
+            ..Default::default()
+        };
+        walker.block(body);
+        walker
+    }
+
+    fn use_var(&mut self, name: &str, mutated: bool) {
+        if self.bound.iter().any(|names| names.contains(name)) {
+            return;
+        }
+        match self.free.iter_mut().find(|capture| capture.name == name) {
+            Some(capture) => capture.mutated |= mutated,
+            None => self.free.push(Capture {
+                name: name.to_string(),
+                mutated,
+            }),
+        }
+    }
+
+    fn block(&mut self, block: &[Statement]) {
+        self.bound.push(HashSet::new());
+        for statement in block {
+            self.statement(statement);
+        }
+        self.bound.pop();
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        match &statement.node {
+            StatementKind::VarDef { name, expr, .. } => {
+                self.expr(expr);
+                self.bound.last_mut().unwrap().insert(name.clone());
+            }
+            StatementKind::Assign { lhs, rhs } => {
+                match &lhs.node {
+                    ExpressionKind::Identifier(name) => {
+                        self.assigned.insert(name.clone());
+                        self.use_var(name, true);
+                    }
+                    _ => self.expr(lhs),
+                }
+                self.expr(rhs);
+            }
+            StatementKind::If {
+                cond,
+                body,
+                else_stmt,
+            } => {
+                self.expr(cond);
+                self.block(body);
+                match else_stmt {
+                    ElseStatement::Block(block) => self.block(block),
+                    ElseStatement::If(statement) => self.block(std::slice::from_ref(statement)),
+                    ElseStatement::None => {}
+                }
+            }
+            StatementKind::While { cond, body } => {
+                self.expr(cond);
+                self.block(body);
+            }
+            StatementKind::Loop(body) => self.block(body),
+            StatementKind::Try { body, catches } => {
+                self.block(body);
+                for catch in catches {
+                    // The value caught is bound in its own scope around the `catch` body
+                    self.bound.push(HashSet::from([catch.name.clone()]));
+                    self.block(&catch.body);
+                    self.bound.pop();
+                }
+            }
+            StatementKind::Return(Some(expr))
+            | StatementKind::Yeet(expr)
+            | StatementKind::Expr(expr) => self.expr(expr),
+            StatementKind::Return(None)
+            | StatementKind::Break
+            | StatementKind::Continue
+            | StatementKind::TypeDef { .. }
+            | StatementKind::Import { .. } => {}
+        }
+    }
+
+    fn expr(&mut self, expr: &Expression) {
+        match &expr.node {
+            ExpressionKind::Identifier(name) => self.use_var(name, false),
+            ExpressionKind::FunctionLiteral { args, body, .. } => {
+                for capture in Walker::function(args, body).free {
+                    *self.captured.entry(capture.name.clone()).or_default() |= capture.mutated;
+                    self.use_var(&capture.name, capture.mutated);
+                }
+            }
+            ExpressionKind::StructLiteral(values) => values.values().for_each(|e| self.expr(e)),
+            ExpressionKind::ArrayLiteral(values) | ExpressionKind::TupleLiteral(values) => {
+                values.iter().for_each(|e| self.expr(e))
+            }
+            ExpressionKind::Add(lhs, rhs)
+            | ExpressionKind::Sub(lhs, rhs)
+            | ExpressionKind::Mul(lhs, rhs)
+            | ExpressionKind::Div(lhs, rhs)
+            | ExpressionKind::Mod(lhs, rhs)
+            | ExpressionKind::Equal(lhs, rhs)
+            | ExpressionKind::GreaterEqual(lhs, rhs)
+            | ExpressionKind::GreaterThan(lhs, rhs)
+            | ExpressionKind::LessEqual(lhs, rhs)
+            | ExpressionKind::LessThan(lhs, rhs)
+            | ExpressionKind::And(lhs, rhs)
+            | ExpressionKind::Or(lhs, rhs)
+            | ExpressionKind::Index {
+                expr: lhs,
+                index: rhs,
+            } => {
+                self.expr(lhs);
+                self.expr(rhs);
+            }
+            ExpressionKind::Neg(inner)
+            | ExpressionKind::Not(inner)
+            | ExpressionKind::Ref(inner)
+            | ExpressionKind::Deref(inner)
+            | ExpressionKind::Dot { expr: inner, .. } => self.expr(inner),
+            ExpressionKind::Call { expr, args } => {
+                self.expr(expr);
+                args.iter().for_each(|e| self.expr(e));
+            }
+            ExpressionKind::Int(_)
+            | ExpressionKind::Float(_)
+            | ExpressionKind::String(_)
+            | ExpressionKind::Char(_)
+            | ExpressionKind::Bool(_)
+            | ExpressionKind::Null => {}
+        }
+    }
+}
+
+/// Lowers a function literal to the address of a closure record for it, lifting its body
+/// into a new function and copying what it captures into a new environment.
+pub fn lower_closure(
+    args: &[(String, Spanned<Type>)],
+    ret: &Spanned<Type>,
+    body: &Block,
+    span: Span,
+    function: &mut FunctionContext,
+) -> Result<Option<NumType>, CodegenError> {
+    // Anything that isn't a local is a global or a top level function, used directly
+    let captured = (captures(args, body).into_iter())
+        .filter_map(|capture| function.get_local(&capture.name).map(|i| (capture.name, i)))
+        .collect::<Vec<_>>();
+
+    let mut params = vec![NumType::I32];
+    for (_, typ) in args {
+        params.push(
+            wasm_type(&typ.node, typ.span, &function.scope)?
+                .ok_or_else(|| unsupported("`void` arguments", typ.span))?,
+        );
+    }
+    let ret_wasm = wasm_type(&ret.node, ret.span, &function.scope)?;
+    let type_idx = function.codegen.type_index(FunctionType {
+        args: params.clone(),
+        ret: ret_wasm.into_iter().collect(),
+    });
+    let index = function.codegen.next_function_index();
+    let name = format!("{}.closure{}", function.name, function.closures);
+    function.closures += 1;
+    function
+        .codegen
+        .module
+        .names
+        .functions
+        .push((index, name.clone()));
+
+    // Each captured variable, its type, the wasm type of its local, and for shared variables
+    // the wasm type of the value in its cell
+    let mut variables = Vec::new();
+    for (name, local) in captured.iter() {
+        let typ = function.scope.get_var(name).unwrap_or(Type::Error);
+        let boxed = function.boxed.get(local).copied();
+        variables.push((name, typ, function.locals[*local as usize], boxed));
+    }
+
+    let scope = function.scope.clone();
+    let file = function.file;
+    let mut closure = FunctionContext::new(function.codegen, ret_wasm);
+    closure.scope = scope;
+    closure.file = file;
+    closure.span = span;
+    closure.name = name;
+    closure.ret_type = check_type(&ret.node, ret.span, &closure.scope).unwrap_or(Type::Error);
+    let environment = closure.temporary(NumType::I32);
+    closure
+        .local_names
+        .push((environment, "environment".to_string()));
+    for ((name, typ), wasm_typ) in args.iter().zip(params[1..].iter()) {
+        closure.declare_local(name, typ.node.clone(), *wasm_typ);
+    }
+    for (i, (name, typ, wasm_typ, boxed)) in variables.iter().enumerate() {
+        closure.emit(Instruction::VariableOp(VariableOp::LocalGet(environment)));
+        closure.emit(load_word(*wasm_typ, i as u32 * 4));
+        let local = match boxed {
+            Some(value) => closure.declare_boxed(name, typ.clone(), *value),
+            None => closure.declare_local(name, typ.clone(), *wasm_typ),
+        };
+        closure.set_local(local);
+    }
+    closure.shared = shared_variables(args, body);
+    box_parameters(args, &mut closure)?;
+    lower_block(body, &mut closure)?;
+    let lifted = closure.finish(index, type_idx, params.len());
+    function.codegen.lifted.push(lifted);
+
+    let slot = function.codegen.table_slot(index);
+    if variables.is_empty() {
+        let record = function.codegen.closure_record(slot);
+        function.emit(Instruction::I32Const(record as i32));
+        return Ok(Some(NumType::I32));
+    }
+
+    let mut references = Vec::new();
+    for (i, (_, typ, _, boxed)) in variables.iter().enumerate() {
+        if boxed.is_some() || is_reference(typ, span, &function.scope)? {
+            references.push(i as u32 * 4);
+        }
+    }
+    let size = variables.len() as u32 * Layout::WORD.size;
+    let environment = allocate(size, &references, function);
+    for (i, ((_, local), (_, _, wasm_typ, _))) in captured.iter().zip(variables.iter()).enumerate()
+    {
+        function.emit(Instruction::VariableOp(VariableOp::LocalGet(environment)));
+        function.emit(Instruction::VariableOp(VariableOp::LocalGet(*local)));
+        function.emit(store_word(*wasm_typ, i as u32 * 4));
+    }
+
+    let record = allocate(2 * Layout::WORD.size, &[ENVIRONMENT], function);
+    function.emit(Instruction::VariableOp(VariableOp::LocalGet(record)));
+    function.emit(Instruction::I32Const(slot as i32));
+    function.emit(store_word(NumType::I32, 0));
+    function.emit(Instruction::VariableOp(VariableOp::LocalGet(record)));
+    function.emit(Instruction::VariableOp(VariableOp::LocalGet(environment)));
+    function.emit(store_word(NumType::I32, ENVIRONMENT));
+    function.emit(Instruction::VariableOp(VariableOp::LocalGet(record)));
+    Ok(Some(NumType::I32))
+}
+
+/// The address of the closure record for the top level function `name`, adding an adapter
+/// for it to the function table the first time it is used as a value.
+pub fn function_value(name: &str, signature: &FunctionSignature, codegen: &mut Codegen) -> u32 {
+    if let Some(record) = codegen.function_values.get(&signature.index) {
+        return *record;
+    }
+
+    let mut adapter = signature.clone();
+    adapter.args.insert(0, NumType::I32);
+    let type_idx = codegen.type_index(adapter.to_function_type());
+    let index = codegen.next_function_index();
+    (codegen.module.names.functions).push((index, format!("{}.adapter", name)));
+    let mut instructions = (1..adapter.args.len() as u32)
+        .map(|arg| Instruction::VariableOp(VariableOp::LocalGet(arg)))
+        .collect::<Vec<_>>();
+    instructions.push(Instruction::ControlOp(ControlOp::Call(signature.index)));
+    codegen.lifted.push(GeneratedFunction {
+        index,
+        function: wasm::Function {
+            type_idx,
+            locals: Vec::new(),
+            body: wasm::Expression { instructions },
+        },
+        positions: Vec::new(),
+        local_names: Vec::new(),
+    });
+
+    let slot = codegen.table_slot(index);
+    let record = codegen.closure_record(slot);
+    codegen.function_values.insert(signature.index, record);
+    record
+}
+
+/// Moves the value on the stack into a new heap cell for the shared variable `name`.
+pub fn box_variable(
+    name: &str,
+    typ: Type,
+    value: NumType,
+    function: &mut FunctionContext,
+) -> Result<u32, CodegenError> {
+    let reference = is_reference(&typ, function.span, &function.scope)?;
+    let temporary = match reference {
+        true => function.reference_temporary(),
+        false => function.temporary(value),
+    };
+    function.set_local(temporary);
+    let references: &[u32] = if reference { &[0] } else { &[] };
+    let cell = allocate(Layout::WORD.size, references, function);
+    let index = function.declare_boxed(name, typ, value);
+    function.emit(Instruction::VariableOp(VariableOp::LocalGet(cell)));
+    function.set_local(index);
+    function.emit(Instruction::VariableOp(VariableOp::LocalGet(index)));
+    function.emit(Instruction::VariableOp(VariableOp::LocalGet(temporary)));
+    function.emit(store_word(value, 0));
+    Ok(index)
+}
+
+/// Moves the parameters that are shared variables into heap cells.
+pub fn box_parameters(
+    args: &[(String, Spanned<Type>)],
+    function: &mut FunctionContext,
+) -> Result<(), CodegenError> {
+    for (name, typ) in args {
+        let Some(param) = function.get_local(name) else {
+            continue;
+        };
+        if function.shared.contains(name) {
+            function.emit(Instruction::VariableOp(VariableOp::LocalGet(param)));
+            let value = function.locals[param as usize];
+            box_variable(name, typ.node.clone(), value, function)?;
+        }
+    }
+    Ok(())
+}
+
+/// Loads a wasm value of type `typ` from a word `offset` bytes after the address on the stack,
+/// as variables are stored in cells and environments.
+pub fn load_word(typ: NumType, offset: u32) -> Instruction {
+    Instruction::MemoryOp(match typ {
+        NumType::F32 => MemoryOp::F32Load(MemArg::natural(4, offset)),
+        _ => MemoryOp::I32Load(MemArg::natural(4, offset)),
+    })
+}
+
+/// Stores a wasm value of type `typ` to a word `offset` bytes after the address below it.
+pub fn store_word(typ: NumType, offset: u32) -> Instruction {
+    Instruction::MemoryOp(match typ {
+        NumType::F32 => MemoryOp::F32Store(MemArg::natural(4, offset)),
+        _ => MemoryOp::I32Store(MemArg::natural(4, offset)),
+    })
+}
@@ -0,0 +1,544 @@
+use crate::{
+    lexer::span::Span,
+    parser::{
+        expressions::{Expression, ExpressionKind},
+        types::Type,
+    },
+    type_checker::types::check_type,
+    wasm::{
+        self, BlockType, ControlOp, FloatOp, FloatOpType, FloatType, Instruction, IntegerOp,
+        IntegerOpType, IntegerType, MemArg, MemoryOp, NumType, VariableOp,
+    },
+};
+
+use super::{
+    closures::{function_value, load_word, lower_closure, ENVIRONMENT},
+    layout::{
+        field, is_aggregate, is_reference, layout_of, reference_offsets, resolve, size_of,
+        struct_fields, Field, LENGTH_PREFIX,
+    },
+    types::{load_op, store_op, wasm_type},
+    unsupported, BranchTarget, CodegenError, CodegenErrorKind, FunctionContext,
+};
+
+/// Emits the instructions computing `expr`, returning the type of the value left on the
+/// stack, or `None` if it leaves nothing.
+pub fn lower_expr(
+    expr: &Expression,
+    function: &mut FunctionContext,
+) -> Result<Option<NumType>, CodegenError> {
+    function.at(expr.span, |function| lower_expr_kind(expr, function))
+}
+
+fn lower_expr_kind(
+    expr: &Expression,
+    function: &mut FunctionContext,
+) -> Result<Option<NumType>, CodegenError> {
+    let span = expr.span;
+    match &expr.node {
+        ExpressionKind::Int(val) => {
+            function.emit(Instruction::I32Const(int_constant(*val, span)?));
+            Ok(Some(NumType::I32))
+        }
+        ExpressionKind::Float(val) => {
+            function.emit(Instruction::F32Const(*val as f32));
+            Ok(Some(NumType::F32))
+        }
+        ExpressionKind::Bool(val) => {
+            function.emit(Instruction::I32Const(*val as i32));
+            Ok(Some(NumType::I32))
+        }
+        ExpressionKind::Char(val) => {
+            function.emit(Instruction::I32Const(*val as i32));
+            Ok(Some(NumType::I32))
+        }
+
+        ExpressionKind::String(val) => {
+            let address = function.codegen.add_string(val);
+            function.emit(Instruction::I32Const(address as i32));
+            Ok(Some(NumType::I32))
+        }
+
+        ExpressionKind::Identifier(name) => {
+            if let Some(index) = function.get_local(name) {
+                function.emit(Instruction::VariableOp(VariableOp::LocalGet(index)));
+                // Shared variables are loaded from their cell
+                if let Some(value) = function.boxed.get(&index).copied() {
+                    function.emit(load_word(value, 0));
+                    return Ok(Some(value));
+                }
+                return Ok(Some(function.locals[index as usize]));
+            }
+            if let Some((index, typ)) = function.codegen.globals.get(name).copied() {
+                function.emit(Instruction::VariableOp(VariableOp::GlobalGet(index)));
+                return Ok(Some(typ));
+            }
+            // Anything else is a top level function, used as a value by its closure record
+            let signature = match function.codegen.functions.get(name) {
+                Some(signature) => signature.clone(),
+                None => return Err(unsupported("unresolved variables", span)),
+            };
+            let record = function_value(name, &signature, function.codegen);
+            function.emit(Instruction::I32Const(record as i32));
+            Ok(Some(NumType::I32))
+        }
+
+        ExpressionKind::Add(lhs, rhs) => {
+            lower_arithmetic(lhs, rhs, IntegerOpType::Add, FloatOpType::Add, function)
+        }
+        ExpressionKind::Sub(lhs, rhs) => {
+            lower_arithmetic(lhs, rhs, IntegerOpType::Sub, FloatOpType::Sub, function)
+        }
+        ExpressionKind::Mul(lhs, rhs) => {
+            lower_arithmetic(lhs, rhs, IntegerOpType::Mul, FloatOpType::Mul, function)
+        }
+        ExpressionKind::Div(lhs, rhs) => {
+            lower_arithmetic(lhs, rhs, IntegerOpType::DivS, FloatOpType::Div, function)
+        }
+        ExpressionKind::Mod(lhs, rhs) => {
+            lower_expr(lhs, function)?;
+            lower_expr(rhs, function)?;
+            function.emit(integer_op(IntegerOpType::RemS));
+            Ok(Some(NumType::I32))
+        }
+
+        ExpressionKind::Neg(inner) => match &inner.node {
+            // Folded so the most negative integer can be written
+            ExpressionKind::Int(val) => {
+                function.emit(Instruction::I32Const(int_constant(-val, span)?));
+                Ok(Some(NumType::I32))
+            }
+            _ => match lower_expr(inner, function)? {
+                Some(NumType::F32) => {
+                    function.emit(float_op(FloatOpType::Neg));
+                    Ok(Some(NumType::F32))
+                }
+                _ => {
+                    // There is no integer negation instruction
+                    function.emit(Instruction::I32Const(-1));
+                    function.emit(integer_op(IntegerOpType::Mul));
+                    Ok(Some(NumType::I32))
+                }
+            },
+        },
+
+        ExpressionKind::Equal(lhs, rhs) => {
+            lower_comparison(lhs, rhs, IntegerOpType::Eq, FloatOpType::Eq, function)
+        }
+        ExpressionKind::GreaterEqual(lhs, rhs) => {
+            lower_comparison(lhs, rhs, IntegerOpType::GeS, FloatOpType::Ge, function)
+        }
+        ExpressionKind::GreaterThan(lhs, rhs) => {
+            lower_comparison(lhs, rhs, IntegerOpType::GtS, FloatOpType::Gt, function)
+        }
+        ExpressionKind::LessEqual(lhs, rhs) => {
+            lower_comparison(lhs, rhs, IntegerOpType::LeS, FloatOpType::Le, function)
+        }
+        ExpressionKind::LessThan(lhs, rhs) => {
+            lower_comparison(lhs, rhs, IntegerOpType::LtS, FloatOpType::Lt, function)
+        }
+
+        // The right hand side is only evaluated if it decides the result
+        ExpressionKind::And(lhs, rhs) => {
+            lower_expr(lhs, function)?;
+            let then =
+                function.lower_nested(BranchTarget::Block, |f| lower_expr(rhs, f).map(|_| ()))?;
+            let otherwise = function.lower_nested(BranchTarget::Block, |f| {
+                f.emit(Instruction::I32Const(0));
+                Ok(())
+            })?;
+            function.emit(bool_if(then, otherwise));
+            Ok(Some(NumType::I32))
+        }
+        ExpressionKind::Or(lhs, rhs) => {
+            lower_expr(lhs, function)?;
+            let then = function.lower_nested(BranchTarget::Block, |f| {
+                f.emit(Instruction::I32Const(1));
+                Ok(())
+            })?;
+            let otherwise =
+                function.lower_nested(BranchTarget::Block, |f| lower_expr(rhs, f).map(|_| ()))?;
+            function.emit(bool_if(then, otherwise));
+            Ok(Some(NumType::I32))
+        }
+        ExpressionKind::Not(inner) => {
+            lower_expr(inner, function)?;
+            function.emit(integer_op(IntegerOpType::Eqz));
+            Ok(Some(NumType::I32))
+        }
+
+        ExpressionKind::Call { expr, args } => {
+            let signature = match &expr.node {
+                ExpressionKind::Identifier(name)
+                    if function.get_local(name).is_none()
+                        && !function.codegen.globals.contains_key(name) =>
+                {
+                    function.codegen.functions.get(name).cloned()
+                }
+                _ => None,
+            };
+            let callee = function.type_of(expr);
+            let Ok(Type::Function {
+                args: arg_types,
+                ret,
+            }) = check_type(&callee, span, &function.scope)
+            else {
+                return Err(unsupported("calls to non-functions", span));
+            };
+            if let Some(signature) = signature {
+                for (arg, typ) in args.iter().zip(arg_types.iter()) {
+                    lower_expr_as(arg, typ, function)?;
+                }
+                function.emit(Instruction::ControlOp(ControlOp::Call(signature.index)));
+                root_result(&ret, span, function)?;
+                return Ok(signature.ret);
+            }
+
+            // Function values are closures, called through the function table by their slot
+            // with their environment as the first argument
+            lower_expr(expr, function)?;
+            let closure = function.reference_temporary();
+            function.set_local(closure);
+            function.emit(Instruction::VariableOp(VariableOp::LocalGet(closure)));
+            function.emit(load_word(NumType::I32, ENVIRONMENT));
+            for (arg, typ) in args.iter().zip(arg_types.iter()) {
+                lower_expr_as(arg, typ, function)?;
+            }
+            function.emit(Instruction::VariableOp(VariableOp::LocalGet(closure)));
+            function.emit(load_word(NumType::I32, 0));
+            let args = arg_types.into_iter().map(|arg| *arg).collect::<Vec<_>>();
+            let mut signature = function.codegen.signature(&args, &ret, span)?;
+            signature.args.insert(0, NumType::I32);
+            let type_idx = function.codegen.type_index(signature.to_function_type());
+            function.emit(Instruction::ControlOp(ControlOp::CallIndirect {
+                type_idx,
+                table: 0,
+            }));
+            root_result(&ret, span, function)?;
+            Ok(signature.ret)
+        }
+
+        // Literals are allocated on the heap, then each part is stored in place
+        ExpressionKind::StructLiteral(values) => {
+            let Type::Struct(types) = resolve(&function.type_of(expr), span, &function.scope)?
+            else {
+                return Err(unsupported("ill-typed values", span));
+            };
+            let fields = struct_fields(&types, span, &function.scope)?;
+            let typ = Type::Struct(types);
+            let size = size_of(&typ, span, &function.scope)?;
+            let references = reference_offsets(&typ, span, &function.scope)?;
+            let address = allocate(size, &references, function);
+            for field in fields {
+                function.emit(Instruction::VariableOp(VariableOp::LocalGet(address)));
+                lower_store(&field.typ, field.offset, span, function, |function| {
+                    lower_expr(&values[&field.name], function).map(|_| ())
+                })?;
+            }
+            function.emit(Instruction::VariableOp(VariableOp::LocalGet(address)));
+            Ok(Some(NumType::I32))
+        }
+        ExpressionKind::ArrayLiteral(values) => {
+            let typ = resolve(&function.type_of(expr), span, &function.scope)?;
+            let Type::SizedArray { element, .. } = &typ else {
+                return Err(unsupported("ill-typed values", span));
+            };
+            let stride = layout_of(element, span, &function.scope)?.stride();
+            let size = size_of(&typ, span, &function.scope)?;
+            let references = reference_offsets(&typ, span, &function.scope)?;
+            let address = allocate(size, &references, function);
+            for (i, value) in values.iter().enumerate() {
+                function.emit(Instruction::VariableOp(VariableOp::LocalGet(address)));
+                lower_store(element, i as u32 * stride, span, function, |function| {
+                    lower_expr(value, function).map(|_| ())
+                })?;
+            }
+            function.emit(Instruction::VariableOp(VariableOp::LocalGet(address)));
+            Ok(Some(NumType::I32))
+        }
+        ExpressionKind::TupleLiteral(_) => Err(unsupported("tuples", span)),
+        ExpressionKind::FunctionLiteral { args, ret, body } => {
+            lower_closure(args, ret, body, span, function)
+        }
+        ExpressionKind::Null => {
+            function.emit(Instruction::I32Const(0));
+            Ok(Some(NumType::I32))
+        }
+        ExpressionKind::Deref(pointer) => {
+            let typ = function.type_of(expr);
+            lower_expr(pointer, function)?;
+            lower_load(&typ, 0, span, function)
+        }
+        ExpressionKind::Ref(inner) => lower_address(inner, function),
+        ExpressionKind::Index { .. } => {
+            let typ = function.type_of(expr);
+            lower_address(expr, function)?;
+            lower_load(&typ, 0, span, function)
+        }
+        ExpressionKind::Dot { expr: inner, field } => {
+            let field = struct_field(inner, field, function)?;
+            lower_expr(inner, function)?;
+            lower_load(&field.typ, field.offset, span, function)
+        }
+    }
+}
+
+/// Lowers `expr` as a value of type `typ`, which the type checker has made sure it can be
+/// assigned to. Structs with fields `typ` doesn't have, and sized arrays used as lists, are
+/// copied into a new value laid out as `typ`.
+pub fn lower_expr_as(
+    expr: &Expression,
+    typ: &Type,
+    function: &mut FunctionContext,
+) -> Result<Option<NumType>, CodegenError> {
+    let span = expr.span;
+    let from = resolve(&function.type_of(expr), span, &function.scope)?;
+    let to = resolve(typ, span, &function.scope)?;
+    match (&from, &to) {
+        (Type::SizedArray { len, .. }, Type::Array(_)) => {
+            let size = size_of(&from, span, &function.scope)?;
+            lower_expr(expr, function)?;
+            let array = function.reference_temporary();
+            function.set_local(array);
+
+            let references = (reference_offsets(&from, span, &function.scope)?.into_iter())
+                .map(|offset| LENGTH_PREFIX + offset)
+                .collect::<Vec<_>>();
+            let list = allocate(LENGTH_PREFIX + size, &references, function);
+            function.emit(Instruction::VariableOp(VariableOp::LocalGet(list)));
+            function.emit(Instruction::I32Const(*len as i32));
+            function.emit(Instruction::MemoryOp(MemoryOp::I32Store(MemArg::natural(
+                4, 0,
+            ))));
+            function.emit(Instruction::VariableOp(VariableOp::LocalGet(list)));
+            function.emit(Instruction::I32Const(LENGTH_PREFIX as i32));
+            function.emit(integer_op(IntegerOpType::Add));
+            function.tee_local(list);
+            function.emit(Instruction::VariableOp(VariableOp::LocalGet(array)));
+            function.emit(Instruction::I32Const(size as i32));
+            function.emit(Instruction::MemoryOp(MemoryOp::MemoryCopy));
+            function.emit(Instruction::VariableOp(VariableOp::LocalGet(list)));
+            Ok(Some(NumType::I32))
+        }
+        (Type::Struct(from_fields), Type::Struct(to_fields)) if from_fields != to_fields => {
+            let from_fields = struct_fields(from_fields, span, &function.scope)?;
+            let to_fields = struct_fields(to_fields, span, &function.scope)?;
+            lower_expr(expr, function)?;
+            let original = function.reference_temporary();
+            function.set_local(original);
+
+            let size = size_of(&to, span, &function.scope)?;
+            let references = reference_offsets(&to, span, &function.scope)?;
+            let copy = allocate(size, &references, function);
+            for to_field in to_fields {
+                let from_field = (from_fields.iter())
+                    .find(|field| field.name == to_field.name)
+                    .ok_or_else(|| unsupported("ill-typed values", span))?;
+                // Every pointer, including `null`, is stored as an address, so they copy as is
+                match (
+                    resolve(&from_field.typ, span, &function.scope)?,
+                    resolve(&to_field.typ, span, &function.scope)?,
+                ) {
+                    (Type::Ptr(_), Type::Ptr(_)) => {}
+                    (from, to) if from == to => {}
+                    _ => return Err(unsupported("conversions between nested struct types", span)),
+                }
+                function.emit(Instruction::VariableOp(VariableOp::LocalGet(copy)));
+                lower_store(&to_field.typ, to_field.offset, span, function, |function| {
+                    function.emit(Instruction::VariableOp(VariableOp::LocalGet(original)));
+                    lower_load(&from_field.typ, from_field.offset, span, function).map(|_| ())
+                })?;
+            }
+            function.emit(Instruction::VariableOp(VariableOp::LocalGet(copy)));
+            Ok(Some(NumType::I32))
+        }
+        _ => lower_expr(expr, function),
+    }
+}
+
+/// Emits the instructions computing the address of `expr`, which has to be stored in memory.
+pub fn lower_address(
+    expr: &Expression,
+    function: &mut FunctionContext,
+) -> Result<Option<NumType>, CodegenError> {
+    let span = expr.span;
+    match &expr.node {
+        // `&*p` is just `p`
+        ExpressionKind::Deref(pointer) => lower_expr(pointer, function),
+        ExpressionKind::Dot { expr: inner, field } => {
+            let field = struct_field(inner, field, function)?;
+            lower_expr(inner, function)?;
+            add_offset(field.offset, function);
+            Ok(Some(NumType::I32))
+        }
+        ExpressionKind::Index { expr: array, index } => {
+            let element = match resolve(&function.type_of(array), span, &function.scope)? {
+                Type::SizedArray { element, .. } | Type::Array(element) => *element,
+                _ => return Err(unsupported("ill-typed values", span)),
+            };
+            let stride = layout_of(&element, span, &function.scope)?.stride();
+            lower_expr(array, function)?;
+            lower_expr(index, function)?;
+            if stride != 1 {
+                function.emit(Instruction::I32Const(stride as i32));
+                function.emit(integer_op(IntegerOpType::Mul));
+            }
+            function.emit(integer_op(IntegerOpType::Add));
+            Ok(Some(NumType::I32))
+        }
+        // Structs and sized arrays are already handled by their address
+        _ if is_aggregate(&function.type_of(expr), span, &function.scope)? => {
+            lower_expr(expr, function)
+        }
+        // Variables live in wasm locals and globals, which have no address
+        _ => Err(unsupported("references to variables", span)),
+    }
+}
+
+/// Loads a value of type `typ` from `offset` bytes after the address on the stack.
+pub fn lower_load(
+    typ: &Type,
+    offset: u32,
+    span: Span,
+    function: &mut FunctionContext,
+) -> Result<Option<NumType>, CodegenError> {
+    if is_aggregate(typ, span, &function.scope)? {
+        add_offset(offset, function);
+        return Ok(Some(NumType::I32));
+    }
+    let load = load_op(typ, offset, span, &function.scope)?;
+    function.emit(Instruction::MemoryOp(load));
+    wasm_type(typ, span, &function.scope)
+}
+
+/// Stores the value of type `typ` computed by `value` at `offset` bytes after the address on
+/// the stack. Values stored in place are copied from their address.
+pub fn lower_store(
+    typ: &Type,
+    offset: u32,
+    span: Span,
+    function: &mut FunctionContext,
+    value: impl FnOnce(&mut FunctionContext) -> Result<(), CodegenError>,
+) -> Result<(), CodegenError> {
+    if is_aggregate(typ, span, &function.scope)? {
+        let size = size_of(typ, span, &function.scope)?;
+        add_offset(offset, function);
+        value(function)?;
+        function.emit(Instruction::I32Const(size as i32));
+        function.emit(Instruction::MemoryOp(MemoryOp::MemoryCopy));
+        return Ok(());
+    }
+    let store = store_op(typ, offset, span, &function.scope)?;
+    value(function)?;
+    function.emit(Instruction::MemoryOp(store));
+    Ok(())
+}
+
+/// Allocates `size` bytes on the heap, returning the local their address is kept in. With a
+/// garbage collector, `references` are the offsets of the addresses it has to follow in them.
+pub fn allocate(size: u32, references: &[u32], function: &mut FunctionContext) -> u32 {
+    function.emit(Instruction::I32Const(size as i32));
+    match function.codegen.runtime.collector {
+        Some(collector) => {
+            let references = function.codegen.add_references(references);
+            function.emit(Instruction::I32Const(references as i32));
+            function.emit(Instruction::ControlOp(ControlOp::Call(collector.alloc)));
+        }
+        None => {
+            let malloc = function.codegen.runtime.malloc;
+            function.emit(Instruction::ControlOp(ControlOp::Call(malloc)));
+        }
+    }
+    let address = function.reference_temporary();
+    function.set_local(address);
+    address
+}
+
+// With a garbage collector, keeps a returned reference rooted while the rest of the
+// expression is evaluated, as it could be the only one to a new value
+fn root_result(ret: &Type, span: Span, function: &mut FunctionContext) -> Result<(), CodegenError> {
+    if function.codegen.runtime.collector.is_some() && is_reference(ret, span, &function.scope)? {
+        let result = function.reference_temporary();
+        function.tee_local(result);
+    }
+    Ok(())
+}
+
+fn add_offset(offset: u32, function: &mut FunctionContext) {
+    if offset != 0 {
+        function.emit(Instruction::I32Const(offset as i32));
+        function.emit(integer_op(IntegerOpType::Add));
+    }
+}
+
+// The field `name` of the struct `expr`
+fn struct_field(
+    expr: &Expression,
+    name: &str,
+    function: &FunctionContext,
+) -> Result<Field, CodegenError> {
+    field(&function.type_of(expr), name, expr.span, &function.scope)?
+        .ok_or_else(|| unsupported("ill-typed values", expr.span))
+}
+
+// Integers are 32 bits wide in wasm, but the lexer accepts anything that fits in 64
+fn int_constant(val: i64, span: Span) -> Result<i32, CodegenError> {
+    i32::try_from(val).map_err(|_| CodegenErrorKind::IntegerOutOfRange(val).at(span))
+}
+
+// An if/else where both branches leave a bool on the stack
+fn bool_if(then: wasm::Expression, otherwise: wasm::Expression) -> Instruction {
+    Instruction::ControlOp(ControlOp::If {
+        typ: BlockType::Value(NumType::I32),
+        then,
+        otherwise: Some(otherwise),
+    })
+}
+
+fn integer_op(op: IntegerOpType) -> Instruction {
+    Instruction::IntegerOp(IntegerOp {
+        op,
+        typ: IntegerType::I32,
+    })
+}
+
+fn float_op(op: FloatOpType) -> Instruction {
+    Instruction::FloatOp(FloatOp {
+        op,
+        typ: FloatType::F32,
+    })
+}
+
+// helper function to lower a binary operator, picking the integer or float version depending on the operands
+fn lower_arithmetic(
+    lhs: &Expression,
+    rhs: &Expression,
+    int_op: IntegerOpType,
+    float_op_type: FloatOpType,
+    function: &mut FunctionContext,
+) -> Result<Option<NumType>, CodegenError> {
+    let typ = lower_expr(lhs, function)?;
+    lower_expr(rhs, function)?;
+    match typ {
+        Some(NumType::F32) => {
+            function.emit(float_op(float_op_type));
+            Ok(Some(NumType::F32))
+        }
+        _ => {
+            function.emit(integer_op(int_op));
+            Ok(Some(NumType::I32))
+        }
+    }
+}
+
+// Comparisons always produce a bool, whatever they compare
+fn lower_comparison(
+    lhs: &Expression,
+    rhs: &Expression,
+    int_op: IntegerOpType,
+    float_op: FloatOpType,
+    function: &mut FunctionContext,
+) -> Result<Option<NumType>, CodegenError> {
+    lower_arithmetic(lhs, rhs, int_op, float_op, function)?;
+    Ok(Some(NumType::I32))
+}
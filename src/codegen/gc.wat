@@ -0,0 +1,300 @@
+;; The runtime linked into modules compiled with `--gc`, in place of `runtime.wat`.
+;;
+;; Memory after the data segments holds the shadow stack, then the heap. Each function keeps
+;; the references in its variables in a frame on the shadow stack, which along with the
+;; globals holding references are the roots of the collector.
+;;
+;; Each block on the heap is a word describing where the references in it are, a word
+;; holding its size and marks, then that many bytes, which allocations point to. The
+;; description is one of
+;;   0: no references
+;;   1: unknown, any word could be a reference, for blocks from `malloc`
+;;   2: a free block
+;;   otherwise the address of a word holding the number of references, followed by the
+;;   offset of each
+;; The low bits of the size are the marks, bit 0 for reachable blocks and bit 1 for reachable
+;; blocks whose references have been marked too.
+;;
+;; References can point anywhere inside a block, so finding the block walks the heap. That
+;; makes collections quadratic, which is fine for the small heaps these programs have.
+(module $gc
+  (memory 1)
+
+  ;; The end of the data segments, set by the compiler
+  (global $data_end (mut i32) (i32.const 0))
+  ;; The top of the shadow stack, 0 until the runtime has been set up
+  (global $shadow_top (mut i32) (i32.const 0))
+  (global $heap_start (mut i32) (i32.const 0))
+  (global $heap_end (mut i32) (i32.const 0))
+  ;; The most recently freed block, or 0 if there are none
+  (global $free_list (mut i32) (i32.const 0))
+  ;; The lowest block marked since the heap was last scanned for blocks to mark from
+  (global $rescan (mut i32) (i32.const 0))
+
+  ;; The shadow stack takes 64KiB
+  (func $setup
+    (if (global.get $shadow_top)
+      (then (return)))
+    (global.set $shadow_top (global.get $data_end))
+    (global.set $heap_start (i32.add (global.get $data_end) (i32.const 0x10000)))
+    (global.set $heap_end (global.get $heap_start))
+    (if (i32.eqz (call $fits (global.get $heap_end)))
+      (then unreachable)))
+
+  ;; Grows the memory until `end` is inside it, returning 0 if it can't
+  (func $fits (param $end i32) (result i32)
+    (local $size i32)
+    (local.set $size (i32.shl (memory.size) (i32.const 16)))
+    (if (i32.le_u (local.get $end) (local.get $size))
+      (then (return (i32.const 1))))
+    (i32.ne
+      (memory.grow
+        (i32.shr_u
+          (i32.sub (i32.add (local.get $end) (i32.const 0xffff)) (local.get $size))
+          (i32.const 16)))
+      (i32.const -1)))
+
+  ;; Takes the first freed block with room for `size` bytes out of the free list, or returns 0.
+  ;; What's left of a block with room for another after it is split off and freed again
+  (func $take_free (param $size i32) (result i32)
+    (local $block i32)
+    (local $previous i32)
+    (local $rest i32)
+    (local.set $block (global.get $free_list))
+    (block $not_found
+      (loop $next
+        (br_if $not_found (i32.eqz (local.get $block)))
+        (if (i32.ge_u (call $size_of (local.get $block)) (local.get $size))
+          (then
+            (if (local.get $previous)
+              (then (i32.store (local.get $previous) (i32.load (local.get $block))))
+              (else (global.set $free_list (i32.load (local.get $block)))))
+            (if (i32.ge_u
+                  (call $size_of (local.get $block))
+                  (i32.add (local.get $size) (i32.const 12)))
+              (then
+                (local.set $rest (i32.add (i32.add (local.get $block) (local.get $size)) (i32.const 8)))
+                (i32.store
+                  (i32.sub (local.get $rest) (i32.const 4))
+                  (i32.sub
+                    (i32.sub (call $size_of (local.get $block)) (local.get $size))
+                    (i32.const 8)))
+                (i32.store (i32.sub (local.get $block) (i32.const 4)) (local.get $size))
+                (call $free (local.get $rest))))
+            (return (local.get $block))))
+        (local.set $previous (local.get $block))
+        (local.set $block (i32.load (local.get $block)))
+        (br $next)))
+    (i32.const 0))
+
+  (func $size_of (param $block i32) (result i32)
+    (i32.and (i32.load (i32.sub (local.get $block) (i32.const 4))) (i32.const -4)))
+
+  ;; Allocates `size` zeroed bytes, with the references in them described by `references`
+  (func $gc_alloc (param $size i32) (param $references i32) (result i32)
+    (local $block i32)
+    (call $setup)
+    (local.set $size (i32.and (i32.add (local.get $size) (i32.const 3)) (i32.const -4)))
+    (if (i32.eqz (local.get $size))
+      (then (local.set $size (i32.const 4))))
+
+    ;; Collect before growing the memory, then grow it if that didn't free enough
+    (local.set $block (call $take_free (local.get $size)))
+    (if (i32.eqz (local.get $block))
+      (then
+        (if (i32.gt_u
+              (i32.add (global.get $heap_end) (i32.add (local.get $size) (i32.const 8)))
+              (i32.shl (memory.size) (i32.const 16)))
+          (then
+            (call $collect)
+            (local.set $block (call $take_free (local.get $size)))))))
+    (if (i32.eqz (local.get $block))
+      (then
+        (local.set $block (i32.add (global.get $heap_end) (i32.const 8)))
+        (global.set $heap_end (i32.add (local.get $block) (local.get $size)))
+        (if (i32.eqz (call $fits (global.get $heap_end)))
+          (then unreachable))
+        (i32.store (i32.sub (local.get $block) (i32.const 4)) (local.get $size))))
+
+    (i32.store (i32.sub (local.get $block) (i32.const 8)) (local.get $references))
+    (memory.fill (local.get $block) (i32.const 0) (call $size_of (local.get $block)))
+    (local.get $block))
+
+  (func $malloc (param $size i32) (result i32)
+    (call $gc_alloc (local.get $size) (i32.const 1)))
+
+  ;; Blocks can still be freed by hand, freeing null does nothing
+  (func $free (param $pointer i32)
+    (if (local.get $pointer)
+      (then
+        (i32.store (i32.sub (local.get $pointer) (i32.const 8)) (i32.const 2))
+        (i32.store (local.get $pointer) (global.get $free_list))
+        (global.set $free_list (local.get $pointer)))))
+
+  ;; Pushes a zeroed frame of `slots` words onto the shadow stack, returning its address
+  (func $enter (param $slots i32) (result i32)
+    (local $frame i32)
+    (call $setup)
+    (local.set $frame (global.get $shadow_top))
+    (global.set $shadow_top
+      (i32.add (local.get $frame) (i32.shl (local.get $slots) (i32.const 2))))
+    (if (i32.gt_u (global.get $shadow_top) (global.get $heap_start))
+      (then unreachable))
+    (memory.fill (local.get $frame) (i32.const 0) (i32.shl (local.get $slots) (i32.const 2)))
+    (local.get $frame))
+
+  ;; Pops every frame from `frame` up
+  (func $leave (param $frame i32)
+    (global.set $shadow_top (local.get $frame)))
+
+  ;; The end of the newest frame, which `leave` pops back to after an exception unwinds the
+  ;; functions above it
+  (func $top (result i32)
+    (global.get $shadow_top))
+
+  ;; Pops every frame, called when the host calls into the module. Imports can't call back
+  ;; into it, so nothing is running then and any frames left are from calls an exception or
+  ;; trap unwound to the host
+  (func $reset
+    (if (global.get $shadow_top)
+      (then (global.set $shadow_top (global.get $data_end)))))
+
+  ;; The block `address` points into, or 0 if it isn't in an allocated block
+  (func $block_of (param $address i32) (result i32)
+    (local $block i32)
+    (if (i32.or
+          (i32.lt_u (local.get $address) (global.get $heap_start))
+          (i32.ge_u (local.get $address) (global.get $heap_end)))
+      (then (return (i32.const 0))))
+    (local.set $block (i32.add (global.get $heap_start) (i32.const 8)))
+    (loop $next
+      ;; Pointers just past the end of a block, eg. to an empty list, belong to it
+      (if (i32.lt_u
+            (local.get $address)
+            (i32.add (i32.add (local.get $block) (call $size_of (local.get $block))) (i32.const 8)))
+        (then
+          (if (i32.eq (i32.load (i32.sub (local.get $block) (i32.const 8))) (i32.const 2))
+            (then (return (i32.const 0))))
+          (return (local.get $block))))
+      (local.set $block
+        (i32.add (i32.add (local.get $block) (call $size_of (local.get $block))) (i32.const 8)))
+      (br $next))
+    (i32.const 0))
+
+  (func $mark (param $address i32)
+    (local $block i32)
+    (local $header i32)
+    (local.set $block (call $block_of (local.get $address)))
+    (if (i32.eqz (local.get $block))
+      (then (return)))
+    (local.set $header (i32.load (i32.sub (local.get $block) (i32.const 4))))
+    (if (i32.and (local.get $header) (i32.const 1))
+      (then (return)))
+    (i32.store (i32.sub (local.get $block) (i32.const 4)) (i32.or (local.get $header) (i32.const 1)))
+    (if (i32.lt_u (local.get $block) (global.get $rescan))
+      (then (global.set $rescan (local.get $block)))))
+
+  ;; Marks the blocks the references in `block` point into
+  (func $mark_references (param $block i32)
+    (local $references i32)
+    (local $i i32)
+    (local $end i32)
+    (local.set $references (i32.load (i32.sub (local.get $block) (i32.const 8))))
+    (if (i32.eqz (local.get $references))
+      (then (return)))
+    (if (i32.eq (local.get $references) (i32.const 1))
+      (then
+        (local.set $i (local.get $block))
+        (local.set $end (i32.add (local.get $block) (call $size_of (local.get $block))))
+        (block $done
+          (loop $next
+            (br_if $done (i32.ge_u (local.get $i) (local.get $end)))
+            (call $mark (i32.load (local.get $i)))
+            (local.set $i (i32.add (local.get $i) (i32.const 4)))
+            (br $next)))
+        (return)))
+    (local.set $end (i32.load (local.get $references)))
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $i) (local.get $end)))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (call $mark
+          (i32.load
+            (i32.add
+              (local.get $block)
+              (i32.load (i32.add (local.get $references) (i32.shl (local.get $i) (i32.const 2)))))))
+        (br $next))))
+
+  ;; Marks the globals holding references. Left empty here, the compiler fills it in
+  (func $mark_globals)
+
+  ;; Frees every block that can't be reached from the roots
+  (func $collect
+    (local $i i32)
+    (local $block i32)
+    (local $header i32)
+    (local $last_free i32)
+    (call $setup)
+
+    (global.set $rescan (global.get $heap_end))
+    (call $mark_globals)
+    (local.set $i (global.get $data_end))
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $i) (global.get $shadow_top)))
+        (call $mark (i32.load (local.get $i)))
+        (local.set $i (i32.add (local.get $i) (i32.const 4)))
+        (br $next)))
+
+    ;; Mark from each marked block until no more are found. Blocks are marked from in address
+    ;; order, so after blocks before the current one are marked the heap is scanned again
+    (block $done
+      (loop $again
+        (br_if $done (i32.ge_u (global.get $rescan) (global.get $heap_end)))
+        (local.set $block (global.get $rescan))
+        (global.set $rescan (global.get $heap_end))
+        (block $scanned
+          (loop $next
+            (br_if $scanned (i32.ge_u (local.get $block) (global.get $heap_end)))
+            (local.set $header (i32.load (i32.sub (local.get $block) (i32.const 4))))
+            (if (i32.eq (i32.and (local.get $header) (i32.const 3)) (i32.const 1))
+              (then
+                (i32.store
+                  (i32.sub (local.get $block) (i32.const 4))
+                  (i32.or (local.get $header) (i32.const 2)))
+                (call $mark_references (local.get $block))))
+            (local.set $block
+              (i32.add (i32.add (local.get $block) (call $size_of (local.get $block))) (i32.const 8)))
+            (br $next)))
+        (br $again)))
+
+    ;; Sweep, rebuilding the free list from the unmarked blocks and merging neighbouring ones
+    (global.set $free_list (i32.const 0))
+    (local.set $block (i32.add (global.get $heap_start) (i32.const 8)))
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_u (local.get $block) (global.get $heap_end)))
+        (local.set $header (i32.load (i32.sub (local.get $block) (i32.const 4))))
+        (if (i32.and (local.get $header) (i32.const 1))
+          (then
+            (i32.store (i32.sub (local.get $block) (i32.const 4)) (i32.and (local.get $header) (i32.const -4)))
+            (local.set $last_free (i32.const 0)))
+          (else
+            (if (local.get $last_free)
+              (then
+                (i32.store
+                  (i32.sub (local.get $last_free) (i32.const 4))
+                  (i32.add
+                    (i32.add (call $size_of (local.get $last_free)) (local.get $header))
+                    (i32.const 8))))
+              (else
+                (i32.store (i32.sub (local.get $block) (i32.const 8)) (i32.const 2))
+                (i32.store (local.get $block) (global.get $free_list))
+                (global.set $free_list (local.get $block))
+                (local.set $last_free (local.get $block))))))
+        (local.set $block (i32.add (i32.add (local.get $block) (i32.and (local.get $header) (i32.const -4))) (i32.const 8)))
+        (br $next))))
+
+  (export "malloc" (func $malloc))
+  (export "free" (func $free))
+  (export "collect" (func $collect)))
@@ -0,0 +1,329 @@
+//! Where the parts of a value are stored in linear memory. Everything the wasm code handles
+//! as a single number is stored as a word, except bools which take a single byte.
+//! Aggregates are laid out like C would, with each part aligned to its own alignment.
+
+use std::collections::HashMap;
+
+use crate::{
+    lexer::span::Span,
+    parser::types::Type,
+    type_checker::{types::check_type, Scope},
+};
+
+use super::{unsupported, CodegenError, CodegenErrorKind};
+
+/// Lists, like strings, point to their first element and keep their length in the word before it.
+pub const LENGTH_PREFIX: u32 = 4;
+
+/// The number of bytes a value takes up in memory, and what its address has to be a multiple of.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Layout {
+    pub size: u32,
+    pub align: u32,
+}
+
+impl Layout {
+    pub const WORD: Layout = Layout { size: 4, align: 4 };
+
+    /// The distance between consecutive elements of an array of values with this layout.
+    pub fn stride(&self) -> u32 {
+        self.size.next_multiple_of(self.align)
+    }
+}
+
+/// A field of a struct, `offset` bytes from the start of it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Field {
+    pub name: String,
+    pub typ: Type,
+    pub offset: u32,
+}
+
+pub fn size_of(ty: &Type, span: Span, scope: &Scope) -> Result<u32, CodegenError> {
+    Ok(layout_of(ty, span, scope)?.size)
+}
+
+pub fn align_of(ty: &Type, span: Span, scope: &Scope) -> Result<u32, CodegenError> {
+    Ok(layout_of(ty, span, scope)?.align)
+}
+
+/// The layout of a value of type `ty`, resolving named types through `scope`.
+/// Errors are reported against `span`.
+pub fn layout_of(ty: &Type, span: Span, scope: &Scope) -> Result<Layout, CodegenError> {
+    layout(ty, span, scope, &mut Vec::new())
+}
+
+/// The fields of a struct in the order they are stored, which is by decreasing alignment
+/// to leave as little padding as possible, then by name.
+pub fn struct_fields(
+    fields: &HashMap<String, Type>,
+    span: Span,
+    scope: &Scope,
+) -> Result<Vec<Field>, CodegenError> {
+    Ok(fields_of(fields, span, scope, &mut Vec::new())?.0)
+}
+
+/// The field `name` of the struct type `ty`, or `None` if `ty` is not a struct with that field.
+pub fn field(
+    ty: &Type,
+    name: &str,
+    span: Span,
+    scope: &Scope,
+) -> Result<Option<Field>, CodegenError> {
+    match resolve(ty, span, scope)? {
+        Type::Struct(fields) => Ok(struct_fields(&fields, span, scope)?
+            .into_iter()
+            .find(|field| field.name == name)),
+        _ => Ok(None),
+    }
+}
+
+/// Whether values of type `ty` are stored in place rather than in a word. Such values are
+/// handled by their address, and copied when they are stored.
+pub fn is_aggregate(ty: &Type, span: Span, scope: &Scope) -> Result<bool, CodegenError> {
+    Ok(matches!(
+        resolve(ty, span, scope)?,
+        Type::Struct(_) | Type::SizedArray { .. }
+    ))
+}
+
+/// Whether values of type `ty` are addresses, which could point into the heap.
+pub fn is_reference(ty: &Type, span: Span, scope: &Scope) -> Result<bool, CodegenError> {
+    Ok(matches!(
+        resolve(ty, span, scope)?,
+        Type::Ptr(_)
+            | Type::String
+            | Type::Array(_)
+            | Type::Struct(_)
+            | Type::SizedArray { .. }
+            | Type::Function { .. }
+    ))
+}
+
+/// The offsets of the words holding addresses in a value of type `ty` stored in memory, which
+/// the garbage collector follows.
+pub fn reference_offsets(ty: &Type, span: Span, scope: &Scope) -> Result<Vec<u32>, CodegenError> {
+    match resolve(ty, span, scope)? {
+        Type::Ptr(_) | Type::String | Type::Array(_) | Type::Function { .. } => Ok(vec![0]),
+        Type::Struct(fields) => {
+            let mut offsets = Vec::new();
+            for field in struct_fields(&fields, span, scope)? {
+                let inner = reference_offsets(&field.typ, span, scope)?;
+                offsets.extend(inner.into_iter().map(|offset| field.offset + offset));
+            }
+            Ok(offsets)
+        }
+        Type::SizedArray { element, len } => {
+            let stride = layout_of(&element, span, scope)?.stride();
+            let inner = reference_offsets(&element, span, scope)?;
+            Ok((0..len as u32)
+                .flat_map(|i| inner.iter().map(move |offset| i * stride + offset))
+                .collect())
+        }
+        Type::Tuple(elements) => {
+            let mut offsets = Vec::new();
+            let mut end: u32 = 0;
+            for element in elements {
+                let layout = layout_of(&element, span, scope)?;
+                let start = end.next_multiple_of(layout.align);
+                end = place(end, layout, span)?;
+                let inner = reference_offsets(&element, span, scope)?;
+                offsets.extend(inner.into_iter().map(|offset| start + offset));
+            }
+            Ok(offsets)
+        }
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// `ty` with named types and `typeof` resolved, to find what it is stored as.
+pub fn resolve(ty: &Type, span: Span, scope: &Scope) -> Result<Type, CodegenError> {
+    check_type(ty, span, scope).map_err(|_| unsupported("unresolved types", span))
+}
+
+// `named` holds the named types being laid out, a type stored inside itself has no size
+fn layout(
+    ty: &Type,
+    span: Span,
+    scope: &Scope,
+    named: &mut Vec<String>,
+) -> Result<Layout, CodegenError> {
+    match ty {
+        Type::Bool => Ok(Layout { size: 1, align: 1 }),
+        Type::Void => Ok(Layout { size: 0, align: 1 }),
+        // Strings, lists and functions are always stored as a pointer
+        Type::Int
+        | Type::Float
+        | Type::Char
+        | Type::String
+        | Type::Ptr(_)
+        | Type::Array(_)
+        | Type::Function { .. } => Ok(Layout::WORD),
+        Type::Named(name) => {
+            if named.contains(name) {
+                return Err(CodegenErrorKind::RecursiveType(name.clone()).at(span));
+            }
+            named.push(name.clone());
+            let layout = layout(&resolve(ty, span, scope)?, span, scope, named);
+            named.pop();
+            layout
+        }
+        Type::TypeOf(_) => layout(&resolve(ty, span, scope)?, span, scope, named),
+        Type::SizedArray { element, len } => {
+            let element = layout(element, span, scope, named)?;
+            let size = u32::try_from(*len)
+                .ok()
+                .and_then(|len| len.checked_mul(element.stride()))
+                .ok_or(CodegenErrorKind::TooLarge.at(span))?;
+            Ok(Layout {
+                size,
+                align: element.align,
+            })
+        }
+        Type::Struct(fields) => Ok(fields_of(fields, span, scope, named)?.1),
+        Type::Tuple(elements) => {
+            let mut offset: u32 = 0;
+            let mut align = 1;
+            for element in elements {
+                let element = layout(element, span, scope, named)?;
+                offset = place(offset, element, span)?;
+                align = align.max(element.align);
+            }
+            Ok(Layout {
+                size: offset.next_multiple_of(align),
+                align,
+            })
+        }
+        Type::Error => Err(unsupported("ill-typed values", span)),
+    }
+}
+
+fn fields_of(
+    fields: &HashMap<String, Type>,
+    span: Span,
+    scope: &Scope,
+    named: &mut Vec<String>,
+) -> Result<(Vec<Field>, Layout), CodegenError> {
+    let mut laid_out = Vec::new();
+    for (name, typ) in fields {
+        laid_out.push((name, typ, layout(typ, span, scope, named)?));
+    }
+    laid_out.sort_by(|(a, _, a_layout), (b, _, b_layout)| {
+        b_layout.align.cmp(&a_layout.align).then(a.cmp(b))
+    });
+
+    let mut offset: u32 = 0;
+    let mut align = 1;
+    let mut ordered = Vec::new();
+    for (name, typ, layout) in laid_out {
+        let start = offset.next_multiple_of(layout.align);
+        offset = place(offset, layout, span)?;
+        align = align.max(layout.align);
+        ordered.push(Field {
+            name: name.clone(),
+            typ: typ.clone(),
+            offset: start,
+        });
+    }
+    let layout = Layout {
+        size: offset.next_multiple_of(align),
+        align,
+    };
+    Ok((ordered, layout))
+}
+
+// The end of a value with `layout` placed at the first suitably aligned offset from `offset`
+fn place(offset: u32, layout: Layout, span: Span) -> Result<u32, CodegenError> {
+    offset
+        .next_multiple_of(layout.align)
+        .checked_add(layout.size)
+        .ok_or(CodegenErrorKind::TooLarge.at(span))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        lexer::lexer::Lexer,
+        parser::{parser::Parser, types::parse_type},
+    };
+
+    fn typ(source: &str) -> Type {
+        parse_type(&mut Parser::new(Lexer::new(source))).unwrap()
+    }
+
+    // The name and offset of each field, in the order they are stored
+    fn offsets(source: &str, scope: &Scope) -> Vec<(String, u32)> {
+        let Type::Struct(fields) = typ(source) else {
+            panic!("`{}` is not a struct", source);
+        };
+        (struct_fields(&fields, Span::default(), scope)
+            .unwrap()
+            .into_iter())
+        .map(|field| (field.name, field.offset))
+        .collect()
+    }
+
+    fn layout(source: &str, scope: &Scope) -> Layout {
+        layout_of(&typ(source), Span::default(), scope).unwrap()
+    }
+
+    #[test]
+    fn struct_fields_are_ordered_by_alignment() {
+        let scope = Scope::new();
+        let source = "{ a: bool, b: int, c: int[] }";
+        assert_eq!(
+            offsets(source, &scope),
+            [
+                ("b".to_string(), 0),
+                ("c".to_string(), 4),
+                ("a".to_string(), 8)
+            ]
+        );
+        // Padded so the next element of an array is aligned
+        assert_eq!(layout(source, &scope), Layout { size: 12, align: 4 });
+        assert_eq!(
+            layout("{ a: bool, b: bool }", &scope),
+            Layout { size: 2, align: 1 }
+        );
+    }
+
+    #[test]
+    fn nested_structs_are_stored_in_place() {
+        let mut scope = Scope::new();
+        scope.set_type("Inner", typ("{ a: bool, b: int }"));
+        assert_eq!(layout("Inner", &scope), Layout { size: 8, align: 4 });
+
+        let source = "{ x: bool, inner: Inner, y: bool }";
+        assert_eq!(
+            offsets(source, &scope),
+            [
+                ("inner".to_string(), 0),
+                ("x".to_string(), 8),
+                ("y".to_string(), 9)
+            ]
+        );
+        assert_eq!(layout(source, &scope), Layout { size: 12, align: 4 });
+        assert_eq!(layout("Inner[3]", &scope), Layout { size: 24, align: 4 });
+    }
+
+    #[test]
+    fn pointers_are_words() {
+        let mut scope = Scope::new();
+        scope.set_type("Node", typ("{ value: int, next: &Node }"));
+        assert_eq!(layout("&bool", &scope), Layout::WORD);
+        assert_eq!(layout("&{ a: bool, b: int[4] }", &scope), Layout::WORD);
+        assert_eq!(layout("Node", &scope), Layout { size: 8, align: 4 });
+        assert_eq!(
+            reference_offsets(&typ("Node"), Span::default(), &scope),
+            Ok(vec![0])
+        );
+
+        // Without the pointer the type would contain itself
+        scope.set_type("Loop", typ("{ value: int, next: Loop }"));
+        assert_eq!(
+            layout_of(&typ("Loop"), Span::default(), &scope),
+            Err(CodegenErrorKind::RecursiveType("Loop".to_string()).at(Span::default()))
+        );
+    }
+}
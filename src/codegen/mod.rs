@@ -0,0 +1,975 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    lexer::span::{Span, Spanned},
+    parser::{
+        expressions::{Expression, ExpressionKind},
+        statements::{Block, StatementKind},
+        types::Type,
+    },
+    type_checker::{
+        expressions::{check_expr, infer_function_type_signature},
+        types::check_type,
+        Scope,
+    },
+    wasm::{
+        self, Data, Element, Export, ExportType, FunctionType, Global, GlobalType, Import,
+        ImportType, Limits, MemArg, MemoryOp, NumType, RefType, TableType, WasmModule,
+    },
+};
+
+use self::{
+    closures::{box_parameters, shared_variables},
+    expressions::lower_expr_as,
+    layout::is_reference,
+    runtime::{link_runtime, runtime_functions, Runtime},
+    statements::{lower_block, lower_statement},
+    types::wasm_type,
+};
+
+pub mod closures;
+pub mod expressions;
+pub mod layout;
+pub mod runtime;
+pub mod statements;
+pub mod types;
+
+// The size of a page of wasm memory
+const PAGE_SIZE: u32 = 1 << 16;
+
+pub type CodegenError = Spanned<CodegenErrorKind>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum CodegenErrorKind {
+    // A language feature the code generator can't lower yet, eg. "string literals"
+    Unsupported(String),
+    IntegerOutOfRange(i64),
+    // `break` or `continue` used outside of a loop
+    OutsideOfLoop(&'static str),
+    // A named type stored inside itself rather than behind a pointer
+    RecursiveType(String),
+    // A type bigger than the 4GiB a wasm memory can hold
+    TooLarge,
+}
+
+impl CodegenErrorKind {
+    pub fn at(self, span: Span) -> CodegenError {
+        Spanned::new(self, span)
+    }
+}
+
+// helper function for the common case of a feature that can't be lowered yet
+pub fn unsupported(what: &str, span: Span) -> CodegenError {
+    CodegenErrorKind::Unsupported(what.to_string()).at(span)
+}
+
+/// A function provided by the host, imported from the `env` module.
+pub struct Builtin {
+    pub name: &'static str,
+    pub args: Vec<Type>,
+    pub ret: Type,
+}
+
+pub fn builtins() -> Vec<Builtin> {
+    vec![Builtin {
+        name: "print_int",
+        args: vec![Type::Int],
+        ret: Type::Void,
+    }]
+}
+
+/// Makes the builtins and the runtime's functions visible to the type checker.
+pub fn declare_builtins(scope: &mut Scope) {
+    for builtin in builtins().into_iter().chain(runtime_functions()) {
+        let typ = Type::Function {
+            args: builtin.args.into_iter().map(Box::new).collect(),
+            ret: Box::new(builtin.ret),
+        };
+        scope.set_var(builtin.name, typ);
+    }
+}
+
+/// The wasm signature of a function that can be called directly.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FunctionSignature {
+    pub index: u32,
+    pub args: Vec<NumType>,
+    pub ret: Option<NumType>,
+}
+
+/// Module wide state shared by the functions being generated.
+pub struct Codegen {
+    pub module: WasmModule,
+    // Type definitions and the types of top level variables, used to resolve types
+    pub scope: Scope,
+    pub functions: HashMap<String, FunctionSignature>,
+    // The index and type of each top level variable
+    pub globals: HashMap<String, (u32, NumType)>,
+    pub runtime: Runtime,
+    // The globals holding references, which are roots of the garbage collector
+    pub roots: Vec<u32>,
+    // The address of each string literal in memory, so each is only stored once
+    strings: HashMap<String, u32>,
+    // The address of each list of reference offsets given to the garbage collector
+    references: HashMap<Vec<u32>, u32>,
+    // The first free address after the data segments
+    data_end: u32,
+    // The number of functions declared so far, imported ones first as in the wasm index space
+    function_count: u32,
+    // The functions used as values, in the order of their slots in the function table
+    table: Vec<u32>,
+    // The address of the closure record of each top level function used as a value
+    function_values: HashMap<u32, u32>,
+    // The address of the closure record without an environment for each table slot
+    records: HashMap<u32, u32>,
+    // The type of the values thrown with each tag, in the order of the tag section
+    tags: Vec<Type>,
+    // Closures and adapters generated while lowering other functions, which are added to the
+    // module after every function declared up front
+    lifted: Vec<GeneratedFunction>,
+}
+
+impl Default for Codegen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Codegen {
+    pub fn new() -> Self {
+        Self {
+            module: WasmModule::default(),
+            scope: Scope::new(),
+            functions: HashMap::new(),
+            globals: HashMap::new(),
+            runtime: Runtime::default(),
+            roots: Vec::new(),
+            strings: HashMap::new(),
+            references: HashMap::new(),
+            // Address 0 is null, so nothing is stored there
+            data_end: 4,
+            function_count: 0,
+            table: Vec::new(),
+            function_values: HashMap::new(),
+            records: HashMap::new(),
+            tags: Vec::new(),
+            lifted: Vec::new(),
+        }
+    }
+
+    /// The index of `typ` in the type section, adding it if it isn't there yet.
+    pub fn type_index(&mut self, typ: FunctionType) -> u32 {
+        match self.module.types.iter().position(|t| *t == typ) {
+            Some(index) => index as u32,
+            None => {
+                self.module.types.push(typ);
+                self.module.types.len() as u32 - 1
+            }
+        }
+    }
+
+    /// Gives `name` the next function index. Imports must be declared before any other functions.
+    pub fn declare_function(
+        &mut self,
+        name: &str,
+        mut signature: FunctionSignature,
+    ) -> FunctionSignature {
+        signature.index = self.next_function_index();
+        self.functions.insert(name.to_string(), signature.clone());
+        self.module
+            .names
+            .functions
+            .push((signature.index, name.to_string()));
+        signature
+    }
+
+    fn next_function_index(&mut self) -> u32 {
+        self.function_count += 1;
+        self.function_count - 1
+    }
+
+    /// The slot of the function with the given index in the function table, adding it if it
+    /// isn't there yet. Slot 0 is left empty, so calling a null function traps.
+    pub fn table_slot(&mut self, index: u32) -> u32 {
+        match self.table.iter().position(|i| *i == index) {
+            Some(position) => position as u32 + 1,
+            None => {
+                self.table.push(index);
+                self.table.len() as u32
+            }
+        }
+    }
+
+    /// The address of a closure record in a data segment for the function in table slot
+    /// `slot`, for functions without an environment.
+    pub fn closure_record(&mut self, slot: u32) -> u32 {
+        if let Some(address) = self.records.get(&slot) {
+            return *address;
+        }
+
+        let mut bytes = slot.to_le_bytes().to_vec();
+        bytes.extend(0u32.to_le_bytes());
+        let address = self.add_data(bytes);
+        self.records.insert(slot, address);
+        address
+    }
+
+    /// The tag values of type `typ`, stored as `wasm_typ`, are yeeted with, adding it if it
+    /// isn't there yet. Each type gets its own tag so a `catch` only gets values of its type.
+    pub fn tag(&mut self, typ: &Type, wasm_typ: NumType) -> u32 {
+        if let Some(index) = self.tags.iter().position(|t| t == typ) {
+            return index as u32;
+        }
+
+        let type_idx = self.type_index(FunctionType {
+            args: vec![wasm_typ],
+            ret: Vec::new(),
+        });
+        self.module.tags.push(wasm::Tag { type_idx });
+        self.tags.push(typ.clone());
+        self.tags.len() as u32 - 1
+    }
+
+    /// Adds a mutable global for the variable `name`, shadowing any previous global with that name.
+    pub fn declare_global(&mut self, name: &str, typ: NumType, init: wasm::Instruction) -> u32 {
+        self.module.globals.push(Global {
+            typ: GlobalType { typ, mutable: true },
+            init: wasm::Expression {
+                instructions: vec![init],
+            },
+        });
+        let index = self.module.globals.len() as u32 - 1;
+        self.globals.insert(name.to_string(), (index, typ));
+        index
+    }
+
+    /// Stores a string literal in memory, returning the address of its first byte.
+    /// Strings are preceded by their length in bytes as an `i32`.
+    pub fn add_string(&mut self, string: &str) -> u32 {
+        if let Some(address) = self.strings.get(string) {
+            return *address;
+        }
+
+        let mut bytes = (string.len() as i32).to_le_bytes().to_vec();
+        bytes.extend(string.as_bytes());
+        let address = self.add_data(bytes) + 4;
+        self.strings.insert(string.to_string(), address);
+        address
+    }
+
+    /// Stores the offsets of the references in a heap block for the garbage collector,
+    /// returning the address the collector is given, or 0 if there are none.
+    /// The offsets are preceded by how many there are.
+    pub fn add_references(&mut self, offsets: &[u32]) -> u32 {
+        if offsets.is_empty() {
+            return 0;
+        }
+        if let Some(address) = self.references.get(offsets) {
+            return *address;
+        }
+
+        let mut bytes = (offsets.len() as i32).to_le_bytes().to_vec();
+        bytes.extend(offsets.iter().flat_map(|offset| offset.to_le_bytes()));
+        let address = self.add_data(bytes);
+        self.references.insert(offsets.to_vec(), address);
+        address
+    }
+
+    // Adds a data segment with the bytes at the next word aligned address, returning it
+    fn add_data(&mut self, bytes: Vec<u8>) -> u32 {
+        let start = self.data_end.next_multiple_of(4);
+        self.data_end = start + bytes.len() as u32;
+        self.module.data.push(Data {
+            memory: 0,
+            offset: wasm::Expression {
+                instructions: vec![wasm::Instruction::I32Const(start as i32)],
+            },
+            bytes,
+        });
+        start
+    }
+
+    fn declare_builtin(&mut self, builtin: Builtin) -> Result<(), CodegenError> {
+        let signature = self.signature(&builtin.args, &builtin.ret, Span::default())?;
+        let type_idx = self.type_index(signature.to_function_type());
+        self.module.imports.push(Import {
+            module: "env".to_string(),
+            name: builtin.name.to_string(),
+            typ: ImportType::Func(type_idx),
+        });
+        self.declare_function(builtin.name, signature);
+        Ok(())
+    }
+
+    // Builds the signature of a function with the given argument and return types,
+    // its index is given when it is declared
+    fn signature(
+        &self,
+        args: &[Type],
+        ret: &Type,
+        span: Span,
+    ) -> Result<FunctionSignature, CodegenError> {
+        let mut wasm_args = Vec::new();
+        for arg in args {
+            match wasm_type(arg, span, &self.scope)? {
+                Some(arg) => wasm_args.push(arg),
+                None => return Err(unsupported("`void` arguments", span)),
+            }
+        }
+        Ok(FunctionSignature {
+            index: 0,
+            args: wasm_args,
+            ret: wasm_type(ret, span, &self.scope)?,
+        })
+    }
+}
+
+impl FunctionSignature {
+    pub fn to_function_type(&self) -> FunctionType {
+        FunctionType {
+            args: self.args.clone(),
+            ret: self.ret.into_iter().collect(),
+        }
+    }
+}
+
+/// The file and span each instruction in each function was generated from, with the
+/// instructions of a function numbered as `validator::Location` does.
+pub type SourcePositions = Vec<Vec<(usize, Span)>>;
+
+/// A function generated from the source, ready to be added to the module.
+pub struct GeneratedFunction {
+    pub index: u32,
+    pub function: wasm::Function,
+    // The position of each instruction, in the order they appear
+    pub positions: Vec<(usize, Span)>,
+    pub local_names: Vec<(u32, String)>,
+}
+
+/// Lowers the type checked files of a program to a single wasm module. Every top level
+/// function is exported under its name, as are the runtime's functions, and the module's
+/// memory as `memory`. Top level variables become globals, initialised along with the rest
+/// of the top level statements by the start function. Errors are returned along with the
+/// index of the file they were found in. With `gc`, heap allocations are freed by a
+/// garbage collector once they can't be reached.
+pub fn generate_module(
+    files: &[Block],
+    gc: bool,
+) -> Result<(WasmModule, SourcePositions), Vec<(usize, CodegenError)>> {
+    let mut codegen = Codegen::new();
+    let mut positions = Vec::new();
+    let mut errors = Vec::new();
+
+    declare_builtins(&mut codegen.scope);
+    for builtin in builtins() {
+        if let Err(err) = codegen.declare_builtin(builtin) {
+            errors.push((0, err));
+        }
+    }
+
+    codegen.module.memories.push(Limits { min: 1, max: None });
+    codegen.module.exports.push(Export {
+        name: "memory".to_string(),
+        typ: ExportType::Memory(0),
+    });
+    codegen.runtime = link_runtime(&mut codegen, gc);
+    let runtime_exports = codegen.module.exports.len();
+    // The runtime has no source to map back to
+    positions.resize(codegen.module.functions.len(), Vec::new());
+
+    // Functions and globals are declared before any code is generated, so functions can
+    // use each other in any order
+    let mut bodies = Vec::new();
+    // The statements run by the start function, with the global and type of each variable
+    let mut start = Vec::new();
+    for (file, statement) in files
+        .iter()
+        .enumerate()
+        .flat_map(|(file, program)| program.iter().map(move |statement| (file, statement)))
+    {
+        match &statement.node {
+            StatementKind::TypeDef { name, typ } => {
+                codegen.scope.set_type(name, typ.node.clone());
+                let resolved = check_type(&typ.node, typ.span, &codegen.scope);
+                codegen
+                    .scope
+                    .set_type(name, resolved.unwrap_or(Type::Error));
+            }
+            StatementKind::VarDef { name, expr, .. } if is_function_literal(expr) => {
+                let ExpressionKind::FunctionLiteral { args, ret, body } = &expr.node else {
+                    unreachable!()
+                };
+                if let Ok(typ) = infer_function_type_signature(expr, &codegen.scope) {
+                    codegen.scope.set_var(name, typ);
+                }
+                let arg_types = args
+                    .iter()
+                    .map(|(_, typ)| typ.node.clone())
+                    .collect::<Vec<_>>();
+                let signature = match codegen.signature(&arg_types, &ret.node, expr.span) {
+                    Ok(signature) => codegen.declare_function(name, signature),
+                    Err(err) => {
+                        errors.push((file, err));
+                        continue;
+                    }
+                };
+                // Names the runtime exports stay bound to the runtime, and a redeclared function
+                // is only exported under its last definition
+                let exports = &mut codegen.module.exports;
+                if !exports[..runtime_exports]
+                    .iter()
+                    .any(|export| export.name == *name)
+                {
+                    exports.retain(|export| export.name != *name);
+                    exports.push(Export {
+                        name: name.clone(),
+                        typ: ExportType::Func(signature.index),
+                    });
+                }
+                bodies.push((file, name, signature, args, ret, body, expr.span));
+            }
+            StatementKind::VarDef { name, typ, expr } => {
+                let typ = match typ {
+                    Some(typ) => check_type(&typ.node, typ.span, &codegen.scope),
+                    None => check_expr(expr, &codegen.scope, &mut Vec::new()),
+                }
+                .unwrap_or(Type::Error);
+                codegen.scope.set_var(name, typ.clone());
+
+                let wasm_typ = match wasm_type(&typ, expr.span, &codegen.scope) {
+                    Ok(Some(wasm_typ)) => wasm_typ,
+                    Ok(None) => {
+                        errors.push((file, unsupported("`void` variables", statement.span)));
+                        continue;
+                    }
+                    Err(err) => {
+                        errors.push((file, err));
+                        continue;
+                    }
+                };
+                // Constants are stored straight in the global rather than set by the start function
+                let init = constant(expr);
+                let global = codegen.declare_global(
+                    name,
+                    wasm_typ,
+                    init.clone().unwrap_or_else(|| zero(wasm_typ)),
+                );
+                if gc && is_reference(&typ, expr.span, &codegen.scope).unwrap_or(false) {
+                    codegen.roots.push(global);
+                }
+                if init.is_none() {
+                    start.push((file, statement, Some((global, typ))));
+                }
+            }
+            _ => start.push((file, statement, None)),
+        }
+    }
+
+    let start_index = (!start.is_empty()).then(|| codegen.next_function_index());
+
+    for (file, name, signature, args, ret, body, span) in bodies {
+        let type_idx = codegen.type_index(signature.to_function_type());
+        let mut function = FunctionContext::new(&mut codegen, signature.ret);
+        function.file = file;
+        function.span = span;
+        function.name = name.clone();
+        function.ret_type = check_type(&ret.node, ret.span, &function.scope).unwrap_or(Type::Error);
+        for ((name, typ), wasm_typ) in args.iter().zip(signature.args.iter()) {
+            function.declare_local(name, typ.node.clone(), *wasm_typ);
+        }
+        function.shared = shared_variables(args, body);
+
+        let result =
+            box_parameters(args, &mut function).and_then(|_| lower_block(body, &mut function));
+        if let Err(err) = result {
+            errors.push((file, err));
+            continue;
+        }
+        let generated = function.finish(signature.index, type_idx, args.len());
+        add_function(&mut codegen, &mut positions, generated);
+    }
+
+    if let Some(start_index) = start_index {
+        let type_idx = codegen.type_index(FunctionType {
+            args: vec![],
+            ret: vec![],
+        });
+        let mut function = FunctionContext::new(&mut codegen, None);
+        function.name = "start".to_string();
+        for (file, statement, global) in start {
+            function.file = file;
+            (function.shared).extend(shared_variables(&[], std::slice::from_ref(statement)));
+            let result = match (&statement.node, global) {
+                (StatementKind::VarDef { expr, .. }, Some((global, typ))) => {
+                    function.at(statement.span, |function| {
+                        lower_expr_as(expr, &typ, function).map(|_| {
+                            function.emit(wasm::Instruction::VariableOp(
+                                wasm::VariableOp::GlobalSet(global),
+                            ))
+                        })
+                    })
+                }
+                _ => lower_statement(statement, &mut function),
+            };
+            if let Err(err) = result {
+                errors.push((file, err));
+            }
+        }
+        let generated = function.finish(start_index, type_idx, 0);
+        add_function(&mut codegen, &mut positions, generated);
+        codegen.module.start = Some(start_index);
+    }
+
+    // Lifted functions were given indices as they were found, after everything else
+    let mut lifted = std::mem::take(&mut codegen.lifted);
+    lifted.sort_by_key(|generated| generated.index);
+    for generated in lifted {
+        add_function(&mut codegen, &mut positions, generated);
+    }
+
+    if let Some(collector) = codegen.runtime.collector {
+        let imported = (codegen.module.imports.iter())
+            .filter(|import| matches!(import.typ, ImportType::Func(_)))
+            .count();
+        // Calls from the host go through an entry that first pops the frames left behind by
+        // calls an exception or trap unwound to the host, which never reached their `leave`
+        for export in runtime_exports..codegen.module.exports.len() {
+            let ExportType::Func(index) = codegen.module.exports[export].typ else {
+                continue;
+            };
+            let name = format!("{}.entry", codegen.module.exports[export].name);
+            let type_idx = codegen.module.functions[index as usize - imported].type_idx;
+            let params = codegen.module.types[type_idx as usize].args.len() as u32;
+            let entry = codegen.next_function_index();
+            (codegen.module.names.functions).push((entry, name));
+            let mut instructions = vec![wasm::Instruction::ControlOp(wasm::ControlOp::Call(
+                collector.reset,
+            ))];
+            instructions.extend(
+                (0..params)
+                    .map(|arg| wasm::Instruction::VariableOp(wasm::VariableOp::LocalGet(arg))),
+            );
+            instructions.push(wasm::Instruction::ControlOp(wasm::ControlOp::Call(index)));
+            let generated = GeneratedFunction {
+                index: entry,
+                function: wasm::Function {
+                    type_idx,
+                    locals: Vec::new(),
+                    body: wasm::Expression { instructions },
+                },
+                positions: Vec::new(),
+                local_names: Vec::new(),
+            };
+            add_function(&mut codegen, &mut positions, generated);
+            codegen.module.exports[export].typ = ExportType::Func(entry);
+        }
+
+        let mark_globals = collector.mark_globals - imported as u32;
+        let body = &mut codegen.module.functions[mark_globals as usize].body;
+        for global in codegen.roots.iter() {
+            body.instructions.extend([
+                wasm::Instruction::VariableOp(wasm::VariableOp::GlobalGet(*global)),
+                wasm::Instruction::ControlOp(wasm::ControlOp::Call(collector.mark)),
+            ]);
+        }
+    }
+
+    let heap_start = codegen.data_end.next_multiple_of(4);
+    codegen.module.globals[codegen.runtime.data_end as usize].init = wasm::Expression {
+        instructions: vec![wasm::Instruction::I32Const(heap_start as i32)],
+    };
+    // The memory has to hold the data segments when the module is instantiated, and the 64KiB
+    // shadow stack the garbage collector puts after them. The heap grows it from there
+    let shadow_stack = if gc { 0x10000 } else { 0 };
+    codegen.module.memories[0].min = (heap_start + shadow_stack).div_ceil(PAGE_SIZE).max(1);
+
+    if !codegen.table.is_empty() {
+        let size = codegen.table.len() as u32 + 1;
+        codegen.module.tables.push(TableType {
+            element: RefType::FuncRef,
+            limits: Limits {
+                min: size,
+                max: Some(size),
+            },
+        });
+        codegen.module.elements.push(Element {
+            table: 0,
+            offset: wasm::Expression {
+                instructions: vec![wasm::Instruction::I32Const(1)],
+            },
+            functions: std::mem::take(&mut codegen.table),
+        });
+    }
+
+    if errors.is_empty() {
+        Ok((codegen.module, positions))
+    } else {
+        Err(errors)
+    }
+}
+
+fn add_function(
+    codegen: &mut Codegen,
+    positions: &mut SourcePositions,
+    generated: GeneratedFunction,
+) {
+    positions.push(generated.positions);
+    if !generated.local_names.is_empty() {
+        (codegen.module.names.locals).push((generated.index, generated.local_names));
+    }
+    codegen.module.functions.push(generated.function);
+}
+
+// Reorders the positions of instructions from the order they were emitted, where the body of a
+// block is emitted before the block itself, to the order they appear in.
+fn in_order(instructions: &[wasm::Instruction], emitted: Vec<(usize, Span)>) -> Vec<(usize, Span)> {
+    fn visit(
+        instructions: &[wasm::Instruction],
+        emitted: &mut impl Iterator<Item = (usize, Span)>,
+        positions: &mut Vec<(usize, Span)>,
+    ) {
+        for instruction in instructions {
+            let mut nested = Vec::new();
+            match instruction {
+                wasm::Instruction::ControlOp(
+                    wasm::ControlOp::Block { body, .. } | wasm::ControlOp::Loop { body, .. },
+                ) => visit(&body.instructions, emitted, &mut nested),
+                wasm::Instruction::ControlOp(wasm::ControlOp::If {
+                    then, otherwise, ..
+                }) => {
+                    visit(&then.instructions, emitted, &mut nested);
+                    if let Some(otherwise) = otherwise {
+                        visit(&otherwise.instructions, emitted, &mut nested);
+                    }
+                }
+                wasm::Instruction::ControlOp(wasm::ControlOp::Try {
+                    body,
+                    catches,
+                    catch_all,
+                    ..
+                }) => {
+                    visit(&body.instructions, emitted, &mut nested);
+                    for catch in catches {
+                        visit(&catch.body.instructions, emitted, &mut nested);
+                    }
+                    if let Some(catch_all) = catch_all {
+                        visit(&catch_all.instructions, emitted, &mut nested);
+                    }
+                }
+                _ => {}
+            }
+            positions.push(emitted.next().unwrap_or_default());
+            positions.append(&mut nested);
+        }
+    }
+    let mut positions = Vec::new();
+    visit(instructions, &mut emitted.into_iter(), &mut positions);
+    positions
+}
+
+fn is_function_literal(expr: &Expression) -> bool {
+    matches!(expr.node, ExpressionKind::FunctionLiteral { .. })
+}
+
+// The instruction pushing the value of `expr` if it is a literal, which globals can be initialised with
+fn constant(expr: &Expression) -> Option<wasm::Instruction> {
+    match &expr.node {
+        ExpressionKind::Int(val) => i32::try_from(*val).ok().map(wasm::Instruction::I32Const),
+        ExpressionKind::Float(val) => Some(wasm::Instruction::F32Const(*val as f32)),
+        ExpressionKind::Bool(val) => Some(wasm::Instruction::I32Const(*val as i32)),
+        ExpressionKind::Char(val) => Some(wasm::Instruction::I32Const(*val as i32)),
+        _ => None,
+    }
+}
+
+fn zero(typ: NumType) -> wasm::Instruction {
+    match typ {
+        NumType::I32 => wasm::Instruction::I32Const(0),
+        NumType::I64 => wasm::Instruction::I64Const(0),
+        NumType::F32 => wasm::Instruction::F32Const(0.0),
+        NumType::F64 => wasm::Instruction::F64Const(0.0),
+    }
+}
+
+/// The state of the function currently being generated.
+pub struct FunctionContext<'a> {
+    pub codegen: &'a mut Codegen,
+    // The name of the function, which closures in it are named after
+    pub name: String,
+    // The types and variables visible in the function, to find the type of expressions
+    pub scope: Scope,
+    pub ret: Option<NumType>,
+    // The type of the function's return value in the language, which values are converted to
+    pub ret_type: Type,
+    // The types of the function's parameters followed by its locals
+    pub locals: Vec<NumType>,
+    // The local each variable is stored in, one map per nested block
+    pub vars: Vec<HashMap<String, u32>>,
+    // The variables stored in heap cells because closures share them, see `closures`
+    pub shared: HashSet<String>,
+    // The locals holding the address of a shared variable's cell, with the type of its value
+    pub boxed: HashMap<u32, NumType>,
+    // The number of closures generated in the function so far
+    closures: u32,
+    // The name of the variable in each local, for the name section
+    pub local_names: Vec<(u32, String)>,
+    // The blocks enclosing the instructions being generated, innermost last
+    pub targets: Vec<BranchTarget>,
+    pub instructions: Vec<wasm::Instruction>,
+    // The file and span of the code being lowered, which instructions are mapped back to
+    pub file: usize,
+    pub span: Span,
+    // The position of every instruction, in the order they were emitted
+    pub positions: Vec<(usize, Span)>,
+    // With a garbage collector, the local holding the address of the function's frame on the
+    // shadow stack, and the local whose value is kept in each slot of the frame
+    frame: Option<u32>,
+    slots: Vec<u32>,
+}
+
+/// What branching to a block does, used to find the blocks `break` and `continue` branch to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BranchTarget {
+    Block,
+    // Branching out of the block exits the loop
+    Break,
+    // Branching to the block starts the next iteration
+    Continue,
+}
+
+impl<'a> FunctionContext<'a> {
+    pub fn new(codegen: &'a mut Codegen, ret: Option<NumType>) -> Self {
+        Self {
+            scope: codegen.scope.clone(),
+            codegen,
+            name: String::new(),
+            ret,
+            ret_type: Type::Void,
+            locals: Vec::new(),
+            vars: vec![HashMap::new()],
+            shared: HashSet::new(),
+            boxed: HashMap::new(),
+            closures: 0,
+            local_names: Vec::new(),
+            targets: Vec::new(),
+            instructions: Vec::new(),
+            file: 0,
+            span: Span::default(),
+            positions: Vec::new(),
+            frame: None,
+            slots: Vec::new(),
+        }
+    }
+
+    pub fn emit(&mut self, instruction: wasm::Instruction) {
+        self.instructions.push(instruction);
+        self.positions.push((self.file, self.span));
+    }
+
+    /// Runs `lower` with the instructions it emits mapped back to `span`.
+    pub fn at<T>(&mut self, span: Span, lower: impl FnOnce(&mut Self) -> T) -> T {
+        let outer = std::mem::replace(&mut self.span, span);
+        let result = lower(self);
+        self.span = outer;
+        result
+    }
+
+    /// Allocates a new local for the variable `name`, shadowing any previous variable with that name.
+    pub fn declare_local(&mut self, name: &str, typ: Type, wasm_typ: NumType) -> u32 {
+        let reference = is_reference(&typ, self.span, &self.scope).unwrap_or(false);
+        self.scope.set_var(name, typ);
+        self.locals.push(wasm_typ);
+        let index = self.locals.len() as u32 - 1;
+        if reference {
+            self.root(index);
+        }
+        self.local_names.push((index, name.to_string()));
+        self.vars
+            .last_mut()
+            .unwrap()
+            .insert(name.to_string(), index);
+        index
+    }
+
+    /// Allocates a new local for the shared variable `name`, which holds the address of the
+    /// cell its value of wasm type `value` is stored in.
+    pub fn declare_boxed(&mut self, name: &str, typ: Type, value: NumType) -> u32 {
+        let index = self.declare_local(name, typ, NumType::I32);
+        self.boxed.insert(index, value);
+        self.root(index);
+        index
+    }
+
+    /// Allocates a new local that no variable is stored in, for keeping intermediate values.
+    pub fn temporary(&mut self, wasm_typ: NumType) -> u32 {
+        self.locals.push(wasm_typ);
+        self.locals.len() as u32 - 1
+    }
+
+    /// Allocates a new local for keeping a reference, which the garbage collector will find.
+    pub fn reference_temporary(&mut self) -> u32 {
+        let index = self.temporary(NumType::I32);
+        self.root(index);
+        index
+    }
+
+    /// Sets the local to the value on top of the stack.
+    pub fn set_local(&mut self, index: u32) {
+        self.emit(wasm::Instruction::VariableOp(wasm::VariableOp::LocalSet(
+            index,
+        )));
+        self.save_root(index);
+    }
+
+    /// Sets the local to the value on top of the stack, leaving the value there.
+    pub fn tee_local(&mut self, index: u32) {
+        self.emit(wasm::Instruction::VariableOp(wasm::VariableOp::LocalTee(
+            index,
+        )));
+        self.save_root(index);
+    }
+
+    // With a garbage collector, keeps the value of the local in a slot of the function's frame
+    fn root(&mut self, index: u32) {
+        if self.codegen.runtime.collector.is_some() && !self.slots.contains(&index) {
+            self.slots.push(index);
+        }
+    }
+
+    // Copies a rooted local to its slot, so the garbage collector sees its new value
+    fn save_root(&mut self, index: u32) {
+        let Some(slot) = self.slots.iter().position(|local| *local == index) else {
+            return;
+        };
+        let frame = self.frame();
+        self.emit(wasm::Instruction::VariableOp(wasm::VariableOp::LocalGet(
+            frame,
+        )));
+        self.emit(wasm::Instruction::VariableOp(wasm::VariableOp::LocalGet(
+            index,
+        )));
+        self.emit(wasm::Instruction::MemoryOp(MemoryOp::I32Store(
+            MemArg::natural(4, slot as u32 * 4),
+        )));
+    }
+
+    // The local holding the address of the frame, allocated when it's first needed
+    fn frame(&mut self) -> u32 {
+        match self.frame {
+            Some(frame) => frame,
+            None => {
+                let frame = self.temporary(NumType::I32);
+                self.frame = Some(frame);
+                frame
+            }
+        }
+    }
+
+    /// With a garbage collector, pushes the function's frame onto the shadow stack when it is
+    /// called. Called once the whole function has been generated, to know how big it is.
+    pub fn enter_frame(&mut self) {
+        let Some(collector) = self.codegen.runtime.collector else {
+            return;
+        };
+        let frame = self.frame();
+        let mut prologue = vec![
+            wasm::Instruction::I32Const(self.slots.len() as i32),
+            wasm::Instruction::ControlOp(wasm::ControlOp::Call(collector.enter)),
+            wasm::Instruction::VariableOp(wasm::VariableOp::LocalSet(frame)),
+        ];
+        // Parameters already hold references, other locals start out as null
+        for (slot, local) in self.slots.iter().enumerate() {
+            prologue.extend([
+                wasm::Instruction::VariableOp(wasm::VariableOp::LocalGet(frame)),
+                wasm::Instruction::VariableOp(wasm::VariableOp::LocalGet(*local)),
+                wasm::Instruction::MemoryOp(MemoryOp::I32Store(MemArg::natural(
+                    4,
+                    slot as u32 * 4,
+                ))),
+            ]);
+        }
+        self.positions
+            .splice(0..0, prologue.iter().map(|_| (self.file, Span::default())));
+        self.instructions.splice(0..0, prologue);
+    }
+
+    /// With a garbage collector, pops the function's frame off the shadow stack before it returns.
+    pub fn leave_frame(&mut self) {
+        let Some(collector) = self.codegen.runtime.collector else {
+            return;
+        };
+        let frame = self.frame();
+        self.emit(wasm::Instruction::VariableOp(wasm::VariableOp::LocalGet(
+            frame,
+        )));
+        self.emit(wasm::Instruction::ControlOp(wasm::ControlOp::Call(
+            collector.leave,
+        )));
+    }
+
+    /// Ends the function with index `index`, whose first `params` locals are its parameters.
+    pub fn finish(mut self, index: u32, type_idx: u32, params: usize) -> GeneratedFunction {
+        if self.ret.is_some() {
+            // The type checker doesn't check every path returns, so trap if one doesn't
+            self.emit(wasm::Instruction::ControlOp(wasm::ControlOp::Unreachable));
+        } else {
+            self.leave_frame();
+        }
+        self.enter_frame();
+        let locals = self.locals.split_off(params);
+        GeneratedFunction {
+            index,
+            positions: in_order(&self.instructions, self.positions),
+            function: wasm::Function {
+                type_idx,
+                locals,
+                body: wasm::Expression {
+                    instructions: self.instructions,
+                },
+            },
+            local_names: self.local_names,
+        }
+    }
+
+    pub fn get_local(&self, name: &str) -> Option<u32> {
+        self.vars
+            .iter()
+            .rev()
+            .find_map(|vars| vars.get(name).copied())
+    }
+
+    /// The type of `expr`, which has already been checked.
+    pub fn type_of(&self, expr: &Expression) -> Type {
+        check_expr(expr, &self.scope, &mut Vec::new()).unwrap_or(Type::Error)
+    }
+
+    /// Generates the body of a nested block with `lower`, with its own variable scope.
+    pub fn lower_nested(
+        &mut self,
+        target: BranchTarget,
+        lower: impl FnOnce(&mut Self) -> Result<(), CodegenError>,
+    ) -> Result<wasm::Expression, CodegenError> {
+        let outer = std::mem::take(&mut self.instructions);
+        let outer_scope = self.scope.clone();
+        self.vars.push(HashMap::new());
+        self.targets.push(target);
+
+        let result = lower(self);
+
+        self.targets.pop();
+        self.vars.pop();
+        self.scope = outer_scope;
+        let instructions = std::mem::replace(&mut self.instructions, outer);
+        result.map(|()| wasm::Expression { instructions })
+    }
+
+    /// The label of the innermost block that is a `target`, counted outwards from the
+    /// innermost block as branch instructions expect.
+    pub fn branch_depth(&self, target: BranchTarget) -> Option<u32> {
+        self.targets
+            .iter()
+            .rev()
+            .position(|t| *t == target)
+            .map(|depth| depth as u32)
+    }
+}
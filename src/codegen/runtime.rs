@@ -0,0 +1,209 @@
+//! Support code linked into every compiled module, written in the text format in
+//! `runtime.wat`, or `gc.wat` when compiling with a garbage collector. Its functions are
+//! declared right after the builtins, so they come before the program's in the function
+//! index space, and are exported under the same names.
+
+use std::collections::HashMap;
+
+use crate::{
+    parser::types::Type,
+    wasm::{
+        self, wat::parser::parse_module, BlockType, ControlOp, Export, ExportType, Instruction,
+        RefOp, VariableOp,
+    },
+};
+
+use super::{Builtin, Codegen, FunctionSignature};
+
+const RUNTIME: &str = include_str!("runtime.wat");
+const GC_RUNTIME: &str = include_str!("gc.wat");
+
+/// The runtime functions programs can call, with their types in the language.
+pub fn runtime_functions() -> Vec<Builtin> {
+    vec![
+        Builtin {
+            name: "malloc",
+            args: vec![Type::Int],
+            ret: Type::Ptr(Box::new(Type::Void)),
+        },
+        Builtin {
+            name: "free",
+            args: vec![Type::Ptr(Box::new(Type::Void))],
+            ret: Type::Void,
+        },
+    ]
+}
+
+/// Where the parts of the runtime the code generator uses ended up in the module.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Runtime {
+    pub malloc: u32,
+    pub free: u32,
+    // The global the runtime expects the end of the data segments in, where the heap starts
+    pub data_end: u32,
+    pub collector: Option<Collector>,
+}
+
+/// The functions of the garbage collecting runtime the generated code calls.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Collector {
+    // Allocates a block given its size and the address of the offsets of the references in it
+    pub alloc: u32,
+    // Pushes a frame with room for the given number of references onto the shadow stack
+    pub enter: u32,
+    // Pops the frame at the given address
+    pub leave: u32,
+    // The address `leave` pops back to so that only frames pushed since are popped
+    pub top: u32,
+    // Pops every frame, when the host calls into the module
+    pub reset: u32,
+    pub mark: u32,
+    // Marks the globals holding references, an empty function until the compiler fills it in
+    pub mark_globals: u32,
+}
+
+/// Adds the runtime's functions, globals and exports to the module being generated.
+pub fn link_runtime(codegen: &mut Codegen, gc: bool) -> Runtime {
+    let source = if gc { GC_RUNTIME } else { RUNTIME };
+    let runtime = parse_module(source).expect("the runtime is valid wat");
+
+    let types = (runtime.types.iter())
+        .map(|typ| codegen.type_index(typ.clone()))
+        .collect::<Vec<_>>();
+    let globals = codegen.module.globals.len() as u32;
+    codegen.module.globals.extend(runtime.globals);
+
+    // Declared before any code is relocated, so calls between runtime functions can be
+    let mut functions = Vec::new();
+    let mut by_name = HashMap::new();
+    for (index, function) in runtime.functions.iter().enumerate() {
+        let name = (runtime.names)
+            .function(index as u32)
+            .expect("runtime functions are named");
+        let typ = &runtime.types[function.type_idx as usize];
+        let signature = FunctionSignature {
+            index: 0,
+            args: typ.args.clone(),
+            ret: typ.ret.first().copied(),
+        };
+        functions.push(codegen.declare_function(name, signature).index);
+        by_name.insert(name, functions[index]);
+    }
+
+    for (index, mut function) in runtime.functions.into_iter().enumerate() {
+        function.type_idx = types[function.type_idx as usize];
+        relocate(&mut function.body, &functions, globals, &types);
+        codegen.module.functions.push(function);
+        if let Some(names) = (runtime.names.locals.iter()).find(|(i, _)| *i == index as u32) {
+            (codegen.module.names.locals).push((functions[index], names.1.clone()));
+        }
+    }
+
+    for export in runtime.exports {
+        if let ExportType::Func(index) = export.typ {
+            codegen.module.exports.push(Export {
+                name: export.name,
+                typ: ExportType::Func(functions[index as usize]),
+            });
+        }
+    }
+
+    let function = |name: &str| by_name[name];
+    Runtime {
+        malloc: function("malloc"),
+        free: function("free"),
+        // The runtime's first global
+        data_end: globals,
+        collector: gc.then(|| Collector {
+            alloc: function("gc_alloc"),
+            enter: function("enter"),
+            leave: function("leave"),
+            top: function("top"),
+            reset: function("reset"),
+            mark: function("mark"),
+            mark_globals: function("mark_globals"),
+        }),
+    }
+}
+
+// Moves the indices in `expression` from the runtime's index spaces to the module's
+fn relocate(expression: &mut wasm::Expression, functions: &[u32], globals: u32, types: &[u32]) {
+    let block_type = |typ: &mut BlockType| {
+        if let BlockType::Type(index) = typ {
+            *index = types[*index as usize];
+        }
+    };
+    for instruction in expression.instructions.iter_mut() {
+        match instruction {
+            Instruction::ControlOp(ControlOp::Call(index))
+            | Instruction::RefOp(RefOp::Func(index)) => *index = functions[*index as usize],
+            Instruction::ControlOp(ControlOp::CallIndirect { type_idx, .. }) => {
+                *type_idx = types[*type_idx as usize]
+            }
+            Instruction::VariableOp(
+                VariableOp::GlobalGet(index) | VariableOp::GlobalSet(index),
+            ) => *index += globals,
+            Instruction::ControlOp(
+                ControlOp::Block { typ, body } | ControlOp::Loop { typ, body },
+            ) => {
+                block_type(typ);
+                relocate(body, functions, globals, types);
+            }
+            Instruction::ControlOp(ControlOp::If {
+                typ,
+                then,
+                otherwise,
+            }) => {
+                block_type(typ);
+                relocate(then, functions, globals, types);
+                if let Some(otherwise) = otherwise {
+                    relocate(otherwise, functions, globals, types);
+                }
+            }
+            Instruction::ControlOp(ControlOp::Try {
+                typ,
+                body,
+                catches,
+                catch_all,
+            }) => {
+                block_type(typ);
+                relocate(body, functions, globals, types);
+                for catch in catches {
+                    relocate(&mut catch.body, functions, globals, types);
+                }
+                if let Some(catch_all) = catch_all {
+                    relocate(catch_all, functions, globals, types);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The body of the last function in the module
+    fn body(source: &str) -> wasm::Expression {
+        let module = parse_module(source).unwrap();
+        module.functions.last().unwrap().body.clone()
+    }
+
+    #[test]
+    fn relocates_inside_try() {
+        let mut relocated = body(
+            "(module (tag $e (param i32)) (global $g (mut i32) (i32.const 0)) (func $f)
+              (func (try (do (call $f))
+                         (catch $e (global.set $g))
+                         (catch_all (call $f) (global.set $g (global.get $g))))))",
+        );
+        relocate(&mut relocated, &[7, 8], 3, &[]);
+        let expected = body(
+            "(module (func (try (do (call 7))
+                                (catch 0 (global.set 3))
+                                (catch_all (call 7) (global.set 3 (global.get 3))))))",
+        );
+        assert_eq!(relocated, expected);
+    }
+}
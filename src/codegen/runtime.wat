@@ -0,0 +1,63 @@
+;; The runtime linked into every compiled module, see `runtime.rs`.
+;;
+;; The heap starts after the data segments and grows upwards. Each block on it is a word
+;; holding the size of the block, followed by that many bytes, and allocations point to the
+;; bytes. Freed blocks are kept in a list linked through their first word, and reused by
+;; later allocations that fit in them.
+(module $runtime
+  (memory 1)
+
+  ;; The end of the heap, which starts wherever the compiler puts the end of the data segments
+  (global $heap_end (mut i32) (i32.const 0))
+  ;; The most recently freed block, or 0 if there are none
+  (global $free_list (mut i32) (i32.const 0))
+
+  (func $malloc (param $size i32) (result i32)
+    (local $block i32)
+    (local $previous i32)
+    ;; Blocks are a whole number of words, and at least one to link them into the free list
+    (local.set $size (i32.and (i32.add (local.get $size) (i32.const 3)) (i32.const -4)))
+    (if (i32.eqz (local.get $size))
+      (then (local.set $size (i32.const 4))))
+
+    ;; Reuse the first freed block that is big enough
+    (local.set $block (global.get $free_list))
+    (block $not_found
+      (loop $next
+        (br_if $not_found (i32.eqz (local.get $block)))
+        (if (i32.ge_u (i32.load (i32.sub (local.get $block) (i32.const 4))) (local.get $size))
+          (then
+            (if (local.get $previous)
+              (then (i32.store (local.get $previous) (i32.load (local.get $block))))
+              (else (global.set $free_list (i32.load (local.get $block)))))
+            (return (local.get $block))))
+        (local.set $previous (local.get $block))
+        (local.set $block (i32.load (local.get $block)))
+        (br $next)))
+
+    ;; Otherwise take a new block from the end of the heap, growing the memory if it's full
+    (local.set $block (i32.add (global.get $heap_end) (i32.const 4)))
+    (global.set $heap_end (i32.add (local.get $block) (local.get $size)))
+    (if (i32.gt_u (global.get $heap_end) (i32.shl (memory.size) (i32.const 16)))
+      (then
+        (if (i32.eq
+              (memory.grow
+                (i32.shr_u
+                  (i32.sub
+                    (i32.add (global.get $heap_end) (i32.const 0xffff))
+                    (i32.shl (memory.size) (i32.const 16)))
+                  (i32.const 16)))
+              (i32.const -1))
+          (then unreachable))))
+    (i32.store (i32.sub (local.get $block) (i32.const 4)) (local.get $size))
+    (local.get $block))
+
+  ;; Freeing null does nothing, like in C
+  (func $free (param $pointer i32)
+    (if (local.get $pointer)
+      (then
+        (i32.store (local.get $pointer) (global.get $free_list))
+        (global.set $free_list (local.get $pointer)))))
+
+  (export "malloc" (func $malloc))
+  (export "free" (func $free)))
@@ -0,0 +1,289 @@
+use crate::{
+    parser::{
+        expressions::ExpressionKind,
+        statements::{Block, ElseStatement, Statement, StatementKind},
+        types::Type,
+    },
+    type_checker::types::check_type,
+    wasm::{
+        BlockType, Catch, ControlOp, Instruction, IntegerOp, IntegerOpType, IntegerType, NumType,
+        ParametricOp, VariableOp,
+    },
+};
+
+use super::{
+    closures::{box_variable, store_word},
+    expressions::{lower_address, lower_expr, lower_expr_as, lower_store},
+    layout::resolve,
+    types::wasm_type,
+    unsupported, BranchTarget, CodegenError, CodegenErrorKind, FunctionContext,
+};
+
+/// Emits the instructions for every statement in the block, stopping at the first error.
+pub fn lower_block(block: &Block, function: &mut FunctionContext) -> Result<(), CodegenError> {
+    for statement in block {
+        lower_statement(statement, function)?;
+    }
+    Ok(())
+}
+
+pub fn lower_statement(
+    statement: &Statement,
+    function: &mut FunctionContext,
+) -> Result<(), CodegenError> {
+    function.at(statement.span, |function| {
+        lower_statement_kind(statement, function)
+    })
+}
+
+fn lower_statement_kind(
+    statement: &Statement,
+    function: &mut FunctionContext,
+) -> Result<(), CodegenError> {
+    let span = statement.span;
+    match &statement.node {
+        StatementKind::VarDef { name, typ, expr } => {
+            // An annotation wins over the type of the value, eg. `let x: &void = &y;`
+            let typ = match typ {
+                Some(typ) => {
+                    check_type(&typ.node, typ.span, &function.scope).unwrap_or(Type::Error)
+                }
+                None => function.type_of(expr),
+            };
+            let wasm_typ = wasm_type(&typ, span, &function.scope)?
+                .ok_or_else(|| unsupported("`void` variables", span))?;
+            lower_expr_as(expr, &typ, function)?;
+            if function.shared.contains(name) {
+                box_variable(name, typ, wasm_typ, function)?;
+                return Ok(());
+            }
+            let index = function.declare_local(name, typ, wasm_typ);
+            function.set_local(index);
+            Ok(())
+        }
+
+        StatementKind::TypeDef { name, typ } => {
+            function.scope.set_type(name, typ.node.clone());
+            let resolved = check_type(&typ.node, typ.span, &function.scope);
+            function
+                .scope
+                .set_type(name, resolved.unwrap_or(Type::Error));
+            Ok(())
+        }
+
+        StatementKind::Assign { lhs, rhs } => match &lhs.node {
+            ExpressionKind::Identifier(name) => {
+                let local = function.get_local(name);
+                if let Some((index, value)) =
+                    local.and_then(|index| Some((index, *function.boxed.get(&index)?)))
+                {
+                    function.emit(Instruction::VariableOp(VariableOp::LocalGet(index)));
+                    lower_expr_as(rhs, &function.type_of(lhs), function)?;
+                    function.emit(store_word(value, 0));
+                    return Ok(());
+                }
+                let global = function.codegen.globals.get(name).map(|(index, _)| *index);
+                lower_expr_as(rhs, &function.type_of(lhs), function)?;
+                match (local, global) {
+                    (Some(index), _) => function.set_local(index),
+                    (None, Some(index)) => {
+                        function.emit(Instruction::VariableOp(VariableOp::GlobalSet(index)))
+                    }
+                    (None, None) => return Err(unsupported("assignments to functions", lhs.span)),
+                }
+                Ok(())
+            }
+            ExpressionKind::Deref(_)
+            | ExpressionKind::Dot { .. }
+            | ExpressionKind::Index { .. } => {
+                let typ = function.type_of(lhs);
+                lower_address(lhs, function)?;
+                lower_store(&typ, 0, lhs.span, function, |function| {
+                    lower_expr_as(rhs, &typ, function).map(|_| ())
+                })
+            }
+            _ => Err(unsupported(
+                "assignments to anything but variables, pointers, fields and elements",
+                lhs.span,
+            )),
+        },
+
+        StatementKind::Expr(expr) => {
+            // The value of an expression statement is thrown away
+            if lower_expr(expr, function)?.is_some() {
+                function.emit(Instruction::ParametricOp(ParametricOp::Drop));
+            }
+            Ok(())
+        }
+
+        StatementKind::Return(expr) => {
+            if let Some(expr) = expr {
+                let ret_type = function.ret_type.clone();
+                lower_expr_as(expr, &ret_type, function)?;
+            }
+            function.leave_frame();
+            function.emit(Instruction::ControlOp(ControlOp::Return));
+            Ok(())
+        }
+
+        StatementKind::If {
+            cond,
+            body,
+            else_stmt,
+        } => {
+            lower_expr(cond, function)?;
+            let then = function.lower_nested(BranchTarget::Block, |f| lower_block(body, f))?;
+            let otherwise = match else_stmt {
+                ElseStatement::Block(block) => {
+                    Some(function.lower_nested(BranchTarget::Block, |f| lower_block(block, f))?)
+                }
+                ElseStatement::If(stmt) => {
+                    Some(function.lower_nested(BranchTarget::Block, |f| lower_statement(stmt, f))?)
+                }
+                ElseStatement::None => None,
+            };
+            function.emit(Instruction::ControlOp(ControlOp::If {
+                typ: BlockType::Empty,
+                then,
+                otherwise,
+            }));
+            Ok(())
+        }
+
+        // block
+        //   loop
+        //     br_if 1 (i32.eqz cond)
+        //     body
+        //     br 0
+        //   end
+        // end
+        StatementKind::While { cond, body } => {
+            let block = function.lower_nested(BranchTarget::Break, |f| {
+                let body = f.lower_nested(BranchTarget::Continue, |f| {
+                    lower_expr(cond, f)?;
+                    f.emit(Instruction::IntegerOp(IntegerOp {
+                        op: IntegerOpType::Eqz,
+                        typ: IntegerType::I32,
+                    }));
+                    f.emit(Instruction::ControlOp(ControlOp::BrIf(1)));
+                    lower_block(body, f)?;
+                    f.emit(Instruction::ControlOp(ControlOp::Br(0)));
+                    Ok(())
+                })?;
+                f.emit(Instruction::ControlOp(ControlOp::Loop {
+                    typ: BlockType::Empty,
+                    body,
+                }));
+                Ok(())
+            })?;
+            function.emit(Instruction::ControlOp(ControlOp::Block {
+                typ: BlockType::Empty,
+                body: block,
+            }));
+            Ok(())
+        }
+
+        // Like a while loop without the condition
+        StatementKind::Loop(body) => {
+            let block = function.lower_nested(BranchTarget::Break, |f| {
+                let body = f.lower_nested(BranchTarget::Continue, |f| {
+                    lower_block(body, f)?;
+                    f.emit(Instruction::ControlOp(ControlOp::Br(0)));
+                    Ok(())
+                })?;
+                f.emit(Instruction::ControlOp(ControlOp::Loop {
+                    typ: BlockType::Empty,
+                    body,
+                }));
+                Ok(())
+            })?;
+            function.emit(Instruction::ControlOp(ControlOp::Block {
+                typ: BlockType::Empty,
+                body: block,
+            }));
+            Ok(())
+        }
+
+        StatementKind::Yeet(expr) => {
+            // Lists of a fixed length are yeeted as lists of any length, which is the only
+            // type they can be caught as
+            let typ = match resolve(&function.type_of(expr), span, &function.scope)? {
+                Type::SizedArray { element, .. } => Type::Array(element),
+                typ => typ,
+            };
+            let wasm_typ = wasm_type(&typ, span, &function.scope)?
+                .ok_or_else(|| unsupported("yeeting `void`", span))?;
+            lower_expr_as(expr, &typ, function)?;
+            let tag = function.codegen.tag(&typ, wasm_typ);
+            function.emit(Instruction::ControlOp(ControlOp::Throw(tag)));
+            Ok(())
+        }
+
+        // try
+        //   body
+        // catch $int
+        //   local.set $e
+        //   catch body
+        // end
+        StatementKind::Try { body, catches } => {
+            // With a garbage collector, the frames of the functions an exception leaves are
+            // popped by the `catch`, back to the top of the shadow stack when the `try` started
+            let collector = function.codegen.runtime.collector;
+            let top = collector.map(|collector| {
+                let top = function.temporary(NumType::I32);
+                function.emit(Instruction::ControlOp(ControlOp::Call(collector.top)));
+                function.emit(Instruction::VariableOp(VariableOp::LocalSet(top)));
+                (top, collector.leave)
+            });
+            let body = function.lower_nested(BranchTarget::Block, |f| lower_block(body, f))?;
+            let mut handlers = Vec::new();
+            for catch in catches {
+                let typ = check_type(&catch.typ.node, catch.typ.span, &function.scope)
+                    .unwrap_or(Type::Error);
+                let wasm_typ = wasm_type(&typ, catch.typ.span, &function.scope)?
+                    .ok_or_else(|| unsupported("catching `void`", catch.typ.span))?;
+                let tag = function.codegen.tag(&typ, wasm_typ);
+                let body = function.lower_nested(BranchTarget::Block, |f| {
+                    if let Some((top, leave)) = top {
+                        f.emit(Instruction::VariableOp(VariableOp::LocalGet(top)));
+                        f.emit(Instruction::ControlOp(ControlOp::Call(leave)));
+                    }
+                    // The value caught is on the stack
+                    if f.shared.contains(&catch.name) {
+                        box_variable(&catch.name, typ, wasm_typ, f)?;
+                    } else {
+                        let index = f.declare_local(&catch.name, typ, wasm_typ);
+                        f.set_local(index);
+                    }
+                    lower_block(&catch.body, f)
+                })?;
+                handlers.push(Catch { tag, body });
+            }
+            function.emit(Instruction::ControlOp(ControlOp::Try {
+                typ: BlockType::Empty,
+                body,
+                catches: handlers,
+                catch_all: None,
+            }));
+            Ok(())
+        }
+
+        StatementKind::Break => {
+            let depth = function
+                .branch_depth(BranchTarget::Break)
+                .ok_or_else(|| CodegenErrorKind::OutsideOfLoop("break").at(span))?;
+            function.emit(Instruction::ControlOp(ControlOp::Br(depth)));
+            Ok(())
+        }
+
+        StatementKind::Continue => {
+            let depth = function
+                .branch_depth(BranchTarget::Continue)
+                .ok_or_else(|| CodegenErrorKind::OutsideOfLoop("continue").at(span))?;
+            function.emit(Instruction::ControlOp(ControlOp::Br(depth)));
+            Ok(())
+        }
+
+        StatementKind::Import { .. } => Err(unsupported("imports", span)),
+    }
+}
@@ -0,0 +1,65 @@
+use crate::{
+    lexer::span::Span,
+    parser::types::Type,
+    type_checker::{types::check_type, Scope},
+    wasm::{MemArg, MemoryOp, NumType},
+};
+
+use super::{unsupported, CodegenError};
+
+/// The wasm type values of `ty` are stored as, or `None` for `void`.
+/// Errors are reported against `span`.
+pub fn wasm_type(ty: &Type, span: Span, scope: &Scope) -> Result<Option<NumType>, CodegenError> {
+    match ty {
+        Type::Int | Type::Bool | Type::Char => Ok(Some(NumType::I32)),
+        Type::Float => Ok(Some(NumType::F32)),
+        Type::Void => Ok(None),
+        // Pointers are addresses into linear memory, strings are pointers to their first byte
+        Type::Ptr(_) | Type::String => Ok(Some(NumType::I32)),
+        Type::Named(_) | Type::TypeOf(_) => match check_type(ty, span, scope) {
+            Ok(ty) => wasm_type(&ty, span, scope),
+            Err(_) => Err(unsupported("unresolved types", span)),
+        },
+        // Structs and arrays are handled by their address, lists by the address of their first element
+        Type::SizedArray { .. } | Type::Array(_) | Type::Struct(_) => Ok(Some(NumType::I32)),
+        Type::Tuple(_) => Err(unsupported("tuples", span)),
+        // Functions are the address of a closure record, see `closures`
+        Type::Function { .. } => Ok(Some(NumType::I32)),
+        Type::Error => Err(unsupported("ill-typed values", span)),
+    }
+}
+
+/// The instruction loading a value of type `ty` from the address on the stack plus `offset`.
+pub fn load_op(
+    ty: &Type,
+    offset: u32,
+    span: Span,
+    scope: &Scope,
+) -> Result<MemoryOp, CodegenError> {
+    match ty {
+        // Bools are stored in a single byte
+        Type::Bool => Ok(MemoryOp::I32Load8U(MemArg::natural(1, offset))),
+        _ => match wasm_type(ty, span, scope)? {
+            Some(NumType::F32) => Ok(MemoryOp::F32Load(MemArg::natural(4, offset))),
+            Some(_) => Ok(MemoryOp::I32Load(MemArg::natural(4, offset))),
+            None => Err(unsupported("`void` values", span)),
+        },
+    }
+}
+
+/// The instruction storing a value of type `ty` to the address below it on the stack plus `offset`.
+pub fn store_op(
+    ty: &Type,
+    offset: u32,
+    span: Span,
+    scope: &Scope,
+) -> Result<MemoryOp, CodegenError> {
+    match ty {
+        Type::Bool => Ok(MemoryOp::I32Store8(MemArg::natural(1, offset))),
+        _ => match wasm_type(ty, span, scope)? {
+            Some(NumType::F32) => Ok(MemoryOp::F32Store(MemArg::natural(4, offset))),
+            Some(_) => Ok(MemoryOp::I32Store(MemArg::natural(4, offset))),
+            None => Err(unsupported("`void` values", span)),
+        },
+    }
+}
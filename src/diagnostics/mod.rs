@@ -0,0 +1,262 @@
+use std::fmt;
+
+use crate::{
+    codegen::{CodegenError, CodegenErrorKind},
+    lexer::{span::Span, token::LexError},
+    parser::helpers::{Expected, ParseError},
+    type_checker::{TypeError, TypeErrorKind},
+    wasm::wat::parser::{WatError, WatErrorKind},
+};
+
+pub mod render;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Note => write!(f, "note"),
+        }
+    }
+}
+
+/// A message attached to a span of the source. Primary labels point at the cause of the
+/// problem and are underlined with `^`, secondary labels add context and use `-`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+    pub primary: bool,
+}
+
+impl Label {
+    pub fn primary(span: Span, message: impl Into<String>) -> Self {
+        Self {
+            span,
+            message: message.into(),
+            primary: true,
+        }
+    }
+
+    pub fn secondary(span: Span, message: impl Into<String>) -> Self {
+        Self {
+            span,
+            message: message.into(),
+            primary: false,
+        }
+    }
+}
+
+/// A problem found while compiling, in a form that can be shown to the user.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, message: impl Into<String>) -> Self {
+        Self {
+            severity,
+            message: message.into(),
+            labels: Vec::new(),
+            notes: Vec::new(),
+            help: None,
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Self::new(Severity::Error, message)
+    }
+
+    pub fn with_label(mut self, label: Label) -> Self {
+        self.labels.push(label);
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn with_help(mut self, help: impl Into<String>) -> Self {
+        self.help = Some(help.into());
+        self
+    }
+
+    /// The label the diagnostic is reported at, if it has any.
+    pub fn primary_label(&self) -> Option<&Label> {
+        self.labels
+            .iter()
+            .find(|label| label.primary)
+            .or(self.labels.first())
+    }
+}
+
+impl From<ParseError> for Diagnostic {
+    fn from(error: ParseError) -> Self {
+        match error {
+            ParseError::UnexpectedToken { found, expected } => match describe_expected(&expected) {
+                Some(expected) => {
+                    Diagnostic::error(format!("expected {}, found `{}`", expected, found.node))
+                        .with_label(Label::primary(found.span, format!("expected {}", expected)))
+                }
+                None => Diagnostic::error(format!("unexpected `{}`", found.node))
+                    .with_label(Label::primary(found.span, "unexpected token")),
+            },
+            ParseError::UnexpectedEOF { span, expected } => match describe_expected(&expected) {
+                Some(expected) => {
+                    Diagnostic::error(format!("expected {}, found end of file", expected))
+                        .with_label(Label::primary(span, format!("expected {}", expected)))
+                }
+                None => Diagnostic::error("unexpected end of file")
+                    .with_label(Label::primary(span, "expected more input")),
+            },
+            ParseError::Lex(err) => {
+                let diagnostic = Diagnostic::error(err.node.to_string())
+                    .with_label(Label::primary(err.span, "could not be read as a token"));
+                match err.node {
+                    LexError::InvalidEscape(_) => diagnostic.with_help(
+                        "valid escapes are `\\n`, `\\r`, `\\t`, `\\'`, `\\\"`, `\\\\`, `\\0` and `\\xNN`",
+                    ),
+                    LexError::IntegerOverflow => {
+                        diagnostic.with_note(format!("the largest integer is {}", i64::MAX))
+                    }
+                    _ => diagnostic,
+                }
+            }
+            ParseError::DuplicateKey(key) => {
+                Diagnostic::error(format!("field `{}` is specified more than once", key.node))
+                    .with_label(Label::primary(key.span, "duplicate field"))
+            }
+        }
+    }
+}
+
+// Lists what the parser expected, eg. "one of `,`, `)` or an operator"
+fn describe_expected(expected: &[Expected]) -> Option<String> {
+    let mut items = expected
+        .iter()
+        .filter(|expected| !expected.is_operator())
+        .map(|expected| expected.to_string())
+        .collect::<Vec<_>>();
+    if expected.iter().any(Expected::is_operator) {
+        items.push("an operator".to_string());
+    }
+
+    match items.as_slice() {
+        [] => None,
+        [item] => Some(item.clone()),
+        [first, second] => Some(format!("{} or {}", first, second)),
+        [rest @ .., last] => Some(format!("one of {} or {}", rest.join(", "), last)),
+    }
+}
+
+impl From<TypeError> for Diagnostic {
+    fn from(error: TypeError) -> Self {
+        let span = error.span;
+        match error.node {
+            TypeErrorKind::Unexpected { got, expected } => Diagnostic::error("mismatched types")
+                .with_label(Label::primary(
+                    span,
+                    format!("expected `{}`, found `{}`", expected, got),
+                )),
+            TypeErrorKind::Invalid(ty) => {
+                Diagnostic::error(format!("a value of type `{}` cannot be used here", ty))
+                    .with_label(Label::primary(span, format!("has type `{}`", ty)))
+            }
+            TypeErrorKind::InvalidIdentifier(name) => {
+                Diagnostic::error(format!("cannot find `{}` in this scope", name))
+                    .with_label(Label::primary(span, "not found in this scope"))
+            }
+            TypeErrorKind::Unsupported(what) => {
+                Diagnostic::error(format!("{} are not supported yet", what))
+                    .with_label(Label::primary(span, "not supported"))
+            }
+            TypeErrorKind::ArgumentCount {
+                expected,
+                found,
+                callee,
+            } => {
+                let arguments = |count: usize| {
+                    format!("{} argument{}", count, if count == 1 { "" } else { "s" })
+                };
+                Diagnostic::error(format!("expected {}, found {}", arguments(expected), found))
+                    .with_label(Label::primary(
+                        span,
+                        format!("called with {}", arguments(found)),
+                    ))
+                    .with_label(Label::secondary(
+                        callee.span,
+                        format!("has type `{}`", callee.node),
+                    ))
+            }
+        }
+    }
+}
+
+impl From<CodegenError> for Diagnostic {
+    fn from(error: CodegenError) -> Self {
+        match error.node {
+            CodegenErrorKind::Unsupported(what) => {
+                Diagnostic::error(format!("{} are not supported yet", what))
+                    .with_label(Label::primary(error.span, "cannot be compiled to wasm"))
+            }
+            CodegenErrorKind::IntegerOutOfRange(val) => {
+                Diagnostic::error(format!("integer `{}` does not fit in 32 bits", val))
+                    .with_label(Label::primary(error.span, "out of range"))
+                    .with_note(format!(
+                        "integers are between {} and {} in wasm",
+                        i32::MIN,
+                        i32::MAX
+                    ))
+            }
+            CodegenErrorKind::OutsideOfLoop(keyword) => {
+                Diagnostic::error(format!("`{}` outside of a loop", keyword))
+                    .with_label(Label::primary(error.span, "not inside a `while` or `loop`"))
+            }
+            CodegenErrorKind::RecursiveType(name) => {
+                Diagnostic::error(format!("type `{}` contains itself", name))
+                    .with_label(Label::primary(error.span, "has infinite size"))
+                    .with_note(format!(
+                        "store the `{}` inside behind a pointer, eg. `&{}`",
+                        name, name
+                    ))
+            }
+            CodegenErrorKind::TooLarge => Diagnostic::error("type is too large").with_label(
+                Label::primary(error.span, "does not fit in wasm's 32 bit memory"),
+            ),
+        }
+    }
+}
+
+impl From<WatError> for Diagnostic {
+    fn from(error: WatError) -> Self {
+        let label = match &error.node {
+            WatErrorKind::UnknownCharacter(_) | WatErrorKind::InvalidEscape(_) => "not valid here",
+            WatErrorKind::UnterminatedString | WatErrorKind::UnterminatedComment => "starts here",
+            WatErrorKind::UnbalancedParen => "has no matching parenthesis",
+            WatErrorKind::Expected { expected, .. } => {
+                return Diagnostic::error(error.node.to_string())
+                    .with_label(Label::primary(error.span, format!("expected {}", expected)))
+            }
+            WatErrorKind::UnknownInstruction(_) | WatErrorKind::UnknownField(_) => "unknown",
+            WatErrorKind::UnknownName { .. } => "not defined",
+            WatErrorKind::DuplicateName { .. } => "redefined here",
+            WatErrorKind::InvalidNumber(_) => "malformed or out of range",
+            WatErrorKind::ImportAfterDefinition(_) => "import after a definition",
+            WatErrorKind::Unsupported(_) => "cannot be represented",
+        };
+        Diagnostic::error(error.node.to_string()).with_label(Label::primary(error.span, label))
+    }
+}
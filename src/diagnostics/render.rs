@@ -0,0 +1,184 @@
+use std::fmt::Write;
+
+use super::{Diagnostic, Label};
+
+const TAB_WIDTH: usize = 4;
+
+impl Diagnostic {
+    /// Renders the diagnostic in the style of rustc, quoting the offending lines of `source`.
+    ///
+    /// ```text
+    /// error: mismatched types
+    ///  --> tests/tree.jj:3:9
+    ///   |
+    /// 3 | let x: int = true;
+    ///   |              ^^^^ expected `int`, found `bool`
+    /// ```
+    pub fn render(&self, source: &str, file_name: &str) -> String {
+        let mut output = String::new();
+        writeln!(output, "{}: {}", self.severity, self.message).unwrap();
+
+        let mut labels = self.labels.iter().collect::<Vec<_>>();
+        labels.sort_by_key(|label| (label.span.line, !label.primary, label.span.column));
+
+        let last_line = labels
+            .iter()
+            .map(|label| label.span.line)
+            .max()
+            .unwrap_or(0);
+        let gutter = " ".repeat(last_line.to_string().len());
+
+        if let Some(primary) = self.primary_label() {
+            writeln!(
+                output,
+                "{}--> {}:{}:{}",
+                gutter, file_name, primary.span.line, primary.span.column
+            )
+            .unwrap();
+            writeln!(output, "{} |", gutter).unwrap();
+        }
+
+        let lines = source.split('\n').collect::<Vec<_>>();
+        let mut previous_line = None;
+        for (i, label) in labels.iter().enumerate() {
+            let line_number = label.span.line;
+            let line = lines.get(line_number - 1).copied().unwrap_or("");
+
+            if previous_line != Some(line_number) {
+                if previous_line.is_some_and(|previous| previous + 1 < line_number) {
+                    writeln!(output, "...").unwrap();
+                }
+                writeln!(
+                    output,
+                    "{:>width$} | {}",
+                    line_number,
+                    expand_tabs(line.trim_end_matches('\r')),
+                    width = gutter.len()
+                )
+                .unwrap();
+                previous_line = Some(line_number);
+            }
+
+            writeln!(output, "{} | {}", gutter, underline(line, label)).unwrap();
+
+            let next_line = labels.get(i + 1).map(|label| label.span.line);
+            if next_line.is_none() && !(self.notes.is_empty() && self.help.is_none()) {
+                writeln!(output, "{} |", gutter).unwrap();
+            }
+        }
+
+        for note in self.notes.iter() {
+            writeln!(output, "{} = note: {}", gutter, note).unwrap();
+        }
+        if let Some(help) = &self.help {
+            writeln!(output, "{} = help: {}", gutter, help).unwrap();
+        }
+
+        output
+    }
+}
+
+fn expand_tabs(line: &str) -> String {
+    line.replace('\t', &" ".repeat(TAB_WIDTH))
+}
+
+// Builds the `^^^^ message` row for a label. Spans running past the end of the line are
+// underlined up to the end of the line.
+fn underline(line: &str, label: &Label) -> String {
+    let start_column = label.span.column - 1;
+    let visual_width = |c: char| if c == '\t' { TAB_WIDTH } else { 1 };
+
+    let padding: usize = line.chars().take(start_column).map(visual_width).sum();
+    let mut underlined = 0;
+    let mut bytes = 0;
+    for c in line.chars().skip(start_column) {
+        if bytes >= label.span.len() {
+            break;
+        }
+        bytes += c.len_utf8();
+        underlined += visual_width(c);
+    }
+    let underlined = underlined.max(1);
+
+    let marker = if label.primary { "^" } else { "-" };
+    let mut row = format!("{}{}", " ".repeat(padding), marker.repeat(underlined));
+    if !label.message.is_empty() {
+        write!(row, " {}", label.message).unwrap();
+    }
+    row
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::span::Span;
+
+    #[test]
+    fn single_label() {
+        let diagnostic = Diagnostic::error("mismatched types").with_label(Label::primary(
+            Span::new(13, 17, 1, 14),
+            "expected `int`, found `bool`",
+        ));
+        assert_eq!(
+            diagnostic.render("let x: int = true;\n", "main.jj"),
+            "\
+error: mismatched types
+ --> main.jj:1:14
+  |
+1 | let x: int = true;
+  |              ^^^^ expected `int`, found `bool`
+"
+        );
+    }
+
+    // Tabs are expanded in both the quoted line and the underline, and `é` is two bytes but
+    // one column wide
+    #[test]
+    fn labels_after_tabs_and_multi_byte_characters() {
+        let source = "let s = \"é\";\n\tlet y: int = s;\n";
+        let diagnostic = Diagnostic::error("mismatched types")
+            .with_label(Label::primary(
+                Span::new(27, 28, 2, 15),
+                "expected `int`, found `string`",
+            ))
+            .with_label(Label::secondary(
+                Span::new(21, 24, 2, 9),
+                "expected due to this",
+            ))
+            .with_label(Label::secondary(Span::new(8, 12, 1, 9), "defined here"))
+            .with_note("strings can't be converted to integers");
+        assert_eq!(
+            diagnostic.render(source, "main.jj"),
+            "\
+error: mismatched types
+ --> main.jj:2:15
+  |
+1 | let s = \"é\";
+  |         --- defined here
+2 |     let y: int = s;
+  |                  ^ expected `int`, found `string`
+  |            --- expected due to this
+  |
+  = note: strings can't be converted to integers
+"
+        );
+    }
+
+    // Errors at the end of the input have an empty span, which is still pointed at
+    #[test]
+    fn error_at_end_of_input() {
+        let source = "let ü = \"é\" + ü";
+        let diagnostic = Diagnostic::error("unexpected end of file")
+            .with_label(Label::primary(Span::new(17, 17, 1, 16), "expected `;`"));
+        assert_eq!(
+            diagnostic.render(source, "main.jj"),
+            "\
+error: unexpected end of file
+ --> main.jj:1:16
+  |
+1 | let ü = \"é\" + ü
+  |                ^ expected `;`
+"
+        );
+    }
+}
@@ -1,110 +1,49 @@
 use std::iter::Peekable;
 
-use crate::parser::helpers::ParseError;
-
-use super::{raw_lexer::RawLexer, token::Token};
+use super::{
+    raw_lexer::RawLexer,
+    span::{Span, Spanned},
+    token::Token,
+};
 
 #[derive(Debug, Clone)]
 pub struct Lexer<'a> {
     raw: Peekable<RawLexer<'a>>,
+    eof: Span,
+    last: Span,
 }
 
 impl<'a> Lexer<'a> {
     pub fn new(input: &'a str) -> Self {
+        let raw = RawLexer::new(input);
         Self {
-            raw: RawLexer::new(input).peekable(),
+            eof: raw.eof_span(),
+            last: Span::default(),
+            raw: raw.peekable(),
         }
     }
 
-    pub fn peek(&mut self) -> Option<&Token> {
-        self.raw.peek()
-    }
-
-    pub fn parse_token(&mut self, token: &Token) -> Result<Token, ParseError> {
-        match self.raw.peek() {
-            Some(tok) if tok == token => {
-                let tok = tok.clone();
-                self.raw
-                    .next()
-                    .ok_or_else(|| ParseError::UnexpectedToken(tok))
-            }
-            Some(tok) => Err(ParseError::UnexpectedToken(tok.clone())),
-            None => Err(ParseError::UnexpectedEOF()),
-        }
+    /// The span of the most recently consumed token.
+    pub fn last_span(&self) -> Span {
+        self.last
     }
 
-    pub fn expect_next(&mut self) -> Result<Token, ParseError> {
-        self.raw.next().ok_or(ParseError::UnexpectedEOF())
-    }
-    pub fn expect_peek(&mut self) -> Result<&Token, ParseError> {
-        self.raw.peek().ok_or(ParseError::UnexpectedEOF())
+    pub fn peek(&mut self) -> Option<&Spanned<Token>> {
+        self.raw.peek()
     }
 
-    pub fn parse_ident(&mut self) -> Result<String, ParseError> {
-        match self.raw.peek() {
-            Some(Token::Identifier(_)) => match self.raw.next() {
-                Some(Token::Identifier(ident)) => Ok(ident),
-                _ => unreachable!(),
-            },
-            Some(tok) => Err(ParseError::UnexpectedToken(tok.clone())),
-            None => Err(ParseError::UnexpectedEOF()),
-        }
-    }
-    pub fn parse_string(&mut self) -> Result<String, ParseError> {
-        match self.raw.peek() {
-            Some(Token::String(_)) => match self.raw.next() {
-                Some(Token::String(val)) => Ok(val),
-                _ => unreachable!(),
-            },
-            Some(tok) => Err(ParseError::UnexpectedToken(tok.clone())),
-            None => Err(ParseError::UnexpectedEOF()),
-        }
-    }
-    pub fn parse_int(&mut self) -> Result<i64, ParseError> {
-        match self.raw.peek() {
-            Some(Token::Integer(_)) => match self.raw.next() {
-                Some(Token::Integer(val)) => Ok(val),
-                _ => unreachable!(),
-            },
-            Some(tok) => Err(ParseError::UnexpectedToken(tok.clone())),
-            None => Err(ParseError::UnexpectedEOF()),
-        }
-    }
-    pub fn parse_float(&mut self) -> Result<f64, ParseError> {
-        match self.raw.peek() {
-            Some(Token::Float(_)) => match self.raw.next() {
-                Some(Token::Float(val)) => Ok(val),
-                _ => unreachable!(),
-            },
-            Some(tok) => Err(ParseError::UnexpectedToken(tok.clone())),
-            None => Err(ParseError::UnexpectedEOF()),
-        }
-    }
-    pub fn parse_char(&mut self) -> Result<char, ParseError> {
-        match self.raw.peek() {
-            Some(Token::Char(_)) => match self.raw.next() {
-                Some(Token::Char(val)) => Ok(val),
-                _ => unreachable!(),
-            },
-            Some(tok) => Err(ParseError::UnexpectedToken(tok.clone())),
-            None => Err(ParseError::UnexpectedEOF()),
-        }
-    }
-    pub fn parse_bool(&mut self) -> Result<bool, ParseError> {
-        match self.raw.peek() {
-            Some(Token::Bool(_)) => match self.raw.next() {
-                Some(Token::Bool(val)) => Ok(val),
-                _ => unreachable!(),
-            },
-            Some(tok) => Err(ParseError::UnexpectedToken(tok.clone())),
-            None => Err(ParseError::UnexpectedEOF()),
-        }
+    /// The span of the next token, or the end of the input if there are no tokens left.
+    pub fn peek_span(&mut self) -> Span {
+        let eof = self.eof;
+        self.raw.peek().map_or(eof, |tok| tok.span)
     }
 }
 
 impl<'a> Iterator for Lexer<'a> {
-    type Item = Token;
+    type Item = Spanned<Token>;
     fn next(&mut self) -> Option<Self::Item> {
-        self.raw.next()
+        let tok = self.raw.next()?;
+        self.last = tok.span;
+        Some(tok)
     }
 }
@@ -1,5 +1,12 @@
 use std::str::Chars;
 
+use super::token::LexError;
+
+/// The result of trying to lex a literal of type `T` at the start of the input.
+/// `None` means the input does not start with this kind of literal. Errors carry the
+/// number of bytes the malformed literal spans, so lexing can continue after it.
+pub type LiteralResult<T> = Option<Result<(T, usize), (LexError, usize)>>;
+
 enum Base {
     Binary,
     Decimal,
@@ -34,7 +41,7 @@ impl Base {
     }
 }
 
-pub fn parse_int_literal(input: &str) -> Option<(i64, usize)> {
+pub fn parse_int_literal(input: &str) -> LiteralResult<i64> {
     let base = Base::parse_from_str(input);
     let mut len = base.prefix_len();
     let mut iterator = input.chars().skip(len);
@@ -44,19 +51,23 @@ pub fn parse_int_literal(input: &str) -> Option<(i64, usize)> {
     }
 
     if (len - base.prefix_len()) == 0 {
+        if base.prefix_len() > 0 {
+            return Some(Err((LexError::MissingDigits, len)));
+        }
         return None;
     }
 
-    let number = i64::from_str_radix(&input[base.prefix_len()..len], base.radix()).ok()?;
-
-    Some((number, len))
+    match i64::from_str_radix(&input[base.prefix_len()..len], base.radix()) {
+        Ok(number) => Some(Ok((number, len))),
+        Err(_) => Some(Err((LexError::IntegerOverflow, len))),
+    }
 }
 
 pub fn parse_float_literal(input: &str) -> Option<(f64, usize)> {
     let mut iterator = input.chars().peekable();
     let mut len_before = 0;
 
-    while iterator.peek().is_some_and(|c| c.is_digit(10)) {
+    while iterator.peek().is_some_and(|c| c.is_ascii_digit()) {
         iterator.next();
         len_before += 1;
     }
@@ -67,7 +78,7 @@ pub fn parse_float_literal(input: &str) -> Option<(f64, usize)> {
 
     let mut len_after = len_before + 1;
 
-    while iterator.next().is_some_and(|c| c.is_digit(10)) {
+    while iterator.next().is_some_and(|c| c.is_ascii_digit()) {
         len_after += 1;
     }
 
@@ -79,49 +90,62 @@ pub fn parse_float_literal(input: &str) -> Option<(f64, usize)> {
     Some((number, len_after))
 }
 
-fn parse_next_char(iterator: &mut Chars) -> Option<(char, usize)> {
-    let (c, len) = match iterator.next() {
-        Some('\\') => match iterator.next() {
+// Returns None at the end of the input. Lengths are in bytes.
+fn parse_next_char(iterator: &mut Chars) -> LiteralResult<char> {
+    let (c, len) = match iterator.next()? {
+        '\\' => match iterator.next() {
             Some('n') => ('\n', 2),
             Some('r') => ('\r', 2),
             Some('t') => ('\t', 2),
             Some('\'') => ('\'', 2),
+            Some('"') => ('"', 2),
             Some('\\') => ('\\', 2),
             Some('0') => ('\0', 2),
             Some('x') => match (iterator.next(), iterator.next()) {
-                (Some(a), Some(b)) => {
-                    let a = a.to_digit(16)?;
-                    let b = b.to_digit(16)?;
-                    let c = (a << 4) | b;
-                    (c as u8 as char, 4)
-                }
-                _ => return None,
+                (Some(a), Some(b)) => match (a.to_digit(16), b.to_digit(16)) {
+                    (Some(a), Some(b)) => {
+                        let c = (a << 4) | b;
+                        (c as u8 as char, 4)
+                    }
+                    _ => {
+                        let len = 2 + a.len_utf8() + b.len_utf8();
+                        return Some(Err((LexError::InvalidEscape('x'), len)));
+                    }
+                },
+                _ => return Some(Err((LexError::InvalidEscape('x'), 2))),
             },
-            _ => return None,
+            Some(c) => return Some(Err((LexError::InvalidEscape(c), 1 + c.len_utf8()))),
+            None => return None,
         },
-        Some(c) => (c, 1),
-        _ => return None,
+        c => (c, c.len_utf8()),
     };
 
-    Some((c, len))
+    Some(Ok((c, len)))
 }
 
-pub fn parse_char_literal(input: &str) -> Option<(char, usize)> {
+pub fn parse_char_literal(input: &str) -> LiteralResult<char> {
     let mut iterator = input.chars();
     if iterator.next() != Some('\'') {
         return None;
     }
 
-    let (c, len) = parse_next_char(&mut iterator)?;
+    let (c, len) = match parse_next_char(&mut iterator) {
+        Some(Ok(c)) => c,
+        Some(Err((err, len))) => {
+            let closing = if iterator.next() == Some('\'') { 1 } else { 0 };
+            return Some(Err((err, len + 1 + closing)));
+        }
+        None => return Some(Err((LexError::UnterminatedChar, 1))),
+    };
 
     if iterator.next() != Some('\'') {
-        return None;
+        return Some(Err((LexError::UnterminatedChar, len + 1)));
     }
 
-    Some((c, len + 2))
+    Some(Ok((c, len + 2)))
 }
 
-pub fn parse_string_literal(input: &str) -> Option<(String, usize)> {
+pub fn parse_string_literal(input: &str) -> LiteralResult<String> {
     let mut iterator = input.chars();
     if iterator.next() != Some('"') {
         return None;
@@ -129,14 +153,26 @@ pub fn parse_string_literal(input: &str) -> Option<(String, usize)> {
 
     let mut len = 1;
     let mut string = String::new();
+    // Keep scanning to the closing quote after an invalid escape so the whole string is skipped
+    let mut error = None;
 
     loop {
-        match parse_next_char(&mut iterator)? {
-            ('"', 1) => return Some((string, len + 1)),
-            (c, l) => {
+        match parse_next_char(&mut iterator) {
+            Some(Ok(('"', 1))) => {
+                return match error {
+                    Some(err) => Some(Err((err, len + 1))),
+                    None => Some(Ok((string, len + 1))),
+                }
+            }
+            Some(Ok((c, l))) => {
                 len += l;
                 string.push(c);
             }
+            Some(Err((err, l))) => {
+                len += l;
+                error.get_or_insert(err);
+            }
+            None => return Some(Err((LexError::UnterminatedString, input.len()))),
         };
     }
 }
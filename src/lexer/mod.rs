@@ -1,4 +1,6 @@
+#[allow(clippy::module_inception)]
 pub mod lexer;
 pub mod literal;
 pub mod raw_lexer;
+pub mod span;
 pub mod token;
@@ -1,68 +1,257 @@
-use super::token::Token;
+use super::{
+    span::{Span, Spanned},
+    token::{LexError, Token},
+};
 
 #[derive(Debug, Clone)]
 pub struct RawLexer<'a> {
     input: &'a str,
     index: usize,
+    line: usize,
+    column: usize,
 }
 
 impl<'a> RawLexer<'a> {
     pub fn new(input: &'a str) -> Self {
-        Self { input, index: 0 }
+        Self {
+            input,
+            index: 0,
+            line: 1,
+            column: 1,
+        }
+    }
+
+    // helper function to move forward by `len` bytes, keeping track of the line and column
+    fn advance(&mut self, len: usize) {
+        let end = (self.index + len).min(self.input.len());
+        for c in self.input[self.index..end].chars() {
+            if c == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
+        }
+        self.index = end;
     }
 
     // helper function to consume until the pattern is matched
-    fn consume_until_match(&mut self, pattern: &str) {
+    // returns false if the input ran out before the pattern was found
+    fn consume_until_match(&mut self, pattern: &str) -> bool {
         loop {
             let view = &self.input[self.index..];
             if view.starts_with(pattern) {
-                self.index += pattern.len();
-                break;
+                self.advance(pattern.len());
+                break true;
+            }
+            match view.chars().next() {
+                Some(c) => self.advance(c.len_utf8()),
+                None => break false,
             }
-            self.index += 1;
         }
     }
 
     // helper function to consume all whitespace and comments
-    fn consume_till_next_token(&mut self) {
+    // returns an error token if a block comment is never closed
+    fn consume_till_next_token(&mut self) -> Option<Spanned<Token>> {
         loop {
             let view = &self.input[self.index..];
 
             if view.starts_with("//") {
-                self.index += 2;
+                self.advance(2);
                 self.consume_until_match("\n");
                 continue;
             }
 
             if view.starts_with("/*") {
-                self.index += 2;
-                self.consume_until_match("*/");
+                let (start, line, column) = (self.index, self.line, self.column);
+                self.advance(2);
+                if !self.consume_until_match("*/") {
+                    return Some(Spanned::new(
+                        Token::Error(LexError::UnterminatedComment),
+                        Span::new(start, start + 2, line, column),
+                    ));
+                }
                 continue;
             }
 
             match view.chars().next() {
                 Some(c) if c.is_whitespace() => {
-                    self.index += 1;
+                    self.advance(c.len_utf8());
                     continue;
                 }
-                _ => break,
+                _ => break None,
             }
         }
     }
+
+    /// The span of the (empty) position just past the last character of the input.
+    pub fn eof_span(&self) -> Span {
+        let mut lexer = self.clone();
+        lexer.advance(self.input.len() - self.index);
+        Span::new(lexer.index, lexer.index, lexer.line, lexer.column)
+    }
 }
 
 impl<'a> Iterator for RawLexer<'a> {
-    type Item = Token;
+    type Item = Spanned<Token>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.consume_till_next_token();
+        if let Some(error) = self.consume_till_next_token() {
+            return Some(error);
+        }
 
         let view = &self.input[self.index..];
 
         let (next_tok, len) = Token::parse_from_str(view)?;
 
-        self.index += len;
+        let (start, line, column) = (self.index, self.line, self.column);
+        self.advance(len);
+
+        Some(Spanned::new(
+            next_tok,
+            Span::new(start, self.index, line, column),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lex(input: &str) -> Vec<(Token, Span)> {
+        RawLexer::new(input)
+            .map(|tok| (tok.node, tok.span))
+            .collect()
+    }
+
+    #[test]
+    fn spans_on_one_line() {
+        assert_eq!(
+            lex("let x = 12;"),
+            [
+                (Token::Let, Span::new(0, 3, 1, 1)),
+                (Token::Identifier("x".to_string()), Span::new(4, 5, 1, 5)),
+                (Token::Assign, Span::new(6, 7, 1, 7)),
+                (Token::Integer(12), Span::new(8, 10, 1, 9)),
+                (Token::Semicolon, Span::new(10, 11, 1, 11)),
+            ]
+        );
+    }
+
+    #[test]
+    fn spans_across_lines_and_comments() {
+        let input = "let x\n  = 1; // done\n/* a\nb */ y";
+        assert_eq!(
+            lex(input),
+            [
+                (Token::Let, Span::new(0, 3, 1, 1)),
+                (Token::Identifier("x".to_string()), Span::new(4, 5, 1, 5)),
+                (Token::Assign, Span::new(8, 9, 2, 3)),
+                (Token::Integer(1), Span::new(10, 11, 2, 5)),
+                (Token::Semicolon, Span::new(11, 12, 2, 6)),
+                (Token::Identifier("y".to_string()), Span::new(31, 32, 4, 6)),
+            ]
+        );
+        assert_eq!(RawLexer::new(input).eof_span(), Span::new(32, 32, 4, 7));
+    }
+
+    // Offsets are in bytes, columns in characters
+    #[test]
+    fn spans_of_multi_byte_characters() {
+        let input = "\"héllo\" ünï + 1";
+        assert_eq!(
+            lex(input),
+            [
+                (Token::String("héllo".to_string()), Span::new(0, 8, 1, 1)),
+                (Token::Identifier("ünï".to_string()), Span::new(9, 14, 1, 9)),
+                (Token::Add, Span::new(15, 16, 1, 13)),
+                (Token::Integer(1), Span::new(17, 18, 1, 15)),
+            ]
+        );
+        assert_eq!(RawLexer::new(input).eof_span(), Span::new(18, 18, 1, 16));
+    }
+
+    #[test]
+    fn eof_span_of_empty_input() {
+        assert!(lex("").is_empty());
+        assert_eq!(RawLexer::new("").eof_span(), Span::new(0, 0, 1, 1));
+        assert_eq!(RawLexer::new("\n\n").eof_span(), Span::new(2, 2, 3, 1));
+    }
+
+    #[test]
+    fn unknown_characters_become_error_tokens() {
+        assert_eq!(
+            lex("a @ b"),
+            [
+                (Token::Identifier("a".to_string()), Span::new(0, 1, 1, 1)),
+                (
+                    Token::Error(LexError::UnknownCharacter('@')),
+                    Span::new(2, 3, 1, 3)
+                ),
+                (Token::Identifier("b".to_string()), Span::new(4, 5, 1, 5)),
+            ]
+        );
+        // The whole character is skipped, not just its first byte
+        assert_eq!(
+            lex("€1"),
+            [
+                (
+                    Token::Error(LexError::UnknownCharacter('€')),
+                    Span::new(0, 3, 1, 1)
+                ),
+                (Token::Integer(1), Span::new(3, 4, 1, 2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn lexing_continues_after_bad_literals() {
+        assert_eq!(
+            lex("\"\\q\" ; y"),
+            [
+                (
+                    Token::Error(LexError::InvalidEscape('q')),
+                    Span::new(0, 4, 1, 1)
+                ),
+                (Token::Semicolon, Span::new(5, 6, 1, 6)),
+                (Token::Identifier("y".to_string()), Span::new(7, 8, 1, 8)),
+            ]
+        );
+        assert_eq!(
+            lex("99999999999999999999 z"),
+            [
+                (
+                    Token::Error(LexError::IntegerOverflow),
+                    Span::new(0, 20, 1, 1)
+                ),
+                (Token::Identifier("z".to_string()), Span::new(21, 22, 1, 22)),
+            ]
+        );
+    }
 
-        Some(next_tok)
+    #[test]
+    fn unterminated_input_ends_with_an_error_token() {
+        assert_eq!(
+            lex("x \"abc"),
+            [
+                (Token::Identifier("x".to_string()), Span::new(0, 1, 1, 1)),
+                (
+                    Token::Error(LexError::UnterminatedString),
+                    Span::new(2, 6, 1, 3)
+                ),
+            ]
+        );
+        // Only the opening `/*` is pointed at, the rest of the input is the comment
+        assert_eq!(
+            lex("a /* b"),
+            [
+                (Token::Identifier("a".to_string()), Span::new(0, 1, 1, 1)),
+                (
+                    Token::Error(LexError::UnterminatedComment),
+                    Span::new(2, 4, 1, 3)
+                ),
+            ]
+        );
     }
 }
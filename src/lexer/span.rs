@@ -0,0 +1,51 @@
+/// A range of bytes in the source file, along with the line and column of its first character.
+/// Lines and columns are 1-based, columns are counted in characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize, line: usize, column: usize) -> Self {
+        Self {
+            start,
+            end,
+            line,
+            column,
+        }
+    }
+
+    /// Creates a span covering everything from the start of `self` to the end of `other`.
+    pub fn to(&self, other: &Span) -> Span {
+        Span {
+            start: self.start,
+            end: other.end.max(self.end),
+            line: self.line,
+            column: self.column,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
+
+/// A value tagged with the span of source it was produced from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+}
+
+impl<T> Spanned<T> {
+    pub fn new(node: T, span: Span) -> Self {
+        Self { node, span }
+    }
+}
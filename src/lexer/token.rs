@@ -1,7 +1,21 @@
+use std::fmt;
+
 use super::literal::{
     parse_char_literal, parse_float_literal, parse_int_literal, parse_string_literal,
 };
 
+/// The reason a piece of input could not be turned into a token.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LexError {
+    UnknownCharacter(char),
+    UnterminatedString,
+    UnterminatedChar,
+    UnterminatedComment,
+    InvalidEscape(char),
+    IntegerOverflow,
+    MissingDigits,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Identifier(String),
@@ -31,6 +45,8 @@ pub enum Token {
     Break,
     Continue,
     Loop,
+    Try,
+    Catch,
 
     // Built in types
     Type,
@@ -80,6 +96,98 @@ pub enum Token {
     Dot,
     Ref,
     FuncArrow,
+
+    // Input that could not be lexed, lexing continues after it
+    Error(LexError),
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LexError::UnknownCharacter(c) => write!(f, "unknown character `{}`", c.escape_debug()),
+            LexError::UnterminatedString => write!(f, "unterminated string literal"),
+            LexError::UnterminatedChar => write!(f, "unterminated character literal"),
+            LexError::UnterminatedComment => write!(f, "unterminated block comment"),
+            LexError::InvalidEscape(c) => write!(f, "invalid escape sequence `\\{}`", c),
+            LexError::IntegerOverflow => write!(f, "integer literal is too large"),
+            LexError::MissingDigits => write!(f, "integer literal has no digits"),
+        }
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Identifier(name) => write!(f, "{}", name),
+            Token::Integer(val) => write!(f, "{}", val),
+            Token::Float(val) => write!(f, "{:?}", val),
+            Token::String(val) => write!(f, "{:?}", val),
+            Token::Bool(val) => write!(f, "{}", val),
+            Token::Char(val) => write!(f, "{:?}", val),
+            Token::Error(err) => write!(f, "{}", err),
+            tok => {
+                // Every other token is spelled exactly as its keyword or symbol
+                let text = match tok {
+                    Token::Import => "import",
+                    Token::As => "as",
+                    Token::From => "from",
+                    Token::Let => "let",
+                    Token::Yeet => "yeet",
+                    Token::Null => "null",
+                    Token::TypeOf => "typeof",
+                    Token::If => "if",
+                    Token::Else => "else",
+                    Token::While => "while",
+                    Token::For => "for",
+                    Token::Return => "return",
+                    Token::Break => "break",
+                    Token::Continue => "continue",
+                    Token::Loop => "loop",
+                    Token::Try => "try",
+                    Token::Catch => "catch",
+                    Token::Type => "type",
+                    Token::IntType => "int",
+                    Token::FloatType => "float",
+                    Token::StringType => "string",
+                    Token::BoolType => "bool",
+                    Token::CharType => "char",
+                    Token::VoidType => "void",
+                    Token::Assign => "=",
+                    Token::Add => "+",
+                    Token::Sub => "-",
+                    Token::Div => "/",
+                    Token::Mul => "*",
+                    Token::AddAssign => "+=",
+                    Token::SubAssign => "-=",
+                    Token::DivAssign => "/=",
+                    Token::MulAssign => "*=",
+                    Token::Mod => "%",
+                    Token::Equal => "==",
+                    Token::GreaterEqual => ">=",
+                    Token::GreaterThan => ">",
+                    Token::LessEqual => "<=",
+                    Token::LessThan => "<",
+                    Token::And => "&&",
+                    Token::Or => "||",
+                    Token::Not => "!",
+                    Token::LParen => "(",
+                    Token::RParen => ")",
+                    Token::LBracket => "[",
+                    Token::RBracket => "]",
+                    Token::LBrace => "{",
+                    Token::RBrace => "}",
+                    Token::Comma => ",",
+                    Token::Semicolon => ";",
+                    Token::Colon => ":",
+                    Token::Dot => ".",
+                    Token::Ref => "&",
+                    Token::FuncArrow => "=>",
+                    _ => unreachable!(),
+                };
+                write!(f, "{}", text)
+            }
+        }
+    }
 }
 
 impl Token {
@@ -87,22 +195,23 @@ impl Token {
         Token::parse_identifier(input)
             .or_else(|| Token::parse_literal(input))
             .or_else(|| Token::parse_basic_token(input))
+            .or_else(|| {
+                let c = input.chars().next()?;
+                Some((Token::Error(LexError::UnknownCharacter(c)), c.len_utf8()))
+            })
     }
 
     pub fn parse_identifier(input: &str) -> Option<(Token, usize)> {
         let mut iterator = input.chars();
 
-        let mut len = 1;
         let first = iterator.next()?;
         if !first.is_alphabetic() && first != '_' {
             return None;
         }
 
-        while iterator
-            .next()
-            .is_some_and(|c| c.is_alphanumeric() || c == '_')
-        {
-            len += 1;
+        let mut len = first.len_utf8();
+        while let Some(c) = iterator.next().filter(|c| c.is_alphanumeric() || *c == '_') {
+            len += c.len_utf8();
         }
 
         let slice = &input[..len];
@@ -120,27 +229,29 @@ impl Token {
             return Some((Token::Float(number), len));
         }
 
-        if let Some((number, len)) = parse_int_literal(input) {
-            return Some((Token::Integer(number), len));
+        if let Some(result) = parse_int_literal(input) {
+            return Some(Token::from_literal(result, Token::Integer));
         }
 
-        if let Some((string, len)) = parse_string_literal(input) {
-            return Some((Token::String(string), len));
+        if let Some(result) = parse_string_literal(input) {
+            return Some(Token::from_literal(result, Token::String));
         }
 
-        if let Some((c, len)) = parse_char_literal(input) {
-            return Some((Token::Char(c), len));
+        if let Some(result) = parse_char_literal(input) {
+            return Some(Token::from_literal(result, Token::Char));
         }
 
-        if input.starts_with("true") {
-            return Some((Token::Bool(true), 4));
-        }
+        None
+    }
 
-        if input.starts_with("false") {
-            return Some((Token::Bool(false), 5));
+    fn from_literal<T>(
+        result: Result<(T, usize), (LexError, usize)>,
+        to_token: impl Fn(T) -> Token,
+    ) -> (Token, usize) {
+        match result {
+            Ok((value, len)) => (to_token(value), len),
+            Err((err, len)) => (Token::Error(err), len),
         }
-
-        None
     }
 
     fn parse_basic_token(input: &str) -> Option<(Token, usize)> {
@@ -149,9 +260,11 @@ impl Token {
             return None;
         }
         loop {
-            let slice = &input[..max_basic_token_len];
-            if let Some(token) = Token::match_basic_token(slice) {
-                break Some((token, max_basic_token_len));
+            if input.is_char_boundary(max_basic_token_len) {
+                let slice = &input[..max_basic_token_len];
+                if let Some(token) = Token::match_basic_token(slice) {
+                    break Some((token, max_basic_token_len));
+                }
             }
             // We go backwards to match the longest token first
             max_basic_token_len -= 1;
@@ -171,6 +284,8 @@ impl Token {
             "yeet" => Some(Token::Yeet),
             "null" => Some(Token::Null),
             "typeof" => Some(Token::TypeOf),
+            "true" => Some(Token::Bool(true)),
+            "false" => Some(Token::Bool(false)),
 
             // Control Flow
             "if" => Some(Token::If),
@@ -181,6 +296,8 @@ impl Token {
             "break" => Some(Token::Break),
             "continue" => Some(Token::Continue),
             "loop" => Some(Token::Loop),
+            "try" => Some(Token::Try),
+            "catch" => Some(Token::Catch),
 
             // Built in types
             "type" => Some(Token::Type),
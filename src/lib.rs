@@ -1,3 +1,5 @@
+pub mod codegen;
+pub mod diagnostics;
 pub mod lexer;
 pub mod parser;
 pub mod type_checker;
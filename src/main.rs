@@ -1,91 +1,535 @@
 use compiler_rs::{
-    lexer::lexer::Lexer,
-    parser::statements::parse_block,
-    type_checker::{statements::check_block, Scope},
-    wasm::{self, encoder::EncodesToWasm, WasmModule},
+    codegen::{declare_builtins, generate_module},
+    diagnostics::Diagnostic,
+    lexer::{lexer::Lexer, span::Spanned, token::Token},
+    parser::{helpers::ParseError, statements::parse_program},
+    type_checker::{check_program, Scope},
+    wasm::{
+        encoder::EncodesToWasm,
+        interpreter::{Imports, Instance, Value},
+        source_map::{source_mapping_url_section, SourceMap},
+        wat::{
+            parser::parse_module,
+            printer::{print_module, Style},
+        },
+        NumType, WasmModule,
+    },
 };
-use std::fs::read_to_string;
+use std::{
+    fmt::{self, Write as _},
+    fs,
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+    process::exit,
+    str::FromStr,
+};
+
+const USAGE: &str = "\
+usage: compiler-rs [options] <inputs...>
+
+options:
+    -o <path>         write the output to <path>
+    --emit <stage>    stop after <stage>, one of: tokens, ast, types, wat, wat-folded,
+                      wasm (default)
+    --run <call>      run an exported function with the built-in interpreter instead of
+                      writing the wasm, eg. `--run \"add 1 2\"`, printing what it returns
+    --source-map      also write a source map next to the wasm, eg. `tree.wasm.map`, so
+                      browser devtools can step through the source
+    --gc              free unreachable heap values with a garbage collector, instead of
+                      leaving that to `free`
+    -h, --help        print this message
+
+Text output is written to stdout unless -o is given. Wasm is written next to the first
+input, eg. `tree.jj` is compiled to `tree.wasm`. `.wasm` inputs can be printed with
+`--emit wat` or `--emit wat-folded`, and a `.wat` input can be assembled to wasm.";
+
+/// The pipeline stage to stop at and print the output of.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Emit {
+    Tokens,
+    Ast,
+    // The types and variables defined at the top level, once checked
+    Types,
+    Wat,
+    // Wat with the operands of instructions nested inside them
+    WatFolded,
+    Wasm,
+}
+
+impl FromStr for Emit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tokens" => Ok(Emit::Tokens),
+            "ast" => Ok(Emit::Ast),
+            "types" => Ok(Emit::Types),
+            "wat" => Ok(Emit::Wat),
+            "wat-folded" => Ok(Emit::WatFolded),
+            "wasm" => Ok(Emit::Wasm),
+            _ => Err(format!("unknown stage `{}` for `--emit`", s)),
+        }
+    }
+}
+
+impl fmt::Display for Emit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Emit::Tokens => write!(f, "tokens"),
+            Emit::Ast => write!(f, "ast"),
+            Emit::Types => write!(f, "types"),
+            Emit::Wat => write!(f, "wat"),
+            Emit::WatFolded => write!(f, "wat-folded"),
+            Emit::Wasm => write!(f, "wasm"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Options {
+    inputs: Vec<PathBuf>,
+    output: Option<PathBuf>,
+    emit: Emit,
+    run: Option<String>,
+    source_map: bool,
+    gc: bool,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut inputs = Vec::new();
+    let mut output = None;
+    let mut emit = Emit::Wasm;
+    let mut run = None;
+    let mut source_map = false;
+    let mut gc = false;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                exit(0);
+            }
+            "-o" => {
+                let path = args.next().ok_or("`-o` needs a path")?;
+                output = Some(PathBuf::from(path));
+            }
+            "--emit" => {
+                let stage = args.next().ok_or("`--emit` needs a stage")?;
+                emit = stage.parse()?;
+            }
+            "--run" => run = Some(args.next().ok_or("`--run` needs a function")?),
+            "--source-map" => source_map = true,
+            "--gc" => gc = true,
+            _ => match arg.strip_prefix("--emit=") {
+                Some(stage) => emit = stage.parse()?,
+                None if arg.starts_with('-') && arg != "-" => {
+                    return Err(format!("unknown option `{}`", arg))
+                }
+                None => inputs.push(PathBuf::from(arg)),
+            },
+        }
+    }
+
+    if inputs.is_empty() {
+        return Err("no input files".to_string());
+    }
+    if run.is_some() && emit != Emit::Wasm {
+        return Err(format!("`--run` can't be used with `--emit {}`", emit));
+    }
+    if source_map {
+        if emit != Emit::Wasm {
+            return Err(format!(
+                "`--source-map` can't be used with `--emit {}`",
+                emit
+            ));
+        }
+        if run.is_some() {
+            return Err("`--source-map` can't be used with `--run`".to_string());
+        }
+        if inputs.iter().any(|path| is_module(path)) {
+            return Err("source maps can only be made when compiling `.jj` files".to_string());
+        }
+    }
+
+    if gc && inputs.iter().any(|path| is_module(path)) {
+        return Err("`--gc` can only be used when compiling `.jj` files".to_string());
+    }
+
+    Ok(Options {
+        inputs,
+        output,
+        emit,
+        run,
+        source_map,
+        gc,
+    })
+}
+
+struct SourceFile {
+    path: String,
+    source: String,
+}
+
+/// A diagnostic along with the file it points into, if any.
+type Report<'a> = (Option<&'a SourceFile>, Diagnostic);
 
 fn main() {
-    let source = read_to_string("tests/tree.jj").unwrap();
+    let options = parse_args(std::env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("error: {}\n\n{}", err, USAGE);
+        exit(2);
+    });
+
+    if options.inputs.iter().any(|path| is_module(path)) {
+        let output = convert_modules(&options).unwrap_or_else(|err| {
+            eprint!("{}", err);
+            exit(1);
+        });
+        match &options.run {
+            Some(function) => run(function, &output),
+            None => write_output(&options, &output),
+        }
+        return;
+    }
+
+    let files = options
+        .inputs
+        .iter()
+        .map(|path| {
+            let source = fs::read_to_string(path).unwrap_or_else(|err| {
+                eprint!(
+                    "{}",
+                    Diagnostic::error(format!("could not read `{}`: {}", path.display(), err))
+                        .render("", "")
+                );
+                exit(1);
+            });
+            SourceFile {
+                path: path.display().to_string(),
+                source,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // The module refers to the map by its file name, as it's written next to the module
+    let map_path = options
+        .source_map
+        .then(|| output_path(&options))
+        .flatten()
+        .map(|path| {
+            let mut path = path.into_os_string();
+            path.push(".map");
+            PathBuf::from(path)
+        });
+    let map_url = map_path
+        .as_ref()
+        .and_then(|path| path.file_name())
+        .map(|name| name.to_string_lossy().into_owned());
+
+    let compiled = compile(&files, &options, map_url.as_deref()).unwrap_or_else(|reports| {
+        for (file, diagnostic) in reports.iter() {
+            match file {
+                Some(file) => eprintln!("{}", diagnostic.render(&file.source, &file.path)),
+                None => eprintln!("{}", diagnostic.render("", "")),
+            }
+        }
+        let errors = reports.len();
+        eprintln!(
+            "error: could not compile due to {} previous error{}",
+            errors,
+            if errors == 1 { "" } else { "s" }
+        );
+        exit(1);
+    });
+    let (output, source_map) = compiled;
+    match &options.run {
+        Some(function) => run(function, &output),
+        None => write_output(&options, &output),
+    }
+    if let (Some(path), Some(source_map)) = (map_path, source_map) {
+        write_file(Some(&path), source_map.as_bytes());
+    }
+}
 
-    let mut lexer = Lexer::new(&source);
+// Runs a call like `add 1 2` on the compiled module, with `print_int` printing to stdout like
+// `tests/main.js`
+fn run(call: &str, wasm: &[u8]) {
+    let module = WasmModule::decode(wasm).expect("encoded by the compiler");
+    let imports = Imports::default().with_function("env", "print_int", |args, _| {
+        println!("{}", args[0]);
+        Ok(Vec::new())
+    });
+    let mut words = call.split_whitespace();
+    let function = words.next().unwrap_or("");
+    let result = Instance::new(&module, imports)
+        .map_err(|err| err.to_string())
+        .and_then(|mut instance| {
+            let typ = instance
+                .export_type(function)
+                .map_err(|err| err.to_string())?;
+            // Extra arguments are reported by `call` along with the expected types
+            let args = words
+                .zip(typ.args.iter().chain(std::iter::repeat(&NumType::I32)))
+                .map(|(word, typ)| parse_value(word, *typ))
+                .collect::<Result<Vec<_>, _>>()?;
+            instance
+                .call(function, &args)
+                .map_err(|err| err.to_string())
+        });
+    match result {
+        Ok(values) => {
+            for value in values {
+                println!("{}", value);
+            }
+        }
+        Err(err) => {
+            eprint!("{}", Diagnostic::error(err).render("", ""));
+            exit(1);
+        }
+    }
+}
+
+fn parse_value(word: &str, typ: NumType) -> Result<Value, String> {
+    let value = match typ {
+        NumType::I32 => word.parse().ok().map(Value::I32),
+        NumType::I64 => word.parse().ok().map(Value::I64),
+        NumType::F32 => word.parse().ok().map(Value::F32),
+        NumType::F64 => word.parse().ok().map(Value::F64),
+    };
+    value.ok_or_else(|| format!("`{}` is not a valid `{}`", word, typ))
+}
+
+// Where the output goes, or `None` for stdout
+fn output_path(options: &Options) -> Option<PathBuf> {
+    match (&options.output, options.emit) {
+        (Some(path), _) => Some(path.clone()),
+        (None, Emit::Wasm) => Some(options.inputs[0].with_extension("wasm")),
+        (None, _) => None,
+    }
+}
 
-    let program = parse_block(&mut lexer, false);
-    dbg! { &program };
-    let program = program.expect("Failed to parse program");
+fn write_output(options: &Options, output: &[u8]) {
+    write_file(output_path(options).as_deref(), output);
+}
+
+fn write_file(output_path: Option<&Path>, output: &[u8]) {
+    let written = match output_path {
+        Some(path) => fs::write(path, output),
+        None => io::stdout().write_all(output),
+    };
+    if let Err(err) = written {
+        let destination =
+            output_path.map_or("stdout".to_string(), |path| format!("`{}`", path.display()));
+        eprint!(
+            "{}",
+            Diagnostic::error(format!("could not write to {}: {}", destination, err))
+                .render("", "")
+        );
+        exit(1);
+    }
+}
+
+/// Runs the pipeline over every file up to the `emit` stage, returning its output. Given the
+/// URL of a source map, the wasm links to it and the map is returned too.
+fn compile<'a>(
+    files: &'a [SourceFile],
+    options: &Options,
+    map_url: Option<&str>,
+) -> Result<(Vec<u8>, Option<String>), Vec<Report<'a>>> {
+    let emit = options.emit;
+    if emit == Emit::Tokens {
+        return emit_tokens(files).map(|tokens| (tokens.into_bytes(), None));
+    }
+
+    // Files are parsed separately but share one scope, so later files can use earlier definitions
+    let mut programs = Vec::new();
+    let mut reports = Vec::new();
+    for file in files {
+        let (program, errors) = parse_program(Lexer::new(&file.source));
+        reports.extend(errors.into_iter().map(|err| (Some(file), err.into())));
+        programs.push((file, program));
+    }
+    if !reports.is_empty() {
+        return Err(reports);
+    }
+
+    if emit == Emit::Ast {
+        let mut output = String::new();
+        for (file, program) in programs.iter() {
+            writeln!(output, "// {}\n{:#?}", file.path, program).unwrap();
+        }
+        return Ok((output.into_bytes(), None));
+    }
 
     let mut scope = Scope::new();
-    check_block(&program, &mut scope).unwrap();
+    declare_builtins(&mut scope);
+    for (file, program) in programs.iter() {
+        if let Err(errors) = check_program(program, &mut scope) {
+            reports.extend(errors.into_iter().map(|err| (Some(*file), err.into())));
+        }
+    }
+    if !reports.is_empty() {
+        return Err(reports);
+    }
 
-    for token in lexer {
-        println!("{:?}", token);
+    if emit == Emit::Types {
+        return Ok((emit_types(&scope).into_bytes(), None));
     }
+    let (files, programs): (Vec<_>, Vec<_>) = programs.into_iter().unzip();
+    let (module, positions) = generate_module(&programs, options.gc).map_err(|errors| {
+        errors
+            .into_iter()
+            .map(|(file, err)| (Some(files[file]), err.into()))
+            .collect::<Vec<_>>()
+    })?;
 
-    dbg!("Global Scope:", scope);
+    match emit {
+        Emit::Wat => Ok((print_module(&module, Style::Flat).into_bytes(), None)),
+        Emit::WatFolded => Ok((print_module(&module, Style::Folded).into_bytes(), None)),
+        _ => {
+            // Otherwise mistakes in codegen only show up when an engine refuses to load the module
+            module.validate().map_err(|errors| {
+                errors
+                    .into_iter()
+                    .map(|err| {
+                        let diagnostic =
+                            Diagnostic::error(format!("generated invalid wasm: {}", err))
+                                .with_note("this is a bug in the compiler");
+                        (None, diagnostic)
+                    })
+                    .collect::<Vec<_>>()
+            })?;
+            let mut bytes = Vec::new();
+            module.encode_to_wasm(&mut bytes);
 
-    let wasm_module = make_wasm_module();
+            let Some(map_url) = map_url else {
+                return Ok((bytes, None));
+            };
+            let (_, offsets) =
+                WasmModule::decode_with_offsets(&bytes).expect("encoded by the compiler");
+            let sources = files
+                .iter()
+                .map(|file| (file.path.clone(), file.source.clone()))
+                .collect();
+            let mut source_map = SourceMap::new(sources);
+            for (offsets, positions) in offsets.iter().zip(positions.iter()) {
+                for (offset, (file, span)) in offsets.iter().zip(positions.iter()) {
+                    source_map.add(*offset, *file, *span);
+                }
+            }
+            bytes.extend(source_mapping_url_section(map_url));
+            Ok((bytes, Some(source_map.to_json())))
+        }
+    }
+}
 
-    let mut bytes = vec![];
-    wasm_module.encode_to_wasm(&mut bytes);
-    std::fs::write("tests/program.wasm", bytes).unwrap();
+// Whether the input is already a wasm module, in binary or text
+fn is_module(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext == "wasm" || ext == "wat")
 }
 
-fn make_wasm_module() -> WasmModule {
-    use wasm::*;
-    WasmModule {
-        start: Some(2),
-        types: vec![
-            FunctionType {
-                args: vec![NumType::I32],
-                ret: vec![],
-            },
-            FunctionType {
-                args: vec![NumType::I32, NumType::I32],
-                ret: vec![NumType::I32],
-            },
-            FunctionType {
-                args: vec![],
-                ret: vec![],
-            },
-        ],
-        imports: vec![Import {
-            module: "env".to_string(),
-            name: "print_int".to_string(),
-            typ: ImportType::Func(0),
-        }],
-        exports: vec![Export {
-            name: "add_two_int_32".to_string(),
-            typ: ExportType::Func(1),
-        }],
-        functions: vec![
-            Function {
-                type_idx: 1,
-                locals: vec![NumType::I32],
-                body: Expression {
-                    instructions: vec![
-                        Instruction::VariableOp(VariableOp::LocalGet(0)),
-                        Instruction::VariableOp(VariableOp::LocalGet(1)),
-                        Instruction::IntegerOp(IntegerOp {
-                            op: IntegerOpType::Add,
-                            typ: IntegerType::I32,
-                        }),
-                        Instruction::VariableOp(VariableOp::LocalTee(2)),
-                        Instruction::ControlOp(ControlOp::Call(0)),
-                        Instruction::VariableOp(VariableOp::LocalGet(2)),
-                    ],
-                },
-            },
-            Function {
-                type_idx: 2,
-                locals: vec![],
-                body: Expression {
-                    instructions: vec![
-                        Instruction::I32Const(42),
-                        Instruction::ControlOp(ControlOp::Call(0)),
-                    ],
-                },
-            },
-        ],
+// Prints `.wasm` and `.wat` inputs as wat, or assembles a `.wat` input, failing with the
+// rendered error
+fn convert_modules(options: &Options) -> Result<Vec<u8>, String> {
+    let fail = |message: String| Err(Diagnostic::error(message).render("", ""));
+    let style = match options.emit {
+        Emit::Wat => Style::Flat,
+        Emit::WatFolded => Style::Folded,
+        Emit::Wasm if options.inputs.len() == 1 => Style::Flat,
+        Emit::Wasm => return fail("only one wasm module can be assembled at a time".to_string()),
+        _ => {
+            return fail(format!(
+                "`.wasm` and `.wat` inputs can't be compiled to {}, only to wat or wasm",
+                options.emit
+            ))
+        }
+    };
+
+    let mut output = String::new();
+    for path in options.inputs.iter() {
+        if !is_module(path) {
+            return fail(format!(
+                "`{}` can't be compiled along with `.wasm` and `.wat` inputs",
+                path.display()
+            ));
+        }
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(err) => return fail(format!("could not read `{}`: {}", path.display(), err)),
+        };
+        let module = if path.extension().is_some_and(|ext| ext == "wat") {
+            let source = String::from_utf8_lossy(&bytes);
+            parse_module(&source)
+                .map_err(|err| Diagnostic::from(err).render(&source, &path.display().to_string()))?
+        } else {
+            match WasmModule::decode(&bytes) {
+                Ok(module) => module,
+                Err(err) => return fail(format!("could not decode `{}`: {}", path.display(), err)),
+            }
+        };
+
+        if options.emit == Emit::Wasm {
+            if let Err(errors) = module.validate() {
+                return Err(errors
+                    .iter()
+                    .map(|err| {
+                        Diagnostic::error(format!("invalid module `{}`: {}", path.display(), err))
+                            .render("", "")
+                    })
+                    .collect());
+            }
+            let mut bytes = Vec::new();
+            module.encode_to_wasm(&mut bytes);
+            return Ok(bytes);
+        }
+        if options.inputs.len() > 1 {
+            writeln!(output, ";; {}", path.display()).unwrap();
+        }
+        output.push_str(&print_module(&module, style));
+    }
+    Ok(output.into_bytes())
+}
+
+// Lists every token with its position, failing if any of them could not be lexed
+fn emit_tokens(files: &[SourceFile]) -> Result<String, Vec<Report<'_>>> {
+    let mut output = String::new();
+    let mut reports = Vec::new();
+    for file in files {
+        for token in Lexer::new(&file.source) {
+            match token.node {
+                Token::Error(err) => reports.push((
+                    Some(file),
+                    ParseError::Lex(Spanned::new(err, token.span)).into(),
+                )),
+                tok => writeln!(
+                    output,
+                    "{}:{}:{}\t{:?}",
+                    file.path, token.span.line, token.span.column, tok
+                )
+                .unwrap(),
+            }
+        }
+    }
+    if reports.is_empty() {
+        Ok(output)
+    } else {
+        Err(reports)
+    }
+}
+
+// The types and variables defined at the top level, as the type checker resolved them
+fn emit_types(scope: &Scope) -> String {
+    let mut output = String::new();
+
+    let mut types = scope.types().collect::<Vec<_>>();
+    types.sort_by_key(|(name, _)| *name);
+    for (name, typ) in types {
+        writeln!(output, "type {} = {};", name, typ).unwrap();
+    }
+    let mut vars = scope.vars().collect::<Vec<_>>();
+    vars.sort_by_key(|(name, _)| *name);
+    for (name, typ) in vars {
+        writeln!(output, "let {}: {};", name, typ).unwrap();
     }
+    output
 }
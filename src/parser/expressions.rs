@@ -1,15 +1,21 @@
 use std::collections::HashMap;
 
-use crate::lexer::{lexer::Lexer, token::Token};
+use crate::lexer::{
+    span::{Span, Spanned},
+    token::Token,
+};
 
 use super::{
-    helpers::{build_hashmap_from_entries, parse_list, ParseError},
+    helpers::{build_hashmap_from_entries, parse_list, parse_spanned, Expected, ParseError},
+    parser::Parser,
     statements::{parse_block, Block},
     types::{parse_type, Type},
 };
 
+pub type Expression = Spanned<ExpressionKind>;
+
 #[derive(Debug, Clone, PartialEq)]
-pub enum Expression {
+pub enum ExpressionKind {
     Int(i64),
     Float(f64),
     String(String),
@@ -19,8 +25,8 @@ pub enum Expression {
     StructLiteral(HashMap<String, Expression>),
     ArrayLiteral(Vec<Expression>),
     FunctionLiteral {
-        args: Vec<(String, Type)>,
-        ret: Type,
+        args: Vec<(String, Spanned<Type>)>,
+        ret: Spanned<Type>,
         body: Block,
     },
     TupleLiteral(Vec<Expression>),
@@ -69,276 +75,313 @@ pub enum Expression {
     // },
 }
 
-pub fn parse_expression(lexer: &mut Lexer) -> Result<Expression, ParseError> {
-    parse_e1(lexer)
+pub type BinaryOp = fn(Box<Expression>, Box<Expression>) -> ExpressionKind;
+
+// helper function to build a binary expression spanning both of its operands
+pub fn binary(op: BinaryOp, lhs: Expression, rhs: Expression) -> Expression {
+    let span = lhs.span.to(&rhs.span);
+    Expression::new(op(Box::new(lhs), Box::new(rhs)), span)
+}
+
+// helper function to build a prefix expression spanning from its operator to its operand
+fn unary(op: fn(Box<Expression>) -> ExpressionKind, op_span: Span, expr: Expression) -> Expression {
+    let span = op_span.to(&expr.span);
+    Expression::new(op(Box::new(expr)), span)
+}
+
+pub fn parse_expression(parser: &mut Parser) -> Result<Expression, ParseError> {
+    parse_e1(parser)
 }
 
 // Or
-fn parse_e1(lexer: &mut Lexer) -> Result<Expression, ParseError> {
-    let lhs = parse_e2(lexer)?;
-    if lexer.parse_token(&Token::Or).is_ok() {
-        let rhs = parse_e1(lexer)?;
-        Ok(Expression::Or(Box::new(lhs), Box::new(rhs)))
+fn parse_e1(parser: &mut Parser) -> Result<Expression, ParseError> {
+    let lhs = parse_e2(parser)?;
+    if parser.parse_token(&Token::Or).is_ok() {
+        let rhs = parse_e1(parser)?;
+        Ok(binary(ExpressionKind::Or, lhs, rhs))
     } else {
         Ok(lhs)
     }
 }
 
 // And
-fn parse_e2(lexer: &mut Lexer) -> Result<Expression, ParseError> {
-    let lhs = parse_e3(lexer)?;
-    if lexer.parse_token(&Token::And).is_ok() {
-        let rhs = parse_e2(lexer)?;
-        Ok(Expression::And(Box::new(lhs), Box::new(rhs)))
+fn parse_e2(parser: &mut Parser) -> Result<Expression, ParseError> {
+    let lhs = parse_e3(parser)?;
+    if parser.parse_token(&Token::And).is_ok() {
+        let rhs = parse_e2(parser)?;
+        Ok(binary(ExpressionKind::And, lhs, rhs))
     } else {
         Ok(lhs)
     }
 }
 
 // Not
-fn parse_e3(lexer: &mut Lexer) -> Result<Expression, ParseError> {
-    if lexer.parse_token(&Token::Not).is_ok() {
-        let expr = parse_e3(lexer)?;
-        Ok(Expression::Not(Box::new(expr)))
+fn parse_e3(parser: &mut Parser) -> Result<Expression, ParseError> {
+    if let Ok(op) = parser.parse_token(&Token::Not) {
+        let expr = parse_e3(parser)?;
+        Ok(unary(ExpressionKind::Not, op.span, expr))
     } else {
-        parse_e4(lexer)
+        parse_e4(parser)
     }
 }
 
 // Comparison
-fn parse_e4(lexer: &mut Lexer) -> Result<Expression, ParseError> {
-    let lhs = parse_e5(lexer)?;
-    match lexer.expect_peek()? {
-        Token::Equal => {
-            lexer.next();
-            let rhs = parse_e4(lexer)?;
-            Ok(Expression::Equal(Box::new(lhs), Box::new(rhs)))
-        }
-        Token::GreaterThan => {
-            lexer.next();
-            let rhs = parse_e4(lexer)?;
-            Ok(Expression::GreaterThan(Box::new(lhs), Box::new(rhs)))
-        }
-        Token::GreaterEqual => {
-            lexer.next();
-            let rhs = parse_e4(lexer)?;
-            Ok(Expression::GreaterEqual(Box::new(lhs), Box::new(rhs)))
-        }
-        Token::LessThan => {
-            lexer.next();
-            let rhs = parse_e4(lexer)?;
-            Ok(Expression::LessThan(Box::new(lhs), Box::new(rhs)))
-        }
-        Token::LessEqual => {
-            lexer.next();
-            let rhs = parse_e4(lexer)?;
-            Ok(Expression::LessEqual(Box::new(lhs), Box::new(rhs)))
-        }
-        _ => Ok(lhs),
-    }
+fn parse_e4(parser: &mut Parser) -> Result<Expression, ParseError> {
+    let lhs = parse_e5(parser)?;
+    let op = match parser.parse_one_of(&[
+        Token::Equal,
+        Token::GreaterThan,
+        Token::GreaterEqual,
+        Token::LessThan,
+        Token::LessEqual,
+    ]) {
+        Ok(op) => op.node,
+        Err(_) => return Ok(lhs),
+    };
+    let op = match op {
+        Token::Equal => ExpressionKind::Equal,
+        Token::GreaterThan => ExpressionKind::GreaterThan,
+        Token::GreaterEqual => ExpressionKind::GreaterEqual,
+        Token::LessThan => ExpressionKind::LessThan,
+        Token::LessEqual => ExpressionKind::LessEqual,
+        _ => unreachable!(),
+    };
+    let rhs = parse_e4(parser)?;
+    Ok(binary(op, lhs, rhs))
 }
 
 // Add/Sub
-fn parse_e5(lexer: &mut Lexer) -> Result<Expression, ParseError> {
-    let mut lhs = parse_e6(lexer)?;
-    loop {
-        match lexer.peek() {
-            Some(Token::Add) => {
-                lexer.next();
-                let rhs = parse_e6(lexer)?;
-                lhs = Expression::Add(Box::new(lhs), Box::new(rhs));
-            }
-            Some(Token::Sub) => {
-                lexer.next();
-                let rhs = parse_e6(lexer)?;
-                lhs = Expression::Sub(Box::new(lhs), Box::new(rhs));
-            }
-            _ => break,
-        }
+fn parse_e5(parser: &mut Parser) -> Result<Expression, ParseError> {
+    let mut lhs = parse_e6(parser)?;
+    while let Ok(op) = parser.parse_one_of(&[Token::Add, Token::Sub]) {
+        let rhs = parse_e6(parser)?;
+        let op: BinaryOp = match op.node {
+            Token::Add => ExpressionKind::Add,
+            Token::Sub => ExpressionKind::Sub,
+            _ => unreachable!(),
+        };
+        lhs = binary(op, lhs, rhs);
     }
     Ok(lhs)
 }
 
 // Mul/Div/Mod
-fn parse_e6(lexer: &mut Lexer) -> Result<Expression, ParseError> {
-    let mut lhs = parse_e7(lexer)?;
-    loop {
-        match lexer.peek() {
-            Some(Token::Mul) => {
-                lexer.next();
-                let rhs = parse_e7(lexer)?;
-                lhs = Expression::Mul(Box::new(lhs), Box::new(rhs));
-            }
-            Some(Token::Div) => {
-                lexer.next();
-                let rhs = parse_e7(lexer)?;
-                lhs = Expression::Div(Box::new(lhs), Box::new(rhs));
-            }
-            Some(Token::Mod) => {
-                lexer.next();
-                let rhs = parse_e7(lexer)?;
-                lhs = Expression::Mod(Box::new(lhs), Box::new(rhs));
-            }
-            _ => break,
-        }
+fn parse_e6(parser: &mut Parser) -> Result<Expression, ParseError> {
+    let mut lhs = parse_e7(parser)?;
+    while let Ok(op) = parser.parse_one_of(&[Token::Mul, Token::Div, Token::Mod]) {
+        let rhs = parse_e7(parser)?;
+        let op: BinaryOp = match op.node {
+            Token::Mul => ExpressionKind::Mul,
+            Token::Div => ExpressionKind::Div,
+            Token::Mod => ExpressionKind::Mod,
+            _ => unreachable!(),
+        };
+        lhs = binary(op, lhs, rhs);
     }
     Ok(lhs)
 }
 
 // Neg/Pos
-fn parse_e7(lexer: &mut Lexer) -> Result<Expression, ParseError> {
-    if lexer.parse_token(&Token::Sub).is_ok() {
-        let expr = parse_e7(lexer)?;
-        Ok(Expression::Neg(Box::new(expr)))
+fn parse_e7(parser: &mut Parser) -> Result<Expression, ParseError> {
+    if let Ok(op) = parser.parse_token(&Token::Sub) {
+        let expr = parse_e7(parser)?;
+        Ok(unary(ExpressionKind::Neg, op.span, expr))
     } else {
-        parse_e8(lexer)
+        parse_e8(parser)
     }
 }
 
-fn parse_e8(lexer: &mut Lexer) -> Result<Expression, ParseError> {
-    if lexer.parse_token(&Token::Ref).is_ok() {
-        let expr = parse_e8(lexer)?;
-        Ok(Expression::Ref(Box::new(expr)))
-    } else if lexer.parse_token(&Token::Mul).is_ok() {
-        let expr = parse_e8(lexer)?;
-        Ok(Expression::Deref(Box::new(expr)))
+fn parse_e8(parser: &mut Parser) -> Result<Expression, ParseError> {
+    if let Ok(op) = parser.parse_token(&Token::Ref) {
+        let expr = parse_e8(parser)?;
+        Ok(unary(ExpressionKind::Ref, op.span, expr))
+    } else if let Ok(op) = parser.parse_token(&Token::Mul) {
+        let expr = parse_e8(parser)?;
+        Ok(unary(ExpressionKind::Deref, op.span, expr))
     } else {
-        parse_e9(lexer)
+        parse_e9(parser)
     }
 }
 
 // Call/Index/Dot
-fn parse_e9(lexer: &mut Lexer) -> Result<Expression, ParseError> {
-    let mut expr = parse_literal(lexer)?;
+fn parse_e9(parser: &mut Parser) -> Result<Expression, ParseError> {
+    let mut expr = parse_literal(parser)?;
     loop {
-        match lexer.expect_peek()? {
+        let kind = match &parser.expect_peek()?.node {
             Token::LParen => {
                 let args = parse_list(
-                    lexer,
+                    parser,
                     &Token::LParen,
                     &Token::Comma,
                     &Token::RParen,
                     parse_expression,
                 )?;
-                expr = Expression::Call {
+                ExpressionKind::Call {
                     expr: Box::new(expr),
                     args,
-                };
+                }
             }
             Token::LBracket => {
-                lexer.next();
-                let index = parse_expression(lexer)?;
-                lexer.parse_token(&Token::RBracket)?;
-                expr = Expression::Index {
+                parser.next();
+                let index = parse_expression(parser)?;
+                parser.parse_token(&Token::RBracket)?;
+                ExpressionKind::Index {
                     expr: Box::new(expr),
                     index: Box::new(index),
-                };
+                }
             }
             Token::Dot => {
-                lexer.next();
-                let field = lexer.parse_ident()?;
-                expr = Expression::Dot {
+                parser.next();
+                let field = parser.parse_ident()?;
+                ExpressionKind::Dot {
                     expr: Box::new(expr),
                     field,
-                };
+                }
             }
             _ => break,
-        }
+        };
+        let span = match &kind {
+            ExpressionKind::Call { expr, .. }
+            | ExpressionKind::Index { expr, .. }
+            | ExpressionKind::Dot { expr, .. } => expr.span.to(&parser.last_span()),
+            _ => unreachable!(),
+        };
+        expr = Expression::new(kind, span);
     }
     Ok(expr)
 }
 
-fn parse_literal(lexer: &mut Lexer) -> Result<Expression, ParseError> {
-    lexer
-        .parse_int()
-        .map(|v| Expression::Int(v))
-        .or_else(|_| lexer.parse_token(&Token::Null).map(|_| Expression::Null))
-        .or_else(|_| lexer.parse_float().map(|v| Expression::Float(v)))
-        .or_else(|_| lexer.parse_char().map(|v| Expression::Char(v)))
-        .or_else(|_| lexer.parse_bool().map(|v| Expression::Bool(v)))
-        .or_else(|_| lexer.parse_string().map(|v| Expression::String(v)))
-        .or_else(|_| lexer.parse_ident().map(|v| Expression::Identifier(v)))
-        .or_else(|_| parse_struct_literal(lexer))
-        .or_else(|_| parse_array_literal(lexer))
-        .or_else(|_| {
-            if is_func_literal(lexer).is_ok() {
-                parse_function_literal(lexer)
+fn parse_literal(parser: &mut Parser) -> Result<Expression, ParseError> {
+    // Literals starting with brackets are told apart by their first token, so that errors
+    // inside them are reported rather than hidden by trying the other alternatives
+    match parser.peek().map(|tok| &tok.node) {
+        Some(Token::LBrace) => return parse_struct_literal(parser),
+        Some(Token::LBracket) => return parse_array_literal(parser),
+        Some(Token::LParen) => {
+            return if is_func_literal(parser).is_ok() {
+                parse_function_literal(parser)
             } else {
-                let items = parse_list(
-                    lexer,
-                    &Token::LParen,
-                    &Token::Comma,
-                    &Token::RParen,
-                    parse_expression,
-                )?;
-                if items.len() == 1 {
-                    Ok(items.into_iter().next().unwrap())
-                } else {
-                    Ok(Expression::TupleLiteral(items))
-                }
+                parse_tuple_literal(parser)
             }
+        }
+        _ => {}
+    }
+
+    let span = parser.peek_span();
+    parser
+        .parse_int()
+        .map(ExpressionKind::Int)
+        .or_else(|_| {
+            parser
+                .parse_token(&Token::Null)
+                .map(|_| ExpressionKind::Null)
+        })
+        .or_else(|_| parser.parse_float().map(ExpressionKind::Float))
+        .or_else(|_| parser.parse_char().map(ExpressionKind::Char))
+        .or_else(|_| parser.parse_bool().map(ExpressionKind::Bool))
+        .or_else(|_| parser.parse_string().map(ExpressionKind::String))
+        .or_else(|_| parser.parse_ident().map(ExpressionKind::Identifier))
+        .map(|kind| Expression::new(kind, span))
+        .map_err(|_| {
+            // "expected expression" rather than every token an expression can start with
+            parser.replace_expected(Expected::starts_expression, Expected::Expression);
+            parser.unexpected()
         })
 }
 
-fn parse_struct_literal(lexer: &mut Lexer) -> Result<Expression, ParseError> {
-    let fields = parse_list(lexer, &Token::LBrace, &Token::Comma, &Token::RBrace, |l| {
-        let name = l.parse_ident()?;
-        l.parse_token(&Token::Colon)?;
-        let expr = parse_expression(l)?;
-        Ok((name, expr))
+fn parse_tuple_literal(parser: &mut Parser) -> Result<Expression, ParseError> {
+    let items = parse_spanned(parser, |parser| {
+        parse_list(
+            parser,
+            &Token::LParen,
+            &Token::Comma,
+            &Token::RParen,
+            parse_expression,
+        )
+    })?;
+    if items.node.len() == 1 {
+        // A parenthesised expression covers its parentheses too
+        let mut item = items.node.into_iter().next().unwrap();
+        item.span = items.span;
+        Ok(item)
+    } else {
+        Ok(Expression::new(
+            ExpressionKind::TupleLiteral(items.node),
+            items.span,
+        ))
+    }
+}
+
+fn parse_struct_literal(parser: &mut Parser) -> Result<Expression, ParseError> {
+    let fields = parse_spanned(parser, |parser| {
+        parse_list(parser, &Token::LBrace, &Token::Comma, &Token::RBrace, |l| {
+            let name = parse_spanned(l, |l| l.parse_ident())?;
+            l.parse_token(&Token::Colon)?;
+            let expr = parse_expression(l)?;
+            Ok((name, expr))
+        })
     })?;
 
-    let fields = build_hashmap_from_entries(fields)?;
+    let span = fields.span;
+    let fields = build_hashmap_from_entries(fields.node)?;
 
-    Ok(Expression::StructLiteral(fields))
+    Ok(Expression::new(ExpressionKind::StructLiteral(fields), span))
 }
 
-fn parse_array_literal(lexer: &mut Lexer) -> Result<Expression, ParseError> {
-    let fields = parse_list(
-        lexer,
-        &Token::LBracket,
-        &Token::Comma,
-        &Token::RBracket,
-        parse_expression,
-    )?;
+fn parse_array_literal(parser: &mut Parser) -> Result<Expression, ParseError> {
+    let fields = parse_spanned(parser, |parser| {
+        parse_list(
+            parser,
+            &Token::LBracket,
+            &Token::Comma,
+            &Token::RBracket,
+            parse_expression,
+        )
+    })?;
 
-    Ok(Expression::ArrayLiteral(fields))
+    Ok(Expression::new(
+        ExpressionKind::ArrayLiteral(fields.node),
+        fields.span,
+    ))
 }
 
-fn is_func_literal(lexer: &Lexer) -> Result<(), ParseError> {
-    let mut lexer = lexer.clone();
-    lexer.parse_token(&Token::LParen)?;
-    if lexer.parse_token(&Token::RParen).is_ok() {
+fn is_func_literal(parser: &Parser) -> Result<(), ParseError> {
+    let mut parser = parser.lookahead();
+    parser.parse_token(&Token::LParen)?;
+    if parser.parse_token(&Token::RParen).is_ok() {
         return Ok(());
     }
-    lexer.parse_ident()?;
-    lexer.parse_token(&Token::Colon)?;
+    parser.parse_ident()?;
+    parser.parse_token(&Token::Colon)?;
     Ok(())
 }
 
-fn parse_function_literal(lexer: &mut Lexer) -> Result<Expression, ParseError> {
-    let args = parse_list(
-        lexer,
-        &Token::LParen,
-        &Token::Comma,
-        &Token::RParen,
-        |lexer| {
-            let name = lexer.parse_ident()?;
-            lexer.parse_token(&Token::Colon)?;
-            let typ = parse_type(lexer)?;
-            Ok((name, typ))
-        },
-    )?;
-
-    let ret = if lexer.parse_token(&Token::Colon).is_ok() {
-        parse_type(lexer)?
-    } else {
-        Type::Void
-    };
-
-    lexer.parse_token(&Token::FuncArrow)?;
-
-    let body = parse_block(lexer, true)?;
-
-    Ok(Expression::FunctionLiteral { args, ret, body })
+fn parse_function_literal(parser: &mut Parser) -> Result<Expression, ParseError> {
+    parse_spanned(parser, |parser| {
+        let args = parse_list(
+            parser,
+            &Token::LParen,
+            &Token::Comma,
+            &Token::RParen,
+            |parser| {
+                let name = parser.parse_ident()?;
+                parser.parse_token(&Token::Colon)?;
+                let typ = parse_spanned(parser, parse_type)?;
+                Ok((name, typ))
+            },
+        )?;
+
+        let ret = if parser.parse_token(&Token::Colon).is_ok() {
+            parse_spanned(parser, parse_type)?
+        } else {
+            // Without an annotation the return type is attributed to the argument list
+            Spanned::new(Type::Void, parser.last_span())
+        };
+
+        parser.parse_token(&Token::FuncArrow)?;
+
+        let body = parse_block(parser, true)?;
+
+        Ok(ExpressionKind::FunctionLiteral { args, ret, body })
+    })
 }
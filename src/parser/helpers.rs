@@ -1,45 +1,60 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt};
 
-use crate::lexer::{lexer::Lexer, token::Token};
+use crate::lexer::{
+    span::{Span, Spanned},
+    token::{LexError, Token},
+};
+
+use super::parser::Parser;
 
 pub fn parse_list<T>(
-    lexer: &mut Lexer,
+    parser: &mut Parser,
     start: &Token,
     separator: &Token,
     end: &Token,
-    parse_item: impl Fn(&mut Lexer) -> Result<T, ParseError>,
+    parse_item: impl Fn(&mut Parser) -> Result<T, ParseError>,
 ) -> Result<Vec<T>, ParseError> {
     let mut items = Vec::new();
 
-    lexer.parse_token(start)?;
+    parser.parse_token(start)?;
 
     loop {
-        if lexer.parse_token(end).is_ok() {
+        if parser.parse_token(end).is_ok() {
             break;
         }
 
-        items.push(parse_item(lexer)?);
+        items.push(parse_item(parser)?);
 
-        if lexer.parse_token(end).is_ok() {
+        if parser.parse_token(end).is_ok() {
             break;
         }
 
-        lexer.parse_token(separator)?;
+        parser.parse_token(separator)?;
     }
 
     Ok(items)
 }
 
+/// Runs `parse_item` and tags its result with the span of every token it consumed.
+pub fn parse_spanned<T>(
+    parser: &mut Parser,
+    parse_item: impl FnOnce(&mut Parser) -> Result<T, ParseError>,
+) -> Result<Spanned<T>, ParseError> {
+    let start = parser.peek_span();
+    let node = parse_item(parser)?;
+    Ok(Spanned::new(node, start.to(&parser.last_span())))
+}
+
 pub fn build_hashmap_from_entries<T>(
-    entries: Vec<(String, T)>,
+    entries: Vec<(Spanned<String>, T)>,
 ) -> Result<HashMap<String, T>, ParseError> {
     let mut map = HashMap::new();
 
     for (key, value) in entries {
-        if map.contains_key(&key) {
-            return Err(ParseError::Unknown("Duplicate key".to_string()));
+        if map.contains_key(&key.node) {
+            return Err(ParseError::DuplicateKey(key));
         }
-        map.insert(key, value);
+        map.insert(key.node, value);
     }
 
     Ok(map)
@@ -47,7 +62,92 @@ pub fn build_hashmap_from_entries<T>(
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum ParseError {
-    UnexpectedToken(Token),
-    UnexpectedEOF(),
-    Unknown(String),
+    UnexpectedToken {
+        found: Spanned<Token>,
+        expected: Vec<Expected>,
+    },
+    UnexpectedEOF {
+        span: Span,
+        expected: Vec<Expected>,
+    },
+    Lex(Spanned<LexError>),
+    DuplicateKey(Spanned<String>),
+}
+
+/// Something the parser would have accepted where it gave up, used to explain parse errors.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expected {
+    Token(Token),
+    Identifier,
+    Integer,
+    Float,
+    String,
+    Char,
+    Bool,
+    Expression,
+    Type,
+}
+
+impl Expected {
+    /// Whether an expression can start with this, see `parse_literal`.
+    pub fn starts_expression(&self) -> bool {
+        match self {
+            Expected::Token(tok) => matches!(
+                tok,
+                Token::Not
+                    | Token::Sub
+                    | Token::Ref
+                    | Token::Mul
+                    | Token::Null
+                    | Token::LBrace
+                    | Token::LBracket
+                    | Token::LParen
+            ),
+            Expected::Identifier
+            | Expected::Integer
+            | Expected::Float
+            | Expected::String
+            | Expected::Char
+            | Expected::Bool
+            | Expected::Expression => true,
+            Expected::Type => false,
+        }
+    }
+
+    /// Whether this is a binary operator, which are listed together as "an operator" in errors.
+    pub fn is_operator(&self) -> bool {
+        matches!(
+            self,
+            Expected::Token(
+                Token::Or
+                    | Token::And
+                    | Token::Equal
+                    | Token::GreaterThan
+                    | Token::GreaterEqual
+                    | Token::LessThan
+                    | Token::LessEqual
+                    | Token::Add
+                    | Token::Sub
+                    | Token::Mul
+                    | Token::Div
+                    | Token::Mod
+            )
+        )
+    }
+}
+
+impl fmt::Display for Expected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expected::Token(tok) => write!(f, "`{}`", tok),
+            Expected::Identifier => write!(f, "identifier"),
+            Expected::Integer => write!(f, "integer literal"),
+            Expected::Float => write!(f, "float literal"),
+            Expected::String => write!(f, "string literal"),
+            Expected::Char => write!(f, "char literal"),
+            Expected::Bool => write!(f, "bool literal"),
+            Expected::Expression => write!(f, "expression"),
+            Expected::Type => write!(f, "type"),
+        }
+    }
 }
@@ -1,4 +1,6 @@
 pub mod expressions;
 pub mod helpers;
+#[allow(clippy::module_inception)]
+pub mod parser;
 pub mod statements;
 pub mod types;
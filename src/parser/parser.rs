@@ -0,0 +1,270 @@
+use crate::lexer::{
+    lexer::Lexer,
+    span::{Span, Spanned},
+    token::Token,
+};
+
+use super::helpers::{Expected, ParseError};
+
+/// Reads tokens from the lexer for the parse functions, keeping track of what they looked
+/// for so that errors can list everything that would have been accepted.
+#[derive(Debug, Clone)]
+pub struct Parser<'a> {
+    lexer: Lexer<'a>,
+    // what the parser tried to find at the current position, see `expect`
+    expected: Vec<Expected>,
+    // errors the parser recovered from, see `parse_program`
+    errors: Vec<ParseError>,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(lexer: Lexer<'a>) -> Self {
+        Self {
+            lexer,
+            expected: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    /// A parser at the same position, for looking ahead without consuming anything.
+    pub fn lookahead(&self) -> Self {
+        Self::new(self.lexer.clone())
+    }
+
+    // helper function to consume the next token, forgetting what was expected before it
+    fn bump(&mut self) -> Option<Spanned<Token>> {
+        let tok = self.lexer.next()?;
+        self.expected.clear();
+        Some(tok)
+    }
+
+    /// The span of the most recently consumed token.
+    pub fn last_span(&self) -> Span {
+        self.lexer.last_span()
+    }
+
+    /// Records an error the parser has recovered from.
+    pub fn report(&mut self, error: ParseError) {
+        // What the failed parse expected no longer applies once the parser moves on
+        self.expected.clear();
+        self.errors.push(error);
+    }
+
+    pub fn take_errors(&mut self) -> Vec<ParseError> {
+        std::mem::take(&mut self.errors)
+    }
+
+    /// Records that the parser would have accepted `expected` at the current position.
+    /// Everything expected is listed in the error if the parser gives up before consuming another token.
+    pub fn expect(&mut self, expected: Expected) {
+        if !self.expected.contains(&expected) {
+            self.expected.push(expected);
+        }
+    }
+
+    /// Replaces everything expected at the current position matching `replaced` with `with`,
+    /// eg. all the tokens an expression can start with become just "expression".
+    pub fn replace_expected(&mut self, replaced: impl Fn(&Expected) -> bool, with: Expected) {
+        self.expected.retain(|expected| !replaced(expected));
+        self.expect(with);
+    }
+
+    pub fn peek(&mut self) -> Option<&Spanned<Token>> {
+        self.lexer.peek()
+    }
+
+    /// The span of the next token, or the end of the input if there are no tokens left.
+    pub fn peek_span(&mut self) -> Span {
+        self.lexer.peek_span()
+    }
+
+    pub fn parse_token(&mut self, token: &Token) -> Result<Spanned<Token>, ParseError> {
+        match self.lexer.peek() {
+            Some(tok) if &tok.node == token => Ok(self.bump().unwrap()),
+            _ => {
+                self.expect(Expected::Token(token.clone()));
+                Err(self.unexpected())
+            }
+        }
+    }
+
+    /// Consumes the next token if it is any of `tokens`.
+    pub fn parse_one_of(&mut self, tokens: &[Token]) -> Result<Spanned<Token>, ParseError> {
+        match self.lexer.peek() {
+            Some(tok) if tokens.contains(&tok.node) => Ok(self.bump().unwrap()),
+            _ => {
+                for token in tokens {
+                    self.expect(Expected::Token(token.clone()));
+                }
+                Err(self.unexpected())
+            }
+        }
+    }
+
+    pub fn expect_next(&mut self) -> Result<Spanned<Token>, ParseError> {
+        match self.bump() {
+            Some(tok) => Ok(tok),
+            None => Err(self.unexpected()),
+        }
+    }
+    pub fn expect_peek(&mut self) -> Result<&Spanned<Token>, ParseError> {
+        if self.lexer.peek().is_none() {
+            return Err(self.unexpected());
+        }
+        Ok(self.lexer.peek().unwrap())
+    }
+
+    pub fn parse_ident(&mut self) -> Result<String, ParseError> {
+        match self.lexer.peek().map(|tok| &tok.node) {
+            Some(Token::Identifier(_)) => match self.bump().map(|tok| tok.node) {
+                Some(Token::Identifier(ident)) => Ok(ident),
+                _ => unreachable!(),
+            },
+            _ => {
+                self.expect(Expected::Identifier);
+                Err(self.unexpected())
+            }
+        }
+    }
+    pub fn parse_string(&mut self) -> Result<String, ParseError> {
+        match self.lexer.peek().map(|tok| &tok.node) {
+            Some(Token::String(_)) => match self.bump().map(|tok| tok.node) {
+                Some(Token::String(val)) => Ok(val),
+                _ => unreachable!(),
+            },
+            _ => {
+                self.expect(Expected::String);
+                Err(self.unexpected())
+            }
+        }
+    }
+    pub fn parse_int(&mut self) -> Result<i64, ParseError> {
+        match self.lexer.peek().map(|tok| &tok.node) {
+            Some(Token::Integer(_)) => match self.bump().map(|tok| tok.node) {
+                Some(Token::Integer(val)) => Ok(val),
+                _ => unreachable!(),
+            },
+            _ => {
+                self.expect(Expected::Integer);
+                Err(self.unexpected())
+            }
+        }
+    }
+    pub fn parse_float(&mut self) -> Result<f64, ParseError> {
+        match self.lexer.peek().map(|tok| &tok.node) {
+            Some(Token::Float(_)) => match self.bump().map(|tok| tok.node) {
+                Some(Token::Float(val)) => Ok(val),
+                _ => unreachable!(),
+            },
+            _ => {
+                self.expect(Expected::Float);
+                Err(self.unexpected())
+            }
+        }
+    }
+    pub fn parse_char(&mut self) -> Result<char, ParseError> {
+        match self.lexer.peek().map(|tok| &tok.node) {
+            Some(Token::Char(_)) => match self.bump().map(|tok| tok.node) {
+                Some(Token::Char(val)) => Ok(val),
+                _ => unreachable!(),
+            },
+            _ => {
+                self.expect(Expected::Char);
+                Err(self.unexpected())
+            }
+        }
+    }
+    pub fn parse_bool(&mut self) -> Result<bool, ParseError> {
+        match self.lexer.peek().map(|tok| &tok.node) {
+            Some(Token::Bool(_)) => match self.bump().map(|tok| tok.node) {
+                Some(Token::Bool(val)) => Ok(val),
+                _ => unreachable!(),
+            },
+            _ => {
+                self.expect(Expected::Bool);
+                Err(self.unexpected())
+            }
+        }
+    }
+
+    /// Builds an error for the next token not being what the parser wanted, listing everything it expected.
+    /// If the token could not be lexed, the lexical error is reported instead.
+    pub fn unexpected(&mut self) -> ParseError {
+        let eof = self.lexer.peek_span();
+        match self.lexer.peek() {
+            Some(Spanned {
+                node: Token::Error(err),
+                span,
+            }) => ParseError::Lex(Spanned::new(err.clone(), *span)),
+            Some(tok) => ParseError::UnexpectedToken {
+                found: tok.clone(),
+                expected: self.expected.clone(),
+            },
+            None => ParseError::UnexpectedEOF {
+                span: eof,
+                expected: self.expected.clone(),
+            },
+        }
+    }
+}
+
+impl<'a> Iterator for Parser<'a> {
+    type Item = Spanned<Token>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.bump()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{diagnostics::Diagnostic, parser::statements::parse_program};
+
+    use super::*;
+
+    // The message of every parse error in `source`, with where it was reported
+    fn messages(source: &str) -> Vec<String> {
+        let (_, errors) = parse_program(Lexer::new(source));
+        errors
+            .into_iter()
+            .map(|error| {
+                let diagnostic = Diagnostic::from(error);
+                let span = diagnostic.primary_label().unwrap().span;
+                format!("{}:{}: {}", span.line, span.column, diagnostic.message)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lists_everything_expected() {
+        assert_eq!(
+            messages("let x 1;"),
+            ["1:7: expected `:` or `=`, found `1`"]
+        );
+        assert_eq!(
+            messages("f(1 2);"),
+            ["1:5: expected one of `)`, `,` or an operator, found `2`"]
+        );
+        assert_eq!(
+            messages("let p = { a: 1 b: 2 };"),
+            ["1:16: expected one of `}`, `,` or an operator, found `b`"]
+        );
+        assert_eq!(
+            messages("if x y"),
+            ["1:6: expected `{` or an operator, found `y`"]
+        );
+    }
+
+    // Tokens tried before the parser moved on aren't listed
+    #[test]
+    fn forgets_what_was_expected_after_consuming_a_token() {
+        assert_eq!(messages("let x: = 1;"), ["1:8: expected type, found `=`"]);
+        assert_eq!(
+            messages("let f = (a: int, b) => a;"),
+            ["1:19: expected `:`, found `)`"]
+        );
+        assert_eq!(
+            messages("let x = 1;\nlet y = "),
+            ["2:9: expected expression, found end of file"]
+        );
+    }
+}
@@ -1,26 +1,29 @@
-use crate::lexer::{lexer::Lexer, token::Token};
+use crate::lexer::{lexer::Lexer, span::Spanned, token::Token};
 
 use super::{
-    expressions::{parse_expression, Expression},
-    helpers::{parse_list, ParseError},
+    expressions::{binary, parse_expression, BinaryOp, Expression, ExpressionKind},
+    helpers::{parse_list, parse_spanned, ParseError},
+    parser::Parser,
     types::{parse_type, Type},
 };
 
 pub type Block = Vec<Statement>;
 
+pub type Statement = Spanned<StatementKind>;
+
 #[derive(Debug, Clone, PartialEq)]
-pub enum Statement {
+pub enum StatementKind {
     Import {
         path: String,
         imports: Vec<ImportIdentifier>,
     },
     TypeDef {
         name: String,
-        typ: Type,
+        typ: Spanned<Type>,
     },
     VarDef {
         name: String,
-        typ: Option<Type>,
+        typ: Option<Spanned<Type>>,
         expr: Expression,
     },
     If {
@@ -42,9 +45,24 @@ pub enum Statement {
     Continue,
     Loop(Block),
 
+    // Exceptions
+    Yeet(Expression),
+    Try {
+        body: Block,
+        catches: Vec<Catch>,
+    },
+
     Expr(Expression),
 }
 
+/// A `catch (name: type) { ... }` after a `try`, run for values of that type yeeted in its body.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Catch {
+    pub name: String,
+    pub typ: Spanned<Type>,
+    pub body: Block,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct ImportIdentifier {
     name: String,
@@ -58,19 +76,46 @@ pub enum ElseStatement {
     None,
 }
 
-pub fn parse_block(lexer: &mut Lexer, enclosed_by_brackets: bool) -> Result<Block, ParseError> {
+/// Parses a whole file. Statements that fail to parse are skipped, so this returns the
+/// statements that could be parsed along with every error found.
+pub fn parse_program(lexer: Lexer) -> (Block, Vec<ParseError>) {
+    let mut parser = Parser::new(lexer);
+    let program = parse_block(&mut parser, false).unwrap_or_else(|err| {
+        parser.report(err);
+        Vec::new()
+    });
+    (program, parser.take_errors())
+}
+
+/// Parses statements until the end of the block. A statement that fails to parse is
+/// reported to the parser and skipped, see `synchronize`.
+pub fn parse_block(parser: &mut Parser, enclosed_by_brackets: bool) -> Result<Block, ParseError> {
     let mut statements = Vec::new();
 
     if enclosed_by_brackets {
-        lexer.parse_token(&Token::LBrace)?;
+        parser.parse_token(&Token::LBrace)?;
     }
 
     loop {
-        if enclosed_by_brackets && lexer.parse_token(&Token::RBrace).is_ok() {
+        if enclosed_by_brackets && parser.parse_token(&Token::RBrace).is_ok() {
             break;
         }
-        match lexer.peek() {
-            Some(_) => statements.push(parse_statement(lexer)?),
+        match parser.peek() {
+            Some(_) => {
+                let start = parser.peek_span();
+                match parse_statement(parser) {
+                    Ok(statement) => statements.push(statement),
+                    Err(err) => {
+                        parser.report(err);
+                        // Always skip at least one token, so we can't get stuck on it
+                        if parser.peek_span() == start {
+                            parser.next();
+                        }
+                        synchronize(parser, enclosed_by_brackets);
+                    }
+                }
+            }
+            None if enclosed_by_brackets => return Err(parser.unexpected()),
             None => break,
         }
     }
@@ -78,139 +123,203 @@ pub fn parse_block(lexer: &mut Lexer, enclosed_by_brackets: bool) -> Result<Bloc
     Ok(statements)
 }
 
-pub fn parse_statement(lexer: &mut Lexer) -> Result<Statement, ParseError> {
-    match lexer.expect_peek()? {
+// Skips tokens until the parser is probably at the start of the next statement: after a `;`
+// or a `{ ... }` block, before a `}` closing the enclosing block, or before a keyword that
+// starts a statement. Outside of a block a stray `}` closes nothing, so it is skipped too.
+fn synchronize(parser: &mut Parser, enclosed_by_brackets: bool) {
+    let mut depth = 0;
+    while let Some(tok) = parser.peek() {
+        match tok.node {
+            Token::Semicolon if depth == 0 => {
+                parser.next();
+                return;
+            }
+            Token::RBrace if depth == 0 && enclosed_by_brackets => return,
+            Token::RBrace if depth == 0 => {}
+            Token::RBrace => {
+                depth -= 1;
+                if depth == 0 {
+                    parser.next();
+                    let _ = parser.parse_token(&Token::Semicolon);
+                    return;
+                }
+            }
+            Token::LBrace => depth += 1,
+            Token::Let
+            | Token::Type
+            | Token::Import
+            | Token::If
+            | Token::While
+            | Token::Loop
+            | Token::Try
+            | Token::Yeet
+            | Token::Return
+            | Token::Break
+            | Token::Continue
+                if depth == 0 =>
+            {
+                return
+            }
+            _ => {}
+        }
+        parser.next();
+    }
+}
+
+pub fn parse_statement(parser: &mut Parser) -> Result<Statement, ParseError> {
+    parse_spanned(parser, parse_statement_kind)
+}
+
+fn parse_statement_kind(parser: &mut Parser) -> Result<StatementKind, ParseError> {
+    match &parser.expect_peek()?.node {
         Token::Type => {
-            lexer.next();
-            let name = lexer.parse_ident()?;
-            lexer.parse_token(&Token::Assign)?;
-            let typ = parse_type(lexer)?;
-            lexer.parse_token(&Token::Semicolon)?;
-            Ok(Statement::TypeDef { name, typ })
+            parser.next();
+            let name = parser.parse_ident()?;
+            parser.parse_token(&Token::Assign)?;
+            let typ = parse_spanned(parser, parse_type)?;
+            parser.parse_token(&Token::Semicolon)?;
+            Ok(StatementKind::TypeDef { name, typ })
         }
 
         Token::Let => {
-            lexer.next();
-            let name = lexer.parse_ident()?;
+            parser.next();
+            let name = parser.parse_ident()?;
 
-            let typ = match lexer.expect_peek()? {
-                Token::Colon => {
-                    lexer.next();
-                    Some(parse_type(lexer)?)
-                }
-                _ => None,
+            let typ = if parser.parse_token(&Token::Colon).is_ok() {
+                Some(parse_spanned(parser, parse_type)?)
+            } else {
+                None
             };
 
-            lexer.parse_token(&Token::Assign)?;
+            parser.parse_token(&Token::Assign)?;
 
-            let expr = parse_expression(lexer)?;
+            let expr = parse_expression(parser)?;
 
-            lexer.parse_token(&Token::Semicolon)?;
+            parser.parse_token(&Token::Semicolon)?;
 
-            Ok(Statement::VarDef { name, typ, expr })
+            Ok(StatementKind::VarDef { name, typ, expr })
         }
 
         Token::Import => {
             let idents = parse_list(
-                lexer,
+                parser,
                 &Token::Import,
                 &Token::Comma,
                 &Token::From,
-                |lexer| {
-                    let name = lexer.parse_ident()?;
-
-                    match lexer.expect_peek()? {
-                        Token::As => {
-                            lexer.next();
-                            let alias = lexer.parse_ident()?;
-                            Ok(ImportIdentifier { name, alias })
-                        }
-                        _ => Ok(ImportIdentifier {
+                |parser| {
+                    let name = parser.parse_ident()?;
+
+                    if parser.parse_token(&Token::As).is_ok() {
+                        let alias = parser.parse_ident()?;
+                        Ok(ImportIdentifier { name, alias })
+                    } else {
+                        Ok(ImportIdentifier {
                             name: name.clone(),
                             alias: name,
-                        }),
+                        })
                     }
                 },
             )?;
 
-            let path = lexer.parse_string()?;
+            let path = parser.parse_string()?;
 
-            lexer.parse_token(&Token::Semicolon)?;
+            parser.parse_token(&Token::Semicolon)?;
 
-            Ok(Statement::Import {
+            Ok(StatementKind::Import {
                 path,
                 imports: idents,
             })
         }
 
         Token::While => {
-            lexer.next();
-            let cond = parse_expression(lexer)?;
-            let body = parse_block(lexer, true)?;
-            Ok(Statement::While { cond, body })
+            parser.next();
+            let cond = parse_expression(parser)?;
+            let body = parse_block(parser, true)?;
+            Ok(StatementKind::While { cond, body })
         }
 
         Token::Loop => {
-            lexer.next();
-            let body = parse_block(lexer, true)?;
-            Ok(Statement::Loop(body))
+            parser.next();
+            let body = parse_block(parser, true)?;
+            Ok(StatementKind::Loop(body))
+        }
+
+        Token::Yeet => {
+            parser.next();
+            let expr = parse_expression(parser)?;
+            parser.parse_token(&Token::Semicolon)?;
+            Ok(StatementKind::Yeet(expr))
+        }
+
+        Token::Try => {
+            parser.next();
+            let body = parse_block(parser, true)?;
+            // There has to be at least one `catch`
+            let mut catches = vec![parse_catch(parser)?];
+            while parser.peek().is_some_and(|tok| tok.node == Token::Catch) {
+                catches.push(parse_catch(parser)?);
+            }
+            Ok(StatementKind::Try { body, catches })
         }
 
         Token::Break => {
-            lexer.next();
-            lexer.parse_token(&Token::Semicolon)?;
-            Ok(Statement::Break)
+            parser.next();
+            parser.parse_token(&Token::Semicolon)?;
+            Ok(StatementKind::Break)
         }
 
         Token::Continue => {
-            lexer.next();
-            lexer.parse_token(&Token::Semicolon)?;
-            Ok(Statement::Continue)
+            parser.next();
+            parser.parse_token(&Token::Semicolon)?;
+            Ok(StatementKind::Continue)
         }
 
         Token::Return => {
-            lexer.next();
-            let expr = if lexer.parse_token(&Token::Semicolon).is_ok() {
+            parser.next();
+            let expr = if parser.parse_token(&Token::Semicolon).is_ok() {
                 None
             } else {
-                let expr = parse_expression(lexer)?;
-                lexer.parse_token(&Token::Semicolon)?;
+                let expr = parse_expression(parser)?;
+                parser.parse_token(&Token::Semicolon)?;
                 Some(expr)
             };
-            Ok(Statement::Return(expr))
+            Ok(StatementKind::Return(expr))
         }
 
         Token::If => {
-            lexer.next();
-            let cond = parse_expression(lexer)?;
-            let body = parse_block(lexer, true)?;
+            parser.next();
+            let cond = parse_expression(parser)?;
+            let body = parse_block(parser, true)?;
 
-            let mut if_stmt = Statement::If {
+            let mut if_stmt = StatementKind::If {
                 body,
                 cond,
                 else_stmt: ElseStatement::None,
             };
             // Keep track of the last if statement to add else ifs to it
             let mut last_if_stmt = &mut if_stmt;
-            while lexer.parse_token(&Token::Else).is_ok() {
-                if let Ok(_) = lexer.parse_token(&Token::If) {
-                    let cond = parse_expression(lexer)?;
-                    let body = parse_block(lexer, true)?;
-                    let else_if_stmt = Statement::If {
-                        cond,
-                        body,
-                        else_stmt: ElseStatement::None,
-                    };
-
-                    if let Statement::If {
+            while parser.parse_token(&Token::Else).is_ok() {
+                if let Ok(if_tok) = parser.parse_token(&Token::If) {
+                    let cond = parse_expression(parser)?;
+                    let body = parse_block(parser, true)?;
+                    let else_if_stmt = Statement::new(
+                        StatementKind::If {
+                            cond,
+                            body,
+                            else_stmt: ElseStatement::None,
+                        },
+                        if_tok.span.to(&parser.last_span()),
+                    );
+
+                    if let StatementKind::If {
                         ref mut else_stmt, ..
                     } = *last_if_stmt
                     {
                         *else_stmt = ElseStatement::If(Box::new(else_if_stmt));
                     }
                 } else {
-                    let else_block = parse_block(lexer, true)?;
-                    if let Statement::If {
+                    let else_block = parse_block(parser, true)?;
+                    if let StatementKind::If {
                         ref mut else_stmt, ..
                     } = *last_if_stmt
                     {
@@ -219,56 +328,166 @@ pub fn parse_statement(lexer: &mut Lexer) -> Result<Statement, ParseError> {
                     break;
                 }
                 // If the last if statement is an else if, update the last if statement to the inner if statement
-                if let Statement::If {
+                if let StatementKind::If {
                     else_stmt: ElseStatement::If(ref mut if_stmt),
                     ..
                 } = *last_if_stmt
                 {
-                    last_if_stmt = if_stmt;
+                    last_if_stmt = &mut if_stmt.node;
                 }
             }
             Ok(if_stmt)
         }
 
         _ => {
-            let expr = parse_expression(lexer)?;
-
-            if lexer.parse_token(&Token::Assign).is_ok() {
-                let rhs = parse_expression(lexer)?;
-                lexer.parse_token(&Token::Semicolon)?;
-                Ok(Statement::Assign { lhs: expr, rhs })
-            } else if lexer.parse_token(&Token::AddAssign).is_ok() {
-                let rhs = parse_expression(lexer)?;
-                lexer.parse_token(&Token::Semicolon)?;
-                Ok(Statement::Assign {
-                    lhs: expr.clone(),
-                    rhs: Expression::Add(Box::new(expr), Box::new(rhs)),
-                })
-            } else if lexer.parse_token(&Token::SubAssign).is_ok() {
-                let rhs = parse_expression(lexer)?;
-                lexer.parse_token(&Token::Semicolon)?;
-                Ok(Statement::Assign {
-                    lhs: expr.clone(),
-                    rhs: Expression::Sub(Box::new(expr), Box::new(rhs)),
-                })
-            } else if lexer.parse_token(&Token::MulAssign).is_ok() {
-                let rhs = parse_expression(lexer)?;
-                lexer.parse_token(&Token::Semicolon)?;
-                Ok(Statement::Assign {
-                    lhs: expr.clone(),
-                    rhs: Expression::Mul(Box::new(expr), Box::new(rhs)),
-                })
-            } else if lexer.parse_token(&Token::DivAssign).is_ok() {
-                let rhs = parse_expression(lexer)?;
-                lexer.parse_token(&Token::Semicolon)?;
-                Ok(Statement::Assign {
-                    lhs: expr.clone(),
-                    rhs: Expression::Div(Box::new(expr), Box::new(rhs)),
-                })
-            } else {
-                lexer.parse_token(&Token::Semicolon)?;
-                Ok(Statement::Expr(expr))
-            }
+            let expr = parse_expression(parser)?;
+
+            let op = match parser.parse_one_of(&[
+                Token::Assign,
+                Token::AddAssign,
+                Token::SubAssign,
+                Token::MulAssign,
+                Token::DivAssign,
+            ]) {
+                Ok(op) => op.node,
+                Err(_) => {
+                    parser.parse_token(&Token::Semicolon)?;
+                    return Ok(StatementKind::Expr(expr));
+                }
+            };
+            let op: Option<BinaryOp> = match op {
+                Token::Assign => None,
+                Token::AddAssign => Some(ExpressionKind::Add),
+                Token::SubAssign => Some(ExpressionKind::Sub),
+                Token::MulAssign => Some(ExpressionKind::Mul),
+                Token::DivAssign => Some(ExpressionKind::Div),
+                _ => unreachable!(),
+            };
+
+            let rhs = parse_expression(parser)?;
+            parser.parse_token(&Token::Semicolon)?;
+            // Compound assignments are desugared, eg. `x += 1` becomes `x = x + 1`
+            let rhs = match op {
+                Some(op) => binary(op, expr.clone(), rhs),
+                None => rhs,
+            };
+            Ok(StatementKind::Assign { lhs: expr, rhs })
+        }
+    }
+}
+
+fn parse_catch(parser: &mut Parser) -> Result<Catch, ParseError> {
+    parser.parse_token(&Token::Catch)?;
+    parser.parse_token(&Token::LParen)?;
+    let name = parser.parse_ident()?;
+    parser.parse_token(&Token::Colon)?;
+    let typ = parse_spanned(parser, parse_type)?;
+    parser.parse_token(&Token::RParen)?;
+    let body = parse_block(parser, true)?;
+    Ok(Catch { name, typ, body })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::span::Span, parser::helpers::Expected};
+
+    // What can follow a complete expression, before the token closing it
+    fn operators_then(token: Token) -> Vec<Expected> {
+        let operators = [
+            Token::Mul,
+            Token::Div,
+            Token::Mod,
+            Token::Add,
+            Token::Sub,
+            Token::Equal,
+            Token::GreaterThan,
+            Token::GreaterEqual,
+            Token::LessThan,
+            Token::LessEqual,
+            Token::And,
+            Token::Or,
+        ];
+        (operators.into_iter().chain([token]))
+            .map(Expected::Token)
+            .collect()
+    }
+
+    fn unexpected(token: Token, span: Span, expected: Vec<Expected>) -> ParseError {
+        ParseError::UnexpectedToken {
+            found: Spanned { node: token, span },
+            expected,
         }
     }
+
+    #[test]
+    fn stray_closing_brace_is_reported_once() {
+        let (program, errors) = parse_program(Lexer::new("let x = 1 + }\nlet y = 2;"));
+        assert_eq!(
+            errors,
+            [unexpected(
+                Token::RBrace,
+                Span::new(12, 13, 1, 13),
+                vec![Expected::Expression]
+            )]
+        );
+        assert_eq!(program.len(), 1);
+
+        let (program, errors) = parse_program(Lexer::new("let x = 1; }\nlet y = 2;"));
+        assert_eq!(
+            errors,
+            [unexpected(
+                Token::RBrace,
+                Span::new(11, 12, 1, 12),
+                vec![Expected::Expression]
+            )]
+        );
+        assert_eq!(program.len(), 2);
+    }
+
+    #[test]
+    fn missing_semicolon() {
+        let (_, errors) = parse_program(Lexer::new("let x = 1\nlet y = 2;"));
+        assert_eq!(
+            errors,
+            [unexpected(
+                Token::Let,
+                Span::new(10, 13, 2, 1),
+                operators_then(Token::Semicolon)
+            )]
+        );
+    }
+
+    #[test]
+    fn recovers_after_each_error() {
+        let source = "let = 1;\nlet y: = 2;\nwhile { }\nlet z = (1;\nlet w = 3;";
+        let (program, errors) = parse_program(Lexer::new(source));
+        assert_eq!(
+            errors,
+            [
+                unexpected(
+                    Token::Assign,
+                    Span::new(4, 5, 1, 5),
+                    vec![Expected::Identifier]
+                ),
+                unexpected(Token::Assign, Span::new(16, 17, 2, 8), vec![Expected::Type]),
+                unexpected(
+                    Token::Let,
+                    Span::new(31, 34, 4, 1),
+                    operators_then(Token::LBrace)
+                ),
+                unexpected(
+                    Token::Semicolon,
+                    Span::new(41, 42, 4, 11),
+                    [
+                        operators_then(Token::RParen),
+                        vec![Expected::Token(Token::Comma)]
+                    ]
+                    .concat()
+                ),
+            ]
+        );
+        // Only `let w = 3;` parses
+        assert_eq!(program.len(), 1);
+    }
 }
@@ -1,10 +1,11 @@
-use std::{boxed::Box, collections::HashMap};
+use std::{boxed::Box, collections::HashMap, fmt};
 
-use crate::lexer::{lexer::Lexer, token::Token};
+use crate::lexer::token::Token;
 
 use super::{
     expressions::{parse_expression, Expression},
-    helpers::{build_hashmap_from_entries, parse_list, ParseError},
+    helpers::{build_hashmap_from_entries, parse_list, parse_spanned, Expected, ParseError},
+    parser::Parser,
 };
 
 #[derive(Debug, Clone, PartialEq)]
@@ -29,82 +30,148 @@ pub enum Type {
         ret: Box<Type>,
     },
     TypeOf(Box<Expression>),
+
+    // Never parsed, given by the type checker to expressions that failed to check
+    // so that one mistake does not cause a cascade of errors
+    Error,
 }
 
-pub fn parse_type(lexer: &mut Lexer) -> Result<Type, ParseError> {
-    let mut ty = parse_type_without_array(lexer)?;
-
-    loop {
-        if let Ok(len) = parse_array_type(lexer) {
-            ty = match len {
-                Some(len) => Type::SizedArray {
-                    element: Box::new(ty),
-                    len,
-                },
-                None => Type::Array(Box::new(ty)),
-            };
-        } else {
-            break;
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_type(self, f, 0)
+    }
+}
+
+// Named types are resolved structurally by the type checker, so recursive types such as
+// `type Tree = { left: &Tree }` expand a few levels deep. Only the outer levels are printed.
+const MAX_DISPLAY_DEPTH: usize = 1;
+
+fn write_type(ty: &Type, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+    match ty {
+        Type::Int => write!(f, "int"),
+        Type::Float => write!(f, "float"),
+        Type::String => write!(f, "string"),
+        Type::Char => write!(f, "char"),
+        Type::Bool => write!(f, "bool"),
+        Type::Void => write!(f, "void"),
+        Type::Named(name) => write!(f, "{}", name),
+        Type::Ptr(ty) => {
+            write!(f, "&")?;
+            write_type(ty, f, depth)
+        }
+        Type::SizedArray { element, len } => {
+            write_type(element, f, depth)?;
+            write!(f, "[{}]", len)
+        }
+        Type::Array(element) => {
+            write_type(element, f, depth)?;
+            write!(f, "[]")
+        }
+        Type::Struct(_) if depth >= MAX_DISPLAY_DEPTH => write!(f, "{{ .. }}"),
+        Type::Struct(fields) => {
+            let mut names = fields.keys().collect::<Vec<_>>();
+            names.sort();
+            write!(f, "{{ ")?;
+            for (i, name) in names.into_iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}: ", name)?;
+                write_type(&fields[name], f, depth + 1)?;
+            }
+            write!(f, " }}")
+        }
+        Type::Tuple(items) => {
+            write!(f, "(")?;
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write_type(item, f, depth + 1)?;
+            }
+            write!(f, ")")
+        }
+        Type::Function { args, ret } => {
+            write!(f, "(")?;
+            for (i, arg) in args.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write_type(arg, f, depth + 1)?;
+            }
+            write!(f, ") => ")?;
+            write_type(ret, f, depth + 1)
         }
+        Type::TypeOf(_) => write!(f, "typeof(..)"),
+        Type::Error => write!(f, "{{error}}"),
+    }
+}
+
+pub fn parse_type(parser: &mut Parser) -> Result<Type, ParseError> {
+    let mut ty = parse_type_without_array(parser)?;
+
+    while let Ok(len) = parse_array_type(parser) {
+        ty = match len {
+            Some(len) => Type::SizedArray {
+                element: Box::new(ty),
+                len,
+            },
+            None => Type::Array(Box::new(ty)),
+        };
     }
 
     Ok(ty)
 }
 
-fn parse_array_type(lexer: &mut Lexer) -> Result<Option<i64>, ParseError> {
-    match lexer.expect_peek()? {
-        Token::LBracket => {
-            lexer.next();
-            let len = lexer.parse_int();
-            lexer.parse_token(&Token::RBracket)?;
-            Ok(len.ok())
-        }
-        tok => Err(ParseError::UnexpectedToken(tok.clone())),
-    }
+fn parse_array_type(parser: &mut Parser) -> Result<Option<i64>, ParseError> {
+    parser.parse_token(&Token::LBracket)?;
+    let len = parser.parse_int();
+    parser.parse_token(&Token::RBracket)?;
+    Ok(len.ok())
 }
 
-fn parse_type_without_array(lexer: &mut Lexer) -> Result<Type, ParseError> {
-    match lexer.expect_peek()? {
+fn parse_type_without_array(parser: &mut Parser) -> Result<Type, ParseError> {
+    match &parser.expect_peek()?.node {
         Token::IntType => {
-            lexer.next();
+            parser.next();
             Ok(Type::Int)
         }
         Token::FloatType => {
-            lexer.next();
+            parser.next();
             Ok(Type::Float)
         }
         Token::StringType => {
-            lexer.next();
+            parser.next();
             Ok(Type::String)
         }
         Token::CharType => {
-            lexer.next();
+            parser.next();
             Ok(Type::Char)
         }
         Token::BoolType => {
-            lexer.next();
+            parser.next();
             Ok(Type::Bool)
         }
         Token::VoidType => {
-            lexer.next();
+            parser.next();
             Ok(Type::Void)
         }
         Token::Identifier(_) => {
-            let name = lexer.parse_ident()?;
+            let name = parser.parse_ident()?;
             Ok(Type::Named(name))
         }
         Token::Ref => {
-            lexer.next();
-            let ty = parse_type(lexer)?;
+            parser.next();
+            let ty = parse_type(parser)?;
             Ok(Type::Ptr(Box::new(ty)))
         }
         Token::LParen => {
-            let fields = parse_list(lexer, &Token::LParen, &Token::Comma, &Token::RParen, |l| {
+            let fields = parse_list(parser, &Token::LParen, &Token::Comma, &Token::RParen, |l| {
                 parse_type(l).map(Box::new)
             })?;
 
-            if lexer.parse_token(&Token::FuncArrow).is_ok() {
-                let ret = parse_type(lexer)?;
+            if parser.parse_token(&Token::FuncArrow).is_ok() {
+                let ret = parse_type(parser)?;
                 Ok(Type::Function {
                     args: fields,
                     ret: Box::new(ret),
@@ -115,8 +182,8 @@ fn parse_type_without_array(lexer: &mut Lexer) -> Result<Type, ParseError> {
         }
 
         Token::LBrace => {
-            let fields = parse_list(lexer, &Token::LBrace, &Token::Comma, &Token::RBrace, |l| {
-                let name = l.parse_ident()?;
+            let fields = parse_list(parser, &Token::LBrace, &Token::Comma, &Token::RBrace, |l| {
+                let name = parse_spanned(l, |l| l.parse_ident())?;
                 l.parse_token(&Token::Colon)?;
                 let ty = parse_type(l)?;
                 Ok((name, ty))
@@ -128,13 +195,16 @@ fn parse_type_without_array(lexer: &mut Lexer) -> Result<Type, ParseError> {
         }
 
         Token::TypeOf => {
-            lexer.next();
-            lexer.parse_token(&Token::LParen)?;
-            let expr = parse_expression(lexer)?;
-            lexer.parse_token(&Token::RParen)?;
+            parser.next();
+            parser.parse_token(&Token::LParen)?;
+            let expr = parse_expression(parser)?;
+            parser.parse_token(&Token::RParen)?;
             Ok(Type::TypeOf(Box::new(expr)))
         }
 
-        tok => Err(ParseError::UnexpectedToken(tok.clone())),
+        _ => {
+            parser.expect(Expected::Type);
+            Err(parser.unexpected())
+        }
     }
 }
@@ -1,71 +1,101 @@
 use std::collections::HashMap;
 
 use crate::{
-    parser::{expressions::Expression, types::Type},
+    lexer::span::{Span, Spanned},
+    parser::{
+        expressions::{Expression, ExpressionKind},
+        types::Type,
+    },
     type_checker::{statements::is_assignable, types::check_type},
 };
 
-use super::{statements::check_block, Scope, TypeError};
-
-pub fn check_expr(expr: &Expression, scope: &Scope) -> Result<Type, TypeError> {
-    match expr {
-        Expression::Null => Ok(Type::Ptr(Box::new(Type::Void))),
-
-        Expression::Bool(_) => Ok(Type::Bool),
-        Expression::Int(_) => Ok(Type::Int),
-        Expression::Float(_) => Ok(Type::Float),
-        Expression::String(_) => Ok(Type::String),
-        Expression::Char(_) => Ok(Type::Char),
-
-        Expression::Add(lhs, rhs) => check_binop_expr(lhs, rhs, scope),
-        Expression::Sub(lhs, rhs) => check_binop_expr(lhs, rhs, scope),
-        Expression::Mul(lhs, rhs) => check_binop_expr(lhs, rhs, scope),
-        Expression::Div(lhs, rhs) => check_binop_expr(lhs, rhs, scope),
-        Expression::Mod(lhs, rhs) => match (check_expr(lhs, scope)?, check_expr(rhs, scope)?) {
-            (Type::Int, Type::Int) => Ok(Type::Int),
-            (lhs, _) => Err(TypeError::Unexpected {
-                got: lhs,
-                expected: Type::Int,
-            }),
-        },
+use super::{statements::check_block, Scope, TypeError, TypeErrorKind};
 
-        Expression::Neg(expr) => match check_expr(expr, scope)? {
+/// Checks an expression, returning the first error found in it. Errors inside the bodies of
+/// function literals are pushed to `errors` instead, so they don't hide each other.
+pub fn check_expr(
+    expr: &Expression,
+    scope: &Scope,
+    errors: &mut Vec<TypeError>,
+) -> Result<Type, TypeError> {
+    let span = expr.span;
+    match &expr.node {
+        ExpressionKind::Null => Ok(Type::Ptr(Box::new(Type::Void))),
+
+        ExpressionKind::Bool(_) => Ok(Type::Bool),
+        ExpressionKind::Int(_) => Ok(Type::Int),
+        ExpressionKind::Float(_) => Ok(Type::Float),
+        ExpressionKind::String(_) => Ok(Type::String),
+        ExpressionKind::Char(_) => Ok(Type::Char),
+
+        ExpressionKind::Add(lhs, rhs) => check_binop_expr(lhs, rhs, span, scope, errors),
+        ExpressionKind::Sub(lhs, rhs) => check_binop_expr(lhs, rhs, span, scope, errors),
+        ExpressionKind::Mul(lhs, rhs) => check_binop_expr(lhs, rhs, span, scope, errors),
+        ExpressionKind::Div(lhs, rhs) => check_binop_expr(lhs, rhs, span, scope, errors),
+        ExpressionKind::Mod(lhs, rhs) => {
+            match (
+                check_expr(lhs, scope, errors)?,
+                check_expr(rhs, scope, errors)?,
+            ) {
+                (Type::Int, Type::Int) => Ok(Type::Int),
+                (Type::Error, _) | (_, Type::Error) => Ok(Type::Error),
+                (Type::Int, rhs_typ) => Err(TypeErrorKind::Unexpected {
+                    got: rhs_typ,
+                    expected: Type::Int,
+                }
+                .at(rhs.span)),
+                (lhs_typ, _) => Err(TypeErrorKind::Unexpected {
+                    got: lhs_typ,
+                    expected: Type::Int,
+                }
+                .at(lhs.span)),
+            }
+        }
+
+        ExpressionKind::Neg(inner) => match check_expr(inner, scope, errors)? {
             Type::Int => Ok(Type::Int),
             Type::Float => Ok(Type::Float),
-            ty => Err(TypeError::Invalid(ty)),
+            Type::Error => Ok(Type::Error),
+            ty => Err(TypeErrorKind::Invalid(ty).at(inner.span)),
         },
 
-        Expression::Equal(lhs, rhs) => check_same_type(lhs, rhs, scope),
+        ExpressionKind::Equal(lhs, rhs) => check_same_type(lhs, rhs, span, scope, errors),
 
-        Expression::GreaterEqual(lhs, rhs) => check_binop_cmp_expr(lhs, rhs, scope),
-        Expression::GreaterThan(lhs, rhs) => check_binop_cmp_expr(lhs, rhs, scope),
-        Expression::LessEqual(lhs, rhs) => check_binop_cmp_expr(lhs, rhs, scope),
-        Expression::LessThan(lhs, rhs) => check_binop_cmp_expr(lhs, rhs, scope),
+        ExpressionKind::GreaterEqual(lhs, rhs) => {
+            check_binop_cmp_expr(lhs, rhs, span, scope, errors)
+        }
+        ExpressionKind::GreaterThan(lhs, rhs) => {
+            check_binop_cmp_expr(lhs, rhs, span, scope, errors)
+        }
+        ExpressionKind::LessEqual(lhs, rhs) => check_binop_cmp_expr(lhs, rhs, span, scope, errors),
+        ExpressionKind::LessThan(lhs, rhs) => check_binop_cmp_expr(lhs, rhs, span, scope, errors),
 
-        Expression::And(lhs, rhs) => check_bool_op(lhs, rhs, scope),
-        Expression::Or(lhs, rhs) => check_bool_op(lhs, rhs, scope),
-        Expression::Not(expr) => match check_expr(expr, scope)? {
+        ExpressionKind::And(lhs, rhs) => check_bool_op(lhs, rhs, scope, errors),
+        ExpressionKind::Or(lhs, rhs) => check_bool_op(lhs, rhs, scope, errors),
+        ExpressionKind::Not(inner) => match check_expr(inner, scope, errors)? {
             Type::Bool => Ok(Type::Bool),
-            ty => Err(TypeError::Invalid(ty)),
+            Type::Error => Ok(Type::Error),
+            ty => Err(TypeErrorKind::Invalid(ty).at(inner.span)),
         },
 
-        Expression::StructLiteral(fields) => {
+        ExpressionKind::StructLiteral(fields) => {
             let mut struct_fields = HashMap::new();
             for (name, expr) in fields {
-                struct_fields.insert(name.clone(), check_expr(expr, scope)?);
+                struct_fields.insert(name.clone(), check_expr(expr, scope, errors)?);
             }
             Ok(Type::Struct(struct_fields))
         }
-        Expression::ArrayLiteral(exprs) => {
+        ExpressionKind::ArrayLiteral(exprs) => {
             let mut array_type = None;
             for expr in exprs {
-                let ty = check_expr(expr, scope)?;
+                let ty = check_expr(expr, scope, errors)?;
                 if let Some(array_type) = &array_type {
-                    if &ty != array_type {
-                        return Err(TypeError::Unexpected {
+                    if &ty != array_type && ty != Type::Error && *array_type != Type::Error {
+                        return Err(TypeErrorKind::Unexpected {
                             got: ty,
                             expected: array_type.clone(),
-                        });
+                        }
+                        .at(expr.span));
                     }
                 } else {
                     array_type = Some(ty);
@@ -76,191 +106,231 @@ pub fn check_expr(expr: &Expression, scope: &Scope) -> Result<Type, TypeError> {
                 len: exprs.len() as i64,
             })
         }
-        Expression::TupleLiteral(exprs) => {
+        ExpressionKind::TupleLiteral(exprs) => {
             let mut tuple_type = Vec::new();
             for expr in exprs {
-                tuple_type.push(Box::new(check_expr(expr, scope)?));
+                tuple_type.push(Box::new(check_expr(expr, scope, errors)?));
             }
             Ok(Type::Tuple(tuple_type))
         }
-        Expression::FunctionLiteral { args, ret, body } => {
+        ExpressionKind::FunctionLiteral { args, ret, body } => {
             let mut arg_types = Vec::new();
             for (_, ty) in args {
-                let ty = check_type(ty, scope)?;
+                let ty = check_type(&ty.node, ty.span, scope)?;
                 arg_types.push(Box::new(ty));
             }
-            let ret = Box::new(check_type(ret, scope)?);
+            let ret_span = ret.span;
+            let ret = Box::new(check_type(&ret.node, ret_span, scope)?);
 
             let mut scope = scope.create_child();
 
             // Insert the arguments into the scope
             for (name, ty) in args {
-                scope.set_var(name, ty.clone());
+                scope.set_var(name, ty.node.clone());
             }
 
-            let ret_type = check_block(body, &mut scope)?;
+            let ret_type = check_block(body, &mut scope, errors).unwrap_or(Type::Void);
 
-            if ret_type != *ret {
-                return Err(TypeError::Unexpected {
+            if ret_type != *ret && ret_type != Type::Error {
+                return Err(TypeErrorKind::Unexpected {
                     got: ret_type,
                     expected: *ret,
-                });
+                }
+                .at(ret_span));
             }
 
-            dbg!("FunctionScope:", scope);
-
             Ok(Type::Function {
                 args: arg_types,
                 ret,
             })
         }
 
-        Expression::Index { expr, index } => {
-            let index = check_expr(index, scope)?;
-            if index != Type::Int {
-                return Err(TypeError::Unexpected {
-                    got: index,
+        ExpressionKind::Index { expr, index } => {
+            let index_typ = check_expr(index, scope, errors)?;
+            if index_typ != Type::Int && index_typ != Type::Error {
+                return Err(TypeErrorKind::Unexpected {
+                    got: index_typ,
                     expected: Type::Int,
-                });
+                }
+                .at(index.span));
             }
 
-            match check_expr(expr, scope)? {
+            match check_expr(expr, scope, errors)? {
                 Type::Array(element) => Ok(*element),
                 Type::SizedArray { element, .. } => Ok(*element),
-                ty => Err(TypeError::Invalid(ty)),
+                Type::Error => Ok(Type::Error),
+                ty => Err(TypeErrorKind::Invalid(ty).at(expr.span)),
             }
         }
 
-        Expression::Ref(expr) => Ok(Type::Ptr(Box::new(check_expr(expr, scope)?))),
+        ExpressionKind::Ref(expr) => Ok(Type::Ptr(Box::new(check_expr(expr, scope, errors)?))),
 
-        Expression::Deref(expr) => match check_expr(expr, scope)? {
-            Type::Ptr(ty) => Ok(check_type(&ty, scope)?),
-            ty => Err(TypeError::Invalid(ty)),
+        ExpressionKind::Deref(inner) => match check_expr(inner, scope, errors)? {
+            Type::Ptr(ty) => Ok(check_type(&ty, span, scope)?),
+            Type::Error => Ok(Type::Error),
+            ty => Err(TypeErrorKind::Invalid(ty).at(inner.span)),
         },
 
-        Expression::Identifier(name) => match scope.get_var(name) {
-            Some(ty) => Ok(check_type(&ty, scope)?),
-            None => Err(TypeError::InvalidIdentifier(name.clone())),
+        ExpressionKind::Identifier(name) => match scope.get_var(name) {
+            Some(ty) => Ok(check_type(&ty, span, scope)?),
+            None => Err(TypeErrorKind::InvalidIdentifier(name.clone()).at(span)),
         },
 
-        Expression::Dot { expr, field } => {
-            let typ = check_expr(expr, scope)?;
+        ExpressionKind::Dot { expr, field } => {
+            let typ = check_expr(expr, scope, errors)?;
             match typ {
                 Type::Struct(fields) => match fields.get(field) {
-                    Some(ty) => Ok(check_type(ty, scope)?),
-                    None => Err(TypeError::InvalidIdentifier(field.clone())),
+                    Some(ty) => Ok(check_type(ty, span, scope)?),
+                    None => Err(TypeErrorKind::InvalidIdentifier(field.clone()).at(span)),
                 },
-                ty => Err(TypeError::Invalid(ty)),
+                Type::Error => Ok(Type::Error),
+                ty => Err(TypeErrorKind::Invalid(ty).at(expr.span)),
             }
         }
 
-        Expression::Call { expr, args } => {
-            let typ = check_expr(expr, scope)?;
+        ExpressionKind::Call { expr, args } => {
+            let typ = check_expr(expr, scope, errors)?;
             let typ_for_errors = typ.clone();
-            println!("Call: {:?}", typ);
             match typ {
                 Type::Function {
                     args: arg_types,
                     ret,
                 } => {
                     if args.len() != arg_types.len() {
-                        return Err(TypeError::Invalid(typ_for_errors));
+                        return Err(TypeErrorKind::ArgumentCount {
+                            expected: arg_types.len(),
+                            found: args.len(),
+                            callee: Spanned::new(typ_for_errors, expr.span),
+                        }
+                        .at(span));
                     }
 
                     for (arg, ty) in args.iter().zip(arg_types.iter()) {
-                        let arg_type = check_expr(arg, scope)?;
-                        if !is_assignable(&arg_type, &ty, scope) {
-                            return Err(TypeError::Unexpected {
+                        let arg_type = check_expr(arg, scope, errors)?;
+                        if !is_assignable(&arg_type, ty, scope) {
+                            return Err(TypeErrorKind::Unexpected {
                                 got: arg_type,
                                 expected: *ty.clone(),
-                            });
+                            }
+                            .at(arg.span));
                         }
                     }
 
                     Ok(*ret)
                 }
-                ty => Err(TypeError::Invalid(ty)),
+                Type::Error => Ok(Type::Error),
+                ty => Err(TypeErrorKind::Invalid(ty).at(expr.span)),
             }
         }
     }
 }
 
 pub fn infer_function_type_signature(expr: &Expression, scope: &Scope) -> Result<Type, TypeError> {
-    match expr {
-        Expression::FunctionLiteral { args, ret, body: _ } => {
+    match &expr.node {
+        ExpressionKind::FunctionLiteral { args, ret, body: _ } => {
             let mut arg_types = Vec::new();
             for (_, ty) in args {
-                let ty = check_type(ty, scope)?;
+                let ty = check_type(&ty.node, ty.span, scope)?;
                 arg_types.push(Box::new(ty));
             }
-            let ret = Box::new(check_type(ret, scope)?);
+            let ret = Box::new(check_type(&ret.node, ret.span, scope)?);
 
             Ok(Type::Function {
                 args: arg_types,
                 ret,
             })
         }
-        _ => Err(TypeError::Unexpected {
+        _ => Err(TypeErrorKind::Unexpected {
             got: Type::Void,
             expected: Type::Function {
                 args: vec![],
                 ret: Box::new(Type::Void),
             },
-        }),
+        }
+        .at(expr.span)),
     }
 }
 
 pub fn check_binop_expr(
     lhs: &Expression,
     rhs: &Expression,
+    span: Span,
     scope: &Scope,
+    errors: &mut Vec<TypeError>,
 ) -> Result<Type, TypeError> {
-    let lhs = check_expr(lhs, scope)?;
-    let rhs = check_expr(rhs, scope)?;
+    let lhs = check_expr(lhs, scope, errors)?;
+    let rhs = check_expr(rhs, scope, errors)?;
 
     match (lhs, rhs) {
         (Type::Int, Type::Int) => Ok(Type::Int),
         (Type::Float, Type::Float) => Ok(Type::Float),
         (Type::Char, Type::Char) => Ok(Type::Char),
-        (lhs, rhs) if lhs == rhs => Err(TypeError::Invalid(lhs)),
-        (lhs, rhs) => Err(TypeError::Unexpected {
+        (Type::Error, _) | (_, Type::Error) => Ok(Type::Error),
+        (lhs, rhs) if lhs == rhs => Err(TypeErrorKind::Invalid(lhs).at(span)),
+        (lhs, rhs) => Err(TypeErrorKind::Unexpected {
             got: lhs,
             expected: rhs,
-        }),
+        }
+        .at(span)),
     }
 }
 
 pub fn check_binop_cmp_expr(
     lhs: &Expression,
     rhs: &Expression,
+    span: Span,
     scope: &Scope,
+    errors: &mut Vec<TypeError>,
 ) -> Result<Type, TypeError> {
-    check_binop_expr(lhs, rhs, scope)?;
-    Ok(Type::Bool)
+    match check_binop_expr(lhs, rhs, span, scope, errors)? {
+        Type::Error => Ok(Type::Error),
+        _ => Ok(Type::Bool),
+    }
 }
 
-fn check_bool_op(lhs: &Expression, rhs: &Expression, scope: &Scope) -> Result<Type, TypeError> {
-    let lhs = check_expr(lhs, scope)?;
-    let rhs = check_expr(rhs, scope)?;
+fn check_bool_op(
+    lhs: &Expression,
+    rhs: &Expression,
+    scope: &Scope,
+    errors: &mut Vec<TypeError>,
+) -> Result<Type, TypeError> {
+    let lhs_typ = check_expr(lhs, scope, errors)?;
+    let rhs_typ = check_expr(rhs, scope, errors)?;
 
-    match (lhs, rhs) {
+    match (lhs_typ, rhs_typ) {
         (Type::Bool, Type::Bool) => Ok(Type::Bool),
-        (lhs, rhs) => Err(TypeError::Unexpected {
-            got: lhs,
-            expected: rhs,
-        }),
+        (Type::Error, _) | (_, Type::Error) => Ok(Type::Error),
+        (Type::Bool, rhs_typ) => Err(TypeErrorKind::Unexpected {
+            got: rhs_typ,
+            expected: Type::Bool,
+        }
+        .at(rhs.span)),
+        (lhs_typ, _) => Err(TypeErrorKind::Unexpected {
+            got: lhs_typ,
+            expected: Type::Bool,
+        }
+        .at(lhs.span)),
     }
 }
 
-fn check_same_type(lhs: &Expression, rhs: &Expression, scope: &Scope) -> Result<Type, TypeError> {
-    let lhs = check_expr(lhs, scope)?;
-    let rhs = check_expr(rhs, scope)?;
-    if lhs == rhs {
+fn check_same_type(
+    lhs: &Expression,
+    rhs: &Expression,
+    span: Span,
+    scope: &Scope,
+    errors: &mut Vec<TypeError>,
+) -> Result<Type, TypeError> {
+    let lhs = check_expr(lhs, scope, errors)?;
+    let rhs = check_expr(rhs, scope, errors)?;
+    if lhs == Type::Error || rhs == Type::Error {
+        Ok(Type::Error)
+    } else if lhs == rhs {
         Ok(Type::Bool)
     } else {
-        Err(TypeError::Unexpected {
+        Err(TypeErrorKind::Unexpected {
             got: lhs,
             expected: rhs,
-        })
+        }
+        .at(span))
     }
 }
@@ -1,16 +1,41 @@
 use std::collections::HashMap;
 
-use crate::parser::types::Type;
+use crate::{
+    lexer::span::{Span, Spanned},
+    parser::{statements::Block, types::Type},
+};
+
+use self::statements::check_block;
 
 pub mod expressions;
 pub mod statements;
 pub mod types;
 
+// Boxed since `Type`s are large and errors are passed around in `Result`s everywhere
+pub type TypeError = Box<Spanned<TypeErrorKind>>;
+
 #[derive(Clone, Debug, PartialEq)]
-pub enum TypeError {
+pub enum TypeErrorKind {
     Invalid(Type),
-    Unexpected { got: Type, expected: Type },
+    Unexpected {
+        got: Type,
+        expected: Type,
+    },
     InvalidIdentifier(String),
+    // Parsed, but not implemented by the type checker, eg. imports
+    Unsupported(&'static str),
+    // A call with the wrong number of arguments, along with the function called
+    ArgumentCount {
+        expected: usize,
+        found: usize,
+        callee: Spanned<Type>,
+    },
+}
+
+impl TypeErrorKind {
+    pub fn at(self, span: Span) -> TypeError {
+        Box::new(Spanned::new(self, span))
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -20,6 +45,12 @@ pub struct Scope {
     vars: HashMap<String, Type>,
 }
 
+impl Default for Scope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Scope {
     pub fn get_type(&self, name: &str) -> Option<Type> {
         self.types.get(name).cloned().or_else(|| {
@@ -40,6 +71,14 @@ impl Scope {
     pub fn set_var(&mut self, name: &str, ty: Type) {
         self.vars.insert(name.to_string(), ty);
     }
+    /// The variables defined directly in this scope, not in its parents.
+    pub fn vars(&self) -> impl Iterator<Item = (&String, &Type)> {
+        self.vars.iter()
+    }
+    /// The types defined directly in this scope, not in its parents.
+    pub fn types(&self) -> impl Iterator<Item = (&String, &Type)> {
+        self.types.iter()
+    }
     pub fn new() -> Self {
         Self {
             parent: None,
@@ -56,10 +95,60 @@ impl Scope {
     }
 }
 
-pub fn expect_type(ty: Type, expected: Type) -> Result<Type, TypeError> {
-    if ty == expected {
+/// Checks a whole program, returning every error found in it.
+pub fn check_program(program: &Block, scope: &mut Scope) -> Result<(), Vec<TypeError>> {
+    let mut errors = Vec::new();
+    check_block(program, scope, &mut errors);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+pub fn expect_type(ty: Type, expected: Type, span: Span) -> Result<Type, TypeError> {
+    if ty == expected || ty == Type::Error {
         Ok(ty)
     } else {
-        Err(TypeError::Unexpected { got: ty, expected })
+        Err(TypeErrorKind::Unexpected { got: ty, expected }.at(span))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::lexer::Lexer, parser::statements::parse_program};
+
+    #[test]
+    fn reports_every_error_once() {
+        let source = "\
+let a: int = true;
+let b = missing + 1;
+let c = b * 2;
+let f = (x: int): int => {
+    let y: bool = x;
+    return c + b;
+};
+";
+        let (program, errors) = parse_program(Lexer::new(source));
+        assert!(errors.is_empty());
+        let errors = check_program(&program, &mut Scope::new()).unwrap_err();
+        // `b` has an error type, so nothing computed from it is reported again
+        assert_eq!(
+            errors,
+            vec![
+                TypeErrorKind::Unexpected {
+                    got: Type::Bool,
+                    expected: Type::Int
+                }
+                .at(Span::new(13, 17, 1, 14)),
+                TypeErrorKind::InvalidIdentifier("missing".to_string()).at(Span::new(27, 34, 2, 9)),
+                TypeErrorKind::Unexpected {
+                    got: Type::Int,
+                    expected: Type::Bool
+                }
+                .at(Span::new(100, 101, 5, 19)),
+            ]
+        );
     }
 }
@@ -1,155 +1,234 @@
-use crate::parser::{
-    expressions::Expression,
-    statements::{Block, ElseStatement, Statement},
-    types::Type,
+use crate::{
+    lexer::span::Span,
+    parser::{
+        expressions::{Expression, ExpressionKind},
+        statements::{Block, ElseStatement, Statement, StatementKind},
+        types::Type,
+    },
 };
 
 use super::{
     expect_type,
     expressions::{check_expr, infer_function_type_signature},
     types::check_type,
-    Scope, TypeError,
+    Scope, TypeError, TypeErrorKind,
 };
 
-pub fn check_block(block: &Block, scope: &mut Scope) -> Result<Type, TypeError> {
+/// Checks every statement in the block, pushing errors to `errors` and carrying on.
+/// Returns the type the block returns, or `None` if it has no return statements.
+pub fn check_block(block: &Block, scope: &mut Scope, errors: &mut Vec<TypeError>) -> Option<Type> {
     let mut ret_type = None;
     for statement in block {
-        let stmt_ret = check_statement(statement, scope)?;
-        if let Some(stmt_ret) = stmt_ret {
-            if let Some(ret_type) = ret_type {
-                if ret_type != stmt_ret {
-                    return Err(TypeError::Unexpected {
-                        got: stmt_ret,
-                        expected: ret_type,
-                    });
-                }
+        let stmt_ret = match check_statement(statement, scope, errors) {
+            Ok(stmt_ret) => stmt_ret,
+            Err(err) => {
+                errors.push(err);
+                None
             }
-            ret_type = Some(stmt_ret);
+        };
+        ret_type = merge_return_types(ret_type, stmt_ret, statement.span, errors);
+    }
+    ret_type
+}
+
+// helper function to combine the types returned by two parts of a block.
+// If they disagree the second is reported at `span`.
+fn merge_return_types(
+    first: Option<Type>,
+    second: Option<Type>,
+    span: Span,
+    errors: &mut Vec<TypeError>,
+) -> Option<Type> {
+    match (first, second) {
+        (first, None) => first,
+        (None, second) | (Some(Type::Error), second) => second,
+        (Some(first), Some(second)) => {
+            if first != second && second != Type::Error {
+                errors.push(
+                    TypeErrorKind::Unexpected {
+                        got: second,
+                        expected: first.clone(),
+                    }
+                    .at(span),
+                );
+            }
+            Some(first)
         }
     }
-    Ok(ret_type.unwrap_or(Type::Void))
 }
 
+/// Checks a statement, returning the type it returns if it is or contains a return statement.
+/// Errors in nested blocks are pushed to `errors`, the first error in the statement itself is returned.
 pub fn check_statement(
     statement: &Statement,
     scope: &mut Scope,
+    errors: &mut Vec<TypeError>,
 ) -> Result<Option<Type>, TypeError> {
-    match statement {
-        Statement::Import {
-            path: _,
-            imports: _,
-        } => {
-            todo!()
+    match &statement.node {
+        StatementKind::Import { .. } => {
+            Err(TypeErrorKind::Unsupported("imports").at(statement.span))
         }
 
-        Statement::VarDef { name, typ, expr } => {
-            let typ = typ.as_ref().map(|typ| check_type(typ, scope)).transpose()?;
+        StatementKind::VarDef { name, typ, expr } => {
+            let typ = typ
+                .as_ref()
+                .map(|typ| check_type(&typ.node, typ.span, scope))
+                .transpose()
+                .unwrap_or_else(|err| {
+                    errors.push(err);
+                    Some(Type::Error)
+                });
 
             // If the expression is a function literal, infer the type signature before fully parsing the body
             // This allows us to use the function type in the body (aka recursive functions)
-            if let Ok(function_type) = infer_function_type_signature(&expr, scope) {
+            if let Ok(function_type) = infer_function_type_signature(expr, scope) {
                 scope.set_var(name, function_type);
             }
 
-            let expr_typ = check_expr(&expr, scope)?;
+            let expr_typ = check_expr(expr, scope, errors).unwrap_or_else(|err| {
+                errors.push(err);
+                Type::Error
+            });
+            // The variable is defined even if its definition is wrong, so later uses of it aren't reported
+            scope.set_var(name, typ.clone().unwrap_or(expr_typ.clone()));
             if let Some(ref typ) = typ {
                 if !is_assignable(&expr_typ, typ, scope) {
-                    return Err(TypeError::Unexpected {
+                    return Err(TypeErrorKind::Unexpected {
                         got: expr_typ,
                         expected: typ.clone(),
-                    });
+                    }
+                    .at(expr.span));
                 }
             }
-            scope.set_var(&name, typ.unwrap_or(expr_typ));
             Ok(None)
         }
 
-        Statement::TypeDef { name, typ } => {
-            scope.set_type(&name, typ.clone());
-            let typ = check_type(&typ, scope)?;
-            scope.set_type(&name, typ);
+        StatementKind::TypeDef { name, typ } => {
+            scope.set_type(name, typ.node.clone());
+            let resolved = check_type(&typ.node, typ.span, scope);
+            scope.set_type(name, resolved.clone().unwrap_or(Type::Error));
+            resolved?;
 
             Ok(None)
         }
 
-        Statement::Expr(expr) => {
-            check_expr(&expr, scope)?;
+        StatementKind::Expr(expr) => {
+            check_expr(expr, scope, errors)?;
             Ok(None)
         }
 
-        Statement::Continue => Ok(None),
-        Statement::Break => Ok(None),
-        Statement::Return(expr) => {
+        StatementKind::Continue => Ok(None),
+        StatementKind::Break => Ok(None),
+        StatementKind::Return(expr) => {
             if let Some(expr) = expr {
-                let ret_type = check_expr(&expr, scope)?;
+                // Still counts as a return on error, so the function isn't reported as returning void
+                let ret_type = check_expr(expr, scope, errors).unwrap_or_else(|err| {
+                    errors.push(err);
+                    Type::Error
+                });
                 return Ok(Some(ret_type));
             }
             Ok(None)
         }
 
-        Statement::Assign { lhs, rhs } => {
-            let lhs_typ = check_expr(&lhs, scope)?;
-            let rhs_typ = check_expr(&rhs, scope)?;
+        StatementKind::Assign { lhs, rhs } => {
+            let lhs_typ = check_expr(lhs, scope, errors)?;
+            let rhs_typ = check_expr(rhs, scope, errors)?;
             if !is_assignable(&rhs_typ, &lhs_typ, scope) {
-                return Err(TypeError::Unexpected {
+                return Err(TypeErrorKind::Unexpected {
                     got: rhs_typ,
                     expected: lhs_typ,
-                });
+                }
+                .at(rhs.span));
             }
-            if !can_assign_to_expr(&lhs) {
-                return Err(TypeError::Invalid(lhs_typ));
+            if !can_assign_to_expr(lhs) {
+                return Err(TypeErrorKind::Invalid(lhs_typ).at(lhs.span));
             }
             Ok(None)
         }
 
-        Statement::If {
+        StatementKind::If {
             body,
             cond,
             else_stmt,
         } => {
-            let cond_typ = check_expr(&cond, scope)?;
-            expect_type(cond_typ, Type::Bool)?;
-            check_block(body, scope)?;
+            check_condition(cond, scope, errors);
+            let ret_type = check_block(body, scope, errors);
 
-            match else_stmt {
-                ElseStatement::Block(block) => {
-                    check_block(block, scope)?;
-                }
-                ElseStatement::If(stmt) => {
-                    check_statement(stmt, scope)?;
-                }
-                ElseStatement::None => {}
-            }
+            let else_ret_type = match else_stmt {
+                ElseStatement::Block(block) => check_block(block, scope, errors),
+                ElseStatement::If(stmt) => check_statement(stmt, scope, errors)?,
+                ElseStatement::None => None,
+            };
 
-            Ok(None)
+            Ok(merge_return_types(
+                ret_type,
+                else_ret_type,
+                statement.span,
+                errors,
+            ))
         }
 
-        Statement::Loop(body) => {
-            check_block(body, scope)?;
-            Ok(None)
+        StatementKind::Loop(body) => Ok(check_block(body, scope, errors)),
+
+        StatementKind::Yeet(expr) => {
+            let typ = check_expr(expr, scope, errors)?;
+            if typ == Type::Void {
+                return Err(TypeErrorKind::Invalid(typ).at(expr.span));
+            }
+            // Doesn't carry on to the end of the function, so counts as a return of any type
+            Ok(Some(Type::Error))
         }
 
-        Statement::While { cond, body } => {
-            let cond_typ = check_expr(&cond, scope)?;
-            expect_type(cond_typ, Type::Bool)?;
-            check_block(body, scope)?;
-            Ok(None)
+        StatementKind::Try { body, catches } => {
+            let mut ret_type = check_block(body, scope, errors);
+            for catch in catches {
+                // The value caught is only in scope in the `catch` body
+                let typ =
+                    check_type(&catch.typ.node, catch.typ.span, scope).unwrap_or_else(|err| {
+                        errors.push(err);
+                        Type::Error
+                    });
+                // Lists of a fixed length are yeeted as lists of any length, so are never caught as one
+                if matches!(typ, Type::Void | Type::SizedArray { .. }) {
+                    errors.push(TypeErrorKind::Invalid(typ.clone()).at(catch.typ.span));
+                }
+                let mut scope = scope.create_child();
+                scope.set_var(&catch.name, typ);
+                let catch_ret = check_block(&catch.body, &mut scope, errors);
+                ret_type = merge_return_types(ret_type, catch_ret, statement.span, errors);
+            }
+            Ok(ret_type)
+        }
+
+        StatementKind::While { cond, body } => {
+            check_condition(cond, scope, errors);
+            Ok(check_block(body, scope, errors))
         }
     }
 }
 
-fn can_assign_to_expr(expr: &Expression) -> bool {
-    match expr {
-        Expression::Identifier(_) => true,
-        Expression::Deref(_) => true,
-        Expression::Index { expr: _, index: _ } => true,
-        Expression::Dot { expr: _, field: _ } => true,
-        _ => false,
+// helper function to check the condition of an if or while, which must be a bool
+fn check_condition(cond: &Expression, scope: &Scope, errors: &mut Vec<TypeError>) {
+    let result = check_expr(cond, scope, errors)
+        .and_then(|cond_typ| expect_type(cond_typ, Type::Bool, cond.span));
+    if let Err(err) = result {
+        errors.push(err);
     }
 }
 
+fn can_assign_to_expr(expr: &Expression) -> bool {
+    matches!(
+        expr.node,
+        ExpressionKind::Identifier(_)
+            | ExpressionKind::Deref(_)
+            | ExpressionKind::Index { .. }
+            | ExpressionKind::Dot { .. }
+    )
+}
+
 pub fn is_assignable(src: &Type, dst: &Type, scope: &Scope) -> bool {
-    if src == dst {
+    if src == dst || *src == Type::Error || *dst == Type::Error {
         return true;
     }
 
@@ -158,11 +237,10 @@ pub fn is_assignable(src: &Type, dst: &Type, scope: &Scope) -> bool {
             if is_assignable(src, dst, scope) {
                 return true;
             }
-            match (src.as_ref(), dst.as_ref()) {
-                (Type::Void, _) => true,
-                (_, Type::Void) => true,
-                _ => false,
-            }
+            matches!(
+                (src.as_ref(), dst.as_ref()),
+                (Type::Void, _) | (_, Type::Void)
+            )
         }
         // (Type::Int, Type::Char) => true,
         // (Type::Char, Type::Int) => true,
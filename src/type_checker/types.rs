@@ -1,10 +1,11 @@
 use std::collections::HashMap;
 
-use crate::parser::types::Type;
+use crate::{lexer::span::Span, parser::types::Type};
 
-use super::{expressions::check_expr, Scope, TypeError};
+use super::{expressions::check_expr, Scope, TypeError, TypeErrorKind};
 
-pub fn check_type(ty: &Type, scope: &Scope) -> Result<Type, TypeError> {
+/// Resolves named types in `ty`. Errors are reported against `span`.
+pub fn check_type(ty: &Type, span: Span, scope: &Scope) -> Result<Type, TypeError> {
     match ty {
         Type::Int => Ok(Type::Int),
         Type::Float => Ok(Type::Float),
@@ -12,45 +13,48 @@ pub fn check_type(ty: &Type, scope: &Scope) -> Result<Type, TypeError> {
         Type::Bool => Ok(Type::Bool),
         Type::String => Ok(Type::String),
         Type::Void => Ok(Type::Void),
-        Type::Ptr(ty) => Ok(Type::Ptr(Box::new(check_type(ty, scope)?))),
+        Type::Ptr(ty) => Ok(Type::Ptr(Box::new(check_type(ty, span, scope)?))),
         Type::SizedArray { element, len } => Ok(Type::SizedArray {
-            element: Box::new(check_type(element, scope)?),
+            element: Box::new(check_type(element, span, scope)?),
             len: *len,
         }),
         Type::Struct(fields) => {
             let mut ret = HashMap::new();
             for (name, ty) in fields {
-                ret.insert(name.clone(), check_type(ty, scope)?);
+                ret.insert(name.clone(), check_type(ty, span, scope)?);
             }
             Ok(Type::Struct(ret))
         }
-        Type::Array(ty) => Ok(Type::Array(Box::new(check_type(ty, scope)?))),
+        Type::Array(ty) => Ok(Type::Array(Box::new(check_type(ty, span, scope)?))),
         Type::Tuple(tys) => {
             let mut ret = Vec::new();
             for ty in tys {
-                ret.push(Box::new(check_type(ty, scope)?));
+                ret.push(Box::new(check_type(ty, span, scope)?));
             }
             Ok(Type::Tuple(ret))
         }
         Type::Function { args, ret } => {
             let mut checked_args = Vec::new();
             for ty in args {
-                checked_args.push(Box::new(check_type(ty, scope)?));
+                checked_args.push(Box::new(check_type(ty, span, scope)?));
             }
             Ok(Type::Function {
                 args: checked_args,
-                ret: Box::new(check_type(ret, scope)?),
+                ret: Box::new(check_type(ret, span, scope)?),
             })
         }
         Type::Named(ident) => match scope.get_type(ident) {
             Some(ty) => Ok(ty.clone()),
-            None => Err(TypeError::InvalidIdentifier(ident.clone())),
+            None => Err(TypeErrorKind::InvalidIdentifier(ident.clone()).at(span)),
         },
         Type::TypeOf(expr) => {
-            dbg!("check_type", expr);
-            let exp = check_expr(expr, scope)?;
-            dbg!(&exp);
-            Ok(exp)
+            let mut errors = Vec::new();
+            let typ = check_expr(expr, scope, &mut errors)?;
+            match errors.into_iter().next() {
+                Some(error) => Err(error),
+                None => Ok(typ),
+            }
         }
+        Type::Error => Ok(Type::Error),
     }
 }
@@ -0,0 +1,1148 @@
+use std::fmt;
+
+use super::little_endian_base_128::{decode_signed, DecodesFromLeb128};
+use super::*;
+
+pub trait DecodesFromWasm: Sized {
+    fn decode_from_wasm(decoder: &mut Decoder) -> Result<Self, DecodeError>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodeError {
+    // The offset into the binary the error was found at
+    pub offset: usize,
+    pub kind: DecodeErrorKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecodeErrorKind {
+    UnexpectedEnd,
+    InvalidMagic,
+    UnsupportedVersion(u32),
+    InvalidLeb128,
+    InvalidUtf8,
+    // A byte that doesn't encode any of the things `what` can be, eg. a value type
+    InvalidByte { what: &'static str, byte: u8 },
+    UnknownOpcode(u8),
+    // An opcode after a prefix byte like 0xFC
+    UnknownPrefixedOpcode(u8, u32),
+    UnexpectedElse,
+    UnexpectedCatch,
+    SectionOutOfOrder(u8),
+    // The contents of a section didn't take up the size it declared
+    SectionSizeMismatch(u8),
+    FunctionCountMismatch { functions: usize, bodies: usize },
+    TooManyLocals,
+    // Valid wasm that `WasmModule` can't represent, eg. passive data segments
+    Unsupported(&'static str),
+}
+
+impl DecodeErrorKind {
+    pub fn at(self, offset: usize) -> DecodeError {
+        DecodeError { offset, kind: self }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            DecodeErrorKind::UnexpectedEnd => write!(f, "unexpected end of input")?,
+            DecodeErrorKind::InvalidMagic => write!(f, "not a wasm binary")?,
+            DecodeErrorKind::UnsupportedVersion(version) => {
+                write!(f, "unsupported wasm version {}", version)?
+            }
+            DecodeErrorKind::InvalidLeb128 => write!(f, "invalid LEB128 integer")?,
+            DecodeErrorKind::InvalidUtf8 => write!(f, "name is not valid UTF-8")?,
+            DecodeErrorKind::InvalidByte { what, byte } => {
+                write!(f, "invalid {} 0x{:02x}", what, byte)?
+            }
+            DecodeErrorKind::UnknownOpcode(opcode) => write!(f, "unknown opcode 0x{:02x}", opcode)?,
+            DecodeErrorKind::UnknownPrefixedOpcode(prefix, opcode) => {
+                write!(f, "unknown opcode 0x{:02x} {}", prefix, opcode)?
+            }
+            DecodeErrorKind::UnexpectedElse => write!(f, "`else` outside of an `if`")?,
+            DecodeErrorKind::UnexpectedCatch => write!(f, "`catch` outside of a `try`")?,
+            DecodeErrorKind::SectionOutOfOrder(id) => write!(f, "section {} is out of order", id)?,
+            DecodeErrorKind::SectionSizeMismatch(id) => {
+                write!(f, "section {} does not match its declared size", id)?
+            }
+            DecodeErrorKind::FunctionCountMismatch { functions, bodies } => write!(
+                f,
+                "{} functions are declared but {} bodies are given",
+                functions, bodies
+            )?,
+            DecodeErrorKind::TooManyLocals => write!(f, "too many locals")?,
+            DecodeErrorKind::Unsupported(what) => write!(f, "{} are not supported", what)?,
+        }
+        write!(f, " at offset 0x{:x}", self.offset)
+    }
+}
+
+/// Reads values from a wasm binary, keeping track of the offset for errors.
+pub struct Decoder<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    // The end of the section being read
+    end: usize,
+    // Where each instruction of each function body starts, if they're being recorded
+    code_offsets: Option<Vec<Vec<usize>>>,
+    // The offsets in the function body being read
+    body_offsets: Option<Vec<usize>>,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+            position: 0,
+            end: bytes.len(),
+            code_offsets: None,
+            body_offsets: None,
+        }
+    }
+
+    pub fn offset(&self) -> usize {
+        self.position
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.position >= self.end
+    }
+
+    pub fn error(&self, kind: DecodeErrorKind) -> DecodeError {
+        kind.at(self.position)
+    }
+
+    pub fn peek(&self) -> Result<u8, DecodeError> {
+        if self.is_empty() {
+            return Err(self.error(DecodeErrorKind::UnexpectedEnd));
+        }
+        Ok(self.bytes[self.position])
+    }
+
+    pub fn byte(&mut self) -> Result<u8, DecodeError> {
+        let byte = self.peek()?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if len > self.end - self.position {
+            return Err(self.error(DecodeErrorKind::UnexpectedEnd));
+        }
+        let bytes = &self.bytes[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    pub fn leb128<T: DecodesFromLeb128>(&mut self) -> Result<T, DecodeError> {
+        let input = &self.bytes[self.position..self.end];
+        match T::decode_from_leb128(input) {
+            Some((value, read)) => {
+                self.position += read;
+                Ok(value)
+            }
+            None => Err(self.error(DecodeErrorKind::InvalidLeb128)),
+        }
+    }
+
+    /// Reads a length prefixed vector.
+    pub fn vec<T: DecodesFromWasm>(&mut self) -> Result<Vec<T>, DecodeError> {
+        let len = self.leb128::<u32>()?;
+        // Every item takes at least a byte, so don't trust larger lengths with the allocation
+        let mut items = Vec::with_capacity((len as usize).min(self.end - self.position));
+        for _ in 0..len {
+            items.push(T::decode_from_wasm(self)?);
+        }
+        Ok(items)
+    }
+
+    pub fn name(&mut self) -> Result<String, DecodeError> {
+        let offset = self.offset();
+        let len = self.leb128::<u32>()?;
+        let bytes = self.bytes(len as usize)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeErrorKind::InvalidUtf8.at(offset))
+    }
+
+    // Runs `decode` over the next `size` bytes, failing if it doesn't read all of them
+    fn section<T>(
+        &mut self,
+        id: u8,
+        size: usize,
+        decode: impl FnOnce(&mut Self) -> Result<T, DecodeError>,
+    ) -> Result<T, DecodeError> {
+        if size > self.end - self.position {
+            return Err(self.error(DecodeErrorKind::UnexpectedEnd));
+        }
+        let outer_end = self.end;
+        self.end = self.position + size;
+        let result = decode(self)?;
+        if self.position != self.end {
+            return Err(self.error(DecodeErrorKind::SectionSizeMismatch(id)));
+        }
+        self.end = outer_end;
+        Ok(result)
+    }
+}
+
+impl WasmModule {
+    /// Parses a wasm binary, as written by `encode_to_wasm`.
+    pub fn decode(bytes: &[u8]) -> Result<WasmModule, DecodeError> {
+        WasmModule::decode_from_wasm(&mut Decoder::new(bytes))
+    }
+
+    /// Parses a wasm binary like `decode`, also returning the offset of every instruction in
+    /// each function body, numbered as `validator::Location` does.
+    pub fn decode_with_offsets(bytes: &[u8]) -> Result<(WasmModule, Vec<Vec<usize>>), DecodeError> {
+        let mut decoder = Decoder::new(bytes);
+        decoder.code_offsets = Some(Vec::new());
+        let module = WasmModule::decode_from_wasm(&mut decoder)?;
+        Ok((module, decoder.code_offsets.unwrap_or_default()))
+    }
+}
+
+// Where a section has to appear in a module. Sections are in the order of their ids, except
+// the tag section comes before the globals and the data count section before the code
+fn section_order(id: u8) -> u8 {
+    const ORDER: [u8; 13] = [1, 2, 3, 4, 5, 13, 6, 7, 8, 9, 12, 10, 11];
+    (ORDER.iter().position(|section| *section == id)).map_or(u8::MAX, |position| position as u8 + 1)
+}
+
+impl DecodesFromWasm for WasmModule {
+    fn decode_from_wasm(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        if decoder.bytes(4).ok() != Some(&[0x00, 0x61, 0x73, 0x6d]) {
+            return Err(DecodeErrorKind::InvalidMagic.at(0));
+        }
+        let version = decoder.bytes(4)?;
+        let version = u32::from_le_bytes([version[0], version[1], version[2], version[3]]);
+        if version != 1 {
+            return Err(DecodeErrorKind::UnsupportedVersion(version).at(4));
+        }
+
+        let mut module = WasmModule::default();
+        let mut function_types = Vec::new();
+        let mut last_section = 0;
+        while !decoder.is_empty() {
+            let offset = decoder.offset();
+            let id = decoder.byte()?;
+            let size = decoder.leb128::<u32>()? as usize;
+            // Custom sections can appear anywhere, everything else at most once and in order
+            if id != 0 {
+                if section_order(id) <= last_section {
+                    return Err(DecodeErrorKind::SectionOutOfOrder(id).at(offset));
+                }
+                last_section = section_order(id);
+            }
+            decoder.section(id, size, |decoder| {
+                match id {
+                    0 => {
+                        let name = decoder.name()?;
+                        let contents = decoder.bytes(decoder.end - decoder.position)?;
+                        // Other custom sections hold nothing the module needs, and a malformed
+                        // name section is ignored rather than stopping the module from loading
+                        if name == "name" {
+                            module.names = Names::decode_from_wasm(&mut Decoder::new(contents))
+                                .unwrap_or_default();
+                        }
+                    }
+                    1 => module.types = decoder.vec()?,
+                    2 => module.imports = decoder.vec()?,
+                    3 => function_types = decoder.vec::<u32>()?,
+                    4 => module.tables = decoder.vec()?,
+                    5 => module.memories = decoder.vec()?,
+                    13 => module.tags = decoder.vec()?,
+                    6 => module.globals = decoder.vec()?,
+                    7 => module.exports = decoder.vec()?,
+                    8 => module.start = Some(decoder.leb128()?),
+                    9 => module.elements = decoder.vec()?,
+                    10 => {
+                        let offset = decoder.offset();
+                        let bodies = decoder.vec::<Function>()?;
+                        if bodies.len() != function_types.len() {
+                            return Err(DecodeErrorKind::FunctionCountMismatch {
+                                functions: function_types.len(),
+                                bodies: bodies.len(),
+                            }
+                            .at(offset));
+                        }
+                        module.functions = bodies;
+                    }
+                    11 => module.data = decoder.vec()?,
+                    // The data count section only matters to validators
+                    12 => {
+                        decoder.leb128::<u32>()?;
+                    }
+                    _ => {
+                        return Err(DecodeErrorKind::InvalidByte {
+                            what: "section id",
+                            byte: id,
+                        }
+                        .at(offset))
+                    }
+                }
+                Ok(())
+            })?;
+        }
+
+        if module.functions.len() != function_types.len() {
+            return Err(decoder.error(DecodeErrorKind::FunctionCountMismatch {
+                functions: function_types.len(),
+                bodies: module.functions.len(),
+            }));
+        }
+        for (function, type_idx) in module.functions.iter_mut().zip(function_types) {
+            function.type_idx = type_idx;
+        }
+        Ok(module)
+    }
+}
+
+impl DecodesFromWasm for u32 {
+    fn decode_from_wasm(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        decoder.leb128()
+    }
+}
+
+impl DecodesFromWasm for String {
+    fn decode_from_wasm(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        decoder.name()
+    }
+}
+
+impl<T: DecodesFromWasm> DecodesFromWasm for (u32, T) {
+    fn decode_from_wasm(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        Ok((decoder.leb128()?, T::decode_from_wasm(decoder)?))
+    }
+}
+
+impl<T: DecodesFromWasm> DecodesFromWasm for Vec<T> {
+    fn decode_from_wasm(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        decoder.vec()
+    }
+}
+
+impl DecodesFromWasm for Names {
+    fn decode_from_wasm(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        let mut names = Names::default();
+        while !decoder.is_empty() {
+            let id = decoder.byte()?;
+            let size = decoder.leb128::<u32>()? as usize;
+            decoder.section(id, size, |decoder| {
+                match id {
+                    0 => names.module = Some(decoder.name()?),
+                    1 => names.functions = decoder.vec()?,
+                    2 => names.locals = decoder.vec()?,
+                    // Names of other things, from extensions to the name section
+                    _ => {
+                        decoder.bytes(size)?;
+                    }
+                }
+                Ok(())
+            })?;
+        }
+        Ok(names)
+    }
+}
+
+impl DecodesFromWasm for FunctionType {
+    fn decode_from_wasm(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        let offset = decoder.offset();
+        match decoder.byte()? {
+            0x60 => Ok(FunctionType {
+                args: decoder.vec()?,
+                ret: decoder.vec()?,
+            }),
+            byte => Err(DecodeErrorKind::InvalidByte {
+                what: "function type",
+                byte,
+            }
+            .at(offset)),
+        }
+    }
+}
+
+impl DecodesFromWasm for NumType {
+    fn decode_from_wasm(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        let offset = decoder.offset();
+        match decoder.byte()? {
+            0x7f => Ok(NumType::I32),
+            0x7e => Ok(NumType::I64),
+            0x7d => Ok(NumType::F32),
+            0x7c => Ok(NumType::F64),
+            byte => Err(DecodeErrorKind::InvalidByte {
+                what: "value type",
+                byte,
+            }
+            .at(offset)),
+        }
+    }
+}
+
+impl DecodesFromWasm for Import {
+    fn decode_from_wasm(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        Ok(Import {
+            module: decoder.name()?,
+            name: decoder.name()?,
+            typ: ImportType::decode_from_wasm(decoder)?,
+        })
+    }
+}
+
+impl DecodesFromWasm for ImportType {
+    fn decode_from_wasm(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        let offset = decoder.offset();
+        match decoder.byte()? {
+            0x00 => Ok(ImportType::Func(decoder.leb128()?)),
+            0x01 => Ok(ImportType::Table(TableType::decode_from_wasm(decoder)?)),
+            0x02 => Ok(ImportType::Memory(Limits::decode_from_wasm(decoder)?)),
+            0x03 => Ok(ImportType::Global(GlobalType::decode_from_wasm(decoder)?)),
+            byte => Err(DecodeErrorKind::InvalidByte {
+                what: "import kind",
+                byte,
+            }
+            .at(offset)),
+        }
+    }
+}
+
+impl DecodesFromWasm for Export {
+    fn decode_from_wasm(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        Ok(Export {
+            name: decoder.name()?,
+            typ: ExportType::decode_from_wasm(decoder)?,
+        })
+    }
+}
+
+impl DecodesFromWasm for ExportType {
+    fn decode_from_wasm(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        let offset = decoder.offset();
+        match decoder.byte()? {
+            0x00 => Ok(ExportType::Func(decoder.leb128()?)),
+            0x01 => Ok(ExportType::Table(decoder.leb128()?)),
+            0x02 => Ok(ExportType::Memory(decoder.leb128()?)),
+            0x03 => Ok(ExportType::Global(decoder.leb128()?)),
+            byte => Err(DecodeErrorKind::InvalidByte {
+                what: "export kind",
+                byte,
+            }
+            .at(offset)),
+        }
+    }
+}
+
+impl DecodesFromWasm for Limits {
+    fn decode_from_wasm(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        let offset = decoder.offset();
+        match decoder.byte()? {
+            0x00 => Ok(Limits {
+                min: decoder.leb128()?,
+                max: None,
+            }),
+            0x01 => Ok(Limits {
+                min: decoder.leb128()?,
+                max: Some(decoder.leb128()?),
+            }),
+            byte => Err(DecodeErrorKind::InvalidByte {
+                what: "limits flag",
+                byte,
+            }
+            .at(offset)),
+        }
+    }
+}
+
+impl DecodesFromWasm for RefType {
+    fn decode_from_wasm(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        let offset = decoder.offset();
+        match decoder.byte()? {
+            0x70 => Ok(RefType::FuncRef),
+            0x6F => Ok(RefType::ExternRef),
+            byte => Err(DecodeErrorKind::InvalidByte {
+                what: "reference type",
+                byte,
+            }
+            .at(offset)),
+        }
+    }
+}
+
+impl DecodesFromWasm for TableType {
+    fn decode_from_wasm(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        Ok(TableType {
+            element: RefType::decode_from_wasm(decoder)?,
+            limits: Limits::decode_from_wasm(decoder)?,
+        })
+    }
+}
+
+impl DecodesFromWasm for Tag {
+    fn decode_from_wasm(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        let offset = decoder.offset();
+        match decoder.byte()? {
+            0x00 => Ok(Tag {
+                type_idx: decoder.leb128()?,
+            }),
+            byte => Err(DecodeErrorKind::InvalidByte {
+                what: "tag attribute",
+                byte,
+            }
+            .at(offset)),
+        }
+    }
+}
+
+impl DecodesFromWasm for GlobalType {
+    fn decode_from_wasm(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        let typ = NumType::decode_from_wasm(decoder)?;
+        let offset = decoder.offset();
+        let mutable = match decoder.byte()? {
+            0x00 => false,
+            0x01 => true,
+            byte => {
+                return Err(DecodeErrorKind::InvalidByte {
+                    what: "mutability",
+                    byte,
+                }
+                .at(offset))
+            }
+        };
+        Ok(GlobalType { typ, mutable })
+    }
+}
+
+impl DecodesFromWasm for Global {
+    fn decode_from_wasm(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        Ok(Global {
+            typ: GlobalType::decode_from_wasm(decoder)?,
+            init: Expression::decode_from_wasm(decoder)?,
+        })
+    }
+}
+
+impl DecodesFromWasm for Element {
+    fn decode_from_wasm(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        let offset = decoder.offset();
+        let (table, offset_expr) = match decoder.leb128::<u32>()? {
+            0x00 => (0, Expression::decode_from_wasm(decoder)?),
+            0x02 => {
+                let table = decoder.leb128()?;
+                let offset_expr = Expression::decode_from_wasm(decoder)?;
+                let kind_offset = decoder.offset();
+                match decoder.byte()? {
+                    0x00 => {}
+                    byte => {
+                        return Err(DecodeErrorKind::InvalidByte {
+                            what: "element kind",
+                            byte,
+                        }
+                        .at(kind_offset))
+                    }
+                }
+                (table, offset_expr)
+            }
+            // Passive and declarative segments, and segments of expressions
+            1 | 3..=7 => {
+                return Err(
+                    DecodeErrorKind::Unsupported("element segments other than active ones")
+                        .at(offset),
+                )
+            }
+            flags => {
+                return Err(DecodeErrorKind::InvalidByte {
+                    what: "element segment flag",
+                    byte: flags as u8,
+                }
+                .at(offset))
+            }
+        };
+        Ok(Element {
+            table,
+            offset: offset_expr,
+            functions: decoder.vec()?,
+        })
+    }
+}
+
+impl DecodesFromWasm for Data {
+    fn decode_from_wasm(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        let offset = decoder.offset();
+        let memory = match decoder.leb128::<u32>()? {
+            0x00 => 0,
+            0x02 => decoder.leb128()?,
+            0x01 => return Err(DecodeErrorKind::Unsupported("passive data segments").at(offset)),
+            flags => {
+                return Err(DecodeErrorKind::InvalidByte {
+                    what: "data segment flag",
+                    byte: flags as u8,
+                }
+                .at(offset))
+            }
+        };
+        let offset_expr = Expression::decode_from_wasm(decoder)?;
+        let len = decoder.leb128::<u32>()?;
+        Ok(Data {
+            memory,
+            offset: offset_expr,
+            bytes: decoder.bytes(len as usize)?.to_vec(),
+        })
+    }
+}
+
+// The most locals a function can declare, the same limit engines use
+const MAX_LOCALS: usize = 50_000;
+
+impl DecodesFromWasm for Function {
+    fn decode_from_wasm(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        let size = decoder.leb128::<u32>()? as usize;
+        decoder.section(10, size, |decoder| {
+            // Locals are grouped into runs of the same type
+            let mut locals = Vec::new();
+            let groups = decoder.leb128::<u32>()?;
+            for _ in 0..groups {
+                let offset = decoder.offset();
+                let count = decoder.leb128::<u32>()? as usize;
+                let typ = NumType::decode_from_wasm(decoder)?;
+                if locals.len() + count > MAX_LOCALS {
+                    return Err(DecodeErrorKind::TooManyLocals.at(offset));
+                }
+                locals.extend(std::iter::repeat_n(typ, count));
+            }
+            // Constant expressions elsewhere in the module aren't recorded
+            decoder.body_offsets = decoder.code_offsets.as_ref().map(|_| Vec::new());
+            let body = Expression::decode_from_wasm(decoder)?;
+            if let (Some(code), Some(body)) =
+                (&mut decoder.code_offsets, decoder.body_offsets.take())
+            {
+                code.push(body);
+            }
+            Ok(Function {
+                // Filled in from the function section
+                type_idx: 0,
+                locals,
+                body,
+            })
+        })
+    }
+}
+
+impl DecodesFromWasm for Expression {
+    fn decode_from_wasm(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        let offset = decoder.offset();
+        match decode_instructions(decoder)? {
+            (instructions, 0x0b) => Ok(Expression { instructions }),
+            (_, 0x05) => Err(DecodeErrorKind::UnexpectedElse.at(offset)),
+            _ => Err(DecodeErrorKind::UnexpectedCatch.at(offset)),
+        }
+    }
+}
+
+// Reads instructions up to an `end`, `else`, `catch` or `catch_all`, returning them along with
+// which one it was
+fn decode_instructions(decoder: &mut Decoder) -> Result<(Vec<Instruction>, u8), DecodeError> {
+    let mut instructions = Vec::new();
+    loop {
+        match decoder.peek()? {
+            terminator @ (0x0b | 0x05 | 0x07 | 0x19) => {
+                decoder.byte()?;
+                return Ok((instructions, terminator));
+            }
+            _ => instructions.push(Instruction::decode_from_wasm(decoder)?),
+        }
+    }
+}
+
+impl DecodesFromWasm for Instruction {
+    fn decode_from_wasm(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        let offset = decoder.offset();
+        if let Some(offsets) = &mut decoder.body_offsets {
+            offsets.push(offset);
+        }
+        let opcode = decoder.byte()?;
+        let instruction = match opcode {
+            0x41 => Instruction::I32Const(decoder.leb128()?),
+            0x42 => Instruction::I64Const(decoder.leb128()?),
+            0x43 => {
+                let bytes = decoder.bytes(4)?;
+                Instruction::F32Const(f32::from_le_bytes(bytes.try_into().unwrap()))
+            }
+            0x44 => {
+                let bytes = decoder.bytes(8)?;
+                Instruction::F64Const(f64::from_le_bytes(bytes.try_into().unwrap()))
+            }
+
+            0x00..=0x04 | 0x06 | 0x08 | 0x09 | 0x0C..=0x11 => {
+                Instruction::ControlOp(decode_control_op(opcode, decoder)?)
+            }
+
+            0xD0 => Instruction::RefOp(RefOp::Null(RefType::decode_from_wasm(decoder)?)),
+            0xD1 => Instruction::RefOp(RefOp::IsNull),
+            0xD2 => Instruction::RefOp(RefOp::Func(decoder.leb128()?)),
+
+            0x1A => Instruction::ParametricOp(ParametricOp::Drop),
+            0x1B => Instruction::ParametricOp(ParametricOp::Select),
+
+            0x20 => Instruction::VariableOp(VariableOp::LocalGet(decoder.leb128()?)),
+            0x21 => Instruction::VariableOp(VariableOp::LocalSet(decoder.leb128()?)),
+            0x22 => Instruction::VariableOp(VariableOp::LocalTee(decoder.leb128()?)),
+            0x23 => Instruction::VariableOp(VariableOp::GlobalGet(decoder.leb128()?)),
+            0x24 => Instruction::VariableOp(VariableOp::GlobalSet(decoder.leb128()?)),
+
+            0x28..=0x40 => Instruction::MemoryOp(decode_memory_op(opcode, decoder)?),
+
+            0xFC => {
+                let sub_opcode = decoder.leb128::<u32>()?;
+                match sub_opcode {
+                    0..=7 => Instruction::ConvertOp(decode_saturating_truncation(sub_opcode)),
+                    10 => {
+                        memory_index(decoder)?;
+                        memory_index(decoder)?;
+                        Instruction::MemoryOp(MemoryOp::MemoryCopy)
+                    }
+                    11 => {
+                        memory_index(decoder)?;
+                        Instruction::MemoryOp(MemoryOp::MemoryFill)
+                    }
+                    _ => {
+                        return Err(
+                            DecodeErrorKind::UnknownPrefixedOpcode(opcode, sub_opcode).at(offset)
+                        )
+                    }
+                }
+            }
+
+            _ => match (
+                decode_integer_op(opcode),
+                decode_float_op(opcode),
+                decode_convert_op(opcode),
+            ) {
+                (Some(op), _, _) => Instruction::IntegerOp(op),
+                (_, Some(op), _) => Instruction::FloatOp(op),
+                (_, _, Some(op)) => Instruction::ConvertOp(op),
+                _ => return Err(DecodeErrorKind::UnknownOpcode(opcode).at(offset)),
+            },
+        };
+        Ok(instruction)
+    }
+}
+
+fn decode_control_op(opcode: u8, decoder: &mut Decoder) -> Result<ControlOp, DecodeError> {
+    let op = match opcode {
+        0x00 => ControlOp::Unreachable,
+        0x01 => ControlOp::Nop,
+        0x02 => ControlOp::Block {
+            typ: BlockType::decode_from_wasm(decoder)?,
+            body: Expression::decode_from_wasm(decoder)?,
+        },
+        0x03 => ControlOp::Loop {
+            typ: BlockType::decode_from_wasm(decoder)?,
+            body: Expression::decode_from_wasm(decoder)?,
+        },
+        0x04 => {
+            let typ = BlockType::decode_from_wasm(decoder)?;
+            let (instructions, terminator) = decode_instructions(decoder)?;
+            let otherwise = match terminator {
+                0x05 => Some(Expression::decode_from_wasm(decoder)?),
+                0x0b => None,
+                _ => return Err(decoder.error(DecodeErrorKind::UnexpectedCatch)),
+            };
+            ControlOp::If {
+                typ,
+                then: Expression { instructions },
+                otherwise,
+            }
+        }
+        0x06 => {
+            let typ = BlockType::decode_from_wasm(decoder)?;
+            let (instructions, mut terminator) = decode_instructions(decoder)?;
+            let mut catches = Vec::new();
+            let mut catch_all = None;
+            // Handlers follow the body until the `end`, with any `catch_all` last
+            loop {
+                match terminator {
+                    0x0b => break,
+                    0x07 if catch_all.is_none() => {
+                        let tag = decoder.leb128()?;
+                        let (instructions, next) = decode_instructions(decoder)?;
+                        let body = Expression { instructions };
+                        catches.push(Catch { tag, body });
+                        terminator = next;
+                    }
+                    0x19 if catch_all.is_none() => {
+                        let (instructions, next) = decode_instructions(decoder)?;
+                        catch_all = Some(Expression { instructions });
+                        terminator = next;
+                    }
+                    0x05 => return Err(decoder.error(DecodeErrorKind::UnexpectedElse)),
+                    _ => return Err(decoder.error(DecodeErrorKind::UnexpectedCatch)),
+                }
+            }
+            ControlOp::Try {
+                typ,
+                body: Expression { instructions },
+                catches,
+                catch_all,
+            }
+        }
+        0x08 => ControlOp::Throw(decoder.leb128()?),
+        0x09 => ControlOp::Rethrow(decoder.leb128()?),
+        0x0C => ControlOp::Br(decoder.leb128()?),
+        0x0D => ControlOp::BrIf(decoder.leb128()?),
+        0x0E => ControlOp::BrTable {
+            labels: decoder.vec()?,
+            default: decoder.leb128()?,
+        },
+        0x0F => ControlOp::Return,
+        0x10 => ControlOp::Call(decoder.leb128()?),
+        0x11 => ControlOp::CallIndirect {
+            type_idx: decoder.leb128()?,
+            table: decoder.leb128()?,
+        },
+        _ => unreachable!("not a control opcode"),
+    };
+    Ok(op)
+}
+
+impl DecodesFromWasm for BlockType {
+    fn decode_from_wasm(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        match decoder.peek()? {
+            0x40 => {
+                decoder.byte()?;
+                Ok(BlockType::Empty)
+            }
+            0x7C..=0x7F => Ok(BlockType::Value(NumType::decode_from_wasm(decoder)?)),
+            byte => {
+                // Type indices are positive 33 bit integers
+                let offset = decoder.offset();
+                match decode_signed(&decoder.bytes[offset..decoder.end], 33) {
+                    Some((index, read)) if index >= 0 => {
+                        decoder.position += read;
+                        Ok(BlockType::Type(index as u32))
+                    }
+                    _ => Err(DecodeErrorKind::InvalidByte {
+                        what: "block type",
+                        byte,
+                    }
+                    .at(offset)),
+                }
+            }
+        }
+    }
+}
+
+impl DecodesFromWasm for MemArg {
+    fn decode_from_wasm(decoder: &mut Decoder) -> Result<Self, DecodeError> {
+        Ok(MemArg {
+            align: decoder.leb128()?,
+            offset: decoder.leb128()?,
+        })
+    }
+}
+
+// The index of the memory an instruction uses, which can only be 0 for now
+fn memory_index(decoder: &mut Decoder) -> Result<(), DecodeError> {
+    let offset = decoder.offset();
+    match decoder.byte()? {
+        0x00 => Ok(()),
+        byte => Err(DecodeErrorKind::InvalidByte {
+            what: "memory index",
+            byte,
+        }
+        .at(offset)),
+    }
+}
+
+fn decode_memory_op(opcode: u8, decoder: &mut Decoder) -> Result<MemoryOp, DecodeError> {
+    use MemoryOp::*;
+    let op: fn(MemArg) -> MemoryOp = match opcode {
+        0x28 => I32Load,
+        0x29 => I64Load,
+        0x2A => F32Load,
+        0x2B => F64Load,
+        0x2C => I32Load8S,
+        0x2D => I32Load8U,
+        0x2E => I32Load16S,
+        0x2F => I32Load16U,
+        0x30 => I64Load8S,
+        0x31 => I64Load8U,
+        0x32 => I64Load16S,
+        0x33 => I64Load16U,
+        0x34 => I64Load32S,
+        0x35 => I64Load32U,
+
+        0x36 => I32Store,
+        0x37 => I64Store,
+        0x38 => F32Store,
+        0x39 => F64Store,
+        0x3A => I32Store8,
+        0x3B => I32Store16,
+        0x3C => I64Store8,
+        0x3D => I64Store16,
+        0x3E => I64Store32,
+
+        0x3F => {
+            memory_index(decoder)?;
+            return Ok(MemorySize);
+        }
+        0x40 => {
+            memory_index(decoder)?;
+            return Ok(MemoryGrow);
+        }
+        _ => unreachable!("not a memory opcode"),
+    };
+    Ok(op(MemArg::decode_from_wasm(decoder)?))
+}
+
+fn decode_integer_op(opcode: u8) -> Option<IntegerOp> {
+    use IntegerOpType::*;
+    use IntegerType::*;
+    let (op, typ) = match opcode {
+        // Unary Ops
+        0x67 => (Clz, I32),
+        0x68 => (Ctz, I32),
+        0x69 => (Popcnt, I32),
+        0x79 => (Clz, I64),
+        0x7A => (Ctz, I64),
+        0x7B => (Popcnt, I64),
+
+        // Test Ops
+        0x45 => (Eqz, I32),
+        0x50 => (Eqz, I64),
+
+        // Relational Ops
+        0x46 => (Eq, I32),
+        0x47 => (Ne, I32),
+        0x48 => (LtS, I32),
+        0x49 => (LtU, I32),
+        0x4A => (GtS, I32),
+        0x4B => (GtU, I32),
+        0x4C => (LeS, I32),
+        0x4D => (LeU, I32),
+        0x4E => (GeS, I32),
+        0x4F => (GeU, I32),
+
+        0x51 => (Eq, I64),
+        0x52 => (Ne, I64),
+        0x53 => (LtS, I64),
+        0x54 => (LtU, I64),
+        0x55 => (GtS, I64),
+        0x56 => (GtU, I64),
+        0x57 => (LeS, I64),
+        0x58 => (LeU, I64),
+        0x59 => (GeS, I64),
+        0x5A => (GeU, I64),
+
+        // Binary Ops
+        0x6A => (Add, I32),
+        0x6B => (Sub, I32),
+        0x6C => (Mul, I32),
+        0x6D => (DivS, I32),
+        0x6E => (DivU, I32),
+        0x6F => (RemS, I32),
+        0x70 => (RemU, I32),
+        0x71 => (And, I32),
+        0x72 => (Or, I32),
+        0x73 => (Xor, I32),
+        0x74 => (Shl, I32),
+        0x75 => (ShrS, I32),
+        0x76 => (ShrU, I32),
+        0x77 => (Rotl, I32),
+        0x78 => (Rotr, I32),
+
+        0x7C => (Add, I64),
+        0x7D => (Sub, I64),
+        0x7E => (Mul, I64),
+        0x7F => (DivS, I64),
+        0x80 => (DivU, I64),
+        0x81 => (RemS, I64),
+        0x82 => (RemU, I64),
+        0x83 => (And, I64),
+        0x84 => (Or, I64),
+        0x85 => (Xor, I64),
+        0x86 => (Shl, I64),
+        0x87 => (ShrS, I64),
+        0x88 => (ShrU, I64),
+        0x89 => (Rotl, I64),
+        0x8A => (Rotr, I64),
+        _ => return None,
+    };
+    Some(IntegerOp { op, typ })
+}
+
+fn decode_float_op(opcode: u8) -> Option<FloatOp> {
+    use FloatOpType::*;
+    use FloatType::*;
+    let (op, typ) = match opcode {
+        // Unary Ops
+        0x8B => (Abs, F32),
+        0x8C => (Neg, F32),
+        0x91 => (Sqrt, F32),
+        0x8D => (Ceil, F32),
+        0x8E => (Floor, F32),
+        0x8F => (Trunc, F32),
+        0x90 => (Nearest, F32),
+
+        0x99 => (Abs, F64),
+        0x9A => (Neg, F64),
+        0x9F => (Sqrt, F64),
+        0x9B => (Ceil, F64),
+        0x9C => (Floor, F64),
+        0x9D => (Trunc, F64),
+        0x9E => (Nearest, F64),
+
+        // Binary Ops
+        0x92 => (Add, F32),
+        0x93 => (Sub, F32),
+        0x94 => (Mul, F32),
+        0x95 => (Div, F32),
+        0x96 => (Min, F32),
+        0x97 => (Max, F32),
+        0x98 => (Copysign, F32),
+
+        0xA0 => (Add, F64),
+        0xA1 => (Sub, F64),
+        0xA2 => (Mul, F64),
+        0xA3 => (Div, F64),
+        0xA4 => (Min, F64),
+        0xA5 => (Max, F64),
+        0xA6 => (Copysign, F64),
+
+        // Compare Ops
+        0x5B => (Eq, F32),
+        0x5C => (Ne, F32),
+        0x5D => (Lt, F32),
+        0x5F => (Le, F32),
+        0x60 => (Ge, F32),
+        0x5E => (Gt, F32),
+
+        0x61 => (Eq, F64),
+        0x62 => (Ne, F64),
+        0x63 => (Lt, F64),
+        0x65 => (Le, F64),
+        0x66 => (Ge, F64),
+        0x64 => (Gt, F64),
+        _ => return None,
+    };
+    Some(FloatOp { op, typ })
+}
+
+fn decode_convert_op(opcode: u8) -> Option<ConvertOp> {
+    use ConvertOp::*;
+    let op = match opcode {
+        0xC0 => I32Extend8S,
+        0xC1 => I32Extend16S,
+        0xC2 => I64Extend8S,
+        0xC3 => I64Extend16S,
+        0xC4 => I64Extend32S,
+
+        0xA7 => I32WrapI64,
+        0xAC => I64ExtendI32S,
+        0xAD => I64ExtendI32U,
+
+        0xA8 => I32TruncF32S,
+        0xA9 => I32TruncF32U,
+        0xAA => I32TruncF64S,
+        0xAB => I32TruncF64U,
+        0xAE => I64TruncF32S,
+        0xAF => I64TruncF32U,
+        0xB0 => I64TruncF64S,
+        0xB1 => I64TruncF64U,
+
+        0xB6 => F32DemoteF64,
+        0xBB => F64PromoteF32,
+
+        0xB2 => F32ConvertI32S,
+        0xB3 => F32ConvertI32U,
+        0xB4 => F32ConvertI64S,
+        0xB5 => F32ConvertI64U,
+        0xB7 => F64ConvertI32S,
+        0xB8 => F64ConvertI32U,
+        0xB9 => F64ConvertI64S,
+        0xBA => F64ConvertI64U,
+
+        0xBC => I32ReinterpretF32,
+        0xBD => I64ReinterpretF64,
+        0xBE => F32ReinterpretI32,
+        0xBF => F64ReinterpretI64,
+        _ => return None,
+    };
+    Some(op)
+}
+
+// The saturating truncations, which follow the 0xFC prefix
+fn decode_saturating_truncation(sub_opcode: u32) -> ConvertOp {
+    use ConvertOp::*;
+    match sub_opcode {
+        0 => I32TruncSatF32S,
+        1 => I32TruncSatF32U,
+        2 => I32TruncSatF64S,
+        3 => I32TruncSatF64U,
+        4 => I64TruncSatF32S,
+        5 => I64TruncSatF32U,
+        6 => I64TruncSatF64S,
+        7 => I64TruncSatF64U,
+        _ => unreachable!("not a saturating truncation"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wasm::{encoder::EncodesToWasm, wat::parser::parse_module};
+
+    // Encodes the module, decodes it, and checks encoding it again gives the same bytes
+    fn round_trip(source: &str) {
+        let module = parse_module(source).unwrap();
+        let mut bytes = Vec::new();
+        module.encode_to_wasm(&mut bytes);
+
+        let decoded = WasmModule::decode(&bytes).unwrap();
+        assert_eq!(decoded, module);
+        let mut again = Vec::new();
+        decoded.encode_to_wasm(&mut again);
+        assert_eq!(again, bytes);
+    }
+
+    #[test]
+    fn module_round_trip() {
+        round_trip(include_str!("../../tests/module.wat"));
+    }
+
+    #[test]
+    fn runtime_round_trip() {
+        round_trip(include_str!("../codegen/runtime.wat"));
+        round_trip(include_str!("../codegen/gc.wat"));
+    }
+
+    #[test]
+    fn names_round_trip() {
+        let mut module = parse_module(
+            "(module $m (func $first (param $a i32) (local $b i64)) (func) (func $third))",
+        )
+        .unwrap();
+        // Names the text format can't hold, eg. the same name twice or non-ASCII ones
+        module
+            .names
+            .locals
+            .push((1, vec![(0, "n".to_string()), (1, "n".to_string())]));
+        module.names.functions.push((1, "zweite_größe".to_string()));
+        module.names.functions.sort();
+        let names = module.names.clone();
+
+        let mut bytes = Vec::new();
+        module.encode_to_wasm(&mut bytes);
+        let decoded = WasmModule::decode(&bytes).unwrap();
+        assert_eq!(decoded.names, names);
+        assert_eq!(decoded.names.module.as_deref(), Some("m"));
+        assert_eq!(decoded.names.function(1), Some("zweite_größe"));
+        assert_eq!(decoded.names.local(1, 1), Some("n"));
+    }
+
+    #[test]
+    fn rejects_invalid_binaries() {
+        let error = WasmModule::decode(b"\0asn\x01\0\0\0").unwrap_err();
+        assert_eq!(error.kind, DecodeErrorKind::InvalidMagic);
+        let error = WasmModule::decode(b"\0asm\x01\0").unwrap_err();
+        assert_eq!(error.kind, DecodeErrorKind::UnexpectedEnd);
+    }
+}
@@ -21,20 +21,40 @@ impl EncodesToWasm for WasmModule {
                 .map(|f| f.type_idx)
                 .collect::<Vec<_>>(),
         );
+        WasmModule::encode_section(output, 0x04, &self.tables);
+        WasmModule::encode_section(output, 0x05, &self.memories);
+        WasmModule::encode_section(output, 0x0d, &self.tags);
+        WasmModule::encode_section(output, 0x06, &self.globals);
         WasmModule::encode_section(output, 0x07, &self.exports);
 
         if let Some(start) = self.start {
             output.push(0x08); // start section
-            output.push(0x01); // start section size
-            start.encode_to_leb128(output);
+            let mut section_bytes = Vec::new();
+            start.encode_to_leb128(&mut section_bytes);
+            section_bytes.len().encode_to_leb128(output);
+            output.extend(section_bytes);
         }
 
+        WasmModule::encode_section(output, 0x09, &self.elements);
         WasmModule::encode_section(output, 0x0a, &self.functions);
+        WasmModule::encode_section(output, 0x0b, &self.data);
+
+        if !self.names.is_empty() {
+            output.push(0x00); // custom section
+            let mut section_bytes = Vec::new();
+            "name".to_string().encode_to_wasm(&mut section_bytes);
+            self.names.encode_to_wasm(&mut section_bytes);
+            section_bytes.len().encode_to_leb128(output);
+            output.extend(section_bytes);
+        }
     }
 }
 
 impl WasmModule {
     fn encode_section<T: EncodesToWasm>(output: &mut Vec<u8>, code: u8, section: &[T]) {
+        if section.is_empty() {
+            return;
+        }
         output.push(code);
         let mut section_bytes = Vec::new();
         section.len().encode_to_leb128(&mut section_bytes);
@@ -52,6 +72,52 @@ impl EncodesToWasm for u32 {
     }
 }
 
+impl EncodesToWasm for String {
+    fn encode_to_wasm(&self, output: &mut Vec<u8>) {
+        self.len().encode_to_leb128(output);
+        output.extend(self.as_bytes());
+    }
+}
+
+// Entries of the maps in the name section
+impl<T: EncodesToWasm> EncodesToWasm for (u32, T) {
+    fn encode_to_wasm(&self, output: &mut Vec<u8>) {
+        self.0.encode_to_leb128(output);
+        self.1.encode_to_wasm(output);
+    }
+}
+
+impl<T: EncodesToWasm> EncodesToWasm for Vec<T> {
+    fn encode_to_wasm(&self, output: &mut Vec<u8>) {
+        self.len().encode_to_leb128(output);
+        for item in self {
+            item.encode_to_wasm(output);
+        }
+    }
+}
+
+impl EncodesToWasm for Names {
+    fn encode_to_wasm(&self, output: &mut Vec<u8>) {
+        // Each kind of name is a subsection, with an id and size like a section
+        let mut subsection = |id: u8, contents: &dyn EncodesToWasm| {
+            let mut bytes = Vec::new();
+            contents.encode_to_wasm(&mut bytes);
+            output.push(id);
+            bytes.len().encode_to_leb128(output);
+            output.extend(bytes);
+        };
+        if let Some(module) = &self.module {
+            subsection(0x00, module);
+        }
+        if !self.functions.is_empty() {
+            subsection(0x01, &self.functions);
+        }
+        if !self.locals.is_empty() {
+            subsection(0x02, &self.locals);
+        }
+    }
+}
+
 impl EncodesToWasm for FunctionType {
     fn encode_to_wasm(&self, output: &mut Vec<u8>) {
         output.push(0x60); // function type
@@ -94,14 +160,17 @@ impl EncodesToWasm for ImportType {
                 output.push(0x00); // func type magic number
                 index.encode_to_leb128(output);
             }
-            ImportType::Memory { min, max } => {
+            ImportType::Table(table) => {
+                output.push(0x01); // table type magic number
+                table.encode_to_wasm(output);
+            }
+            ImportType::Memory(limits) => {
                 output.push(0x02); // memory type magic number
-                let flags = if max.is_some() { 0x01 } else { 0x00 };
-                output.push(flags);
-                min.encode_to_leb128(output);
-                if let Some(max) = max {
-                    max.encode_to_leb128(output);
-                }
+                limits.encode_to_wasm(output);
+            }
+            ImportType::Global(global) => {
+                output.push(0x03); // global type magic number
+                global.encode_to_wasm(output);
             }
         }
     }
@@ -122,7 +191,106 @@ impl EncodesToWasm for ExportType {
                 output.push(0x00); // func type magic number
                 index.encode_to_leb128(output);
             }
+            ExportType::Table(index) => {
+                output.push(0x01); // table type magic number
+                index.encode_to_leb128(output);
+            }
+            ExportType::Memory(index) => {
+                output.push(0x02); // memory type magic number
+                index.encode_to_leb128(output);
+            }
+            ExportType::Global(index) => {
+                output.push(0x03); // global type magic number
+                index.encode_to_leb128(output);
+            }
+        }
+    }
+}
+
+impl EncodesToWasm for Limits {
+    fn encode_to_wasm(&self, output: &mut Vec<u8>) {
+        match self.max {
+            Some(max) => {
+                output.push(0x01);
+                self.min.encode_to_leb128(output);
+                max.encode_to_leb128(output);
+            }
+            None => {
+                output.push(0x00);
+                self.min.encode_to_leb128(output);
+            }
+        }
+    }
+}
+
+impl EncodesToWasm for RefType {
+    fn encode_to_wasm(&self, output: &mut Vec<u8>) {
+        output.push(match self {
+            RefType::FuncRef => 0x70,
+            RefType::ExternRef => 0x6F,
+        });
+    }
+}
+
+impl EncodesToWasm for TableType {
+    fn encode_to_wasm(&self, output: &mut Vec<u8>) {
+        self.element.encode_to_wasm(output);
+        self.limits.encode_to_wasm(output);
+    }
+}
+
+impl EncodesToWasm for Tag {
+    fn encode_to_wasm(&self, output: &mut Vec<u8>) {
+        output.push(0x00); // exception attribute
+        self.type_idx.encode_to_leb128(output);
+    }
+}
+
+impl EncodesToWasm for GlobalType {
+    fn encode_to_wasm(&self, output: &mut Vec<u8>) {
+        self.typ.encode_to_wasm(output);
+        output.push(if self.mutable { 0x01 } else { 0x00 });
+    }
+}
+
+impl EncodesToWasm for Global {
+    fn encode_to_wasm(&self, output: &mut Vec<u8>) {
+        self.typ.encode_to_wasm(output);
+        self.init.encode_to_wasm(output);
+    }
+}
+
+impl EncodesToWasm for Element {
+    fn encode_to_wasm(&self, output: &mut Vec<u8>) {
+        // Active segments of function indices, which have a shorter encoding for table 0
+        if self.table == 0 {
+            output.push(0x00);
+            self.offset.encode_to_wasm(output);
+        } else {
+            output.push(0x02);
+            self.table.encode_to_leb128(output);
+            self.offset.encode_to_wasm(output);
+            output.push(0x00); // elemkind funcref
+        }
+        self.functions.len().encode_to_leb128(output);
+        for function in self.functions.iter() {
+            function.encode_to_leb128(output);
+        }
+    }
+}
+
+impl EncodesToWasm for Data {
+    fn encode_to_wasm(&self, output: &mut Vec<u8>) {
+        // Active segments, which have a shorter encoding for memory 0
+        if self.memory == 0 {
+            output.push(0x00);
+        } else {
+            output.push(0x02);
+            self.memory.encode_to_leb128(output);
         }
+        self.offset.encode_to_wasm(output);
+        self.bytes.len().encode_to_leb128(output);
+        output.extend(self.bytes.iter());
     }
 }
 
@@ -174,7 +342,10 @@ impl EncodesToWasm for Instruction {
             Instruction::IntegerOp(op) => op.encode_to_wasm(output),
             Instruction::FloatOp(op) => op.encode_to_wasm(output),
             Instruction::ConvertOp(op) => op.encode_to_wasm(output),
+            Instruction::RefOp(op) => op.encode_to_wasm(output),
+            Instruction::ParametricOp(op) => op.encode_to_wasm(output),
             Instruction::VariableOp(op) => op.encode_to_wasm(output),
+            Instruction::MemoryOp(op) => op.encode_to_wasm(output),
             Instruction::ControlOp(op) => op.encode_to_wasm(output),
         }
     }
@@ -183,12 +354,136 @@ impl EncodesToWasm for Instruction {
 impl EncodesToWasm for ControlOp {
     fn encode_to_wasm(&self, output: &mut Vec<u8>) {
         match self {
+            ControlOp::Unreachable => output.push(0x00),
+            ControlOp::Nop => output.push(0x01),
+            ControlOp::Block { typ, body } => {
+                output.push(0x02);
+                typ.encode_to_wasm(output);
+                body.encode_to_wasm(output);
+            }
+            ControlOp::Loop { typ, body } => {
+                output.push(0x03);
+                typ.encode_to_wasm(output);
+                body.encode_to_wasm(output);
+            }
+            ControlOp::If {
+                typ,
+                then,
+                otherwise,
+            } => {
+                output.push(0x04);
+                typ.encode_to_wasm(output);
+                match otherwise {
+                    Some(otherwise) => {
+                        // The `else` takes the place of the `end` of the first branch
+                        for instruction in then.instructions.iter() {
+                            instruction.encode_to_wasm(output);
+                        }
+                        output.push(0x05);
+                        otherwise.encode_to_wasm(output);
+                    }
+                    None => then.encode_to_wasm(output),
+                }
+            }
+            ControlOp::Br(label) => {
+                output.push(0x0C);
+                label.encode_to_leb128(output);
+            }
+            ControlOp::BrIf(label) => {
+                output.push(0x0D);
+                label.encode_to_leb128(output);
+            }
+            ControlOp::BrTable { labels, default } => {
+                output.push(0x0E);
+                labels.len().encode_to_leb128(output);
+                for label in labels.iter() {
+                    label.encode_to_leb128(output);
+                }
+                default.encode_to_leb128(output);
+            }
+            ControlOp::Return => output.push(0x0F),
             ControlOp::Call(index) => {
                 output.push(0x10);
                 index.encode_to_leb128(output);
             }
-            ControlOp::Return => output.push(0x0F),
-            ControlOp::Nop => output.push(0x01),
+            ControlOp::CallIndirect { type_idx, table } => {
+                output.push(0x11);
+                type_idx.encode_to_leb128(output);
+                table.encode_to_leb128(output);
+            }
+            ControlOp::Try {
+                typ,
+                body,
+                catches,
+                catch_all,
+            } => {
+                output.push(0x06);
+                typ.encode_to_wasm(output);
+                // Each handler starts where the previous one ends, only the last is ended
+                for instruction in body.instructions.iter() {
+                    instruction.encode_to_wasm(output);
+                }
+                for catch in catches.iter() {
+                    output.push(0x07);
+                    catch.tag.encode_to_leb128(output);
+                    for instruction in catch.body.instructions.iter() {
+                        instruction.encode_to_wasm(output);
+                    }
+                }
+                if let Some(catch_all) = catch_all {
+                    output.push(0x19);
+                    for instruction in catch_all.instructions.iter() {
+                        instruction.encode_to_wasm(output);
+                    }
+                }
+                output.push(0x0b); // end
+            }
+            ControlOp::Throw(tag) => {
+                output.push(0x08);
+                tag.encode_to_leb128(output);
+            }
+            ControlOp::Rethrow(label) => {
+                output.push(0x09);
+                label.encode_to_leb128(output);
+            }
+        }
+    }
+}
+
+impl EncodesToWasm for BlockType {
+    fn encode_to_wasm(&self, output: &mut Vec<u8>) {
+        match self {
+            BlockType::Empty => output.push(0x40),
+            BlockType::Value(typ) => typ.encode_to_wasm(output),
+            // Type indices are signed, so they can't be confused with the value types
+            BlockType::Type(index) => {
+                (*index as i64).encode_to_leb128(output);
+            }
+        }
+    }
+}
+
+impl EncodesToWasm for RefOp {
+    fn encode_to_wasm(&self, output: &mut Vec<u8>) {
+        match self {
+            RefOp::Null(typ) => {
+                output.push(0xD0);
+                typ.encode_to_wasm(output);
+            }
+            RefOp::IsNull => output.push(0xD1),
+            RefOp::Func(index) => {
+                output.push(0xD2);
+                index.encode_to_leb128(output);
+            }
+        }
+    }
+}
+
+impl EncodesToWasm for ParametricOp {
+    fn encode_to_wasm(&self, output: &mut Vec<u8>) {
+        match self {
+            ParametricOp::Drop => output.push(0x1A),
+            ParametricOp::Select => output.push(0x1B),
         }
     }
 }
@@ -207,6 +502,61 @@ impl EncodesToWasm for VariableOp {
     }
 }
 
+impl EncodesToWasm for MemoryOp {
+    fn encode_to_wasm(&self, output: &mut Vec<u8>) {
+        use MemoryOp::*;
+        let (opcode, arg) = match self {
+            I32Load(arg) => (0x28, arg),
+            I64Load(arg) => (0x29, arg),
+            F32Load(arg) => (0x2A, arg),
+            F64Load(arg) => (0x2B, arg),
+            I32Load8S(arg) => (0x2C, arg),
+            I32Load8U(arg) => (0x2D, arg),
+            I32Load16S(arg) => (0x2E, arg),
+            I32Load16U(arg) => (0x2F, arg),
+            I64Load8S(arg) => (0x30, arg),
+            I64Load8U(arg) => (0x31, arg),
+            I64Load16S(arg) => (0x32, arg),
+            I64Load16U(arg) => (0x33, arg),
+            I64Load32S(arg) => (0x34, arg),
+            I64Load32U(arg) => (0x35, arg),
+
+            I32Store(arg) => (0x36, arg),
+            I64Store(arg) => (0x37, arg),
+            F32Store(arg) => (0x38, arg),
+            F64Store(arg) => (0x39, arg),
+            I32Store8(arg) => (0x3A, arg),
+            I32Store16(arg) => (0x3B, arg),
+            I64Store8(arg) => (0x3C, arg),
+            I64Store16(arg) => (0x3D, arg),
+            I64Store32(arg) => (0x3E, arg),
+
+            // The trailing zeros are the index of the memory, which is always 0 for now
+            MemorySize => return output.extend([0x3F, 0x00]),
+            MemoryGrow => return output.extend([0x40, 0x00]),
+            MemoryCopy => {
+                output.push(0xFC);
+                10u32.encode_to_leb128(output);
+                return output.extend([0x00, 0x00]);
+            }
+            MemoryFill => {
+                output.push(0xFC);
+                11u32.encode_to_leb128(output);
+                return output.push(0x00);
+            }
+        };
+        output.push(opcode);
+        arg.encode_to_wasm(output);
+    }
+}
+
+impl EncodesToWasm for MemArg {
+    fn encode_to_wasm(&self, output: &mut Vec<u8>) {
+        self.align.encode_to_leb128(output);
+        self.offset.encode_to_leb128(output);
+    }
+}
+
 impl EncodesToWasm for IntegerOp {
     fn encode_to_wasm(&self, output: &mut Vec<u8>) {
         use IntegerOpType::*;
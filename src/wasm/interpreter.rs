@@ -0,0 +1,927 @@
+use std::{collections::HashMap, fmt};
+
+use super::validator::ValidationError;
+use super::*;
+
+const MAX_CALL_DEPTH: usize = 10_000;
+// Each wasm call nests a few Rust frames per block, several KiB without optimizations, so calls
+// from the host run on a thread with a stack big enough for `MAX_CALL_DEPTH` of them
+const STACK_SIZE: usize = 256 << 20;
+
+const PAGE_SIZE: usize = 1 << 16;
+const MAX_PAGES: u32 = 1 << 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    I32(i32),
+    I64(i64),
+    F32(f32),
+    F64(f64),
+}
+
+impl Value {
+    /// The zero value locals of `typ` start out as.
+    pub fn default_of(typ: NumType) -> Value {
+        match typ {
+            NumType::I32 => Value::I32(0),
+            NumType::I64 => Value::I64(0),
+            NumType::F32 => Value::F32(0.0),
+            NumType::F64 => Value::F64(0.0),
+        }
+    }
+
+    pub fn typ(&self) -> NumType {
+        match self {
+            Value::I32(_) => NumType::I32,
+            Value::I64(_) => NumType::I64,
+            Value::F32(_) => NumType::F32,
+            Value::F64(_) => NumType::F64,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::I32(val) => write!(f, "{}", val),
+            Value::I64(val) => write!(f, "{}", val),
+            Value::F32(val) => write!(f, "{:?}", val),
+            Value::F64(val) => write!(f, "{:?}", val),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeError {
+    Invalid(Vec<ValidationError>),
+    MissingImport {
+        module: String,
+        name: String,
+    },
+    // Valid wasm the interpreter can't run, eg. imported memories
+    Unsupported(&'static str),
+    UnknownExport(String),
+    // The arguments to an exported function, or the results of a host function, have the wrong types
+    WrongTypes {
+        expected: Vec<NumType>,
+        found: Vec<NumType>,
+    },
+    // Raised by a host function
+    Host(String),
+    // Thrown by `throw`, which is only an error once it leaves the module uncaught
+    Exception {
+        tag: u32,
+        values: Vec<Value>,
+    },
+
+    // Traps, which stop the program while it's running
+    Unreachable,
+    DivideByZero,
+    IntegerOverflow,
+    InvalidConversion,
+    OutOfBoundsMemory,
+    OutOfBoundsTable,
+    UninitializedElement,
+    IndirectCallTypeMismatch,
+    CallStackExhausted,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuntimeError::Invalid(errors) => match errors.as_slice() {
+                [error] => write!(f, "invalid module: {}", error),
+                _ => write!(
+                    f,
+                    "invalid module: {} errors, the first {}",
+                    errors.len(),
+                    errors[0]
+                ),
+            },
+            RuntimeError::MissingImport { module, name } => {
+                write!(f, "missing import `{}.{}`", module, name)
+            }
+            RuntimeError::Unsupported(what) => write!(f, "{} are not supported", what),
+            RuntimeError::UnknownExport(name) => write!(f, "no function exported as `{}`", name),
+            RuntimeError::WrongTypes { expected, found } => {
+                let list = |types: &[NumType]| {
+                    types
+                        .iter()
+                        .map(|typ| typ.to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                };
+                write!(
+                    f,
+                    "expected values of type [{}], found [{}]",
+                    list(expected),
+                    list(found)
+                )
+            }
+            RuntimeError::Host(message) => write!(f, "{}", message),
+            RuntimeError::Exception { tag, .. } => {
+                write!(f, "uncaught exception with tag {}", tag)
+            }
+            RuntimeError::Unreachable => write!(f, "unreachable executed"),
+            RuntimeError::DivideByZero => write!(f, "integer divide by zero"),
+            RuntimeError::IntegerOverflow => write!(f, "integer overflow"),
+            RuntimeError::InvalidConversion => write!(f, "invalid conversion to integer"),
+            RuntimeError::OutOfBoundsMemory => write!(f, "out of bounds memory access"),
+            RuntimeError::OutOfBoundsTable => write!(f, "out of bounds table access"),
+            RuntimeError::UninitializedElement => write!(f, "uninitialized table element"),
+            RuntimeError::IndirectCallTypeMismatch => write!(f, "indirect call type mismatch"),
+            RuntimeError::CallStackExhausted => write!(f, "call stack exhausted"),
+        }
+    }
+}
+
+/// A function provided by the host, which can read and write the instance's memory.
+pub type HostFunction =
+    Box<dyn FnMut(&[Value], &mut [u8]) -> Result<Vec<Value>, RuntimeError> + Send>;
+
+/// The functions and globals a module can import, by module and name.
+#[derive(Default)]
+pub struct Imports {
+    functions: HashMap<(String, String), HostFunction>,
+    globals: HashMap<(String, String), Value>,
+}
+
+impl Imports {
+    pub fn with_function(
+        mut self,
+        module: &str,
+        name: &str,
+        function: impl FnMut(&[Value], &mut [u8]) -> Result<Vec<Value>, RuntimeError> + Send + 'static,
+    ) -> Self {
+        (self.functions).insert((module.to_string(), name.to_string()), Box::new(function));
+        self
+    }
+
+    pub fn with_global(mut self, module: &str, name: &str, value: Value) -> Self {
+        (self.globals).insert((module.to_string(), name.to_string()), value);
+        self
+    }
+}
+
+enum Callable {
+    // The index into the instance's host functions, along with the type of the import
+    Host(usize, u32),
+    Defined(usize),
+}
+
+/// A module ready to run, with its own memory, globals and tables.
+pub struct Instance<'a> {
+    module: &'a WasmModule,
+    functions: Vec<Callable>,
+    host: Vec<HostFunction>,
+    memory: Vec<u8>,
+    max_pages: u32,
+    globals: Vec<Value>,
+    tables: Vec<Vec<Option<u32>>>,
+    depth: usize,
+}
+
+// Where to go after running a sequence of instructions
+enum Flow {
+    Continue,
+    // Out of the given number of enclosing blocks, 0 being the innermost
+    Branch(u32),
+    // Out to the `catch` the given number of blocks out, throwing its exception again
+    Rethrow(u32),
+    Return,
+}
+
+/// The locals and operand stack of a function call.
+struct Frame {
+    locals: Vec<Value>,
+    stack: Vec<Value>,
+}
+
+impl Frame {
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("validated")
+    }
+
+    fn pop_i32(&mut self) -> i32 {
+        match self.pop() {
+            Value::I32(val) => val,
+            _ => unreachable!("validated"),
+        }
+    }
+
+    // Moves the top `count` values down to `height`, dropping everything in between
+    fn unwind(&mut self, height: usize, count: usize) {
+        let top = self.stack.len() - count;
+        self.stack.drain(height..top);
+    }
+}
+
+impl<'a> Instance<'a> {
+    /// Validates `module`, links its imports and runs its initializers and start function.
+    pub fn new(module: &'a WasmModule, mut imports: Imports) -> Result<Self, RuntimeError> {
+        module.validate().map_err(RuntimeError::Invalid)?;
+        let mut instance = Instance {
+            module,
+            functions: Vec::new(),
+            host: Vec::new(),
+            memory: Vec::new(),
+            max_pages: MAX_PAGES,
+            globals: Vec::new(),
+            tables: Vec::new(),
+            depth: 0,
+        };
+
+        let mut linked = HashMap::new();
+        for import in module.imports.iter() {
+            let key = (import.module.clone(), import.name.clone());
+            let missing = || RuntimeError::MissingImport {
+                module: import.module.clone(),
+                name: import.name.clone(),
+            };
+            match &import.typ {
+                ImportType::Func(typ) => {
+                    // The same function can be imported more than once
+                    let host = match linked.get(&key) {
+                        Some(host) => *host,
+                        None => {
+                            let function = imports.functions.remove(&key).ok_or_else(missing)?;
+                            instance.host.push(function);
+                            linked.insert(key, instance.host.len() - 1);
+                            instance.host.len() - 1
+                        }
+                    };
+                    instance.functions.push(Callable::Host(host, *typ));
+                }
+                ImportType::Global(typ) => {
+                    let value = *imports.globals.get(&key).ok_or_else(missing)?;
+                    check_types(&[typ.typ], &[value])?;
+                    instance.globals.push(value);
+                }
+                ImportType::Table(_) => return Err(RuntimeError::Unsupported("imported tables")),
+                ImportType::Memory(_) => {
+                    return Err(RuntimeError::Unsupported("imported memories"))
+                }
+            }
+        }
+        instance
+            .functions
+            .extend((0..module.functions.len()).map(Callable::Defined));
+
+        if let Some(limits) = module.memories.first() {
+            instance.memory = vec![0; limits.min as usize * PAGE_SIZE];
+            instance.max_pages = limits.max.unwrap_or(MAX_PAGES);
+        }
+        for global in module.globals.iter() {
+            let value = instance.constant(&global.init)?;
+            instance.globals.push(value);
+        }
+        for table in module.tables.iter() {
+            instance.tables.push(vec![None; table.limits.min as usize]);
+        }
+
+        for element in module.elements.iter() {
+            let offset = instance.constant_offset(&element.offset)?;
+            let table = &mut instance.tables[element.table as usize];
+            let slots = table
+                .get_mut(offset..offset + element.functions.len())
+                .ok_or(RuntimeError::OutOfBoundsTable)?;
+            for (slot, function) in slots.iter_mut().zip(element.functions.iter()) {
+                *slot = Some(*function);
+            }
+        }
+        for data in module.data.iter() {
+            let offset = instance.constant_offset(&data.offset)?;
+            let bytes = (instance.memory)
+                .get_mut(offset..offset + data.bytes.len())
+                .ok_or(RuntimeError::OutOfBoundsMemory)?;
+            bytes.copy_from_slice(&data.bytes);
+        }
+
+        if let Some(start) = module.start {
+            instance.call_from_host(start, &[])?;
+        }
+        Ok(instance)
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+
+    fn export(&self, name: &str) -> Result<u32, RuntimeError> {
+        (self.module.exports.iter())
+            .find_map(|export| match export.typ {
+                ExportType::Func(index) if export.name == name => Some(index),
+                _ => None,
+            })
+            .ok_or_else(|| RuntimeError::UnknownExport(name.to_string()))
+    }
+
+    /// The type of the function exported as `name`.
+    pub fn export_type(&self, name: &str) -> Result<&'a FunctionType, RuntimeError> {
+        Ok(self.function_type(self.export(name)?))
+    }
+
+    /// Calls the function exported as `name`.
+    pub fn call(&mut self, name: &str, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+        let index = self.export(name)?;
+        check_types(&self.function_type(index).args, args)?;
+        self.call_from_host(index, args)
+    }
+
+    fn call_from_host(&mut self, index: u32, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+        std::thread::scope(|scope| {
+            let thread = std::thread::Builder::new()
+                .stack_size(STACK_SIZE)
+                .spawn_scoped(scope, || self.call_index(index, args))
+                .expect("could not start the interpreter thread");
+            thread
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+        })
+    }
+
+    fn function_type(&self, index: u32) -> &'a FunctionType {
+        let type_idx = match self.functions[index as usize] {
+            Callable::Host(_, typ) => typ,
+            Callable::Defined(i) => self.module.functions[i].type_idx,
+        };
+        &self.module.types[type_idx as usize]
+    }
+
+    fn call_index(&mut self, index: u32, args: &[Value]) -> Result<Vec<Value>, RuntimeError> {
+        let typ = self.function_type(index);
+        match self.functions[index as usize] {
+            Callable::Host(host, _) => {
+                let results = (self.host[host])(args, &mut self.memory)?;
+                check_types(&typ.ret, &results)?;
+                Ok(results)
+            }
+            Callable::Defined(i) => {
+                if self.depth == MAX_CALL_DEPTH {
+                    return Err(RuntimeError::CallStackExhausted);
+                }
+                let function = &self.module.functions[i];
+                let mut frame = Frame {
+                    locals: args.to_vec(),
+                    stack: Vec::new(),
+                };
+                frame
+                    .locals
+                    .extend(function.locals.iter().map(|typ| Value::default_of(*typ)));
+
+                self.depth += 1;
+                let flow = self.run(&function.body.instructions, &mut frame);
+                self.depth -= 1;
+                flow?;
+                let results = frame.stack.split_off(frame.stack.len() - typ.ret.len());
+                Ok(results)
+            }
+        }
+    }
+
+    fn constant(&mut self, expression: &Expression) -> Result<Value, RuntimeError> {
+        let mut frame = Frame {
+            locals: Vec::new(),
+            stack: Vec::new(),
+        };
+        self.run(&expression.instructions, &mut frame)?;
+        Ok(frame.pop())
+    }
+
+    fn constant_offset(&mut self, expression: &Expression) -> Result<usize, RuntimeError> {
+        match self.constant(expression)? {
+            Value::I32(offset) => Ok(offset as u32 as usize),
+            _ => unreachable!("validated"),
+        }
+    }
+
+    fn block_arity(&self, typ: &BlockType) -> (usize, usize) {
+        match typ {
+            BlockType::Empty => (0, 0),
+            BlockType::Value(_) => (0, 1),
+            BlockType::Type(index) => {
+                let typ = &self.module.types[*index as usize];
+                (typ.args.len(), typ.ret.len())
+            }
+        }
+    }
+
+    fn run(
+        &mut self,
+        instructions: &[Instruction],
+        frame: &mut Frame,
+    ) -> Result<Flow, RuntimeError> {
+        for instruction in instructions {
+            let Instruction::ControlOp(op) = instruction else {
+                self.instruction(instruction, frame)?;
+                continue;
+            };
+            match self.control(op, frame)? {
+                Flow::Continue => {}
+                flow => return Ok(flow),
+            }
+        }
+        Ok(Flow::Continue)
+    }
+
+    // Runs anything but control flow, kept apart from `run` to keep the stack small when calls and
+    // blocks recurse
+    fn instruction(
+        &mut self,
+        instruction: &Instruction,
+        frame: &mut Frame,
+    ) -> Result<(), RuntimeError> {
+        match instruction {
+            Instruction::I32Const(val) => frame.stack.push(Value::I32(*val)),
+            Instruction::I64Const(val) => frame.stack.push(Value::I64(*val)),
+            Instruction::F32Const(val) => frame.stack.push(Value::F32(*val)),
+            Instruction::F64Const(val) => frame.stack.push(Value::F64(*val)),
+            Instruction::IntegerOp(op) => integer_op(op, frame)?,
+            Instruction::FloatOp(op) => float_op(op, frame),
+            Instruction::ConvertOp(op) => {
+                let result = convert(op, frame.pop())?;
+                frame.stack.push(result);
+            }
+            // Rejected by the validator
+            Instruction::RefOp(_) => unreachable!("reference instructions are not supported"),
+            Instruction::ParametricOp(ParametricOp::Drop) => {
+                frame.pop();
+            }
+            Instruction::ParametricOp(ParametricOp::Select) => {
+                let condition = frame.pop_i32();
+                let second = frame.pop();
+                let first = frame.pop();
+                frame
+                    .stack
+                    .push(if condition != 0 { first } else { second });
+            }
+            Instruction::VariableOp(op) => match op {
+                VariableOp::LocalGet(index) => frame.stack.push(frame.locals[*index as usize]),
+                VariableOp::LocalSet(index) => frame.locals[*index as usize] = frame.pop(),
+                VariableOp::LocalTee(index) => {
+                    frame.locals[*index as usize] = *frame.stack.last().expect("validated")
+                }
+                VariableOp::GlobalGet(index) => frame.stack.push(self.globals[*index as usize]),
+                VariableOp::GlobalSet(index) => self.globals[*index as usize] = frame.pop(),
+            },
+            Instruction::MemoryOp(op) => self.memory_op(op, frame)?,
+            Instruction::ControlOp(_) => unreachable!("run by `control`"),
+        }
+        Ok(())
+    }
+
+    fn control(&mut self, op: &ControlOp, frame: &mut Frame) -> Result<Flow, RuntimeError> {
+        match op {
+            ControlOp::Unreachable => Err(RuntimeError::Unreachable),
+            ControlOp::Nop => Ok(Flow::Continue),
+            ControlOp::Block { typ, body } => {
+                let (params, results) = self.block_arity(typ);
+                let height = frame.stack.len() - params;
+                self.block(&body.instructions, frame, height, results)
+            }
+            ControlOp::Loop { typ, body } => {
+                let (params, _) = self.block_arity(typ);
+                let height = frame.stack.len() - params;
+                loop {
+                    match self.run(&body.instructions, frame)? {
+                        // Branching to a loop starts it again with new params
+                        Flow::Branch(0) => frame.unwind(height, params),
+                        Flow::Branch(depth) => return Ok(Flow::Branch(depth - 1)),
+                        Flow::Rethrow(depth) => return Ok(Flow::Rethrow(depth - 1)),
+                        flow => return Ok(flow),
+                    }
+                }
+            }
+            ControlOp::If {
+                typ,
+                then,
+                otherwise,
+            } => {
+                let condition = frame.pop_i32();
+                let (params, results) = self.block_arity(typ);
+                let height = frame.stack.len() - params;
+                match (condition != 0, otherwise) {
+                    (true, _) => self.block(&then.instructions, frame, height, results),
+                    (false, Some(otherwise)) => {
+                        self.block(&otherwise.instructions, frame, height, results)
+                    }
+                    (false, None) => Ok(Flow::Continue),
+                }
+            }
+            ControlOp::Br(depth) => Ok(Flow::Branch(*depth)),
+            ControlOp::BrIf(depth) => match frame.pop_i32() {
+                0 => Ok(Flow::Continue),
+                _ => Ok(Flow::Branch(*depth)),
+            },
+            ControlOp::BrTable { labels, default } => {
+                let index = frame.pop_i32() as u32 as usize;
+                Ok(Flow::Branch(*labels.get(index).unwrap_or(default)))
+            }
+            ControlOp::Return => Ok(Flow::Return),
+            ControlOp::Call(index) => {
+                let args = self.function_type(*index).args.len();
+                let args = frame.stack.split_off(frame.stack.len() - args);
+                let results = self.call_index(*index, &args)?;
+                frame.stack.extend(results);
+                Ok(Flow::Continue)
+            }
+            ControlOp::CallIndirect { type_idx, table } => {
+                let slot = frame.pop_i32() as u32 as usize;
+                let index = self.tables[*table as usize]
+                    .get(slot)
+                    .ok_or(RuntimeError::OutOfBoundsTable)?
+                    .ok_or(RuntimeError::UninitializedElement)?;
+                // Types are compared by their params and results, not their index
+                let typ = &self.module.types[*type_idx as usize];
+                if self.function_type(index) != typ {
+                    return Err(RuntimeError::IndirectCallTypeMismatch);
+                }
+                let args = frame.stack.split_off(frame.stack.len() - typ.args.len());
+                let results = self.call_index(index, &args)?;
+                frame.stack.extend(results);
+                Ok(Flow::Continue)
+            }
+            ControlOp::Try {
+                typ,
+                body,
+                catches,
+                catch_all,
+            } => {
+                let (params, results) = self.block_arity(typ);
+                let height = frame.stack.len() - params;
+                let (tag, values) = match self.block(&body.instructions, frame, height, results) {
+                    Err(RuntimeError::Exception { tag, values }) => (tag, values),
+                    flow => return flow,
+                };
+                frame.stack.truncate(height);
+                let handler = match catches.iter().find(|catch| catch.tag == tag) {
+                    Some(catch) => {
+                        frame.stack.extend(values.iter().copied());
+                        &catch.body
+                    }
+                    None => match catch_all {
+                        Some(catch_all) => catch_all,
+                        None => return Err(RuntimeError::Exception { tag, values }),
+                    },
+                };
+                match self.run(&handler.instructions, frame)? {
+                    Flow::Rethrow(0) => Err(RuntimeError::Exception { tag, values }),
+                    Flow::Rethrow(depth) => Ok(Flow::Rethrow(depth - 1)),
+                    Flow::Branch(0) => {
+                        frame.unwind(height, results);
+                        Ok(Flow::Continue)
+                    }
+                    Flow::Branch(depth) => Ok(Flow::Branch(depth - 1)),
+                    flow => Ok(flow),
+                }
+            }
+            ControlOp::Throw(tag) => {
+                let tag_type = self.module.tags[*tag as usize].type_idx;
+                let count = self.module.types[tag_type as usize].args.len();
+                let values = frame.stack.split_off(frame.stack.len() - count);
+                Err(RuntimeError::Exception { tag: *tag, values })
+            }
+            ControlOp::Rethrow(depth) => Ok(Flow::Rethrow(*depth)),
+        }
+    }
+
+    // Runs a block or branch of an `if`, which branches leave with `results` values
+    fn block(
+        &mut self,
+        instructions: &[Instruction],
+        frame: &mut Frame,
+        height: usize,
+        results: usize,
+    ) -> Result<Flow, RuntimeError> {
+        match self.run(instructions, frame)? {
+            Flow::Branch(0) => {
+                frame.unwind(height, results);
+                Ok(Flow::Continue)
+            }
+            Flow::Branch(depth) => Ok(Flow::Branch(depth - 1)),
+            Flow::Rethrow(depth) => Ok(Flow::Rethrow(depth - 1)),
+            flow => Ok(flow),
+        }
+    }
+
+    // The range of memory a load or store of `size` bytes at `address` covers
+    fn address(
+        &self,
+        address: i32,
+        arg: MemArg,
+        size: u32,
+    ) -> Result<std::ops::Range<usize>, RuntimeError> {
+        let start = address as u32 as u64 + arg.offset as u64;
+        let end = start + size as u64;
+        if end > self.memory.len() as u64 {
+            return Err(RuntimeError::OutOfBoundsMemory);
+        }
+        Ok(start as usize..end as usize)
+    }
+
+    fn memory_op(&mut self, op: &MemoryOp, frame: &mut Frame) -> Result<(), RuntimeError> {
+        if let (Some((_, size, store)), Some(arg)) = (op.access(), op.memarg()) {
+            if store {
+                let value = frame.pop();
+                let range = self.address(frame.pop_i32(), arg, size)?;
+                let bytes = match value {
+                    Value::I32(val) => val.to_le_bytes().to_vec(),
+                    Value::I64(val) => val.to_le_bytes().to_vec(),
+                    Value::F32(val) => val.to_le_bytes().to_vec(),
+                    Value::F64(val) => val.to_le_bytes().to_vec(),
+                };
+                // Narrow stores keep the low bytes
+                self.memory[range].copy_from_slice(&bytes[..size as usize]);
+            } else {
+                let range = self.address(frame.pop_i32(), arg, size)?;
+                let mut bytes = [0; 8];
+                bytes[..size as usize].copy_from_slice(&self.memory[range]);
+                frame.stack.push(load(op, bytes));
+            }
+            return Ok(());
+        }
+
+        let pages = (self.memory.len() / PAGE_SIZE) as u32;
+        match op {
+            MemoryOp::MemorySize => frame.stack.push(Value::I32(pages as i32)),
+            MemoryOp::MemoryGrow => {
+                let delta = frame.pop_i32() as u32;
+                match pages
+                    .checked_add(delta)
+                    .filter(|new| *new <= self.max_pages)
+                {
+                    Some(new) => {
+                        self.memory.resize(new as usize * PAGE_SIZE, 0);
+                        frame.stack.push(Value::I32(pages as i32));
+                    }
+                    None => frame.stack.push(Value::I32(-1)),
+                }
+            }
+            MemoryOp::MemoryCopy => {
+                let len = frame.pop_i32() as u32 as usize;
+                let source = frame.pop_i32() as u32 as usize;
+                let destination = frame.pop_i32() as u32 as usize;
+                if source + len > self.memory.len() || destination + len > self.memory.len() {
+                    return Err(RuntimeError::OutOfBoundsMemory);
+                }
+                self.memory.copy_within(source..source + len, destination);
+            }
+            MemoryOp::MemoryFill => {
+                let len = frame.pop_i32() as u32 as usize;
+                let value = frame.pop_i32() as u8;
+                let destination = frame.pop_i32() as u32 as usize;
+                let bytes = (self.memory)
+                    .get_mut(destination..destination + len)
+                    .ok_or(RuntimeError::OutOfBoundsMemory)?;
+                bytes.fill(value);
+            }
+            _ => unreachable!("loads and stores are handled above"),
+        }
+        Ok(())
+    }
+}
+
+fn check_types(expected: &[NumType], values: &[Value]) -> Result<(), RuntimeError> {
+    let found = values.iter().map(Value::typ).collect::<Vec<_>>();
+    if found != expected {
+        return Err(RuntimeError::WrongTypes {
+            expected: expected.to_vec(),
+            found,
+        });
+    }
+    Ok(())
+}
+
+// The value a load reads from little endian `bytes`, of which it uses as many as it accesses
+fn load(op: &MemoryOp, bytes: [u8; 8]) -> Value {
+    let [b0, b1, b2, b3, ..] = bytes;
+    let word = [b0, b1, b2, b3];
+    use MemoryOp::*;
+    match op {
+        I32Load(_) => Value::I32(i32::from_le_bytes(word)),
+        I64Load(_) => Value::I64(i64::from_le_bytes(bytes)),
+        F32Load(_) => Value::F32(f32::from_le_bytes(word)),
+        F64Load(_) => Value::F64(f64::from_le_bytes(bytes)),
+        I32Load8S(_) => Value::I32(b0 as i8 as i32),
+        I32Load8U(_) => Value::I32(b0 as i32),
+        I32Load16S(_) => Value::I32(i16::from_le_bytes([b0, b1]) as i32),
+        I32Load16U(_) => Value::I32(u16::from_le_bytes([b0, b1]) as i32),
+        I64Load8S(_) => Value::I64(b0 as i8 as i64),
+        I64Load8U(_) => Value::I64(b0 as i64),
+        I64Load16S(_) => Value::I64(i16::from_le_bytes([b0, b1]) as i64),
+        I64Load16U(_) => Value::I64(u16::from_le_bytes([b0, b1]) as i64),
+        I64Load32S(_) => Value::I64(i32::from_le_bytes(word) as i64),
+        I64Load32U(_) => Value::I64(u32::from_le_bytes(word) as i64),
+        _ => unreachable!("only called for loads"),
+    }
+}
+
+// Generates the same operations for i32 and i64, with their unsigned counterparts
+macro_rules! integer_ops {
+    ($op:expr, $frame:expr, $variant:ident, $signed:ty, $unsigned:ty) => {{
+        let pop = |frame: &mut Frame| match frame.pop() {
+            Value::$variant(val) => val,
+            _ => unreachable!("validated"),
+        };
+        let bits = <$signed>::BITS;
+        let result = match $op {
+            IntegerOpType::Clz => Value::$variant(pop($frame).leading_zeros() as $signed),
+            IntegerOpType::Ctz => Value::$variant(pop($frame).trailing_zeros() as $signed),
+            IntegerOpType::Popcnt => Value::$variant(pop($frame).count_ones() as $signed),
+            IntegerOpType::Eqz => Value::I32((pop($frame) == 0) as i32),
+            op => {
+                let b = pop($frame);
+                let a = pop($frame);
+                let (ua, ub) = (a as $unsigned, b as $unsigned);
+                let compare = |result: bool| Value::I32(result as i32);
+                match op {
+                    IntegerOpType::Eq => compare(a == b),
+                    IntegerOpType::Ne => compare(a != b),
+                    IntegerOpType::LtS => compare(a < b),
+                    IntegerOpType::LtU => compare(ua < ub),
+                    IntegerOpType::GtS => compare(a > b),
+                    IntegerOpType::GtU => compare(ua > ub),
+                    IntegerOpType::LeS => compare(a <= b),
+                    IntegerOpType::LeU => compare(ua <= ub),
+                    IntegerOpType::GeS => compare(a >= b),
+                    IntegerOpType::GeU => compare(ua >= ub),
+                    IntegerOpType::Add => Value::$variant(a.wrapping_add(b)),
+                    IntegerOpType::Sub => Value::$variant(a.wrapping_sub(b)),
+                    IntegerOpType::Mul => Value::$variant(a.wrapping_mul(b)),
+                    IntegerOpType::DivS | IntegerOpType::RemS if b == 0 => {
+                        return Err(RuntimeError::DivideByZero)
+                    }
+                    IntegerOpType::DivU | IntegerOpType::RemU if b == 0 => {
+                        return Err(RuntimeError::DivideByZero)
+                    }
+                    IntegerOpType::DivS if a == <$signed>::MIN && b == -1 => {
+                        return Err(RuntimeError::IntegerOverflow)
+                    }
+                    IntegerOpType::DivS => Value::$variant(a / b),
+                    IntegerOpType::DivU => Value::$variant((ua / ub) as $signed),
+                    IntegerOpType::RemS => Value::$variant(a.wrapping_rem(b)),
+                    IntegerOpType::RemU => Value::$variant((ua % ub) as $signed),
+                    IntegerOpType::And => Value::$variant(a & b),
+                    IntegerOpType::Or => Value::$variant(a | b),
+                    IntegerOpType::Xor => Value::$variant(a ^ b),
+                    // Shift amounts are taken modulo the width
+                    IntegerOpType::Shl => Value::$variant(a.wrapping_shl(ub as u32 % bits)),
+                    IntegerOpType::ShrS => Value::$variant(a.wrapping_shr(ub as u32 % bits)),
+                    IntegerOpType::ShrU => {
+                        Value::$variant(ua.wrapping_shr(ub as u32 % bits) as $signed)
+                    }
+                    IntegerOpType::Rotl => {
+                        Value::$variant(a.rotate_left((ub % bits as $unsigned) as u32))
+                    }
+                    IntegerOpType::Rotr => {
+                        Value::$variant(a.rotate_right((ub % bits as $unsigned) as u32))
+                    }
+                    IntegerOpType::Clz
+                    | IntegerOpType::Ctz
+                    | IntegerOpType::Popcnt
+                    | IntegerOpType::Eqz => unreachable!("unary ops are handled above"),
+                }
+            }
+        };
+        $frame.stack.push(result);
+    }};
+}
+
+fn integer_op(op: &IntegerOp, frame: &mut Frame) -> Result<(), RuntimeError> {
+    match op.typ {
+        IntegerType::I32 => integer_ops!(&op.op, frame, I32, i32, u32),
+        IntegerType::I64 => integer_ops!(&op.op, frame, I64, i64, u64),
+    }
+    Ok(())
+}
+
+macro_rules! float_ops {
+    ($op:expr, $frame:expr, $variant:ident, $float:ty) => {{
+        let pop = |frame: &mut Frame| match frame.pop() {
+            Value::$variant(val) => val,
+            _ => unreachable!("validated"),
+        };
+        let result = match $op {
+            FloatOpType::Abs => Value::$variant(pop($frame).abs()),
+            FloatOpType::Neg => Value::$variant(-pop($frame)),
+            FloatOpType::Sqrt => Value::$variant(pop($frame).sqrt()),
+            FloatOpType::Ceil => Value::$variant(pop($frame).ceil()),
+            FloatOpType::Floor => Value::$variant(pop($frame).floor()),
+            FloatOpType::Trunc => Value::$variant(pop($frame).trunc()),
+            FloatOpType::Nearest => Value::$variant(pop($frame).round_ties_even()),
+            op => {
+                let b = pop($frame);
+                let a = pop($frame);
+                let compare = |result: bool| Value::I32(result as i32);
+                match op {
+                    FloatOpType::Add => Value::$variant(a + b),
+                    FloatOpType::Sub => Value::$variant(a - b),
+                    FloatOpType::Mul => Value::$variant(a * b),
+                    FloatOpType::Div => Value::$variant(a / b),
+                    // Unlike Rust's `min` and `max`, NaNs win and -0 is less than 0
+                    FloatOpType::Min if a.is_nan() || b.is_nan() => Value::$variant(<$float>::NAN),
+                    FloatOpType::Max if a.is_nan() || b.is_nan() => Value::$variant(<$float>::NAN),
+                    FloatOpType::Min if a == b => {
+                        Value::$variant(<$float>::from_bits(a.to_bits() | b.to_bits()))
+                    }
+                    FloatOpType::Max if a == b => {
+                        Value::$variant(<$float>::from_bits(a.to_bits() & b.to_bits()))
+                    }
+                    FloatOpType::Min => Value::$variant(a.min(b)),
+                    FloatOpType::Max => Value::$variant(a.max(b)),
+                    FloatOpType::Copysign => Value::$variant(a.copysign(b)),
+                    FloatOpType::Eq => compare(a == b),
+                    FloatOpType::Ne => compare(a != b),
+                    FloatOpType::Lt => compare(a < b),
+                    FloatOpType::Le => compare(a <= b),
+                    FloatOpType::Ge => compare(a >= b),
+                    FloatOpType::Gt => compare(a > b),
+                    _ => unreachable!("unary ops are handled above"),
+                }
+            }
+        };
+        $frame.stack.push(result);
+    }};
+}
+
+fn float_op(op: &FloatOp, frame: &mut Frame) {
+    match op.typ {
+        FloatType::F32 => float_ops!(&op.op, frame, F32, f32),
+        FloatType::F64 => float_ops!(&op.op, frame, F64, f64),
+    }
+}
+
+// Checks a float truncated towards zero fits between `min` and `max`, exclusive
+fn trunc(val: f64, min: f64, max: f64) -> Result<f64, RuntimeError> {
+    if val.is_nan() {
+        return Err(RuntimeError::InvalidConversion);
+    }
+    let val = val.trunc();
+    if val <= min || val >= max {
+        return Err(RuntimeError::IntegerOverflow);
+    }
+    Ok(val)
+}
+
+fn convert(op: &ConvertOp, value: Value) -> Result<Value, RuntimeError> {
+    use ConvertOp::*;
+    use Value::*;
+
+    const I32_MIN: f64 = i32::MIN as f64 - 1.0;
+    const I32_MAX: f64 = i32::MAX as f64 + 1.0;
+    const U32_MAX: f64 = u32::MAX as f64 + 1.0;
+    const I64_MIN: f64 = -9223372036854775808.0;
+    const I64_MAX: f64 = 9223372036854775808.0;
+    const U64_MAX: f64 = 18446744073709551616.0;
+
+    // Floats widened to f64 exactly, for checking the range of truncations
+    let float = match value {
+        F32(val) => val as f64,
+        F64(val) => val,
+        _ => 0.0,
+    };
+    Ok(match (op, value) {
+        (I32Extend8S, I32(val)) => I32(val as i8 as i32),
+        (I32Extend16S, I32(val)) => I32(val as i16 as i32),
+        (I64Extend8S, I64(val)) => I64(val as i8 as i64),
+        (I64Extend16S, I64(val)) => I64(val as i16 as i64),
+        (I64Extend32S, I64(val)) => I64(val as i32 as i64),
+        (I32WrapI64, I64(val)) => I32(val as i32),
+        (I64ExtendI32S, I32(val)) => I64(val as i64),
+        (I64ExtendI32U, I32(val)) => I64(val as u32 as i64),
+
+        (I32TruncF32S | I32TruncF64S, _) => I32(trunc(float, I32_MIN, I32_MAX)? as i32),
+        (I32TruncF32U | I32TruncF64U, _) => I32(trunc(float, -1.0, U32_MAX)? as u32 as i32),
+        // -2^63 is the one value below the range that fits, as it's exactly representable
+        (I64TruncF32S | I64TruncF64S, _) if float == I64_MIN => I64(i64::MIN),
+        (I64TruncF32S | I64TruncF64S, _) => I64(trunc(float, I64_MIN, I64_MAX)? as i64),
+        (I64TruncF32U | I64TruncF64U, _) => I64(trunc(float, -1.0, U64_MAX)? as u64 as i64),
+
+        // Rust's casts saturate and turn NaN into 0, just like these
+        (I32TruncSatF32S | I32TruncSatF64S, _) => I32(float as i32),
+        (I32TruncSatF32U | I32TruncSatF64U, _) => I32(float as u32 as i32),
+        (I64TruncSatF32S | I64TruncSatF64S, _) => I64(float as i64),
+        (I64TruncSatF32U | I64TruncSatF64U, _) => I64(float as u64 as i64),
+
+        (F32DemoteF64, F64(val)) => F32(val as f32),
+        (F64PromoteF32, F32(val)) => F64(val as f64),
+        (F32ConvertI32S, I32(val)) => F32(val as f32),
+        (F32ConvertI32U, I32(val)) => F32(val as u32 as f32),
+        (F32ConvertI64S, I64(val)) => F32(val as f32),
+        (F32ConvertI64U, I64(val)) => F32(val as u64 as f32),
+        (F64ConvertI32S, I32(val)) => F64(val as f64),
+        (F64ConvertI32U, I32(val)) => F64(val as u32 as f64),
+        (F64ConvertI64S, I64(val)) => F64(val as f64),
+        (F64ConvertI64U, I64(val)) => F64(val as u64 as f64),
+
+        (I32ReinterpretF32, F32(val)) => I32(val.to_bits() as i32),
+        (I64ReinterpretF64, F64(val)) => I64(val.to_bits() as i64),
+        (F32ReinterpretI32, I32(val)) => F32(f32::from_bits(val as u32)),
+        (F64ReinterpretI64, I64(val)) => F64(f64::from_bits(val as u64)),
+        _ => unreachable!("validated"),
+    })
+}
@@ -103,3 +103,178 @@ impl EncodesToLeb128 for i64 {
         }
     }
 }
+
+pub trait DecodesFromLeb128: Sized {
+    /// Decode a value from the start of `input`.
+    /// Returns the value and the number of bytes read, or `None` if the input ends early or
+    /// the encoded value doesn't fit in the type.
+    fn decode_from_leb128(input: &[u8]) -> Option<(Self, usize)>;
+}
+
+impl DecodesFromLeb128 for u32 {
+    fn decode_from_leb128(input: &[u8]) -> Option<(Self, usize)> {
+        decode_unsigned(input, 32).map(|(value, read)| (value as u32, read))
+    }
+}
+
+impl DecodesFromLeb128 for usize {
+    fn decode_from_leb128(input: &[u8]) -> Option<(Self, usize)> {
+        decode_unsigned(input, usize::BITS).map(|(value, read)| (value as usize, read))
+    }
+}
+
+impl DecodesFromLeb128 for u64 {
+    fn decode_from_leb128(input: &[u8]) -> Option<(Self, usize)> {
+        decode_unsigned(input, 64)
+    }
+}
+
+impl DecodesFromLeb128 for i32 {
+    fn decode_from_leb128(input: &[u8]) -> Option<(Self, usize)> {
+        decode_signed(input, 32).map(|(value, read)| (value as i32, read))
+    }
+}
+
+impl DecodesFromLeb128 for i64 {
+    fn decode_from_leb128(input: &[u8]) -> Option<(Self, usize)> {
+        decode_signed(input, 64)
+    }
+}
+
+/// Decodes an unsigned integer that must fit in `bits` bits.
+pub fn decode_unsigned(input: &[u8], bits: u32) -> Option<(u64, usize)> {
+    let mut result = 0u64;
+    let mut shift = 0;
+    for (i, byte) in input.iter().enumerate() {
+        if shift >= bits {
+            return None; // More bytes than the type needs
+        }
+        let low = (byte & 0x7F) as u64;
+        // The bits past the width of the type must be unset
+        if shift + 7 > bits && low >> (bits - shift) != 0 {
+            return None;
+        }
+        result |= low << shift;
+        if byte & 0x80 == 0 {
+            return Some((result, i + 1));
+        }
+        shift += 7;
+    }
+    None
+}
+
+/// Decodes a signed integer that must fit in `bits` bits, eg. 33 for block types.
+pub fn decode_signed(input: &[u8], bits: u32) -> Option<(i64, usize)> {
+    let mut result = 0i64;
+    let mut shift = 0;
+    for (i, byte) in input.iter().enumerate() {
+        if shift >= bits {
+            return None;
+        }
+        let low = (byte & 0x7F) as i64;
+        // Only the lowest bit of the tenth byte is part of a 64 bit value
+        if shift == 63 && low != 0 && low != 0x7F {
+            return None;
+        }
+        result |= low << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            if shift < 64 && byte & 0x40 != 0 {
+                result |= -1 << shift; // Sign extend
+            }
+            // The bits past the width of the type must all be copies of the sign bit
+            let unused = 64 - bits;
+            if (result << unused) >> unused != result {
+                return None;
+            }
+            return Some((result, i + 1));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Encodes `value`, checks it decodes back to itself from exactly those bytes, and that
+    // encoding it again gives the same bytes
+    fn round_trip<T>(value: T) -> Vec<u8>
+    where
+        T: EncodesToLeb128 + DecodesFromLeb128 + PartialEq + std::fmt::Debug,
+    {
+        let bytes = value.encode_to_leb128_bytes();
+        let (decoded, read) = T::decode_from_leb128(&bytes).unwrap();
+        assert_eq!(decoded, value);
+        assert_eq!(read, bytes.len());
+        assert_eq!(decoded.encode_to_leb128_bytes(), bytes);
+        bytes
+    }
+
+    #[test]
+    fn unsigned_round_trip() {
+        assert_eq!(round_trip(0u32), [0x00]);
+        assert_eq!(round_trip(127u32), [0x7f]);
+        assert_eq!(round_trip(128u32), [0x80, 0x01]);
+        assert_eq!(round_trip(u32::MAX), [0xff, 0xff, 0xff, 0xff, 0x0f]);
+        assert_eq!(round_trip(u64::MAX).len(), 10);
+        round_trip(usize::MAX);
+    }
+
+    #[test]
+    fn signed_round_trip() {
+        assert_eq!(round_trip(0i32), [0x00]);
+        assert_eq!(round_trip(63i32), [0x3f]);
+        // 64 sets the sign bit of the first byte, so it needs another
+        assert_eq!(round_trip(64i32), [0xc0, 0x00]);
+        assert_eq!(round_trip(-1i32), [0x7f]);
+        assert_eq!(round_trip(-64i32), [0x40]);
+        // Negative values past the first byte have to be sign extended when decoded
+        assert_eq!(round_trip(-65i32), [0xbf, 0x7f]);
+        assert_eq!(round_trip(-129i32), [0xff, 0x7e]);
+        assert_eq!(round_trip(i32::MIN), [0x80, 0x80, 0x80, 0x80, 0x78]);
+        assert_eq!(round_trip(i32::MAX), [0xff, 0xff, 0xff, 0xff, 0x07]);
+        assert_eq!(
+            round_trip(i64::MIN),
+            [0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x7f]
+        );
+        assert_eq!(
+            round_trip(i64::MAX),
+            [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00]
+        );
+        assert_eq!(round_trip(-1i64), [0x7f]);
+    }
+
+    #[test]
+    fn rejects_values_too_wide_for_the_type() {
+        assert_eq!(
+            u32::decode_from_leb128(&[0xff, 0xff, 0xff, 0xff, 0x1f]),
+            None
+        );
+        assert_eq!(
+            u32::decode_from_leb128(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x00]),
+            None
+        );
+        assert_eq!(
+            i32::decode_from_leb128(&[0x80, 0x80, 0x80, 0x80, 0x70]),
+            None
+        );
+        assert_eq!(
+            i32::decode_from_leb128(&[0xff, 0xff, 0xff, 0xff, 0x0f]),
+            None
+        );
+        assert_eq!(
+            i64::decode_from_leb128(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x01]),
+            None
+        );
+        // Block types are 33 bit signed integers
+        assert_eq!(decode_signed(&[0x40], 33), Some((-64, 1)));
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        assert_eq!(u32::decode_from_leb128(&[]), None);
+        assert_eq!(u32::decode_from_leb128(&[0x80]), None);
+        assert_eq!(i64::decode_from_leb128(&[0xff, 0xff]), None);
+    }
+}
@@ -1,13 +1,59 @@
+pub mod decoder;
 pub mod encoder;
+pub mod interpreter;
 pub mod little_endian_base_128;
+pub mod source_map;
+pub mod validator;
+pub mod wat;
+#[derive(Debug, Clone, PartialEq, Default)]
 pub struct WasmModule {
     pub types: Vec<FunctionType>,
     pub imports: Vec<Import>,
+    pub tables: Vec<TableType>,
+    pub memories: Vec<Limits>,
+    pub tags: Vec<Tag>,
+    pub globals: Vec<Global>,
     pub exports: Vec<Export>,
     pub functions: Vec<Function>,
     pub start: Option<u32>,
+    pub elements: Vec<Element>,
+    pub data: Vec<Data>,
+    pub names: Names,
 }
 
+/// Names for debuggers and stack traces, stored in the custom `name` section. Each list is
+/// sorted by index, and names don't have to be unique.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Names {
+    pub module: Option<String>,
+    pub functions: Vec<(u32, String)>,
+    // The names of the params and locals of each function
+    pub locals: Vec<(u32, Vec<(u32, String)>)>,
+}
+
+impl Names {
+    pub fn is_empty(&self) -> bool {
+        self.module.is_none() && self.functions.is_empty() && self.locals.is_empty()
+    }
+
+    pub fn function(&self, index: u32) -> Option<&str> {
+        let position = (self.functions)
+            .binary_search_by_key(&index, |(i, _)| *i)
+            .ok()?;
+        Some(&self.functions[position].1)
+    }
+
+    pub fn local(&self, function: u32, index: u32) -> Option<&str> {
+        let position = (self.locals)
+            .binary_search_by_key(&function, |(i, _)| *i)
+            .ok()?;
+        let locals = &self.locals[position].1;
+        let position = locals.binary_search_by_key(&index, |(i, _)| *i).ok()?;
+        Some(&locals[position].1)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum NumType {
     I32,
     I64,
@@ -15,41 +61,111 @@ pub enum NumType {
     F64,
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct FunctionType {
     pub args: Vec<NumType>,
     pub ret: Vec<NumType>,
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct Import {
     pub module: String,
     pub name: String,
     pub typ: ImportType,
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum ImportType {
     Func(u32),
-    Memory { min: u32, max: Option<u32> },
+    Table(TableType),
+    Memory(Limits),
+    Global(GlobalType),
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct Export {
     pub name: String,
     pub typ: ExportType,
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum ExportType {
     Func(u32),
+    Table(u32),
+    Memory(u32),
+    Global(u32),
+}
+
+/// The size of a memory in pages of 64KiB, or of a table in elements.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limits {
+    pub min: u32,
+    pub max: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefType {
+    FuncRef,
+    ExternRef,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TableType {
+    pub element: RefType,
+    pub limits: Limits,
+}
+
+/// An exception tag from the exception handling proposal. Its type's arguments are the
+/// values thrown with it, and it has no results.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tag {
+    pub type_idx: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlobalType {
+    pub typ: NumType,
+    pub mutable: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Global {
+    pub typ: GlobalType,
+    // A constant expression, eg. a single `i32.const`
+    pub init: Expression,
+}
+
+/// Functions placed in a table when the module is instantiated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Element {
+    pub table: u32,
+    // A constant expression giving the index of the first function in the table
+    pub offset: Expression,
+    pub functions: Vec<u32>,
+}
+
+/// Bytes copied into a memory when the module is instantiated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Data {
+    pub memory: u32,
+    // A constant expression giving the address of the first byte
+    pub offset: Expression,
+    pub bytes: Vec<u8>,
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct Function {
     pub type_idx: u32,
     pub locals: Vec<NumType>,
     pub body: Expression,
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct Expression {
     pub instructions: Vec<Instruction>,
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum Instruction {
     I32Const(i32),
     I64Const(i64),
@@ -58,17 +174,28 @@ pub enum Instruction {
     IntegerOp(IntegerOp),
     FloatOp(FloatOp),
     ConvertOp(ConvertOp),
-    // RefOp(RefOp),
+    RefOp(RefOp),
+    ParametricOp(ParametricOp),
     VariableOp(VariableOp),
+    MemoryOp(MemoryOp),
     ControlOp(ControlOp),
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum RefOp {
+    // A reference to the function with the index
     Func(u32),
     IsNull,
-    Null(u32),
+    Null(RefType),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParametricOp {
+    Drop,
+    Select,
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum VariableOp {
     LocalGet(u32),
     LocalSet(u32),
@@ -77,32 +204,160 @@ pub enum VariableOp {
     GlobalSet(u32),
 }
 
+/// The static part of the address of a memory access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemArg {
+    // The log2 of the alignment the address is expected to have, a hint for the engine
+    pub align: u32,
+    // Added to the address on the stack
+    pub offset: u32,
+}
+
+impl MemArg {
+    /// A memarg for accessing `size` bytes with their natural alignment.
+    pub fn natural(size: u32, offset: u32) -> Self {
+        Self {
+            align: size.trailing_zeros(),
+            offset,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MemoryOp {
+    I32Load(MemArg),
+    I64Load(MemArg),
+    F32Load(MemArg),
+    F64Load(MemArg),
+    I32Load8S(MemArg),
+    I32Load8U(MemArg),
+    I32Load16S(MemArg),
+    I32Load16U(MemArg),
+    I64Load8S(MemArg),
+    I64Load8U(MemArg),
+    I64Load16S(MemArg),
+    I64Load16U(MemArg),
+    I64Load32S(MemArg),
+    I64Load32U(MemArg),
+
+    I32Store(MemArg),
+    I64Store(MemArg),
+    F32Store(MemArg),
+    F64Store(MemArg),
+    I32Store8(MemArg),
+    I32Store16(MemArg),
+    I64Store8(MemArg),
+    I64Store16(MemArg),
+    I64Store32(MemArg),
+
+    MemorySize,
+    MemoryGrow,
+    // Bulk memory operations
+    MemoryCopy,
+    MemoryFill,
+}
+
+impl MemoryOp {
+    /// The address of a load or store, `None` for the instructions on the memory as a whole.
+    pub fn memarg(&self) -> Option<MemArg> {
+        use MemoryOp::*;
+        match self {
+            I32Load(arg) | I64Load(arg) | F32Load(arg) | F64Load(arg) | I32Load8S(arg)
+            | I32Load8U(arg) | I32Load16S(arg) | I32Load16U(arg) | I64Load8S(arg)
+            | I64Load8U(arg) | I64Load16S(arg) | I64Load16U(arg) | I64Load32S(arg)
+            | I64Load32U(arg) | I32Store(arg) | I64Store(arg) | F32Store(arg) | F64Store(arg)
+            | I32Store8(arg) | I32Store16(arg) | I64Store8(arg) | I64Store16(arg)
+            | I64Store32(arg) => Some(*arg),
+            MemorySize | MemoryGrow | MemoryCopy | MemoryFill => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum ControlOp {
-    Call(u32),
-    Return,
+    Unreachable,
     Nop,
+    Block {
+        typ: BlockType,
+        body: Expression,
+    },
+    Loop {
+        typ: BlockType,
+        body: Expression,
+    },
+    If {
+        typ: BlockType,
+        then: Expression,
+        otherwise: Option<Expression>,
+    },
+    // Branches name their target by how many blocks out it is, 0 is the innermost
+    Br(u32),
+    BrIf(u32),
+    BrTable {
+        labels: Vec<u32>,
+        default: u32,
+    },
+    Return,
+    Call(u32),
+    // Calls the function at the index on top of the stack in `table`, which has to have the type
+    CallIndirect {
+        type_idx: u32,
+        table: u32,
+    },
+    // Exception handling. Exceptions thrown in the body of a `try` with the tag of one of its
+    // `catches` are handled by it, getting the values thrown, and `catch_all` handles the rest
+    Try {
+        typ: BlockType,
+        body: Expression,
+        catches: Vec<Catch>,
+        catch_all: Option<Expression>,
+    },
+    Throw(u32),
+    // Throws the exception caught by the `catch` the label refers to again
+    Rethrow(u32),
+}
+
+/// A handler of a `try`, for exceptions with the tag.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Catch {
+    pub tag: u32,
+    pub body: Expression,
+}
+
+/// The values a block takes and leaves on the stack.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockType {
+    Empty,
+    Value(NumType),
+    // An index into the type section, for blocks taking arguments or returning several values
+    Type(u32),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum IntegerType {
     I32,
     I64,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FloatType {
     F32,
     F64,
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct IntegerOp {
     pub op: IntegerOpType,
     pub typ: IntegerType,
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct FloatOp {
     pub op: FloatOpType,
     pub typ: FloatType,
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum IntegerOpType {
     // Unary Ops
     Clz,
@@ -142,6 +397,7 @@ pub enum IntegerOpType {
     Rotr,
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum FloatOpType {
     // Unary Ops
     Abs,
@@ -170,6 +426,7 @@ pub enum FloatOpType {
     Gt,
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum ConvertOp {
     I32Extend8S,
     I32Extend16S,
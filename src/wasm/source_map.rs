@@ -0,0 +1,218 @@
+//! Source maps, which let browser devtools step through the code a module was compiled from.
+//! See <https://sourcemaps.info/spec.html>. For wasm the generated code is all on one line,
+//! with the offset of each instruction in the module as its column.
+
+use std::fmt::Write;
+
+use crate::lexer::span::Span;
+
+use super::{encoder::EncodesToWasm, little_endian_base_128::EncodesToLeb128};
+
+pub struct SourceMap {
+    // The path and contents of each source file
+    sources: Vec<(String, String)>,
+    // The offset of some code, and the file and span it came from
+    mappings: Vec<(usize, usize, Span)>,
+}
+
+impl SourceMap {
+    pub fn new(sources: Vec<(String, String)>) -> Self {
+        Self {
+            sources,
+            mappings: Vec::new(),
+        }
+    }
+
+    /// Maps the code from `offset` in the module up to the next mapping back to `span` in
+    /// the `file`th source. Mappings must be added in order of their offsets.
+    pub fn add(&mut self, offset: usize, file: usize, span: Span) {
+        // Code generated without a position keeps the position of the code before it
+        if span == Span::default() {
+            return;
+        }
+        // Consecutive instructions from the same place only need the first mapping
+        if let Some((_, last_file, last_span)) = self.mappings.last() {
+            if (*last_file, last_span.line, last_span.column) == (file, span.line, span.column) {
+                return;
+            }
+        }
+        self.mappings.push((offset, file, span));
+    }
+
+    /// The map as the JSON the spec describes. The sources are included, so the map works
+    /// wherever it is served from.
+    pub fn to_json(&self) -> String {
+        let (paths, contents): (Vec<_>, Vec<_>) = self
+            .sources
+            .iter()
+            .map(|(path, content)| (json_string(path), json_string(content)))
+            .unzip();
+        format!(
+            "{{\"version\":3,\"sources\":[{}],\"sourcesContent\":[{}],\"names\":[],\"mappings\":\"{}\"}}\n",
+            paths.join(","),
+            contents.join(","),
+            self.mappings_text()
+        )
+    }
+
+    // Each mapping is a segment of the offset, file index, line and column, all 0-based and
+    // relative to the segment before
+    fn mappings_text(&self) -> String {
+        let mut text = String::new();
+        let mut last = [0, 0, 0, 0];
+        for (offset, file, span) in self.mappings.iter() {
+            let segment = [*offset, *file, span.line - 1, span.column - 1].map(|n| n as i64);
+            if !text.is_empty() {
+                text.push(',');
+            }
+            for (value, last) in segment.iter().zip(last.iter()) {
+                base64_vlq(value - last, &mut text);
+            }
+            last = segment;
+        }
+        text
+    }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+// The sign goes in the lowest bit, then 5 bits per digit with the 6th set if more follow
+fn base64_vlq(value: i64, output: &mut String) {
+    let mut vlq = (value.unsigned_abs() << 1) | (value < 0) as u64;
+    loop {
+        let mut digit = vlq & 0b11111;
+        vlq >>= 5;
+        if vlq != 0 {
+            digit |= 0b100000;
+        }
+        output.push(BASE64[digit as usize] as char);
+        if vlq == 0 {
+            break;
+        }
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut json = "\"".to_string();
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// The custom section telling engines where to find the source map of a module, relative to
+/// the module itself. It goes at the end of the module, so no code moves.
+pub fn source_mapping_url_section(url: &str) -> Vec<u8> {
+    let mut section_bytes = Vec::new();
+    "sourceMappingURL"
+        .to_string()
+        .encode_to_wasm(&mut section_bytes);
+    url.to_string().encode_to_wasm(&mut section_bytes);
+    let mut output = vec![0x00]; // custom section
+    section_bytes.len().encode_to_leb128(&mut output);
+    output.extend(section_bytes);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        codegen::{declare_builtins, generate_module},
+        lexer::lexer::Lexer,
+        parser::statements::parse_program,
+        type_checker::{check_program, Scope},
+        wasm::WasmModule,
+    };
+
+    fn vlq(value: i64) -> String {
+        let mut text = String::new();
+        base64_vlq(value, &mut text);
+        text
+    }
+
+    // Reads the segments back out of `mappings`, undoing the deltas
+    fn segments(mappings: &str) -> Vec<[i64; 4]> {
+        let mut segments = Vec::new();
+        let mut last = [0; 4];
+        for segment in mappings.split(',') {
+            let mut values = Vec::new();
+            let (mut value, mut shift) = (0, 0);
+            for c in segment.bytes() {
+                let digit = BASE64.iter().position(|b| *b == c).unwrap() as i64;
+                value |= (digit & 0b11111) << shift;
+                shift += 5;
+                if digit & 0b100000 == 0 {
+                    let sign = if value & 1 == 1 { -1 } else { 1 };
+                    values.push(sign * (value >> 1));
+                    (value, shift) = (0, 0);
+                }
+            }
+            for (i, value) in values.iter().enumerate() {
+                last[i] += value;
+            }
+            segments.push(last);
+        }
+        segments
+    }
+
+    #[test]
+    fn encodes_vlqs() {
+        assert_eq!(vlq(0), "A");
+        assert_eq!(vlq(1), "C");
+        assert_eq!(vlq(-1), "D");
+        assert_eq!(vlq(15), "e");
+        assert_eq!(vlq(-15), "f");
+        // Digits past the first 4 bits continue in the next character, lowest first
+        assert_eq!(vlq(16), "gB");
+        assert_eq!(vlq(-16), "hB");
+        assert_eq!(vlq(1000), "w+B");
+        assert_eq!(vlq(123456), "gkxH");
+    }
+
+    #[test]
+    fn maps_code_to_where_it_came_from() {
+        let source = "let add = (a: int, b: int): int => {\n    return a + b;\n};\n";
+        let (program, errors) = parse_program(Lexer::new(source));
+        assert!(errors.is_empty(), "{:?}", errors);
+        let mut scope = Scope::new();
+        declare_builtins(&mut scope);
+        check_program(&program, &mut scope).unwrap();
+        let (module, positions) = generate_module(&[program], false).unwrap();
+        let mut bytes = Vec::new();
+        module.encode_to_wasm(&mut bytes);
+
+        let (_, offsets) = WasmModule::decode_with_offsets(&bytes).unwrap();
+        let mut source_map = SourceMap::new(vec![("add.jj".to_string(), source.to_string())]);
+        for (offsets, positions) in offsets.iter().zip(positions.iter()) {
+            for (offset, (file, span)) in offsets.iter().zip(positions.iter()) {
+                source_map.add(*offset, *file, *span);
+            }
+        }
+
+        // 0-based lines and columns of `a`, `b`, `a + b`, `return` and the function literal,
+        // which its implicit end comes from
+        let segments = segments(&source_map.mappings_text());
+        let positions = (segments.iter())
+            .map(|[_, file, line, column]| (*file, *line, *column))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            positions,
+            [(0, 1, 11), (0, 1, 15), (0, 1, 11), (0, 1, 4), (0, 0, 10)]
+        );
+        // Every offset is in the module, in order
+        assert!(segments.windows(2).all(|pair| pair[0][0] < pair[1][0]));
+        assert!(segments
+            .iter()
+            .all(|segment| (segment[0] as usize) < bytes.len()));
+    }
+}
@@ -0,0 +1,1076 @@
+use std::{collections::HashSet, fmt};
+
+use super::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError {
+    pub location: Location,
+    pub kind: ValidationErrorKind,
+}
+
+/// The part of a module a validation error was found in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Location {
+    Import(u32),
+    // The index of the function among all functions, imports first. Instructions are counted
+    // in the order they are written in flat wat, from 0 and without `else`, `catch` and `end`
+    Function {
+        index: u32,
+        instruction: Option<usize>,
+    },
+    Table(u32),
+    Memory(u32),
+    Tag(u32),
+    Global(u32),
+    Export(u32),
+    Start,
+    Element(u32),
+    Data(u32),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationErrorKind {
+    UnknownType(u32),
+    UnknownFunction(u32),
+    UnknownTable(u32),
+    UnknownMemory(u32),
+    UnknownGlobal(u32),
+    UnknownLocal(u32),
+    UnknownLabel(u32),
+    UnknownTag(u32),
+    // `None` means any value was expected, or that the stack was empty
+    TypeMismatch {
+        expected: Option<NumType>,
+        found: Option<NumType>,
+    },
+    // Values left on the stack at the end of a block, on top of its results
+    ExtraValues(usize),
+    // The targets of a `br_table` don't all take the same number of values
+    LabelArityMismatch {
+        expected: usize,
+        found: usize,
+    },
+    // An `if` without an `else` has to leave its params as its results
+    MissingElse,
+    // Exceptions can't return to where they were thrown
+    TagWithResults(u32),
+    // `rethrow` has to refer to an enclosing `catch`
+    NotACatch(u32),
+    ImmutableGlobal(u32),
+    NonConstantExpression,
+    AlignmentTooLarge {
+        align: u32,
+        max: u32,
+    },
+    InvalidLimits {
+        min: u32,
+        max: u32,
+    },
+    MemoryTooLarge(u32),
+    MultipleMemories,
+    ElementsNotFunctions(u32),
+    DuplicateExport(String),
+    InvalidStartFunction(u32),
+    // Valid wasm the validator can't check, eg. reference instructions
+    Unsupported(&'static str),
+}
+
+impl ValidationErrorKind {
+    pub fn at(self, location: Location) -> ValidationError {
+        ValidationError {
+            location,
+            kind: self,
+        }
+    }
+}
+
+// The largest memory wasm32 can address, in 64KiB pages
+const MAX_PAGES: u32 = 1 << 16;
+
+fn type_name(typ: &Option<NumType>) -> String {
+    match typ {
+        Some(typ) => format!("`{}`", typ),
+        None => "a value".to_string(),
+    }
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            ValidationErrorKind::UnknownType(index) => write!(f, "unknown type {}", index)?,
+            ValidationErrorKind::UnknownFunction(index) => write!(f, "unknown function {}", index)?,
+            ValidationErrorKind::UnknownTable(index) => write!(f, "unknown table {}", index)?,
+            ValidationErrorKind::UnknownMemory(index) => write!(f, "unknown memory {}", index)?,
+            ValidationErrorKind::UnknownGlobal(index) => write!(f, "unknown global {}", index)?,
+            ValidationErrorKind::UnknownLocal(index) => write!(f, "unknown local {}", index)?,
+            ValidationErrorKind::UnknownLabel(depth) => write!(f, "unknown label {}", depth)?,
+            ValidationErrorKind::UnknownTag(index) => write!(f, "unknown tag {}", index)?,
+            ValidationErrorKind::TypeMismatch {
+                expected,
+                found: Some(found),
+            } => write!(f, "expected {}, found `{}`", type_name(expected), found)?,
+            ValidationErrorKind::TypeMismatch {
+                expected,
+                found: None,
+            } => write!(f, "expected {}, found an empty stack", type_name(expected))?,
+            ValidationErrorKind::ExtraValues(count) => write!(
+                f,
+                "{} value{} left on the stack at the end of a block",
+                count,
+                if *count == 1 { "" } else { "s" }
+            )?,
+            ValidationErrorKind::LabelArityMismatch { expected, found } => write!(
+                f,
+                "`br_table` targets take {} and {} values",
+                expected, found
+            )?,
+            ValidationErrorKind::MissingElse => {
+                write!(f, "`if` without an `else` changes the types on the stack")?
+            }
+            ValidationErrorKind::TagWithResults(typ) => write!(f, "tag type {} has results", typ)?,
+            ValidationErrorKind::NotACatch(depth) => write!(f, "label {} is not a `catch`", depth)?,
+            ValidationErrorKind::ImmutableGlobal(index) => {
+                write!(f, "global {} is immutable", index)?
+            }
+            ValidationErrorKind::NonConstantExpression => write!(f, "expression is not constant")?,
+            ValidationErrorKind::AlignmentTooLarge { align, max } => {
+                // The alignment is read straight from the binary, so can be too big to shift by
+                match 1u64.checked_shl(*align) {
+                    Some(bytes) => write!(f, "alignment of {} bytes", bytes)?,
+                    None => write!(f, "alignment of 2^{} bytes", align)?,
+                }
+                write!(f, " is larger than the {} bytes accessed", 1u64 << max)?
+            }
+            ValidationErrorKind::InvalidLimits { min, max } => {
+                write!(f, "minimum size {} is larger than the maximum {}", min, max)?
+            }
+            ValidationErrorKind::MemoryTooLarge(pages) => {
+                write!(f, "memory of {} pages is larger than 4GiB", pages)?
+            }
+            ValidationErrorKind::MultipleMemories => write!(f, "more than one memory")?,
+            ValidationErrorKind::ElementsNotFunctions(table) => {
+                write!(f, "table {} does not hold functions", table)?
+            }
+            ValidationErrorKind::DuplicateExport(name) => {
+                write!(f, "`{}` is exported more than once", name)?
+            }
+            ValidationErrorKind::InvalidStartFunction(index) => {
+                write!(f, "start function {} has to take and return nothing", index)?
+            }
+            ValidationErrorKind::Unsupported(what) => write!(f, "{} are not supported", what)?,
+        }
+        write!(f, " {}", self.location)
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Location::Import(index) => write!(f, "in import {}", index),
+            Location::Function {
+                index,
+                instruction: Some(instruction),
+            } => write!(f, "in function {} at instruction {}", index, instruction),
+            Location::Function {
+                index,
+                instruction: None,
+            } => write!(f, "in function {}", index),
+            Location::Table(index) => write!(f, "in table {}", index),
+            Location::Memory(index) => write!(f, "in memory {}", index),
+            Location::Tag(index) => write!(f, "in tag {}", index),
+            Location::Global(index) => write!(f, "in global {}", index),
+            Location::Export(index) => write!(f, "in export {}", index),
+            Location::Start => write!(f, "in the start section"),
+            Location::Element(index) => write!(f, "in element segment {}", index),
+            Location::Data(index) => write!(f, "in data segment {}", index),
+        }
+    }
+}
+
+impl WasmModule {
+    /// Checks that the module is well-formed and its instructions are well-typed, as an engine
+    /// would before running it, returning every problem found.
+    pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let mut validator = Validator::new(self);
+        validator.validate_module();
+        if validator.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(validator.errors)
+        }
+    }
+}
+
+/// Everything defined in the module, imports first, in each index space.
+struct Validator<'a> {
+    module: &'a WasmModule,
+    functions: Vec<u32>,
+    tables: Vec<&'a TableType>,
+    memories: Vec<&'a Limits>,
+    globals: Vec<&'a GlobalType>,
+    // How many of the globals are imported, which are the only ones constants can read
+    imported_globals: usize,
+    errors: Vec<ValidationError>,
+}
+
+impl<'a> Validator<'a> {
+    fn new(module: &'a WasmModule) -> Self {
+        let mut validator = Self {
+            module,
+            functions: Vec::new(),
+            tables: Vec::new(),
+            memories: Vec::new(),
+            globals: Vec::new(),
+            imported_globals: 0,
+            errors: Vec::new(),
+        };
+        for import in module.imports.iter() {
+            match &import.typ {
+                ImportType::Func(typ) => validator.functions.push(*typ),
+                ImportType::Table(table) => validator.tables.push(table),
+                ImportType::Memory(limits) => validator.memories.push(limits),
+                ImportType::Global(global) => validator.globals.push(global),
+            }
+        }
+        validator.imported_globals = validator.globals.len();
+        validator
+            .functions
+            .extend(module.functions.iter().map(|function| function.type_idx));
+        validator.tables.extend(module.tables.iter());
+        validator.memories.extend(module.memories.iter());
+        validator
+            .globals
+            .extend(module.globals.iter().map(|global| &global.typ));
+        validator
+    }
+
+    fn function_type(&self, index: u32) -> Option<&'a FunctionType> {
+        let typ = *self.functions.get(index as usize)?;
+        self.module.types.get(typ as usize)
+    }
+
+    fn tag_type(&self, index: u32) -> Option<&'a FunctionType> {
+        let tag = self.module.tags.get(index as usize)?;
+        self.module.types.get(tag.type_idx as usize)
+    }
+
+    fn validate_module(&mut self) {
+        let module = self.module;
+        for (i, import) in module.imports.iter().enumerate() {
+            let location = Location::Import(i as u32);
+            match &import.typ {
+                ImportType::Func(typ) if *typ as usize >= module.types.len() => {
+                    self.errors
+                        .push(ValidationErrorKind::UnknownType(*typ).at(location));
+                }
+                ImportType::Func(_) | ImportType::Global(_) => {}
+                ImportType::Table(table) => self.limits(&table.limits, u32::MAX, location),
+                ImportType::Memory(limits) => self.limits(limits, MAX_PAGES, location),
+            }
+        }
+
+        let imported_functions = self.functions.len() - module.functions.len();
+        for (i, function) in module.functions.iter().enumerate() {
+            let index = (imported_functions + i) as u32;
+            match module.types.get(function.type_idx as usize) {
+                Some(typ) => self.function(index, function, typ),
+                None => self
+                    .errors
+                    .push(ValidationErrorKind::UnknownType(function.type_idx).at(
+                        Location::Function {
+                            index,
+                            instruction: None,
+                        },
+                    )),
+            }
+        }
+
+        let imported_tables = self.tables.len() - module.tables.len();
+        for (i, table) in module.tables.iter().enumerate() {
+            let location = Location::Table((imported_tables + i) as u32);
+            self.limits(&table.limits, u32::MAX, location);
+        }
+
+        let imported_memories = self.memories.len() - module.memories.len();
+        for (i, limits) in module.memories.iter().enumerate() {
+            let location = Location::Memory((imported_memories + i) as u32);
+            self.limits(limits, MAX_PAGES, location);
+            if imported_memories + i > 0 {
+                self.errors
+                    .push(ValidationErrorKind::MultipleMemories.at(location));
+            }
+        }
+
+        for (i, tag) in module.tags.iter().enumerate() {
+            let location = Location::Tag(i as u32);
+            match module.types.get(tag.type_idx as usize) {
+                Some(typ) if !typ.ret.is_empty() => self
+                    .errors
+                    .push(ValidationErrorKind::TagWithResults(tag.type_idx).at(location)),
+                Some(_) => {}
+                None => self
+                    .errors
+                    .push(ValidationErrorKind::UnknownType(tag.type_idx).at(location)),
+            }
+        }
+
+        for (i, global) in module.globals.iter().enumerate() {
+            let location = Location::Global((self.imported_globals + i) as u32);
+            self.constant(&global.init, global.typ.typ, location);
+        }
+
+        let mut names = HashSet::new();
+        for (i, export) in module.exports.iter().enumerate() {
+            let location = Location::Export(i as u32);
+            if !names.insert(&export.name) {
+                self.errors
+                    .push(ValidationErrorKind::DuplicateExport(export.name.clone()).at(location));
+            }
+            let error = match export.typ {
+                ExportType::Func(index) if index as usize >= self.functions.len() => {
+                    ValidationErrorKind::UnknownFunction(index)
+                }
+                ExportType::Table(index) if index as usize >= self.tables.len() => {
+                    ValidationErrorKind::UnknownTable(index)
+                }
+                ExportType::Memory(index) if index as usize >= self.memories.len() => {
+                    ValidationErrorKind::UnknownMemory(index)
+                }
+                ExportType::Global(index) if index as usize >= self.globals.len() => {
+                    ValidationErrorKind::UnknownGlobal(index)
+                }
+                _ => continue,
+            };
+            self.errors.push(error.at(location));
+        }
+
+        if let Some(start) = module.start {
+            match self.function_type(start) {
+                Some(typ) if typ.args.is_empty() && typ.ret.is_empty() => {}
+                Some(_) => self
+                    .errors
+                    .push(ValidationErrorKind::InvalidStartFunction(start).at(Location::Start)),
+                None => self
+                    .errors
+                    .push(ValidationErrorKind::UnknownFunction(start).at(Location::Start)),
+            }
+        }
+
+        for (i, element) in module.elements.iter().enumerate() {
+            let location = Location::Element(i as u32);
+            match self.tables.get(element.table as usize) {
+                Some(table) if table.element != RefType::FuncRef => self
+                    .errors
+                    .push(ValidationErrorKind::ElementsNotFunctions(element.table).at(location)),
+                Some(_) => {}
+                None => self
+                    .errors
+                    .push(ValidationErrorKind::UnknownTable(element.table).at(location)),
+            }
+            self.constant(&element.offset, NumType::I32, location);
+            for function in element.functions.iter() {
+                if *function as usize >= self.functions.len() {
+                    self.errors
+                        .push(ValidationErrorKind::UnknownFunction(*function).at(location));
+                }
+            }
+        }
+
+        for (i, data) in module.data.iter().enumerate() {
+            let location = Location::Data(i as u32);
+            if data.memory as usize >= self.memories.len() {
+                self.errors
+                    .push(ValidationErrorKind::UnknownMemory(data.memory).at(location));
+            }
+            self.constant(&data.offset, NumType::I32, location);
+        }
+    }
+
+    fn limits(&mut self, limits: &Limits, largest: u32, location: Location) {
+        if let Some(max) = limits.max {
+            if limits.min > max {
+                self.errors.push(
+                    ValidationErrorKind::InvalidLimits {
+                        min: limits.min,
+                        max,
+                    }
+                    .at(location),
+                );
+            }
+        }
+        let size = limits.max.unwrap_or(limits.min).max(limits.min);
+        if size > largest {
+            self.errors
+                .push(ValidationErrorKind::MemoryTooLarge(size).at(location));
+        }
+    }
+
+    // An initializer, which can only be made of constants and reads of imported immutable globals
+    fn constant(&mut self, expression: &Expression, typ: NumType, location: Location) {
+        let constant = expression
+            .instructions
+            .iter()
+            .all(|instruction| match instruction {
+                Instruction::I32Const(_)
+                | Instruction::I64Const(_)
+                | Instruction::F32Const(_)
+                | Instruction::F64Const(_) => true,
+                Instruction::VariableOp(VariableOp::GlobalGet(index)) => {
+                    (*index as usize) < self.imported_globals
+                        && !self.globals[*index as usize].mutable
+                }
+                _ => false,
+            });
+        if !constant {
+            self.errors
+                .push(ValidationErrorKind::NonConstantExpression.at(location));
+            return;
+        }
+        let mut body = BodyValidator::new(self, Vec::new(), vec![typ]);
+        if let Err(kind) = body.body(expression) {
+            self.errors.push(kind.at(location));
+        }
+    }
+
+    fn function(&mut self, index: u32, function: &Function, typ: &FunctionType) {
+        let locals = typ
+            .args
+            .iter()
+            .chain(function.locals.iter())
+            .copied()
+            .collect();
+        let mut body = BodyValidator::new(self, locals, typ.ret.clone());
+        if let Err(kind) = body.body(&function.body) {
+            let instruction = body.instruction.checked_sub(1);
+            self.errors
+                .push(kind.at(Location::Function { index, instruction }));
+        }
+    }
+}
+
+/// A block being validated.
+struct Frame {
+    // The types a branch to the block takes, its params for a loop and results otherwise
+    labels: Vec<NumType>,
+    results: Vec<NumType>,
+    // The height of the stack when the block started
+    height: usize,
+    // After a branch the stack can be treated as holding whatever is needed
+    unreachable: bool,
+    // Whether the block is a `catch`, which `rethrow` can refer to
+    catch: bool,
+}
+
+/// Runs through a function body keeping track of the types on the stack.
+struct BodyValidator<'v, 'a> {
+    validator: &'v Validator<'a>,
+    locals: Vec<NumType>,
+    ret: Vec<NumType>,
+    // `None` for values of unknown type in unreachable code
+    stack: Vec<Option<NumType>>,
+    frames: Vec<Frame>,
+    // How many instructions have been started, which locates any error
+    instruction: usize,
+}
+
+impl<'v, 'a> BodyValidator<'v, 'a> {
+    fn new(validator: &'v Validator<'a>, locals: Vec<NumType>, ret: Vec<NumType>) -> Self {
+        Self {
+            validator,
+            locals,
+            ret,
+            stack: Vec::new(),
+            frames: Vec::new(),
+            instruction: 0,
+        }
+    }
+
+    fn body(&mut self, body: &Expression) -> Result<(), ValidationErrorKind> {
+        let ret = self.ret.clone();
+        self.block(&[], &ret, ret.clone(), body)?;
+        Ok(())
+    }
+
+    fn push(&mut self, typ: NumType) {
+        self.stack.push(Some(typ));
+    }
+
+    fn pop(&mut self, expected: Option<NumType>) -> Result<Option<NumType>, ValidationErrorKind> {
+        let frame = self
+            .frames
+            .last()
+            .expect("always inside the function's block");
+        if self.stack.len() == frame.height {
+            if frame.unreachable {
+                return Ok(expected);
+            }
+            return Err(ValidationErrorKind::TypeMismatch {
+                expected,
+                found: None,
+            });
+        }
+        match (self.stack.pop().unwrap(), expected) {
+            (Some(found), Some(expected)) if found != expected => {
+                Err(ValidationErrorKind::TypeMismatch {
+                    expected: Some(expected),
+                    found: Some(found),
+                })
+            }
+            (found, expected) => Ok(found.or(expected)),
+        }
+    }
+
+    fn pop_all(&mut self, types: &[NumType]) -> Result<(), ValidationErrorKind> {
+        for typ in types.iter().rev() {
+            self.pop(Some(*typ))?;
+        }
+        Ok(())
+    }
+
+    fn unreachable(&mut self) {
+        let frame = self.frames.last_mut().unwrap();
+        self.stack.truncate(frame.height);
+        frame.unreachable = true;
+    }
+
+    fn label(&self, depth: u32) -> Result<Vec<NumType>, ValidationErrorKind> {
+        let frame = (self.frames.len())
+            .checked_sub(depth as usize + 1)
+            .ok_or(ValidationErrorKind::UnknownLabel(depth))?;
+        Ok(self.frames[frame].labels.clone())
+    }
+
+    // Validates `body` as a block taking `params` from the stack and leaving `results`
+    fn block(
+        &mut self,
+        params: &[NumType],
+        results: &[NumType],
+        labels: Vec<NumType>,
+        body: &Expression,
+    ) -> Result<(), ValidationErrorKind> {
+        self.pop_all(params)?;
+        self.frame(params, results, labels, false, body)
+    }
+
+    // Validates `body` as a block starting with `values` on the stack, which it didn't take from
+    // the block around it
+    fn frame(
+        &mut self,
+        values: &[NumType],
+        results: &[NumType],
+        labels: Vec<NumType>,
+        catch: bool,
+        body: &Expression,
+    ) -> Result<(), ValidationErrorKind> {
+        self.frames.push(Frame {
+            labels,
+            results: results.to_vec(),
+            height: self.stack.len(),
+            unreachable: false,
+            catch,
+        });
+        self.stack.extend(values.iter().copied().map(Some));
+        for instruction in body.instructions.iter() {
+            self.instruction(instruction)?;
+        }
+        self.end()
+    }
+
+    fn end(&mut self) -> Result<(), ValidationErrorKind> {
+        let results = self.frames.last().unwrap().results.clone();
+        self.pop_all(&results)?;
+        let frame = self.frames.pop().unwrap();
+        if self.stack.len() > frame.height {
+            return Err(ValidationErrorKind::ExtraValues(
+                self.stack.len() - frame.height,
+            ));
+        }
+        self.stack.extend(results.into_iter().map(Some));
+        Ok(())
+    }
+
+    fn block_type(
+        &self,
+        typ: &BlockType,
+    ) -> Result<(Vec<NumType>, Vec<NumType>), ValidationErrorKind> {
+        match typ {
+            BlockType::Empty => Ok((Vec::new(), Vec::new())),
+            BlockType::Value(typ) => Ok((Vec::new(), vec![*typ])),
+            BlockType::Type(index) => {
+                let typ = (self.validator.module.types)
+                    .get(*index as usize)
+                    .ok_or(ValidationErrorKind::UnknownType(*index))?;
+                Ok((typ.args.clone(), typ.ret.clone()))
+            }
+        }
+    }
+
+    fn local(&self, index: u32) -> Result<NumType, ValidationErrorKind> {
+        (self.locals.get(index as usize).copied()).ok_or(ValidationErrorKind::UnknownLocal(index))
+    }
+
+    fn global(&self, index: u32) -> Result<&'a GlobalType, ValidationErrorKind> {
+        (self.validator.globals.get(index as usize).copied())
+            .ok_or(ValidationErrorKind::UnknownGlobal(index))
+    }
+
+    fn instruction(&mut self, instruction: &Instruction) -> Result<(), ValidationErrorKind> {
+        self.instruction += 1;
+        match instruction {
+            Instruction::I32Const(_) => self.push(NumType::I32),
+            Instruction::I64Const(_) => self.push(NumType::I64),
+            Instruction::F32Const(_) => self.push(NumType::F32),
+            Instruction::F64Const(_) => self.push(NumType::F64),
+            Instruction::IntegerOp(op) => {
+                let typ = match op.typ {
+                    IntegerType::I32 => NumType::I32,
+                    IntegerType::I64 => NumType::I64,
+                };
+                match op.op {
+                    IntegerOpType::Clz | IntegerOpType::Ctz | IntegerOpType::Popcnt => {
+                        self.pop(Some(typ))?;
+                        self.push(typ);
+                    }
+                    IntegerOpType::Eqz => {
+                        self.pop(Some(typ))?;
+                        self.push(NumType::I32);
+                    }
+                    IntegerOpType::Eq
+                    | IntegerOpType::Ne
+                    | IntegerOpType::LtS
+                    | IntegerOpType::LtU
+                    | IntegerOpType::GtS
+                    | IntegerOpType::GtU
+                    | IntegerOpType::LeS
+                    | IntegerOpType::LeU
+                    | IntegerOpType::GeS
+                    | IntegerOpType::GeU => {
+                        self.pop_all(&[typ, typ])?;
+                        self.push(NumType::I32);
+                    }
+                    _ => {
+                        self.pop_all(&[typ, typ])?;
+                        self.push(typ);
+                    }
+                }
+            }
+            Instruction::FloatOp(op) => {
+                let typ = match op.typ {
+                    FloatType::F32 => NumType::F32,
+                    FloatType::F64 => NumType::F64,
+                };
+                match op.op {
+                    FloatOpType::Abs
+                    | FloatOpType::Neg
+                    | FloatOpType::Sqrt
+                    | FloatOpType::Ceil
+                    | FloatOpType::Floor
+                    | FloatOpType::Trunc
+                    | FloatOpType::Nearest => {
+                        self.pop(Some(typ))?;
+                        self.push(typ);
+                    }
+                    FloatOpType::Eq
+                    | FloatOpType::Ne
+                    | FloatOpType::Lt
+                    | FloatOpType::Le
+                    | FloatOpType::Ge
+                    | FloatOpType::Gt => {
+                        self.pop_all(&[typ, typ])?;
+                        self.push(NumType::I32);
+                    }
+                    _ => {
+                        self.pop_all(&[typ, typ])?;
+                        self.push(typ);
+                    }
+                }
+            }
+            Instruction::ConvertOp(op) => {
+                let (from, to) = op.signature();
+                self.pop(Some(from))?;
+                self.push(to);
+            }
+            // Values on the stack are only ever numbers here
+            Instruction::RefOp(_) => {
+                return Err(ValidationErrorKind::Unsupported("reference instructions"))
+            }
+            Instruction::ParametricOp(ParametricOp::Drop) => {
+                self.pop(None)?;
+            }
+            Instruction::ParametricOp(ParametricOp::Select) => {
+                self.pop(Some(NumType::I32))?;
+                let first = self.pop(None)?;
+                let second = self.pop(first)?;
+                self.stack.push(first.or(second));
+            }
+            Instruction::VariableOp(op) => match op {
+                VariableOp::LocalGet(index) => {
+                    let typ = self.local(*index)?;
+                    self.push(typ);
+                }
+                VariableOp::LocalSet(index) => {
+                    let typ = self.local(*index)?;
+                    self.pop(Some(typ))?;
+                }
+                VariableOp::LocalTee(index) => {
+                    let typ = self.local(*index)?;
+                    self.pop(Some(typ))?;
+                    self.push(typ);
+                }
+                VariableOp::GlobalGet(index) => {
+                    let global = self.global(*index)?;
+                    self.push(global.typ);
+                }
+                VariableOp::GlobalSet(index) => {
+                    let global = self.global(*index)?;
+                    if !global.mutable {
+                        return Err(ValidationErrorKind::ImmutableGlobal(*index));
+                    }
+                    self.pop(Some(global.typ))?;
+                }
+            },
+            Instruction::MemoryOp(op) => {
+                if self.validator.memories.is_empty() {
+                    return Err(ValidationErrorKind::UnknownMemory(0));
+                }
+                match op.access() {
+                    Some((typ, size, store)) => {
+                        let align = op.memarg().unwrap().align;
+                        let max = size.trailing_zeros();
+                        if align > max {
+                            return Err(ValidationErrorKind::AlignmentTooLarge { align, max });
+                        }
+                        if store {
+                            self.pop_all(&[NumType::I32, typ])?;
+                        } else {
+                            self.pop(Some(NumType::I32))?;
+                            self.push(typ);
+                        }
+                    }
+                    None => match op {
+                        MemoryOp::MemorySize => self.push(NumType::I32),
+                        MemoryOp::MemoryGrow => {
+                            self.pop(Some(NumType::I32))?;
+                            self.push(NumType::I32);
+                        }
+                        _ => self.pop_all(&[NumType::I32; 3])?,
+                    },
+                }
+            }
+            Instruction::ControlOp(op) => self.control(op)?,
+        }
+        Ok(())
+    }
+
+    fn control(&mut self, op: &ControlOp) -> Result<(), ValidationErrorKind> {
+        match op {
+            ControlOp::Unreachable => self.unreachable(),
+            ControlOp::Nop => {}
+            ControlOp::Block { typ, body } => {
+                let (params, results) = self.block_type(typ)?;
+                self.block(&params, &results, results.clone(), body)?;
+            }
+            ControlOp::Loop { typ, body } => {
+                let (params, results) = self.block_type(typ)?;
+                self.block(&params, &results, params.clone(), body)?;
+            }
+            ControlOp::If {
+                typ,
+                then,
+                otherwise,
+            } => {
+                let (params, results) = self.block_type(typ)?;
+                self.pop(Some(NumType::I32))?;
+                self.block(&params, &results, results.clone(), then)?;
+                // The else branch starts from the same params
+                self.pop_all(&results)?;
+                self.stack.extend(params.iter().copied().map(Some));
+                match otherwise {
+                    Some(otherwise) => {
+                        self.block(&params, &results, results.clone(), otherwise)?;
+                    }
+                    None if params != results => return Err(ValidationErrorKind::MissingElse),
+                    None => {}
+                }
+            }
+            ControlOp::Br(depth) => {
+                let labels = self.label(*depth)?;
+                self.pop_all(&labels)?;
+                self.unreachable();
+            }
+            ControlOp::BrIf(depth) => {
+                let labels = self.label(*depth)?;
+                self.pop(Some(NumType::I32))?;
+                self.pop_all(&labels)?;
+                self.stack.extend(labels.into_iter().map(Some));
+            }
+            ControlOp::BrTable { labels, default } => {
+                self.pop(Some(NumType::I32))?;
+                let default = self.label(*default)?;
+                for depth in labels.iter() {
+                    let label = self.label(*depth)?;
+                    if label.len() != default.len() {
+                        return Err(ValidationErrorKind::LabelArityMismatch {
+                            expected: default.len(),
+                            found: label.len(),
+                        });
+                    }
+                    // Every target has to accept the values, which are left for the next one
+                    let mut values = Vec::new();
+                    for typ in label.iter().rev() {
+                        values.push(self.pop(Some(*typ))?);
+                    }
+                    self.stack.extend(values.into_iter().rev());
+                }
+                self.pop_all(&default)?;
+                self.unreachable();
+            }
+            ControlOp::Return => {
+                let ret = self.ret.clone();
+                self.pop_all(&ret)?;
+                self.unreachable();
+            }
+            ControlOp::Call(index) => {
+                let typ = self
+                    .validator
+                    .function_type(*index)
+                    .ok_or(ValidationErrorKind::UnknownFunction(*index))?;
+                self.pop_all(&typ.args)?;
+                self.stack.extend(typ.ret.iter().copied().map(Some));
+            }
+            ControlOp::CallIndirect { type_idx, table } => {
+                match self.validator.tables.get(*table as usize) {
+                    Some(table) if table.element == RefType::FuncRef => {}
+                    Some(_) => return Err(ValidationErrorKind::ElementsNotFunctions(*table)),
+                    None => return Err(ValidationErrorKind::UnknownTable(*table)),
+                }
+                let typ = (self.validator.module.types)
+                    .get(*type_idx as usize)
+                    .ok_or(ValidationErrorKind::UnknownType(*type_idx))?;
+                self.pop(Some(NumType::I32))?;
+                self.pop_all(&typ.args)?;
+                self.stack.extend(typ.ret.iter().copied().map(Some));
+            }
+            ControlOp::Try {
+                typ,
+                body,
+                catches,
+                catch_all,
+            } => {
+                let (params, results) = self.block_type(typ)?;
+                self.block(&params, &results, results.clone(), body)?;
+                // Each handler starts from the values thrown instead
+                for catch in catches.iter() {
+                    let typ = (self.validator.tag_type(catch.tag))
+                        .ok_or(ValidationErrorKind::UnknownTag(catch.tag))?;
+                    self.pop_all(&results)?;
+                    self.frame(&typ.args, &results, results.clone(), true, &catch.body)?;
+                }
+                if let Some(catch_all) = catch_all {
+                    self.pop_all(&results)?;
+                    self.frame(&[], &results, results.clone(), true, catch_all)?;
+                }
+            }
+            ControlOp::Throw(tag) => {
+                let typ =
+                    (self.validator.tag_type(*tag)).ok_or(ValidationErrorKind::UnknownTag(*tag))?;
+                self.pop_all(&typ.args)?;
+                self.unreachable();
+            }
+            ControlOp::Rethrow(depth) => {
+                let frame = (self.frames.len())
+                    .checked_sub(*depth as usize + 1)
+                    .ok_or(ValidationErrorKind::UnknownLabel(*depth))?;
+                if !self.frames[frame].catch {
+                    return Err(ValidationErrorKind::NotACatch(*depth));
+                }
+                self.unreachable();
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MemoryOp {
+    /// The type of value a load or store accesses, how many bytes of memory it covers and whether
+    /// it's a store.
+    pub fn access(&self) -> Option<(NumType, u32, bool)> {
+        use MemoryOp::*;
+        Some(match self {
+            I32Load(_) => (NumType::I32, 4, false),
+            I64Load(_) => (NumType::I64, 8, false),
+            F32Load(_) => (NumType::F32, 4, false),
+            F64Load(_) => (NumType::F64, 8, false),
+            I32Load8S(_) | I32Load8U(_) => (NumType::I32, 1, false),
+            I32Load16S(_) | I32Load16U(_) => (NumType::I32, 2, false),
+            I64Load8S(_) | I64Load8U(_) => (NumType::I64, 1, false),
+            I64Load16S(_) | I64Load16U(_) => (NumType::I64, 2, false),
+            I64Load32S(_) | I64Load32U(_) => (NumType::I64, 4, false),
+            I32Store(_) => (NumType::I32, 4, true),
+            I64Store(_) => (NumType::I64, 8, true),
+            F32Store(_) => (NumType::F32, 4, true),
+            F64Store(_) => (NumType::F64, 8, true),
+            I32Store8(_) => (NumType::I32, 1, true),
+            I32Store16(_) => (NumType::I32, 2, true),
+            I64Store8(_) => (NumType::I64, 1, true),
+            I64Store16(_) => (NumType::I64, 2, true),
+            I64Store32(_) => (NumType::I64, 4, true),
+            MemorySize | MemoryGrow | MemoryCopy | MemoryFill => return None,
+        })
+    }
+}
+
+impl ConvertOp {
+    /// The type of the operand and of the result.
+    pub fn signature(&self) -> (NumType, NumType) {
+        use ConvertOp::*;
+        use NumType::*;
+        match self {
+            I32Extend8S | I32Extend16S => (I32, I32),
+            I64Extend8S | I64Extend16S | I64Extend32S => (I64, I64),
+            I32WrapI64 => (I64, I32),
+            I64ExtendI32S | I64ExtendI32U => (I32, I64),
+            I32TruncF32S | I32TruncF32U | I32TruncSatF32S | I32TruncSatF32U => (F32, I32),
+            I32TruncF64S | I32TruncF64U | I32TruncSatF64S | I32TruncSatF64U => (F64, I32),
+            I64TruncF32S | I64TruncF32U | I64TruncSatF32S | I64TruncSatF32U => (F32, I64),
+            I64TruncF64S | I64TruncF64U | I64TruncSatF64S | I64TruncSatF64U => (F64, I64),
+            F32DemoteF64 => (F64, F32),
+            F64PromoteF32 => (F32, F64),
+            F32ConvertI32S | F32ConvertI32U => (I32, F32),
+            F32ConvertI64S | F32ConvertI64U => (I64, F32),
+            F64ConvertI32S | F64ConvertI32U => (I32, F64),
+            F64ConvertI64S | F64ConvertI64U => (I64, F64),
+            I32ReinterpretF32 => (F32, I32),
+            I64ReinterpretF64 => (F64, I64),
+            F32ReinterpretI32 => (I32, F32),
+            F64ReinterpretI64 => (I64, F64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wasm::wat::parser::parse_module;
+
+    // A module loading from memory with the given alignment
+    fn load_aligned(align: u32) -> WasmModule {
+        let source = "(module (memory 1) (func (result i32) (i32.load (i32.const 0))))";
+        let mut module = parse_module(source).unwrap();
+        let instructions = &mut module.functions[0].body.instructions;
+        let Some(Instruction::MemoryOp(MemoryOp::I32Load(arg))) = instructions.last_mut() else {
+            panic!("expected a load, found {:?}", instructions);
+        };
+        arg.align = align;
+        module
+    }
+
+    #[test]
+    fn alignment_too_large() {
+        assert_eq!(load_aligned(2).validate(), Ok(()));
+
+        let errors = load_aligned(3).validate().unwrap_err();
+        assert_eq!(
+            errors[0].kind,
+            ValidationErrorKind::AlignmentTooLarge { align: 3, max: 2 }
+        );
+        assert!(errors[0]
+            .to_string()
+            .contains("alignment of 8 bytes is larger than the 4 bytes accessed"));
+
+        // Alignments too big to shift by are printed as powers of two
+        for align in [64, u32::MAX] {
+            let errors = load_aligned(align).validate().unwrap_err();
+            let message = errors[0].to_string();
+            assert!(
+                message.contains(&format!("alignment of 2^{} bytes", align)),
+                "{}",
+                message
+            );
+        }
+    }
+
+    // The errors found validating the module written in `source`
+    fn errors(source: &str) -> Vec<ValidationError> {
+        parse_module(source).unwrap().validate().unwrap_err()
+    }
+
+    fn in_function(instruction: Option<usize>) -> Location {
+        Location::Function {
+            index: 0,
+            instruction,
+        }
+    }
+
+    #[test]
+    fn operand_type_mismatch() {
+        let errors = errors("(module (func (result i32) (i32.add (i32.const 1) (i64.const 2))))");
+        assert_eq!(
+            errors,
+            [ValidationErrorKind::TypeMismatch {
+                expected: Some(NumType::I32),
+                found: Some(NumType::I64),
+            }
+            .at(in_function(Some(2)))]
+        );
+        assert_eq!(
+            errors[0].to_string(),
+            "expected `i32`, found `i64` in function 0 at instruction 2"
+        );
+    }
+
+    #[test]
+    fn unknown_indices() {
+        assert_eq!(
+            errors("(module (func (call 3)))"),
+            [ValidationErrorKind::UnknownFunction(3).at(in_function(Some(0)))]
+        );
+        assert_eq!(
+            errors("(module (func (param i32) (drop (local.get 1))))"),
+            [ValidationErrorKind::UnknownLocal(1).at(in_function(Some(0)))]
+        );
+        assert_eq!(
+            errors("(module (global i32 (i32.const 0)) (func (drop (global.get 1))))"),
+            [ValidationErrorKind::UnknownGlobal(1).at(in_function(Some(0)))]
+        );
+    }
+
+    #[test]
+    fn branch_too_deep() {
+        // The function body and the block are labels 0 and 1
+        assert_eq!(
+            parse_module("(module (func (block (br 1))))")
+                .unwrap()
+                .validate(),
+            Ok(())
+        );
+        assert_eq!(
+            errors("(module (func (block (br 2))))"),
+            [ValidationErrorKind::UnknownLabel(2).at(in_function(Some(1)))]
+        );
+    }
+
+    #[test]
+    fn wrong_result_type() {
+        assert_eq!(
+            errors("(module (func (result i32) (i64.const 1)))"),
+            [ValidationErrorKind::TypeMismatch {
+                expected: Some(NumType::I32),
+                found: Some(NumType::I64),
+            }
+            .at(in_function(Some(0)))]
+        );
+        assert_eq!(
+            errors("(module (func (result i32)))"),
+            [ValidationErrorKind::TypeMismatch {
+                expected: Some(NumType::I32),
+                found: None,
+            }
+            .at(in_function(None))]
+        );
+        assert_eq!(
+            errors("(module (func (i32.const 1)))"),
+            [ValidationErrorKind::ExtraValues(1).at(in_function(Some(0)))]
+        );
+    }
+}
@@ -0,0 +1,178 @@
+//! The WebAssembly text format.
+
+use std::fmt;
+
+use super::*;
+
+pub mod parser;
+pub mod printer;
+
+impl fmt::Display for NumType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NumType::I32 => write!(f, "i32"),
+            NumType::I64 => write!(f, "i64"),
+            NumType::F32 => write!(f, "f32"),
+            NumType::F64 => write!(f, "f64"),
+        }
+    }
+}
+
+impl fmt::Display for IntegerType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IntegerType::I32 => write!(f, "i32"),
+            IntegerType::I64 => write!(f, "i64"),
+        }
+    }
+}
+
+impl fmt::Display for FloatType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FloatType::F32 => write!(f, "f32"),
+            FloatType::F64 => write!(f, "f64"),
+        }
+    }
+}
+
+impl fmt::Display for RefType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RefType::FuncRef => write!(f, "funcref"),
+            RefType::ExternRef => write!(f, "externref"),
+        }
+    }
+}
+
+/// Whether `c` can be part of an atom, like a keyword, number or `$name`.
+pub fn is_idchar(c: char) -> bool {
+    c.is_ascii_graphic() && !matches!(c, '"' | ',' | ';' | '(' | ')' | '[' | ']' | '{' | '}')
+}
+
+// The names of the operators, which follow the type in an instruction, eg. `i32.add`
+
+pub const INTEGER_OPS: &[(IntegerOpType, &str)] = &[
+    (IntegerOpType::Clz, "clz"),
+    (IntegerOpType::Ctz, "ctz"),
+    (IntegerOpType::Popcnt, "popcnt"),
+    (IntegerOpType::Eqz, "eqz"),
+    (IntegerOpType::Eq, "eq"),
+    (IntegerOpType::Ne, "ne"),
+    (IntegerOpType::LtS, "lt_s"),
+    (IntegerOpType::LtU, "lt_u"),
+    (IntegerOpType::GtS, "gt_s"),
+    (IntegerOpType::GtU, "gt_u"),
+    (IntegerOpType::LeS, "le_s"),
+    (IntegerOpType::LeU, "le_u"),
+    (IntegerOpType::GeS, "ge_s"),
+    (IntegerOpType::GeU, "ge_u"),
+    (IntegerOpType::Add, "add"),
+    (IntegerOpType::Sub, "sub"),
+    (IntegerOpType::Mul, "mul"),
+    (IntegerOpType::DivS, "div_s"),
+    (IntegerOpType::DivU, "div_u"),
+    (IntegerOpType::RemS, "rem_s"),
+    (IntegerOpType::RemU, "rem_u"),
+    (IntegerOpType::And, "and"),
+    (IntegerOpType::Or, "or"),
+    (IntegerOpType::Xor, "xor"),
+    (IntegerOpType::Shl, "shl"),
+    (IntegerOpType::ShrU, "shr_u"),
+    (IntegerOpType::ShrS, "shr_s"),
+    (IntegerOpType::Rotl, "rotl"),
+    (IntegerOpType::Rotr, "rotr"),
+];
+
+pub const FLOAT_OPS: &[(FloatOpType, &str)] = &[
+    (FloatOpType::Abs, "abs"),
+    (FloatOpType::Neg, "neg"),
+    (FloatOpType::Sqrt, "sqrt"),
+    (FloatOpType::Ceil, "ceil"),
+    (FloatOpType::Floor, "floor"),
+    (FloatOpType::Trunc, "trunc"),
+    (FloatOpType::Nearest, "nearest"),
+    (FloatOpType::Add, "add"),
+    (FloatOpType::Sub, "sub"),
+    (FloatOpType::Mul, "mul"),
+    (FloatOpType::Div, "div"),
+    (FloatOpType::Min, "min"),
+    (FloatOpType::Max, "max"),
+    (FloatOpType::Copysign, "copysign"),
+    (FloatOpType::Eq, "eq"),
+    (FloatOpType::Ne, "ne"),
+    (FloatOpType::Lt, "lt"),
+    (FloatOpType::Le, "le"),
+    (FloatOpType::Ge, "ge"),
+    (FloatOpType::Gt, "gt"),
+];
+
+pub const CONVERT_OPS: &[(ConvertOp, &str)] = &[
+    (ConvertOp::I32Extend8S, "i32.extend8_s"),
+    (ConvertOp::I32Extend16S, "i32.extend16_s"),
+    (ConvertOp::I64Extend8S, "i64.extend8_s"),
+    (ConvertOp::I64Extend16S, "i64.extend16_s"),
+    (ConvertOp::I64Extend32S, "i64.extend32_s"),
+    (ConvertOp::I32WrapI64, "i32.wrap_i64"),
+    (ConvertOp::I64ExtendI32S, "i64.extend_i32_s"),
+    (ConvertOp::I64ExtendI32U, "i64.extend_i32_u"),
+    (ConvertOp::I32TruncF32S, "i32.trunc_f32_s"),
+    (ConvertOp::I32TruncF32U, "i32.trunc_f32_u"),
+    (ConvertOp::I32TruncF64S, "i32.trunc_f64_s"),
+    (ConvertOp::I32TruncF64U, "i32.trunc_f64_u"),
+    (ConvertOp::I64TruncF32S, "i64.trunc_f32_s"),
+    (ConvertOp::I64TruncF32U, "i64.trunc_f32_u"),
+    (ConvertOp::I64TruncF64S, "i64.trunc_f64_s"),
+    (ConvertOp::I64TruncF64U, "i64.trunc_f64_u"),
+    (ConvertOp::I32TruncSatF32S, "i32.trunc_sat_f32_s"),
+    (ConvertOp::I32TruncSatF32U, "i32.trunc_sat_f32_u"),
+    (ConvertOp::I32TruncSatF64S, "i32.trunc_sat_f64_s"),
+    (ConvertOp::I32TruncSatF64U, "i32.trunc_sat_f64_u"),
+    (ConvertOp::I64TruncSatF32S, "i64.trunc_sat_f32_s"),
+    (ConvertOp::I64TruncSatF32U, "i64.trunc_sat_f32_u"),
+    (ConvertOp::I64TruncSatF64S, "i64.trunc_sat_f64_s"),
+    (ConvertOp::I64TruncSatF64U, "i64.trunc_sat_f64_u"),
+    (ConvertOp::F32DemoteF64, "f32.demote_f64"),
+    (ConvertOp::F64PromoteF32, "f64.promote_f32"),
+    (ConvertOp::F32ConvertI32S, "f32.convert_i32_s"),
+    (ConvertOp::F32ConvertI32U, "f32.convert_i32_u"),
+    (ConvertOp::F32ConvertI64S, "f32.convert_i64_s"),
+    (ConvertOp::F32ConvertI64U, "f32.convert_i64_u"),
+    (ConvertOp::F64ConvertI32S, "f64.convert_i32_s"),
+    (ConvertOp::F64ConvertI32U, "f64.convert_i32_u"),
+    (ConvertOp::F64ConvertI64S, "f64.convert_i64_s"),
+    (ConvertOp::F64ConvertI64U, "f64.convert_i64_u"),
+    (ConvertOp::I32ReinterpretF32, "i32.reinterpret_f32"),
+    (ConvertOp::I64ReinterpretF64, "i64.reinterpret_f64"),
+    (ConvertOp::F32ReinterpretI32, "f32.reinterpret_i32"),
+    (ConvertOp::F64ReinterpretI64, "f64.reinterpret_i64"),
+];
+
+pub type MemoryOpConstructor = fn(MemArg) -> MemoryOp;
+
+/// The loads and stores, with the number of bytes they access, which is their default alignment.
+pub const MEMORY_OPS: &[(MemoryOpConstructor, &str, u32)] = &[
+    (MemoryOp::I32Load, "i32.load", 4),
+    (MemoryOp::I64Load, "i64.load", 8),
+    (MemoryOp::F32Load, "f32.load", 4),
+    (MemoryOp::F64Load, "f64.load", 8),
+    (MemoryOp::I32Load8S, "i32.load8_s", 1),
+    (MemoryOp::I32Load8U, "i32.load8_u", 1),
+    (MemoryOp::I32Load16S, "i32.load16_s", 2),
+    (MemoryOp::I32Load16U, "i32.load16_u", 2),
+    (MemoryOp::I64Load8S, "i64.load8_s", 1),
+    (MemoryOp::I64Load8U, "i64.load8_u", 1),
+    (MemoryOp::I64Load16S, "i64.load16_s", 2),
+    (MemoryOp::I64Load16U, "i64.load16_u", 2),
+    (MemoryOp::I64Load32S, "i64.load32_s", 4),
+    (MemoryOp::I64Load32U, "i64.load32_u", 4),
+    (MemoryOp::I32Store, "i32.store", 4),
+    (MemoryOp::I64Store, "i64.store", 8),
+    (MemoryOp::F32Store, "f32.store", 4),
+    (MemoryOp::F64Store, "f64.store", 8),
+    (MemoryOp::I32Store8, "i32.store8", 1),
+    (MemoryOp::I32Store16, "i32.store16", 2),
+    (MemoryOp::I64Store8, "i64.store8", 1),
+    (MemoryOp::I64Store16, "i64.store16", 2),
+    (MemoryOp::I64Store32, "i64.store32", 4),
+];
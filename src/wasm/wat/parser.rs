@@ -0,0 +1,1870 @@
+use std::{collections::HashMap, fmt};
+
+use crate::lexer::span::{Span, Spanned};
+
+use super::*;
+
+pub type WatError = Spanned<WatErrorKind>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WatErrorKind {
+    UnknownCharacter(char),
+    UnterminatedString,
+    UnterminatedComment,
+    InvalidEscape(char),
+    UnbalancedParen,
+    // `found` is the text of the token, or `None` at the end of a list
+    Expected {
+        expected: &'static str,
+        found: Option<String>,
+    },
+    UnknownInstruction(String),
+    UnknownField(String),
+    UnknownName {
+        kind: &'static str,
+        name: String,
+    },
+    DuplicateName {
+        kind: &'static str,
+        name: String,
+    },
+    // A number that is malformed or out of range for where it is used
+    InvalidNumber(String),
+    // Imports take the first indices, so have to come before any definitions of their kind
+    ImportAfterDefinition(&'static str),
+    // Valid wat that `WasmModule` can't represent, eg. passive data segments
+    Unsupported(&'static str),
+}
+
+impl WatErrorKind {
+    pub fn at(self, span: Span) -> WatError {
+        Spanned::new(self, span)
+    }
+}
+
+impl fmt::Display for WatErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WatErrorKind::UnknownCharacter(c) => {
+                write!(f, "unknown character `{}`", c.escape_debug())
+            }
+            WatErrorKind::UnterminatedString => write!(f, "unterminated string"),
+            WatErrorKind::UnterminatedComment => write!(f, "unterminated block comment"),
+            WatErrorKind::InvalidEscape(c) => write!(f, "invalid escape sequence `\\{}`", c),
+            WatErrorKind::UnbalancedParen => write!(f, "unbalanced parenthesis"),
+            WatErrorKind::Expected {
+                expected,
+                found: Some(found),
+            } => write!(f, "expected {}, found `{}`", expected, found),
+            WatErrorKind::Expected {
+                expected,
+                found: None,
+            } => write!(f, "expected {}, found `)`", expected),
+            WatErrorKind::UnknownInstruction(name) => write!(f, "unknown instruction `{}`", name),
+            WatErrorKind::UnknownField(name) => write!(f, "unknown module field `{}`", name),
+            WatErrorKind::UnknownName { kind, name } => write!(f, "unknown {} `{}`", kind, name),
+            WatErrorKind::DuplicateName { kind, name } => {
+                write!(f, "{} `{}` is defined more than once", kind, name)
+            }
+            WatErrorKind::InvalidNumber(text) => write!(f, "invalid number `{}`", text),
+            WatErrorKind::ImportAfterDefinition(kind) => {
+                write!(f, "{} imports must come before {} definitions", kind, kind)
+            }
+            WatErrorKind::Unsupported(what) => write!(f, "{} are not supported", what),
+        }
+    }
+}
+
+/// Parses a module in the WebAssembly text format, either a `(module ...)` or just its fields.
+pub fn parse_module(source: &str) -> Result<WasmModule, WatError> {
+    let sexprs = read(source)?;
+    let mut parser = ModuleParser::default();
+    let fields = match sexprs.as_slice() {
+        [Sexpr::List(list)] if list.head() == Some("module") => {
+            let mut cursor = list.cursor();
+            cursor.next();
+            parser.module.names.module = cursor.id().map(name);
+            cursor.rest()
+        }
+        _ => &sexprs,
+    };
+    parser.parse(fields)
+}
+
+// Tokens
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    // Anything made of idchars, eg. `i32.add`, `offset=4`, `-1.5` or `nan:0x1`
+    Atom(String),
+    // Strings can hold any bytes, not just UTF-8
+    String(Vec<u8>),
+}
+
+struct Lexer<'a> {
+    source: &'a str,
+    position: usize,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn new(source: &'a str) -> Self {
+        Self {
+            source,
+            position: 0,
+            line: 1,
+            column: 1,
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.source[self.position..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn span_from(&self, start: usize, line: usize, column: usize) -> Span {
+        Span::new(start, self.position, line, column)
+    }
+
+    // Skips whitespace, `;;` line comments and nested `(; ;)` block comments
+    fn skip_trivia(&mut self) -> Result<(), WatError> {
+        loop {
+            let rest = &self.source[self.position..];
+            if rest.starts_with(";;") {
+                while self.peek().is_some_and(|c| c != '\n') {
+                    self.bump();
+                }
+            } else if rest.starts_with("(;") {
+                let (start, line, column) = (self.position, self.line, self.column);
+                let mut depth = 0;
+                loop {
+                    let rest = &self.source[self.position..];
+                    if rest.starts_with("(;") {
+                        depth += 1;
+                        self.bump();
+                    } else if rest.starts_with(";)") {
+                        depth -= 1;
+                        self.bump();
+                    }
+                    if self.bump().is_none() {
+                        return Err(WatErrorKind::UnterminatedComment
+                            .at(self.span_from(start, line, column)));
+                    }
+                    if depth == 0 {
+                        break;
+                    }
+                }
+            } else if self.peek().is_some_and(|c| c.is_whitespace()) {
+                self.bump();
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn next_token(&mut self) -> Result<Option<Spanned<Token>>, WatError> {
+        self.skip_trivia()?;
+        let (start, line, column) = (self.position, self.line, self.column);
+        let token = match self.bump() {
+            None => return Ok(None),
+            Some('(') => Token::LParen,
+            Some(')') => Token::RParen,
+            Some('"') => Token::String(self.string(start, line, column)?),
+            Some(c) if is_idchar(c) => {
+                while self.peek().is_some_and(is_idchar) {
+                    self.bump();
+                }
+                Token::Atom(self.source[start..self.position].to_string())
+            }
+            Some(c) => {
+                return Err(
+                    WatErrorKind::UnknownCharacter(c).at(self.span_from(start, line, column))
+                )
+            }
+        };
+        Ok(Some(Spanned::new(
+            token,
+            self.span_from(start, line, column),
+        )))
+    }
+
+    fn string(&mut self, start: usize, line: usize, column: usize) -> Result<Vec<u8>, WatError> {
+        let mut bytes = Vec::new();
+        loop {
+            let escape_start = (self.position, self.line, self.column);
+            match self.bump() {
+                None => {
+                    return Err(
+                        WatErrorKind::UnterminatedString.at(self.span_from(start, line, column))
+                    )
+                }
+                Some('"') => return Ok(bytes),
+                Some('\\') => {
+                    let invalid = |lexer: &Self, c| {
+                        let (start, line, column) = escape_start;
+                        WatErrorKind::InvalidEscape(c).at(lexer.span_from(start, line, column))
+                    };
+                    match self.bump() {
+                        Some('n') => bytes.push(b'\n'),
+                        Some('r') => bytes.push(b'\r'),
+                        Some('t') => bytes.push(b'\t'),
+                        Some('"') => bytes.push(b'"'),
+                        Some('\'') => bytes.push(b'\''),
+                        Some('\\') => bytes.push(b'\\'),
+                        Some('u') => {
+                            if self.bump() != Some('{') {
+                                return Err(invalid(self, 'u'));
+                            }
+                            let digits_start = self.position;
+                            while self
+                                .peek()
+                                .is_some_and(|c| c.is_ascii_hexdigit() || c == '_')
+                            {
+                                self.bump();
+                            }
+                            let digits = self.source[digits_start..self.position].replace('_', "");
+                            let c = u32::from_str_radix(&digits, 16)
+                                .ok()
+                                .and_then(char::from_u32);
+                            match (c, self.bump()) {
+                                (Some(c), Some('}')) => {
+                                    bytes.extend(c.encode_utf8(&mut [0; 4]).as_bytes())
+                                }
+                                _ => return Err(invalid(self, 'u')),
+                            }
+                        }
+                        // A byte given as two hex digits, eg. `\0a`
+                        Some(high) if high.is_ascii_hexdigit() => match self.bump() {
+                            Some(low) if low.is_ascii_hexdigit() => {
+                                let byte =
+                                    high.to_digit(16).unwrap() * 16 + low.to_digit(16).unwrap();
+                                bytes.push(byte as u8);
+                            }
+                            _ => return Err(invalid(self, high)),
+                        },
+                        Some(c) => return Err(invalid(self, c)),
+                        None => {
+                            return Err(WatErrorKind::UnterminatedString
+                                .at(self.span_from(start, line, column)))
+                        }
+                    }
+                }
+                Some(c) => bytes.extend(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            }
+        }
+    }
+}
+
+// S-expressions
+
+#[derive(Debug, Clone)]
+enum Sexpr {
+    List(List),
+    Atom(Spanned<String>),
+    String(Spanned<Vec<u8>>),
+}
+
+#[derive(Debug, Clone)]
+struct List {
+    items: Vec<Sexpr>,
+    open: Span,
+    // The closing paren, which errors about missing items point at
+    close: Span,
+}
+
+impl Sexpr {
+    fn span(&self) -> Span {
+        match self {
+            Sexpr::List(list) => list.open.to(&list.close),
+            Sexpr::Atom(atom) => atom.span,
+            Sexpr::String(string) => string.span,
+        }
+    }
+
+    fn text(&self) -> String {
+        match self {
+            Sexpr::List(list) => format!("({} ...)", list.head().unwrap_or("")),
+            Sexpr::Atom(atom) => atom.node.clone(),
+            Sexpr::String(string) => format!("\"{}\"", String::from_utf8_lossy(&string.node)),
+        }
+    }
+
+    fn as_list(&self) -> Option<&List> {
+        match self {
+            Sexpr::List(list) => Some(list),
+            _ => None,
+        }
+    }
+}
+
+impl List {
+    // The keyword the list starts with, eg. `func`
+    fn head(&self) -> Option<&str> {
+        match self.items.first() {
+            Some(Sexpr::Atom(atom)) => Some(&atom.node),
+            _ => None,
+        }
+    }
+
+    fn cursor(&self) -> Cursor<'_> {
+        Cursor {
+            items: &self.items,
+            position: 0,
+            end: self.close,
+        }
+    }
+}
+
+fn read(source: &str) -> Result<Vec<Sexpr>, WatError> {
+    let mut lexer = Lexer::new(source);
+    // The lists being read, innermost last, with the span of their opening paren
+    let mut stack: Vec<(Vec<Sexpr>, Span)> = Vec::new();
+    let mut top = Vec::new();
+    while let Some(token) = lexer.next_token()? {
+        let sexpr = match token.node {
+            Token::LParen => {
+                stack.push((Vec::new(), token.span));
+                continue;
+            }
+            Token::RParen => match stack.pop() {
+                Some((items, open)) => Sexpr::List(List {
+                    items,
+                    open,
+                    close: token.span,
+                }),
+                None => return Err(WatErrorKind::UnbalancedParen.at(token.span)),
+            },
+            Token::Atom(atom) => Sexpr::Atom(Spanned::new(atom, token.span)),
+            Token::String(bytes) => Sexpr::String(Spanned::new(bytes, token.span)),
+        };
+        match stack.last_mut() {
+            Some((items, _)) => items.push(sexpr),
+            None => top.push(sexpr),
+        }
+    }
+    match stack.pop() {
+        Some((_, open)) => Err(WatErrorKind::UnbalancedParen.at(open)),
+        None => Ok(top),
+    }
+}
+
+/// Walks the items of a list.
+struct Cursor<'a> {
+    items: &'a [Sexpr],
+    position: usize,
+    end: Span,
+}
+
+impl<'a> Cursor<'a> {
+    fn peek(&self) -> Option<&'a Sexpr> {
+        self.items.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<&'a Sexpr> {
+        let item = self.peek()?;
+        self.position += 1;
+        Some(item)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.position >= self.items.len()
+    }
+
+    // Skips the keyword at the start of a list
+    fn skip_head(mut self) -> Self {
+        self.next();
+        self
+    }
+
+    fn rest(&self) -> &'a [Sexpr] {
+        &self.items[self.position.min(self.items.len())..]
+    }
+
+    fn error(&self, expected: &'static str) -> WatError {
+        match self.peek() {
+            Some(item) => WatErrorKind::Expected {
+                expected,
+                found: Some(item.text()),
+            }
+            .at(item.span()),
+            None => WatErrorKind::Expected {
+                expected,
+                found: None,
+            }
+            .at(self.end),
+        }
+    }
+
+    fn peek_atom(&self) -> Option<&'a Spanned<String>> {
+        match self.peek() {
+            Some(Sexpr::Atom(atom)) => Some(atom),
+            _ => None,
+        }
+    }
+
+    fn atom(&mut self, expected: &'static str) -> Result<&'a Spanned<String>, WatError> {
+        let atom = self.peek_atom().ok_or_else(|| self.error(expected))?;
+        self.position += 1;
+        Ok(atom)
+    }
+
+    fn keyword_if(&mut self, keyword: &str) -> bool {
+        let found = self.peek_atom().is_some_and(|atom| atom.node == keyword);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    // An optional `$name`
+    fn id(&mut self) -> Option<&'a Spanned<String>> {
+        let atom = self.peek_atom().filter(|atom| atom.node.starts_with('$'))?;
+        self.position += 1;
+        Some(atom)
+    }
+
+    fn string(&mut self) -> Result<&'a Spanned<Vec<u8>>, WatError> {
+        match self.peek() {
+            Some(Sexpr::String(string)) => {
+                self.position += 1;
+                Ok(string)
+            }
+            _ => Err(self.error("a string")),
+        }
+    }
+
+    fn name(&mut self) -> Result<String, WatError> {
+        let string = self.string()?;
+        String::from_utf8(string.node.clone()).map_err(|_| {
+            WatErrorKind::Expected {
+                expected: "a UTF-8 string",
+                found: Some(String::from_utf8_lossy(&string.node).into_owned()),
+            }
+            .at(string.span)
+        })
+    }
+
+    // The next item if it is a list starting with `head`
+    fn list_if(&mut self, head: &str) -> Option<&'a List> {
+        let list = self
+            .peek()
+            .and_then(Sexpr::as_list)
+            .filter(|list| list.head() == Some(head))?;
+        self.position += 1;
+        Some(list)
+    }
+
+    fn list(&mut self, head: &'static str) -> Result<&'a List, WatError> {
+        self.list_if(head).ok_or_else(|| self.error(head))
+    }
+
+    fn u32(&mut self, expected: &'static str) -> Result<u32, WatError> {
+        let atom = self.atom(expected)?;
+        match parse_integer(&atom.node) {
+            Some((false, val)) => u32::try_from(val)
+                .map_err(|_| WatErrorKind::InvalidNumber(atom.node.clone()).at(atom.span)),
+            _ => Err(WatErrorKind::InvalidNumber(atom.node.clone()).at(atom.span)),
+        }
+    }
+
+    fn done(&self) -> Result<(), WatError> {
+        match self.peek() {
+            Some(item) => Err(WatErrorKind::Expected {
+                expected: "`)`",
+                found: Some(item.text()),
+            }
+            .at(item.span())),
+            None => Ok(()),
+        }
+    }
+}
+
+// Numbers
+
+// The sign and magnitude of an integer in decimal or hex, eg. `-0x1_000`
+fn parse_integer(text: &str) -> Option<(bool, u64)> {
+    let text = text.replace('_', "");
+    let (negative, digits) = match text.as_bytes().first()? {
+        b'-' => (true, &text[1..]),
+        b'+' => (false, &text[1..]),
+        _ => (false, &text[..]),
+    };
+    let magnitude = match digits.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<u64>().ok()?,
+    };
+    // `from_str_radix` accepts a sign of its own
+    if digits.contains(['+', '-']) {
+        return None;
+    }
+    Some((negative, magnitude))
+}
+
+// Integers may be written signed or unsigned, eg. both `-1` and `4294967295` are the same i32
+fn parse_i32(text: &str) -> Option<i32> {
+    match parse_integer(text)? {
+        (true, val) if val <= 1 << 31 => Some((val as i64).wrapping_neg() as i32),
+        (false, val) if val <= u32::MAX as u64 => Some(val as u32 as i32),
+        _ => None,
+    }
+}
+
+fn parse_i64(text: &str) -> Option<i64> {
+    match parse_integer(text)? {
+        (true, val) if val <= 1 << 63 => Some((val as i64).wrapping_neg()),
+        (false, val) => Some(val as i64),
+        _ => None,
+    }
+}
+
+fn parse_f64(text: &str) -> Option<f64> {
+    let text = text.replace('_', "");
+    let (negative, rest) = match text.as_bytes().first()? {
+        b'-' => (true, &text[1..]),
+        b'+' => (false, &text[1..]),
+        _ => (false, &text[..]),
+    };
+    let magnitude = if rest == "inf" {
+        f64::INFINITY
+    } else if let Some(hex) = rest.strip_prefix("0x") {
+        parse_hex_float(hex)?
+    } else if rest.starts_with(|c: char| c.is_ascii_digit()) {
+        rest.parse::<f64>().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -magnitude } else { magnitude })
+}
+
+// Hex floats like `1.8p3`, with the `0x` and sign already removed
+fn parse_hex_float(text: &str) -> Option<f64> {
+    let (mantissa, exponent) = match text.split_once(['p', 'P']) {
+        Some((mantissa, exponent)) => (mantissa, exponent.parse::<i32>().ok()?),
+        None => (text, 0),
+    };
+    let (whole, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+    if whole.is_empty() {
+        return None;
+    }
+    let mut val = 0.0;
+    for digit in whole.chars().chain(fraction.chars()) {
+        val = val * 16.0 + digit.to_digit(16)? as f64;
+    }
+    Some(val * 2f64.powi(exponent - 4 * fraction.len() as i32))
+}
+
+fn parse_f32(text: &str) -> Option<f32> {
+    match nan_payload(text, 0x7f_ffff)? {
+        Some((negative, payload)) => {
+            let sign = if negative { 1 << 31 } else { 0 };
+            Some(f32::from_bits(
+                sign | 0x7f80_0000 | payload.unwrap_or(1 << 22) as u32,
+            ))
+        }
+        None => parse_f64(text).map(|val| val as f32),
+    }
+}
+
+fn parse_f64_const(text: &str) -> Option<f64> {
+    match nan_payload(text, 0xf_ffff_ffff_ffff)? {
+        Some((negative, payload)) => {
+            let sign = if negative { 1 << 63 } else { 0 };
+            Some(f64::from_bits(
+                sign | 0x7ff0_0000_0000_0000 | payload.unwrap_or(1 << 51),
+            ))
+        }
+        None => parse_f64(text),
+    }
+}
+
+// `Some(Some(..))` for `nan` with its sign and payload, if it has one, and `Some(None)` for anything else
+#[allow(clippy::type_complexity)]
+fn nan_payload(text: &str, max: u64) -> Option<Option<(bool, Option<u64>)>> {
+    let (negative, rest) = match text.as_bytes().first() {
+        Some(b'-') => (true, &text[1..]),
+        Some(b'+') => (false, &text[1..]),
+        _ => (false, text),
+    };
+    match rest.strip_prefix("nan") {
+        Some("") => Some(Some((negative, None))),
+        Some(payload) => {
+            let payload = payload.strip_prefix(":0x")?.replace('_', "");
+            let payload = u64::from_str_radix(&payload, 16).ok()?;
+            (payload != 0 && payload <= max).then_some(Some((negative, Some(payload))))
+        }
+        None => Some(None),
+    }
+}
+
+// Modules
+
+/// The names defined in each index space.
+#[derive(Default)]
+struct Names {
+    types: HashMap<String, u32>,
+    functions: HashMap<String, u32>,
+    tables: HashMap<String, u32>,
+    memories: HashMap<String, u32>,
+    tags: HashMap<String, u32>,
+    globals: HashMap<String, u32>,
+}
+
+#[derive(Default)]
+struct ModuleParser {
+    module: WasmModule,
+    names: Names,
+}
+
+// The things a module field can define, which each have their own index space
+#[derive(Clone, Copy, PartialEq)]
+enum Space {
+    Function,
+    Table,
+    Memory,
+    Global,
+}
+
+impl Space {
+    fn from_keyword(keyword: &str) -> Option<Space> {
+        match keyword {
+            "func" => Some(Space::Function),
+            "table" => Some(Space::Table),
+            "memory" => Some(Space::Memory),
+            "global" => Some(Space::Global),
+            _ => None,
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Space::Function => "function",
+            Space::Table => "table",
+            Space::Memory => "memory",
+            Space::Global => "global",
+        }
+    }
+}
+
+/// The start of a function, table, memory or global definition: its name, inline exports and
+/// inline import, eg. `(func $f (export "f") (import "env" "f") ...`.
+struct FieldHeader<'a> {
+    id: Option<&'a Spanned<String>>,
+    exports: Vec<String>,
+    import: Option<(String, String)>,
+    rest: Cursor<'a>,
+}
+
+fn field_header(list: &List) -> Result<FieldHeader<'_>, WatError> {
+    let mut cursor = list.cursor();
+    cursor.next();
+    let id = cursor.id();
+    let mut exports = Vec::new();
+    while let Some(export) = cursor.list_if("export") {
+        let mut export = export.cursor();
+        export.next();
+        exports.push(export.name()?);
+        export.done()?;
+    }
+    let import = match cursor.list_if("import") {
+        Some(import) => {
+            let mut import = import.cursor();
+            import.next();
+            let module = import.name()?;
+            let name = import.name()?;
+            import.done()?;
+            Some((module, name))
+        }
+        None => None,
+    };
+    Ok(FieldHeader {
+        id,
+        exports,
+        import,
+        rest: cursor,
+    })
+}
+
+impl ModuleParser {
+    fn parse(mut self, fields: &[Sexpr]) -> Result<WasmModule, WatError> {
+        let fields = fields
+            .iter()
+            .map(|field| {
+                field.as_list().ok_or_else(|| {
+                    WatErrorKind::Expected {
+                        expected: "a module field",
+                        found: Some(field.text()),
+                    }
+                    .at(field.span())
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Every name is found first, so fields can refer to ones defined after them
+        self.declare_names(&fields)?;
+
+        for field in fields {
+            self.field(field)?;
+        }
+        Ok(self.module)
+    }
+
+    fn declare_names(&mut self, fields: &[&List]) -> Result<(), WatError> {
+        let mut counts = HashMap::new();
+        let mut defined = Vec::new();
+        let (mut types, mut tags) = (0, 0);
+        for field in fields {
+            let head = field.head().unwrap_or("");
+            let (space, id, imported) = match head {
+                "type" => {
+                    let mut cursor = field.cursor();
+                    cursor.next();
+                    if let Some(id) = cursor.id() {
+                        declare(&mut self.names.types, "type", id, types)?;
+                    }
+                    // Types are parsed now, as everything using them needs their params
+                    let func = cursor.list("func")?;
+                    let typ = function_type(&mut func.cursor().skip_head(), &mut HashMap::new())?;
+                    cursor.done()?;
+                    self.module.types.push(typ);
+                    types += 1;
+                    continue;
+                }
+                // Tags can't be imported or exported, so they don't need a `Space`
+                "tag" => {
+                    let mut cursor = field.cursor();
+                    cursor.next();
+                    if let Some(id) = cursor.id() {
+                        declare(&mut self.names.tags, "tag", id, tags)?;
+                    }
+                    tags += 1;
+                    continue;
+                }
+                "import" => {
+                    let mut cursor = field.cursor();
+                    cursor.next();
+                    cursor.name()?;
+                    cursor.name()?;
+                    let desc = cursor.peek().and_then(Sexpr::as_list);
+                    let space = desc.and_then(|desc| Space::from_keyword(desc.head()?));
+                    match (space, desc) {
+                        (Some(space), Some(desc)) => {
+                            let mut desc = desc.cursor();
+                            desc.next();
+                            (space, desc.id(), true)
+                        }
+                        _ => return Err(cursor.error("an import description")),
+                    }
+                }
+                _ => match Space::from_keyword(head) {
+                    Some(space) => {
+                        let header = field_header(field)?;
+                        (space, header.id, header.import.is_some())
+                    }
+                    None => continue,
+                },
+            };
+
+            if imported && defined.contains(&space) {
+                return Err(WatErrorKind::ImportAfterDefinition(space.kind()).at(field.open));
+            }
+            if !imported {
+                defined.push(space);
+            }
+            let count = counts.entry(space as u8).or_insert(0);
+            if let Some(id) = id {
+                let names = match space {
+                    Space::Function => &mut self.names.functions,
+                    Space::Table => &mut self.names.tables,
+                    Space::Memory => &mut self.names.memories,
+                    Space::Global => &mut self.names.globals,
+                };
+                declare(names, space.kind(), id, *count)?;
+                if space == Space::Function {
+                    self.module.names.functions.push((*count, name(id)));
+                }
+            }
+            *count += 1;
+        }
+        Ok(())
+    }
+
+    fn field(&mut self, field: &List) -> Result<(), WatError> {
+        let head = field.head().unwrap_or("");
+        match head {
+            // Already parsed along with the names
+            "type" => Ok(()),
+            "import" => {
+                let mut cursor = field.cursor();
+                cursor.next();
+                let module = cursor.name()?;
+                let name = cursor.name()?;
+                let desc = cursor
+                    .next()
+                    .and_then(Sexpr::as_list)
+                    .expect("checked when declaring names");
+                let mut desc_cursor = desc.cursor();
+                desc_cursor.next();
+                desc_cursor.id();
+                let typ = self.import_type(desc.head().unwrap_or(""), &mut desc_cursor)?;
+                desc_cursor.done()?;
+                cursor.done()?;
+                self.module.imports.push(Import { module, name, typ });
+                Ok(())
+            }
+            "func" | "table" | "memory" | "global" => self.definition(head, field),
+            "tag" => {
+                let mut cursor = field.cursor();
+                cursor.next();
+                cursor.id();
+                let type_idx = self.type_use(&mut cursor, &mut HashMap::new())?;
+                cursor.done()?;
+                self.module.tags.push(Tag { type_idx });
+                Ok(())
+            }
+            "export" => {
+                let mut cursor = field.cursor();
+                cursor.next();
+                let name = cursor.name()?;
+                let desc = cursor.next().and_then(Sexpr::as_list);
+                let desc = desc.ok_or_else(|| cursor.error("an export description"))?;
+                let mut desc_cursor = desc.cursor();
+                desc_cursor.next();
+                let typ = match desc.head() {
+                    Some("func") => {
+                        ExportType::Func(self.index(&mut desc_cursor, Space::Function)?)
+                    }
+                    Some("table") => ExportType::Table(self.index(&mut desc_cursor, Space::Table)?),
+                    Some("memory") => {
+                        ExportType::Memory(self.index(&mut desc_cursor, Space::Memory)?)
+                    }
+                    Some("global") => {
+                        ExportType::Global(self.index(&mut desc_cursor, Space::Global)?)
+                    }
+                    _ => {
+                        return Err(WatErrorKind::Expected {
+                            expected: "an export description",
+                            found: Some(format!("({} ...)", desc.head().unwrap_or(""))),
+                        }
+                        .at(desc.open))
+                    }
+                };
+                desc_cursor.done()?;
+                cursor.done()?;
+                self.module.exports.push(Export { name, typ });
+                Ok(())
+            }
+            "start" => {
+                let mut cursor = field.cursor();
+                cursor.next();
+                self.module.start = Some(self.index(&mut cursor, Space::Function)?);
+                cursor.done()
+            }
+            "elem" => self.element(field),
+            "data" => self.data(field),
+            _ => Err(WatErrorKind::UnknownField(head.to_string()).at(field.open)),
+        }
+    }
+
+    fn import_type(&mut self, kind: &str, cursor: &mut Cursor) -> Result<ImportType, WatError> {
+        match kind {
+            "func" => Ok(ImportType::Func(
+                self.type_use(cursor, &mut HashMap::new())?,
+            )),
+            "table" => Ok(ImportType::Table(table_type(cursor)?)),
+            "memory" => Ok(ImportType::Memory(limits(cursor)?)),
+            _ => Ok(ImportType::Global(global_type(cursor)?)),
+        }
+    }
+
+    // A function, table, memory or global, which may be imported rather than defined
+    fn definition(&mut self, head: &str, field: &List) -> Result<(), WatError> {
+        let space = Space::from_keyword(head).unwrap();
+        let mut header = field_header(field)?;
+        let cursor = &mut header.rest;
+
+        let index = match header.import {
+            Some((module, name)) => {
+                let typ = self.import_type(head, cursor)?;
+                cursor.done()?;
+                self.module.imports.push(Import { module, name, typ });
+                self.imported(space) - 1
+            }
+            None => {
+                match space {
+                    Space::Function => self.function(cursor)?,
+                    Space::Table => self.table(cursor)?,
+                    Space::Memory => {
+                        self.module.memories.push(limits(cursor)?);
+                        cursor.done()?;
+                    }
+                    Space::Global => {
+                        let typ = global_type(cursor)?;
+                        let init = self.const_expr(cursor)?;
+                        self.module.globals.push(Global { typ, init });
+                    }
+                }
+                self.imported(space) + self.defined(space) - 1
+            }
+        };
+
+        for name in header.exports {
+            let typ = match space {
+                Space::Function => ExportType::Func(index),
+                Space::Table => ExportType::Table(index),
+                Space::Memory => ExportType::Memory(index),
+                Space::Global => ExportType::Global(index),
+            };
+            self.module.exports.push(Export { name, typ });
+        }
+        Ok(())
+    }
+
+    fn imported(&self, space: Space) -> u32 {
+        self.module
+            .imports
+            .iter()
+            .filter(|import| {
+                matches!(
+                    (&import.typ, space),
+                    (ImportType::Func(_), Space::Function)
+                        | (ImportType::Table(_), Space::Table)
+                        | (ImportType::Memory(_), Space::Memory)
+                        | (ImportType::Global(_), Space::Global)
+                )
+            })
+            .count() as u32
+    }
+
+    fn defined(&self, space: Space) -> u32 {
+        (match space {
+            Space::Function => self.module.functions.len(),
+            Space::Table => self.module.tables.len(),
+            Space::Memory => self.module.memories.len(),
+            Space::Global => self.module.globals.len(),
+        }) as u32
+    }
+
+    fn function(&mut self, cursor: &mut Cursor) -> Result<(), WatError> {
+        let mut locals = HashMap::new();
+        let type_idx = self.type_use(cursor, &mut locals)?;
+        let params = self.module.types[type_idx as usize].args.len() as u32;
+
+        let mut local_types = Vec::new();
+        while let Some(list) = cursor.list_if("local") {
+            let mut local = list.cursor();
+            local.next();
+            match local.id() {
+                Some(id) => {
+                    declare(&mut locals, "local", id, params + local_types.len() as u32)?;
+                    local_types.push(value_type(&mut local)?);
+                }
+                None => {
+                    while !local.is_empty() {
+                        local_types.push(value_type(&mut local)?);
+                    }
+                }
+            }
+            local.done()?;
+        }
+
+        let mut names: Vec<_> = (locals.iter())
+            .map(|(id, index)| (*index, id[1..].to_string()))
+            .collect();
+        if !names.is_empty() {
+            names.sort();
+            let index = self.imported(Space::Function) + self.defined(Space::Function);
+            self.module.names.locals.push((index, names));
+        }
+
+        let mut scope = FunctionScope {
+            locals,
+            labels: vec![None],
+        };
+        let instructions = self.instructions(cursor, &mut scope)?;
+        self.module.functions.push(Function {
+            type_idx,
+            locals: local_types,
+            body: Expression { instructions },
+        });
+        Ok(())
+    }
+
+    fn table(&mut self, cursor: &mut Cursor) -> Result<(), WatError> {
+        // `(table funcref (elem $f $g))` makes a table just big enough for the functions
+        if cursor
+            .peek_atom()
+            .is_some_and(|atom| !atom.node.starts_with(|c: char| c.is_ascii_digit()))
+        {
+            let element = ref_type(cursor)?;
+            let list = cursor.list("elem")?;
+            cursor.done()?;
+            let mut elem = list.cursor();
+            elem.next();
+            let mut functions = Vec::new();
+            while !elem.is_empty() {
+                functions.push(self.index(&mut elem, Space::Function)?);
+            }
+            let table = self.imported(Space::Table) + self.defined(Space::Table);
+            let len = functions.len() as u32;
+            self.module.tables.push(TableType {
+                element,
+                limits: Limits {
+                    min: len,
+                    max: Some(len),
+                },
+            });
+            self.module.elements.push(Element {
+                table,
+                offset: Expression {
+                    instructions: vec![Instruction::I32Const(0)],
+                },
+                functions,
+            });
+            return Ok(());
+        }
+        self.module.tables.push(table_type(cursor)?);
+        cursor.done()
+    }
+
+    fn element(&mut self, field: &List) -> Result<(), WatError> {
+        let mut cursor = field.cursor();
+        cursor.next();
+        cursor.id();
+        let table = match cursor.list_if("table") {
+            Some(list) => {
+                let mut table = list.cursor();
+                table.next();
+                let index = self.index(&mut table, Space::Table)?;
+                table.done()?;
+                index
+            }
+            None => 0,
+        };
+        if cursor
+            .peek_atom()
+            .is_some_and(|atom| atom.node == "declare")
+            || cursor.peek().is_none()
+        {
+            return Err(
+                WatErrorKind::Unsupported("passive and declarative element segments")
+                    .at(field.open),
+            );
+        }
+        let offset = self.offset(&mut cursor)?;
+        cursor.keyword_if("func");
+        let mut functions = Vec::new();
+        while !cursor.is_empty() {
+            functions.push(self.index(&mut cursor, Space::Function)?);
+        }
+        self.module.elements.push(Element {
+            table,
+            offset,
+            functions,
+        });
+        Ok(())
+    }
+
+    fn data(&mut self, field: &List) -> Result<(), WatError> {
+        let mut cursor = field.cursor();
+        cursor.next();
+        cursor.id();
+        let memory = match cursor.list_if("memory") {
+            Some(list) => {
+                let mut memory = list.cursor();
+                memory.next();
+                let index = self.index(&mut memory, Space::Memory)?;
+                memory.done()?;
+                index
+            }
+            None => 0,
+        };
+        if !matches!(cursor.peek(), Some(Sexpr::List(_))) {
+            return Err(WatErrorKind::Unsupported("passive data segments").at(field.open));
+        }
+        let offset = self.offset(&mut cursor)?;
+        let mut bytes = Vec::new();
+        while !cursor.is_empty() {
+            bytes.extend(cursor.string()?.node.iter());
+        }
+        self.module.data.push(Data {
+            memory,
+            offset,
+            bytes,
+        });
+        Ok(())
+    }
+
+    // `(offset instr*)` or a single folded instruction
+    fn offset(&mut self, cursor: &mut Cursor) -> Result<Expression, WatError> {
+        match cursor.list_if("offset") {
+            Some(list) => {
+                let mut offset = list.cursor();
+                offset.next();
+                self.const_expr(&mut offset)
+            }
+            None => {
+                let item = cursor.next().ok_or_else(|| cursor.error("an offset"))?;
+                let list = item.as_list().ok_or_else(|| {
+                    WatErrorKind::Expected {
+                        expected: "an offset",
+                        found: Some(item.text()),
+                    }
+                    .at(item.span())
+                })?;
+                let mut instructions = Vec::new();
+                let mut scope = FunctionScope::default();
+                self.folded(list, &mut scope, &mut instructions)?;
+                Ok(Expression { instructions })
+            }
+        }
+    }
+
+    // The rest of the list as instructions outside of any function
+    fn const_expr(&mut self, cursor: &mut Cursor) -> Result<Expression, WatError> {
+        let instructions = self.instructions(cursor, &mut FunctionScope::default())?;
+        Ok(Expression { instructions })
+    }
+
+    /// `(type $t)? (param ...)* (result ...)*`, returning the index of the type, which is added
+    /// if it isn't given. Parameter names are added to `locals`.
+    fn type_use(
+        &mut self,
+        cursor: &mut Cursor,
+        locals: &mut HashMap<String, u32>,
+    ) -> Result<u32, WatError> {
+        let index = match cursor.list_if("type") {
+            Some(list) => {
+                let mut typ = list.cursor();
+                typ.next();
+                let index = self.type_index(&mut typ)?;
+                typ.done()?;
+                Some(index)
+            }
+            None => None,
+        };
+        let inline = function_type(cursor, locals)?;
+        match index {
+            // The params and results may be repeated after the type, but they have to match it
+            Some(index) => {
+                let typ = &self.module.types[index as usize];
+                if (!inline.args.is_empty() || !inline.ret.is_empty()) && inline != *typ {
+                    return Err(cursor.error("params and results matching the type"));
+                }
+                Ok(index)
+            }
+            None => Ok(self.add_type(inline)),
+        }
+    }
+
+    fn type_index(&self, cursor: &mut Cursor) -> Result<u32, WatError> {
+        let index = self.resolve(cursor, &self.names.types, "type")?;
+        match (index as usize) < self.module.types.len() {
+            true => Ok(index),
+            false => Err(WatErrorKind::UnknownName {
+                kind: "type",
+                name: index.to_string(),
+            }
+            .at(cursor.items[cursor.position - 1].span())),
+        }
+    }
+
+    // The index of an existing type, or a new one
+    fn add_type(&mut self, typ: FunctionType) -> u32 {
+        match self.module.types.iter().position(|other| *other == typ) {
+            Some(index) => index as u32,
+            None => {
+                self.module.types.push(typ);
+                self.module.types.len() as u32 - 1
+            }
+        }
+    }
+
+    fn index(&self, cursor: &mut Cursor, space: Space) -> Result<u32, WatError> {
+        let names = match space {
+            Space::Function => &self.names.functions,
+            Space::Table => &self.names.tables,
+            Space::Memory => &self.names.memories,
+            Space::Global => &self.names.globals,
+        };
+        self.resolve(cursor, names, space.kind())
+    }
+
+    // A number or a `$name` from `names`
+    fn resolve(
+        &self,
+        cursor: &mut Cursor,
+        names: &HashMap<String, u32>,
+        kind: &'static str,
+    ) -> Result<u32, WatError> {
+        match cursor.peek_atom() {
+            Some(atom) if atom.node.starts_with('$') => {
+                cursor.next();
+                names.get(&atom.node).copied().ok_or_else(|| {
+                    WatErrorKind::UnknownName {
+                        kind,
+                        name: atom.node.clone(),
+                    }
+                    .at(atom.span)
+                })
+            }
+            _ => cursor.u32("an index"),
+        }
+    }
+
+    // Instructions
+
+    // Flat and folded instructions up to the end of the list
+    fn instructions(
+        &mut self,
+        cursor: &mut Cursor,
+        scope: &mut FunctionScope,
+    ) -> Result<Vec<Instruction>, WatError> {
+        let mut instructions = Vec::new();
+        let terminator = self.instructions_until(cursor, scope, &[], &mut instructions)?;
+        debug_assert!(terminator.is_none());
+        Ok(instructions)
+    }
+
+    /// Reads instructions until one of the `terminators` keywords, or the end of the list if
+    /// there are none, returning the terminator found.
+    fn instructions_until(
+        &mut self,
+        cursor: &mut Cursor,
+        scope: &mut FunctionScope,
+        terminators: &[&'static str],
+        instructions: &mut Vec<Instruction>,
+    ) -> Result<Option<&'static str>, WatError> {
+        loop {
+            let item = match cursor.peek() {
+                Some(item) => item,
+                None if terminators.is_empty() => return Ok(None),
+                None => return Err(cursor.error(terminators[terminators.len() - 1])),
+            };
+            match item {
+                Sexpr::List(list) => {
+                    cursor.next();
+                    self.folded(list, scope, instructions)?;
+                }
+                Sexpr::Atom(atom) => {
+                    if let Some(terminator) = terminators.iter().find(|t| **t == atom.node) {
+                        cursor.next();
+                        // The label can be repeated after `end` and `else`
+                        if matches!(*terminator, "end" | "else") {
+                            cursor.id();
+                        }
+                        return Ok(Some(terminator));
+                    }
+                    let instruction = self.flat(cursor, scope)?;
+                    instructions.push(instruction);
+                }
+                Sexpr::String(_) => return Err(cursor.error("an instruction")),
+            }
+        }
+    }
+
+    // A plain instruction, or a block ending with `end`
+    fn flat(
+        &mut self,
+        cursor: &mut Cursor,
+        scope: &mut FunctionScope,
+    ) -> Result<Instruction, WatError> {
+        let keyword = cursor.atom("an instruction")?;
+        match keyword.node.as_str() {
+            "block" | "loop" => {
+                let label = cursor.id().map(|id| id.node.clone());
+                let typ = self.block_type(cursor)?;
+                scope.labels.push(label);
+                let mut instructions = Vec::new();
+                let result = self.instructions_until(cursor, scope, &["end"], &mut instructions);
+                scope.labels.pop();
+                result?;
+                let body = Expression { instructions };
+                Ok(Instruction::ControlOp(match keyword.node.as_str() {
+                    "block" => ControlOp::Block { typ, body },
+                    _ => ControlOp::Loop { typ, body },
+                }))
+            }
+            "if" => {
+                let label = cursor.id().map(|id| id.node.clone());
+                let typ = self.block_type(cursor)?;
+                scope.labels.push(label);
+                let mut then = Vec::new();
+                let result = self
+                    .instructions_until(cursor, scope, &["else", "end"], &mut then)
+                    .and_then(|terminator| match terminator {
+                        Some("else") => {
+                            let mut otherwise = Vec::new();
+                            self.instructions_until(cursor, scope, &["end"], &mut otherwise)?;
+                            Ok(Some(Expression {
+                                instructions: otherwise,
+                            }))
+                        }
+                        _ => Ok(None),
+                    });
+                scope.labels.pop();
+                Ok(Instruction::ControlOp(ControlOp::If {
+                    typ,
+                    then: Expression { instructions: then },
+                    otherwise: result?,
+                }))
+            }
+            "try" => {
+                let label = cursor.id().map(|id| id.node.clone());
+                let typ = self.block_type(cursor)?;
+                scope.labels.push(label);
+                let result = self.flat_handlers(cursor, scope);
+                scope.labels.pop();
+                let (body, catches, catch_all) = result?;
+                Ok(Instruction::ControlOp(ControlOp::Try {
+                    typ,
+                    body,
+                    catches,
+                    catch_all,
+                }))
+            }
+            _ => self.plain(keyword, cursor, scope),
+        }
+    }
+
+    // The body of a flat `try`, then its `catch`es and `catch_all` up to the `end`
+    fn flat_handlers(
+        &mut self,
+        cursor: &mut Cursor,
+        scope: &mut FunctionScope,
+    ) -> Result<(Expression, Vec<Catch>, Option<Expression>), WatError> {
+        let terminators = &["catch", "catch_all", "end"];
+        let mut body = Vec::new();
+        let mut terminator = self.instructions_until(cursor, scope, terminators, &mut body)?;
+        let mut catches = Vec::new();
+        while terminator == Some("catch") {
+            let tag = self.resolve(cursor, &self.names.tags, "tag")?;
+            let mut instructions = Vec::new();
+            terminator = self.instructions_until(cursor, scope, terminators, &mut instructions)?;
+            catches.push(Catch {
+                tag,
+                body: Expression { instructions },
+            });
+        }
+        let catch_all = match terminator {
+            Some("catch_all") => {
+                let mut instructions = Vec::new();
+                self.instructions_until(cursor, scope, &["end"], &mut instructions)?;
+                Some(Expression { instructions })
+            }
+            _ => None,
+        };
+        Ok((Expression { instructions: body }, catches, catch_all))
+    }
+
+    // A folded instruction, which is emitted after its operands
+    fn folded(
+        &mut self,
+        list: &List,
+        scope: &mut FunctionScope,
+        instructions: &mut Vec<Instruction>,
+    ) -> Result<(), WatError> {
+        let mut cursor = list.cursor();
+        let keyword = cursor.atom("an instruction")?;
+        match keyword.node.as_str() {
+            "block" | "loop" => {
+                let label = cursor.id().map(|id| id.node.clone());
+                let typ = self.block_type(&mut cursor)?;
+                scope.labels.push(label);
+                let body = self.instructions(&mut cursor, scope);
+                scope.labels.pop();
+                let body = Expression {
+                    instructions: body?,
+                };
+                instructions.push(Instruction::ControlOp(match keyword.node.as_str() {
+                    "block" => ControlOp::Block { typ, body },
+                    _ => ControlOp::Loop { typ, body },
+                }));
+            }
+            "if" => {
+                let label = cursor.id().map(|id| id.node.clone());
+                let typ = self.block_type(&mut cursor)?;
+                // The condition is folded in before the branches
+                while let Some(Sexpr::List(operand)) = cursor.peek() {
+                    if matches!(operand.head(), Some("then" | "else")) {
+                        break;
+                    }
+                    cursor.next();
+                    self.folded(operand, scope, instructions)?;
+                }
+                scope.labels.push(label);
+                let result = self.branches(&mut cursor, scope);
+                scope.labels.pop();
+                let (then, otherwise) = result?;
+                cursor.done()?;
+                instructions.push(Instruction::ControlOp(ControlOp::If {
+                    typ,
+                    then,
+                    otherwise,
+                }));
+            }
+            "try" => {
+                let label = cursor.id().map(|id| id.node.clone());
+                let typ = self.block_type(&mut cursor)?;
+                scope.labels.push(label);
+                let result = self.folded_handlers(&mut cursor, scope);
+                scope.labels.pop();
+                let (body, catches, catch_all) = result?;
+                cursor.done()?;
+                instructions.push(Instruction::ControlOp(ControlOp::Try {
+                    typ,
+                    body,
+                    catches,
+                    catch_all,
+                }));
+            }
+            _ => {
+                let instruction = self.plain(keyword, &mut cursor, scope)?;
+                while !cursor.is_empty() {
+                    match cursor.next() {
+                        Some(Sexpr::List(operand)) => self.folded(operand, scope, instructions)?,
+                        _ => {
+                            cursor.position -= 1;
+                            return Err(cursor.error("a folded instruction"));
+                        }
+                    }
+                }
+                instructions.push(instruction);
+            }
+        }
+        Ok(())
+    }
+
+    // The `(then ...)` and optional `(else ...)` of a folded `if`
+    fn branches(
+        &mut self,
+        cursor: &mut Cursor,
+        scope: &mut FunctionScope,
+    ) -> Result<(Expression, Option<Expression>), WatError> {
+        let then = cursor.list("then")?;
+        let mut then = then.cursor();
+        then.next();
+        let then = Expression {
+            instructions: self.instructions(&mut then, scope)?,
+        };
+        let otherwise = match cursor.list_if("else") {
+            Some(list) => {
+                let mut otherwise = list.cursor();
+                otherwise.next();
+                Some(Expression {
+                    instructions: self.instructions(&mut otherwise, scope)?,
+                })
+            }
+            None => None,
+        };
+        Ok((then, otherwise))
+    }
+
+    // The `(do ...)`, `(catch ...)`es and optional `(catch_all ...)` of a folded `try`
+    fn folded_handlers(
+        &mut self,
+        cursor: &mut Cursor,
+        scope: &mut FunctionScope,
+    ) -> Result<(Expression, Vec<Catch>, Option<Expression>), WatError> {
+        let body = cursor.list("do")?;
+        let mut body = body.cursor();
+        body.next();
+        let body = Expression {
+            instructions: self.instructions(&mut body, scope)?,
+        };
+        let mut catches = Vec::new();
+        while let Some(list) = cursor.list_if("catch") {
+            let mut catch = list.cursor();
+            catch.next();
+            let tag = self.resolve(&mut catch, &self.names.tags, "tag")?;
+            catches.push(Catch {
+                tag,
+                body: Expression {
+                    instructions: self.instructions(&mut catch, scope)?,
+                },
+            });
+        }
+        let catch_all = match cursor.list_if("catch_all") {
+            Some(list) => {
+                let mut catch_all = list.cursor();
+                catch_all.next();
+                Some(Expression {
+                    instructions: self.instructions(&mut catch_all, scope)?,
+                })
+            }
+            None => None,
+        };
+        Ok((body, catches, catch_all))
+    }
+
+    fn block_type(&mut self, cursor: &mut Cursor) -> Result<BlockType, WatError> {
+        if cursor
+            .peek()
+            .and_then(Sexpr::as_list)
+            .is_some_and(|list| list.head() == Some("type"))
+        {
+            return Ok(BlockType::Type(self.type_use(cursor, &mut HashMap::new())?));
+        }
+        // Block params can't be named
+        let typ = function_type(cursor, &mut HashMap::new())?;
+        match (typ.args.as_slice(), typ.ret.as_slice()) {
+            ([], []) => Ok(BlockType::Empty),
+            ([], [ret]) => Ok(BlockType::Value(*ret)),
+            _ => Ok(BlockType::Type(self.add_type(typ))),
+        }
+    }
+
+    // An instruction that isn't a block, with its immediates
+    fn plain(
+        &mut self,
+        keyword: &Spanned<String>,
+        cursor: &mut Cursor,
+        scope: &mut FunctionScope,
+    ) -> Result<Instruction, WatError> {
+        let name = keyword.node.as_str();
+        let number = |cursor: &mut Cursor, parse: &dyn Fn(&str) -> Option<Instruction>| {
+            let atom = cursor.atom("a number")?;
+            parse(&atom.node)
+                .ok_or_else(|| WatErrorKind::InvalidNumber(atom.node.clone()).at(atom.span))
+        };
+        let instruction = match name {
+            "i32.const" => number(cursor, &|text| parse_i32(text).map(Instruction::I32Const))?,
+            "i64.const" => number(cursor, &|text| parse_i64(text).map(Instruction::I64Const))?,
+            "f32.const" => number(cursor, &|text| parse_f32(text).map(Instruction::F32Const))?,
+            "f64.const" => number(cursor, &|text| {
+                parse_f64_const(text).map(Instruction::F64Const)
+            })?,
+
+            "unreachable" => Instruction::ControlOp(ControlOp::Unreachable),
+            "nop" => Instruction::ControlOp(ControlOp::Nop),
+            "br" => Instruction::ControlOp(ControlOp::Br(scope.label(cursor)?)),
+            "br_if" => Instruction::ControlOp(ControlOp::BrIf(scope.label(cursor)?)),
+            "br_table" => {
+                let mut labels = vec![scope.label(cursor)?];
+                // In flat bodies the next instruction follows straight after the labels
+                while cursor.peek_atom().is_some_and(|atom| {
+                    atom.node
+                        .starts_with(|c: char| c == '$' || c.is_ascii_digit())
+                }) {
+                    labels.push(scope.label(cursor)?);
+                }
+                let default = labels.pop().unwrap();
+                Instruction::ControlOp(ControlOp::BrTable { labels, default })
+            }
+            "return" => Instruction::ControlOp(ControlOp::Return),
+            "throw" => Instruction::ControlOp(ControlOp::Throw(self.resolve(
+                cursor,
+                &self.names.tags,
+                "tag",
+            )?)),
+            "rethrow" => Instruction::ControlOp(ControlOp::Rethrow(scope.label(cursor)?)),
+            "call" => Instruction::ControlOp(ControlOp::Call(self.index(cursor, Space::Function)?)),
+            "call_indirect" => {
+                // The table can be left out when it's the first one
+                let table = match cursor.peek_atom() {
+                    Some(atom)
+                        if atom
+                            .node
+                            .starts_with(|c: char| c == '$' || c.is_ascii_digit()) =>
+                    {
+                        self.index(cursor, Space::Table)?
+                    }
+                    _ => 0,
+                };
+                let type_idx = self.type_use(cursor, &mut HashMap::new())?;
+                Instruction::ControlOp(ControlOp::CallIndirect { type_idx, table })
+            }
+
+            "ref.null" => {
+                let atom = cursor.atom("a heap type")?;
+                match atom.node.as_str() {
+                    "func" => Instruction::RefOp(RefOp::Null(RefType::FuncRef)),
+                    "extern" => Instruction::RefOp(RefOp::Null(RefType::ExternRef)),
+                    _ => {
+                        return Err(WatErrorKind::Expected {
+                            expected: "`func` or `extern`",
+                            found: Some(atom.node.clone()),
+                        }
+                        .at(atom.span))
+                    }
+                }
+            }
+            "ref.is_null" => Instruction::RefOp(RefOp::IsNull),
+            "ref.func" => Instruction::RefOp(RefOp::Func(self.index(cursor, Space::Function)?)),
+
+            "drop" => Instruction::ParametricOp(ParametricOp::Drop),
+            "select" => Instruction::ParametricOp(ParametricOp::Select),
+
+            "local.get" => Instruction::VariableOp(VariableOp::LocalGet(scope.local(cursor)?)),
+            "local.set" => Instruction::VariableOp(VariableOp::LocalSet(scope.local(cursor)?)),
+            "local.tee" => Instruction::VariableOp(VariableOp::LocalTee(scope.local(cursor)?)),
+            "global.get" => {
+                Instruction::VariableOp(VariableOp::GlobalGet(self.index(cursor, Space::Global)?))
+            }
+            "global.set" => {
+                Instruction::VariableOp(VariableOp::GlobalSet(self.index(cursor, Space::Global)?))
+            }
+
+            "memory.size" | "memory.grow" | "memory.copy" | "memory.fill" => {
+                // Only memory 0 exists, so an index is allowed but ignored
+                if cursor.peek_atom().is_some_and(|atom| {
+                    atom.node.starts_with('$')
+                        || atom.node.starts_with(|c: char| c.is_ascii_digit())
+                }) {
+                    self.index(cursor, Space::Memory)?;
+                }
+                Instruction::MemoryOp(match name {
+                    "memory.size" => MemoryOp::MemorySize,
+                    "memory.grow" => MemoryOp::MemoryGrow,
+                    "memory.copy" => MemoryOp::MemoryCopy,
+                    _ => MemoryOp::MemoryFill,
+                })
+            }
+
+            _ => {
+                if let Some((make, _, size)) = MEMORY_OPS.iter().find(|(_, op, _)| *op == name) {
+                    Instruction::MemoryOp(make(memarg(cursor, *size)?))
+                } else if let Some((op, _)) = CONVERT_OPS.iter().find(|(_, op)| *op == name) {
+                    Instruction::ConvertOp(op.clone())
+                } else {
+                    typed_op(name).ok_or_else(|| {
+                        WatErrorKind::UnknownInstruction(name.to_string()).at(keyword.span)
+                    })?
+                }
+            }
+        };
+        Ok(instruction)
+    }
+}
+
+// Integer and float operators, eg. `i64.rem_u`
+fn typed_op(name: &str) -> Option<Instruction> {
+    let (typ, op) = name.split_once('.')?;
+    let integer = match typ {
+        "i32" => Some(IntegerType::I32),
+        "i64" => Some(IntegerType::I64),
+        _ => None,
+    };
+    let float = match typ {
+        "f32" => Some(FloatType::F32),
+        "f64" => Some(FloatType::F64),
+        _ => None,
+    };
+    if let Some(typ) = integer {
+        let (op, _) = INTEGER_OPS.iter().find(|(_, name)| *name == op)?;
+        return Some(Instruction::IntegerOp(IntegerOp {
+            op: op.clone(),
+            typ,
+        }));
+    }
+    let (op, _) = FLOAT_OPS.iter().find(|(_, name)| *name == op)?;
+    Some(Instruction::FloatOp(FloatOp {
+        op: op.clone(),
+        typ: float?,
+    }))
+}
+
+// `offset=N` and `align=N`, both optional
+fn memarg(cursor: &mut Cursor, size: u32) -> Result<MemArg, WatError> {
+    let mut arg = MemArg::natural(size, 0);
+    for (key, is_offset) in [("offset=", true), ("align=", false)] {
+        let Some(atom) = cursor.peek_atom() else {
+            break;
+        };
+        let Some(value) = atom.node.strip_prefix(key) else {
+            continue;
+        };
+        cursor.next();
+        let invalid = || WatErrorKind::InvalidNumber(atom.node.clone()).at(atom.span);
+        let value = match parse_integer(value) {
+            Some((false, value)) => u32::try_from(value).map_err(|_| invalid())?,
+            _ => return Err(invalid()),
+        };
+        if is_offset {
+            arg.offset = value;
+        } else if value.is_power_of_two() {
+            arg.align = value.trailing_zeros();
+        } else {
+            return Err(invalid());
+        }
+    }
+    Ok(arg)
+}
+
+/// The names visible inside a function body.
+#[derive(Default)]
+struct FunctionScope {
+    locals: HashMap<String, u32>,
+    // The labels of the enclosing blocks, innermost last, starting with the function itself
+    labels: Vec<Option<String>>,
+}
+
+impl FunctionScope {
+    fn local(&self, cursor: &mut Cursor) -> Result<u32, WatError> {
+        match cursor.peek_atom() {
+            Some(atom) if atom.node.starts_with('$') => {
+                cursor.next();
+                self.locals.get(&atom.node).copied().ok_or_else(|| {
+                    WatErrorKind::UnknownName {
+                        kind: "local",
+                        name: atom.node.clone(),
+                    }
+                    .at(atom.span)
+                })
+            }
+            _ => cursor.u32("a local"),
+        }
+    }
+
+    // A label as a depth, counting out from the innermost block
+    fn label(&self, cursor: &mut Cursor) -> Result<u32, WatError> {
+        match cursor.peek_atom() {
+            Some(atom) if atom.node.starts_with('$') => {
+                cursor.next();
+                self.labels
+                    .iter()
+                    .rev()
+                    .position(|label| label.as_ref() == Some(&atom.node))
+                    .map(|depth| depth as u32)
+                    .ok_or_else(|| {
+                        WatErrorKind::UnknownName {
+                            kind: "label",
+                            name: atom.node.clone(),
+                        }
+                        .at(atom.span)
+                    })
+            }
+            _ => cursor.u32("a label"),
+        }
+    }
+}
+
+// The name given by a `$name`, as it's stored in the name section
+fn name(id: &Spanned<String>) -> String {
+    id.node[1..].to_string()
+}
+
+fn declare(
+    names: &mut HashMap<String, u32>,
+    kind: &'static str,
+    id: &Spanned<String>,
+    index: u32,
+) -> Result<(), WatError> {
+    match names.insert(id.node.clone(), index) {
+        Some(_) => Err(WatErrorKind::DuplicateName {
+            kind,
+            name: id.node.clone(),
+        }
+        .at(id.span)),
+        None => Ok(()),
+    }
+}
+
+// Types
+
+fn value_type(cursor: &mut Cursor) -> Result<NumType, WatError> {
+    let atom = cursor.atom("a value type")?;
+    match atom.node.as_str() {
+        "i32" => Ok(NumType::I32),
+        "i64" => Ok(NumType::I64),
+        "f32" => Ok(NumType::F32),
+        "f64" => Ok(NumType::F64),
+        _ => Err(WatErrorKind::Expected {
+            expected: "a value type",
+            found: Some(atom.node.clone()),
+        }
+        .at(atom.span)),
+    }
+}
+
+/// `(param ...)* (result ...)*`, adding the names of params to `names`.
+fn function_type(
+    cursor: &mut Cursor,
+    names: &mut HashMap<String, u32>,
+) -> Result<FunctionType, WatError> {
+    let mut args = Vec::new();
+    while let Some(list) = cursor.list_if("param") {
+        let mut param = list.cursor();
+        param.next();
+        match param.id() {
+            Some(id) => {
+                declare(names, "param", id, args.len() as u32)?;
+                args.push(value_type(&mut param)?);
+            }
+            None => {
+                while !param.is_empty() {
+                    args.push(value_type(&mut param)?);
+                }
+            }
+        }
+        param.done()?;
+    }
+    let mut ret = Vec::new();
+    while let Some(list) = cursor.list_if("result") {
+        let mut result = list.cursor();
+        result.next();
+        while !result.is_empty() {
+            ret.push(value_type(&mut result)?);
+        }
+    }
+    Ok(FunctionType { args, ret })
+}
+
+fn limits(cursor: &mut Cursor) -> Result<Limits, WatError> {
+    let min = cursor.u32("a size")?;
+    let max = match cursor.peek_atom() {
+        Some(atom) if atom.node.starts_with(|c: char| c.is_ascii_digit()) => {
+            Some(cursor.u32("a size")?)
+        }
+        _ => None,
+    };
+    Ok(Limits { min, max })
+}
+
+fn ref_type(cursor: &mut Cursor) -> Result<RefType, WatError> {
+    let atom = cursor.atom("a reference type")?;
+    match atom.node.as_str() {
+        "funcref" => Ok(RefType::FuncRef),
+        "externref" => Ok(RefType::ExternRef),
+        _ => Err(WatErrorKind::Expected {
+            expected: "a reference type",
+            found: Some(atom.node.clone()),
+        }
+        .at(atom.span)),
+    }
+}
+
+fn table_type(cursor: &mut Cursor) -> Result<TableType, WatError> {
+    let limits = limits(cursor)?;
+    let element = ref_type(cursor)?;
+    Ok(TableType { element, limits })
+}
+
+fn global_type(cursor: &mut Cursor) -> Result<GlobalType, WatError> {
+    match cursor.list_if("mut") {
+        Some(list) => {
+            let mut inner = list.cursor();
+            inner.next();
+            let typ = value_type(&mut inner)?;
+            inner.done()?;
+            Ok(GlobalType { typ, mutable: true })
+        }
+        None => Ok(GlobalType {
+            typ: value_type(cursor)?,
+            mutable: false,
+        }),
+    }
+}
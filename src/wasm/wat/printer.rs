@@ -0,0 +1,899 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+};
+
+use super::*;
+
+/// How instructions are laid out in function bodies.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Style {
+    // One instruction per line, blocks closed with `end`
+    Flat,
+    // S-expressions with operands nested in the instructions using them, eg. `(i32.add (local.get 0) (i32.const 1))`
+    Folded,
+}
+
+/// Renders `module` in the WebAssembly text format.
+pub fn print_module(module: &WasmModule, style: Style) -> String {
+    let mut printer = Printer {
+        module,
+        style,
+        output: String::new(),
+        functions: printable_names(&module.names.functions),
+        locals: HashMap::new(),
+    };
+    printer.module();
+    printer.output
+}
+
+// Folded instructions longer than this are split over several lines
+const LINE_WIDTH: usize = 80;
+
+struct Printer<'a> {
+    module: &'a WasmModule,
+    style: Style,
+    output: String,
+    // Names from the name section that can be written as `$name`s, by index
+    functions: HashMap<u32, String>,
+    // The locals of the function being printed
+    locals: HashMap<u32, String>,
+}
+
+impl Printer<'_> {
+    fn line(&mut self, indent: usize, text: &str) {
+        writeln!(self.output, "{:indent$}{}", "", text, indent = indent * 2).unwrap();
+    }
+
+    fn module(&mut self) {
+        let module = self.module;
+        let name = module.names.module.as_deref();
+        match name.filter(|name| printable(name)) {
+            Some(name) => self.line(0, &format!("(module ${}", name)),
+            None => self.line(0, "(module"),
+        }
+
+        for (i, typ) in module.types.iter().enumerate() {
+            self.line(1, &format!("(type (;{};) {})", i, function_type(typ)));
+        }
+
+        // Imports take the first indices of their kind
+        let (mut functions, mut tables, mut memories, mut globals) = (0, 0, 0, 0);
+        for import in module.imports.iter() {
+            let desc = match &import.typ {
+                ImportType::Func(typ) => {
+                    functions += 1;
+                    format!("(func {} (type {}))", self.function_id(functions - 1), typ)
+                }
+                ImportType::Table(table) => {
+                    tables += 1;
+                    format!("(table (;{};) {})", tables - 1, table_type(table))
+                }
+                ImportType::Memory(limits) => {
+                    memories += 1;
+                    format!("(memory (;{};) {})", memories - 1, self::limits(limits))
+                }
+                ImportType::Global(global) => {
+                    globals += 1;
+                    format!("(global (;{};) {})", globals - 1, global_type(global))
+                }
+            };
+            self.line(
+                1,
+                &format!(
+                    "(import {} {} {})",
+                    string(import.module.as_bytes()),
+                    string(import.name.as_bytes()),
+                    desc
+                ),
+            );
+        }
+
+        for (i, function) in module.functions.iter().enumerate() {
+            self.function(functions + i as u32, function);
+        }
+        for (i, table) in module.tables.iter().enumerate() {
+            self.line(
+                1,
+                &format!("(table (;{};) {})", tables + i, table_type(table)),
+            );
+        }
+        for (i, memory) in module.memories.iter().enumerate() {
+            self.line(
+                1,
+                &format!("(memory (;{};) {})", memories + i, limits(memory)),
+            );
+        }
+        for (i, tag) in module.tags.iter().enumerate() {
+            self.line(1, &format!("(tag (;{};) (type {}))", i, tag.type_idx));
+        }
+        for (i, global) in module.globals.iter().enumerate() {
+            self.line(
+                1,
+                &format!(
+                    "(global (;{};) {} {})",
+                    globals + i,
+                    global_type(&global.typ),
+                    const_expr(&global.init)
+                ),
+            );
+        }
+
+        for export in module.exports.iter() {
+            let desc = match export.typ {
+                ExportType::Func(index) => format!("(func {})", self.function_ref(index)),
+                ExportType::Table(index) => format!("(table {})", index),
+                ExportType::Memory(index) => format!("(memory {})", index),
+                ExportType::Global(index) => format!("(global {})", index),
+            };
+            self.line(
+                1,
+                &format!("(export {} {})", string(export.name.as_bytes()), desc),
+            );
+        }
+
+        if let Some(start) = module.start {
+            self.line(1, &format!("(start {})", self.function_ref(start)));
+        }
+
+        for (i, element) in module.elements.iter().enumerate() {
+            let mut text = format!("(elem (;{};) ", i);
+            if element.table != 0 {
+                write!(text, "(table {}) ", element.table).unwrap();
+            }
+            write!(text, "{} func", offset_expr(&element.offset)).unwrap();
+            for function in element.functions.iter() {
+                write!(text, " {}", self.function_ref(*function)).unwrap();
+            }
+            text.push(')');
+            self.line(1, &text);
+        }
+
+        for (i, data) in module.data.iter().enumerate() {
+            let mut text = format!("(data (;{};) ", i);
+            if data.memory != 0 {
+                write!(text, "(memory {}) ", data.memory).unwrap();
+            }
+            write!(
+                text,
+                "{} {})",
+                offset_expr(&data.offset),
+                string(&data.bytes)
+            )
+            .unwrap();
+            self.line(1, &text);
+        }
+
+        self.line(0, ")");
+    }
+
+    fn function(&mut self, index: u32, function: &Function) {
+        self.locals = match (self.module.names.locals).binary_search_by_key(&index, |(i, _)| *i) {
+            Ok(position) => printable_names(&self.module.names.locals[position].1),
+            Err(_) => HashMap::new(),
+        };
+
+        let mut header = format!(
+            "(func {} (type {})",
+            self.function_id(index),
+            function.type_idx
+        );
+        let typ = self.module.types.get(function.type_idx as usize);
+        if let Some(typ) = typ {
+            // The signature is repeated so the parameters can be read off
+            if self.locals.is_empty() {
+                write!(header, "{}", signature(typ)).unwrap();
+            } else {
+                // Named parameters have to be declared one at a time
+                for (i, param) in typ.args.iter().enumerate() {
+                    match self.locals.get(&(i as u32)) {
+                        Some(name) => write!(header, " (param ${} {})", name, param).unwrap(),
+                        None => write!(header, " (param {})", param).unwrap(),
+                    }
+                }
+                if !typ.ret.is_empty() {
+                    write!(header, " (result{})", value_types(&typ.ret)).unwrap();
+                }
+            }
+        }
+        self.line(1, &header);
+        if !function.locals.is_empty() {
+            let params = typ.map_or(0, |typ| typ.args.len());
+            let names = (params..params + function.locals.len())
+                .any(|i| self.locals.contains_key(&(i as u32)));
+            if names {
+                let locals = (function.locals.iter().enumerate())
+                    .map(|(i, local)| match self.locals.get(&((params + i) as u32)) {
+                        Some(name) => format!("(local ${} {})", name, local),
+                        None => format!("(local {})", local),
+                    })
+                    .collect::<Vec<_>>();
+                self.line(2, &locals.join(" "));
+            } else {
+                self.line(2, &format!("(local{})", value_types(&function.locals)));
+            }
+        }
+
+        match self.style {
+            Style::Flat => self.flat(&function.body.instructions, 2),
+            Style::Folded => {
+                let results = typ.map_or(0, |typ| typ.ret.len());
+                let nodes = self.fold(&function.body.instructions, &mut vec![results]);
+                for node in nodes {
+                    node.render(2, &mut self.output);
+                }
+            }
+        }
+        self.line(1, ")");
+    }
+
+    fn flat(&mut self, instructions: &[Instruction], indent: usize) {
+        for instruction in instructions {
+            self.line(indent, &self.instruction_text(instruction));
+            match instruction {
+                Instruction::ControlOp(ControlOp::Block { body, .. })
+                | Instruction::ControlOp(ControlOp::Loop { body, .. }) => {
+                    self.flat(&body.instructions, indent + 1);
+                    self.line(indent, "end");
+                }
+                Instruction::ControlOp(ControlOp::If {
+                    then, otherwise, ..
+                }) => {
+                    self.flat(&then.instructions, indent + 1);
+                    if let Some(otherwise) = otherwise {
+                        self.line(indent, "else");
+                        self.flat(&otherwise.instructions, indent + 1);
+                    }
+                    self.line(indent, "end");
+                }
+                Instruction::ControlOp(ControlOp::Try {
+                    body,
+                    catches,
+                    catch_all,
+                    ..
+                }) => {
+                    self.flat(&body.instructions, indent + 1);
+                    for catch in catches {
+                        self.line(indent, &format!("catch {}", catch.tag));
+                        self.flat(&catch.body.instructions, indent + 1);
+                    }
+                    if let Some(catch_all) = catch_all {
+                        self.line(indent, "catch_all");
+                        self.flat(&catch_all.instructions, indent + 1);
+                    }
+                    self.line(indent, "end");
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Nests the operands of each instruction inside it, as far as they can be found.
+    /// `labels` holds the number of values a branch to each enclosing block takes.
+    fn fold(&self, instructions: &[Instruction], labels: &mut Vec<usize>) -> Vec<Node> {
+        let mut nodes = Vec::new();
+        // Instructions leaving a single value that hasn't been used yet
+        let mut pending: Vec<Node> = Vec::new();
+        for instruction in instructions {
+            let (params, results) = self.stack_effect(instruction, labels);
+            let mut node = self.fold_instruction(instruction, labels);
+            match params {
+                Some(params) if params <= pending.len() => {
+                    let operands = pending.split_off(pending.len() - params);
+                    node.children.splice(0..0, operands);
+                }
+                // Some operands are left on the stack by earlier instructions, so take them all from there
+                _ => nodes.append(&mut pending),
+            }
+            if results == Some(1) {
+                pending.push(node);
+            } else {
+                nodes.append(&mut pending);
+                nodes.push(node);
+            }
+        }
+        nodes.append(&mut pending);
+        nodes
+    }
+
+    fn fold_instruction(&self, instruction: &Instruction, labels: &mut Vec<usize>) -> Node {
+        let head = self.instruction_text(instruction);
+        let children = match instruction {
+            Instruction::ControlOp(ControlOp::Block { typ, body }) => {
+                labels.push(self.block_results(typ).unwrap_or(0));
+                let children = self.fold(&body.instructions, labels);
+                labels.pop();
+                children
+            }
+            Instruction::ControlOp(ControlOp::Loop { typ, body }) => {
+                labels.push(self.block_params(typ).unwrap_or(0));
+                let children = self.fold(&body.instructions, labels);
+                labels.pop();
+                children
+            }
+            Instruction::ControlOp(ControlOp::If {
+                typ,
+                then,
+                otherwise,
+            }) => {
+                labels.push(self.block_results(typ).unwrap_or(0));
+                let mut children = vec![Node {
+                    head: "then".to_string(),
+                    children: self.fold(&then.instructions, labels),
+                    block: true,
+                }];
+                if let Some(otherwise) = otherwise {
+                    children.push(Node {
+                        head: "else".to_string(),
+                        children: self.fold(&otherwise.instructions, labels),
+                        block: true,
+                    });
+                }
+                labels.pop();
+                children
+            }
+            Instruction::ControlOp(ControlOp::Try {
+                typ,
+                body,
+                catches,
+                catch_all,
+            }) => {
+                labels.push(self.block_results(typ).unwrap_or(0));
+                let mut children = vec![Node {
+                    head: "do".to_string(),
+                    children: self.fold(&body.instructions, labels),
+                    block: true,
+                }];
+                for catch in catches {
+                    children.push(Node {
+                        head: format!("catch {}", catch.tag),
+                        children: self.fold(&catch.body.instructions, labels),
+                        block: true,
+                    });
+                }
+                if let Some(catch_all) = catch_all {
+                    children.push(Node {
+                        head: "catch_all".to_string(),
+                        children: self.fold(&catch_all.instructions, labels),
+                        block: true,
+                    });
+                }
+                labels.pop();
+                children
+            }
+            _ => Vec::new(),
+        };
+        Node {
+            block: matches!(
+                instruction,
+                Instruction::ControlOp(
+                    ControlOp::Block { .. }
+                        | ControlOp::Loop { .. }
+                        | ControlOp::If { .. }
+                        | ControlOp::Try { .. }
+                )
+            ),
+            head,
+            children,
+        }
+    }
+
+    /// How many values `instruction` takes from and leaves on the stack. The number taken is
+    /// `None` if it can't be folded, and the number left `None` if the rest of the block is
+    /// unreachable after it.
+    fn stack_effect(
+        &self,
+        instruction: &Instruction,
+        labels: &[usize],
+    ) -> (Option<usize>, Option<usize>) {
+        let label = |depth: &u32| {
+            labels
+                .len()
+                .checked_sub(*depth as usize + 1)
+                .map(|i| labels[i])
+        };
+        match instruction {
+            Instruction::I32Const(_)
+            | Instruction::I64Const(_)
+            | Instruction::F32Const(_)
+            | Instruction::F64Const(_) => (Some(0), Some(1)),
+            Instruction::IntegerOp(op) => match op.op {
+                IntegerOpType::Clz
+                | IntegerOpType::Ctz
+                | IntegerOpType::Popcnt
+                | IntegerOpType::Eqz => (Some(1), Some(1)),
+                _ => (Some(2), Some(1)),
+            },
+            Instruction::FloatOp(op) => match op.op {
+                FloatOpType::Abs
+                | FloatOpType::Neg
+                | FloatOpType::Sqrt
+                | FloatOpType::Ceil
+                | FloatOpType::Floor
+                | FloatOpType::Trunc
+                | FloatOpType::Nearest => (Some(1), Some(1)),
+                _ => (Some(2), Some(1)),
+            },
+            Instruction::ConvertOp(_) => (Some(1), Some(1)),
+            Instruction::RefOp(op) => match op {
+                RefOp::Null(_) | RefOp::Func(_) => (Some(0), Some(1)),
+                RefOp::IsNull => (Some(1), Some(1)),
+            },
+            Instruction::ParametricOp(ParametricOp::Drop) => (Some(1), Some(0)),
+            Instruction::ParametricOp(ParametricOp::Select) => (Some(3), Some(1)),
+            Instruction::VariableOp(op) => match op {
+                VariableOp::LocalGet(_) | VariableOp::GlobalGet(_) => (Some(0), Some(1)),
+                VariableOp::LocalSet(_) | VariableOp::GlobalSet(_) => (Some(1), Some(0)),
+                VariableOp::LocalTee(_) => (Some(1), Some(1)),
+            },
+            Instruction::MemoryOp(op) => match op {
+                MemoryOp::MemorySize => (Some(0), Some(1)),
+                MemoryOp::MemoryGrow => (Some(1), Some(1)),
+                MemoryOp::MemoryCopy | MemoryOp::MemoryFill => (Some(3), Some(0)),
+                MemoryOp::I32Store(_)
+                | MemoryOp::I64Store(_)
+                | MemoryOp::F32Store(_)
+                | MemoryOp::F64Store(_)
+                | MemoryOp::I32Store8(_)
+                | MemoryOp::I32Store16(_)
+                | MemoryOp::I64Store8(_)
+                | MemoryOp::I64Store16(_)
+                | MemoryOp::I64Store32(_) => (Some(2), Some(0)),
+                // Loads
+                _ => (Some(1), Some(1)),
+            },
+            Instruction::ControlOp(op) => match op {
+                ControlOp::Unreachable => (Some(0), None),
+                ControlOp::Nop => (Some(0), Some(0)),
+                // Blocks taking parameters can't have them folded in
+                ControlOp::Block { typ, .. } | ControlOp::Loop { typ, .. } => {
+                    let params = self.block_params(typ).filter(|params| *params == 0);
+                    (params, self.block_results(typ))
+                }
+                ControlOp::If { typ, .. } => {
+                    let params = self.block_params(typ).filter(|params| *params == 0);
+                    (params.map(|_| 1), self.block_results(typ))
+                }
+                ControlOp::Try { typ, .. } => {
+                    let params = self.block_params(typ).filter(|params| *params == 0);
+                    (params, self.block_results(typ))
+                }
+                ControlOp::Throw(tag) => match self.tag_type(*tag) {
+                    Some(typ) => (Some(typ.args.len()), None),
+                    None => (None, None),
+                },
+                ControlOp::Rethrow(_) => (Some(0), None),
+                ControlOp::Br(depth) => (label(depth), None),
+                ControlOp::BrIf(depth) => (label(depth).map(|n| n + 1), label(depth)),
+                ControlOp::BrTable { default, .. } => (label(default).map(|n| n + 1), None),
+                ControlOp::Return => (labels.first().copied(), None),
+                ControlOp::Call(index) => match self.function_type(*index) {
+                    Some(typ) => (Some(typ.args.len()), Some(typ.ret.len())),
+                    None => (None, Some(0)),
+                },
+                // The index of the function comes after its arguments
+                ControlOp::CallIndirect { type_idx, .. } => {
+                    match self.module.types.get(*type_idx as usize) {
+                        Some(typ) => (Some(typ.args.len() + 1), Some(typ.ret.len())),
+                        None => (None, Some(0)),
+                    }
+                }
+            },
+        }
+    }
+
+    /// `instruction_text`, with functions and locals referred to by name where they have one.
+    fn instruction_text(&self, instruction: &Instruction) -> String {
+        match instruction {
+            Instruction::VariableOp(VariableOp::LocalGet(index)) => {
+                format!("local.get {}", self.local_ref(*index))
+            }
+            Instruction::VariableOp(VariableOp::LocalSet(index)) => {
+                format!("local.set {}", self.local_ref(*index))
+            }
+            Instruction::VariableOp(VariableOp::LocalTee(index)) => {
+                format!("local.tee {}", self.local_ref(*index))
+            }
+            Instruction::ControlOp(ControlOp::Call(index)) => {
+                format!("call {}", self.function_ref(*index))
+            }
+            Instruction::RefOp(RefOp::Func(index)) => {
+                format!("ref.func {}", self.function_ref(*index))
+            }
+            _ => instruction_text(instruction),
+        }
+    }
+
+    // How a function is named where it's defined, eg. `$main` or `(;3;)`
+    fn function_id(&self, index: u32) -> String {
+        match self.functions.get(&index) {
+            Some(name) => format!("${}", name),
+            None => format!("(;{};)", index),
+        }
+    }
+
+    fn function_ref(&self, index: u32) -> String {
+        match self.functions.get(&index) {
+            Some(name) => format!("${}", name),
+            None => index.to_string(),
+        }
+    }
+
+    fn local_ref(&self, index: u32) -> String {
+        match self.locals.get(&index) {
+            Some(name) => format!("${}", name),
+            None => index.to_string(),
+        }
+    }
+
+    fn block_params(&self, typ: &BlockType) -> Option<usize> {
+        match typ {
+            BlockType::Empty | BlockType::Value(_) => Some(0),
+            BlockType::Type(index) => self.module.types.get(*index as usize).map(|t| t.args.len()),
+        }
+    }
+
+    fn block_results(&self, typ: &BlockType) -> Option<usize> {
+        match typ {
+            BlockType::Empty => Some(0),
+            BlockType::Value(_) => Some(1),
+            BlockType::Type(index) => self.module.types.get(*index as usize).map(|t| t.ret.len()),
+        }
+    }
+
+    fn tag_type(&self, tag: u32) -> Option<&FunctionType> {
+        let tag = self.module.tags.get(tag as usize)?;
+        self.module.types.get(tag.type_idx as usize)
+    }
+
+    // The type of a function, counting imported functions first
+    fn function_type(&self, index: u32) -> Option<&FunctionType> {
+        let mut imports = self
+            .module
+            .imports
+            .iter()
+            .filter_map(|import| match import.typ {
+                ImportType::Func(typ) => Some(typ),
+                _ => None,
+            });
+        let imported = imports.clone().count();
+        let type_idx = match (index as usize).checked_sub(imported) {
+            None => imports.nth(index as usize)?,
+            Some(index) => self.module.functions.get(index)?.type_idx,
+        };
+        self.module.types.get(type_idx as usize)
+    }
+}
+
+/// A folded instruction and the instructions nested inside it.
+struct Node {
+    head: String,
+    children: Vec<Node>,
+    // Blocks are always split over several lines
+    block: bool,
+}
+
+impl Node {
+    fn inline(&self) -> Option<String> {
+        if self.block {
+            return None;
+        }
+        let mut text = format!("({}", self.head);
+        for child in self.children.iter() {
+            write!(text, " {}", child.inline()?).unwrap();
+        }
+        text.push(')');
+        Some(text)
+    }
+
+    fn render(&self, indent: usize, output: &mut String) {
+        if let Some(text) = self
+            .inline()
+            .filter(|text| indent * 2 + text.len() <= LINE_WIDTH)
+        {
+            writeln!(output, "{:indent$}{}", "", text, indent = indent * 2).unwrap();
+            return;
+        }
+        writeln!(output, "{:indent$}({}", "", self.head, indent = indent * 2).unwrap();
+        for child in self.children.iter() {
+            child.render(indent + 1, output);
+        }
+        // The closing paren goes after the last child rather than on a line of its own
+        output.pop();
+        output.push_str(")\n");
+    }
+}
+
+/// The text of an instruction without any nested instructions, eg. `block (result i32)`.
+pub fn instruction_text(instruction: &Instruction) -> String {
+    match instruction {
+        Instruction::I32Const(val) => format!("i32.const {}", val),
+        Instruction::I64Const(val) => format!("i64.const {}", val),
+        Instruction::F32Const(val) => format!("f32.const {}", f32_text(*val)),
+        Instruction::F64Const(val) => format!("f64.const {}", f64_text(*val)),
+        Instruction::IntegerOp(op) => format!("{}.{}", op.typ, name(INTEGER_OPS, &op.op)),
+        Instruction::FloatOp(op) => format!("{}.{}", op.typ, name(FLOAT_OPS, &op.op)),
+        Instruction::ConvertOp(op) => name(CONVERT_OPS, op).to_string(),
+        Instruction::RefOp(op) => match op {
+            RefOp::Null(RefType::FuncRef) => "ref.null func".to_string(),
+            RefOp::Null(RefType::ExternRef) => "ref.null extern".to_string(),
+            RefOp::IsNull => "ref.is_null".to_string(),
+            RefOp::Func(index) => format!("ref.func {}", index),
+        },
+        Instruction::ParametricOp(ParametricOp::Drop) => "drop".to_string(),
+        Instruction::ParametricOp(ParametricOp::Select) => "select".to_string(),
+        Instruction::VariableOp(op) => match op {
+            VariableOp::LocalGet(index) => format!("local.get {}", index),
+            VariableOp::LocalSet(index) => format!("local.set {}", index),
+            VariableOp::LocalTee(index) => format!("local.tee {}", index),
+            VariableOp::GlobalGet(index) => format!("global.get {}", index),
+            VariableOp::GlobalSet(index) => format!("global.set {}", index),
+        },
+        Instruction::MemoryOp(op) => {
+            let (name, size) = memory_op(op);
+            let mut text = name.to_string();
+            if let Some(arg) = op.memarg() {
+                if arg.offset != 0 {
+                    write!(text, " offset={}", arg.offset).unwrap();
+                }
+                if arg.align != MemArg::natural(size, 0).align {
+                    write!(text, " align={}", 1u64 << arg.align.min(63)).unwrap();
+                }
+            }
+            text
+        }
+        Instruction::ControlOp(op) => match op {
+            ControlOp::Unreachable => "unreachable".to_string(),
+            ControlOp::Nop => "nop".to_string(),
+            ControlOp::Block { typ, .. } => format!("block{}", block_type(typ)),
+            ControlOp::Loop { typ, .. } => format!("loop{}", block_type(typ)),
+            ControlOp::If { typ, .. } => format!("if{}", block_type(typ)),
+            ControlOp::Br(depth) => format!("br {}", depth),
+            ControlOp::BrIf(depth) => format!("br_if {}", depth),
+            ControlOp::BrTable { labels, default } => {
+                let mut text = "br_table".to_string();
+                for label in labels.iter().chain([default]) {
+                    write!(text, " {}", label).unwrap();
+                }
+                text
+            }
+            ControlOp::Return => "return".to_string(),
+            ControlOp::Call(index) => format!("call {}", index),
+            ControlOp::CallIndirect { type_idx, table } => match table {
+                0 => format!("call_indirect (type {})", type_idx),
+                _ => format!("call_indirect {} (type {})", table, type_idx),
+            },
+            ControlOp::Try { typ, .. } => format!("try{}", block_type(typ)),
+            ControlOp::Throw(tag) => format!("throw {}", tag),
+            ControlOp::Rethrow(depth) => format!("rethrow {}", depth),
+        },
+    }
+}
+
+fn name<'a, T: PartialEq>(names: &[(T, &'a str)], op: &T) -> &'a str {
+    names
+        .iter()
+        .find(|(other, _)| other == op)
+        .map(|(_, name)| *name)
+        .expect("every operator has a name")
+}
+
+// The name of a memory instruction and the size of the values it accesses
+fn memory_op(op: &MemoryOp) -> (&'static str, u32) {
+    match op {
+        MemoryOp::MemorySize => ("memory.size", 0),
+        MemoryOp::MemoryGrow => ("memory.grow", 0),
+        MemoryOp::MemoryCopy => ("memory.copy", 0),
+        MemoryOp::MemoryFill => ("memory.fill", 0),
+        _ => {
+            let arg = op.memarg().unwrap();
+            MEMORY_OPS
+                .iter()
+                .find(|(make, _, _)| make(arg) == *op)
+                .map(|(_, name, size)| (*name, *size))
+                .expect("every load and store has a name")
+        }
+    }
+}
+
+// Floats are written in decimal, which round trips, and NaNs with their payload if it isn't the default
+fn f32_text(val: f32) -> String {
+    match val.is_nan() {
+        true => nan_text(
+            val.is_sign_negative(),
+            (val.to_bits() & 0x7f_ffff) as u64,
+            1 << 22,
+        ),
+        false => format!("{:?}", val),
+    }
+}
+
+fn f64_text(val: f64) -> String {
+    match val.is_nan() {
+        true => nan_text(
+            val.is_sign_negative(),
+            val.to_bits() & 0xf_ffff_ffff_ffff,
+            1 << 51,
+        ),
+        false => format!("{:?}", val),
+    }
+}
+
+fn nan_text(negative: bool, payload: u64, canonical: u64) -> String {
+    let sign = if negative { "-" } else { "" };
+    match payload == canonical {
+        true => format!("{}nan", sign),
+        false => format!("{}nan:0x{:x}", sign, payload),
+    }
+}
+
+fn block_type(typ: &BlockType) -> String {
+    match typ {
+        BlockType::Empty => String::new(),
+        BlockType::Value(typ) => format!(" (result {})", typ),
+        BlockType::Type(index) => format!(" (type {})", index),
+    }
+}
+
+fn value_types(types: &[NumType]) -> String {
+    types.iter().map(|typ| format!(" {}", typ)).collect()
+}
+
+// The params and results of a function, eg. ` (param i32) (result i32)`
+fn signature(typ: &FunctionType) -> String {
+    let mut text = String::new();
+    if !typ.args.is_empty() {
+        write!(text, " (param{})", value_types(&typ.args)).unwrap();
+    }
+    if !typ.ret.is_empty() {
+        write!(text, " (result{})", value_types(&typ.ret)).unwrap();
+    }
+    text
+}
+
+fn function_type(typ: &FunctionType) -> String {
+    format!("(func{})", signature(typ))
+}
+
+fn limits(limits: &Limits) -> String {
+    match limits.max {
+        Some(max) => format!("{} {}", limits.min, max),
+        None => limits.min.to_string(),
+    }
+}
+
+fn table_type(table: &TableType) -> String {
+    format!("{} {}", limits(&table.limits), table.element)
+}
+
+fn global_type(global: &GlobalType) -> String {
+    if global.mutable {
+        format!("(mut {})", global.typ)
+    } else {
+        global.typ.to_string()
+    }
+}
+
+fn printable(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(is_idchar)
+}
+
+// The names that can be written as `$name`s. Names used more than once get a suffix after
+// their first use, eg. `$n` and `$n_1`, so they can be told apart.
+fn printable_names(names: &[(u32, String)]) -> HashMap<u32, String> {
+    let mut taken = (names.iter())
+        .filter(|(_, name)| printable(name))
+        .map(|(_, name)| name.clone())
+        .collect::<HashSet<_>>();
+    let mut printed = HashMap::new();
+    let mut seen = HashSet::new();
+    for (index, name) in names.iter().filter(|(_, name)| printable(name)) {
+        let name = if seen.insert(name) {
+            name.clone()
+        } else {
+            let unique = (1..)
+                .map(|suffix| format!("{}_{}", name, suffix))
+                .find(|unique| !taken.contains(unique))
+                .unwrap();
+            taken.insert(unique.clone());
+            unique
+        };
+        printed.insert(*index, name);
+    }
+    printed
+}
+
+// A constant expression as a list of plain folded instructions, eg. `(i32.const 4)`
+fn const_expr(expr: &Expression) -> String {
+    expr.instructions
+        .iter()
+        .map(|instruction| format!("({})", instruction_text(instruction)))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// The offset of a segment, which can be written as a bare instruction when it is just one
+fn offset_expr(expr: &Expression) -> String {
+    match expr.instructions.len() {
+        1 => const_expr(expr),
+        _ => format!("(offset {})", const_expr(expr)),
+    }
+}
+
+// A string literal, escaping anything that isn't printable ASCII
+fn string(bytes: &[u8]) -> String {
+    let mut text = "\"".to_string();
+    for byte in bytes {
+        match byte {
+            b'"' | b'\\' => write!(text, "\\{}", *byte as char).unwrap(),
+            0x20..=0x7e => text.push(*byte as char),
+            _ => write!(text, "\\{:02x}", byte).unwrap(),
+        }
+    }
+    text.push('"');
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wasm::wat::parser::parse_module;
+
+    // Prints the module, parses the text back, and checks printing it again gives the same text
+    fn round_trip(source: &str, style: Style) {
+        let module = parse_module(source).unwrap();
+        let text = print_module(&module, style);
+
+        let parsed = parse_module(&text).unwrap_or_else(|err| panic!("{:?}\n{}", err, text));
+        assert_eq!(parsed, module);
+        assert_eq!(print_module(&parsed, style), text);
+    }
+
+    #[test]
+    fn flat_round_trip() {
+        round_trip(include_str!("../../../tests/module.wat"), Style::Flat);
+        round_trip(include_str!("../../codegen/gc.wat"), Style::Flat);
+    }
+
+    #[test]
+    fn folded_round_trip() {
+        round_trip(include_str!("../../../tests/module.wat"), Style::Folded);
+        round_trip(include_str!("../../codegen/gc.wat"), Style::Folded);
+    }
+
+    #[test]
+    fn escapes_strings() {
+        let module = parse_module(r#"(module (memory 1) (data (i32.const 0) "a\"\\\n\ff"))"#);
+        let text = print_module(&module.unwrap(), Style::Flat);
+        assert!(text.contains(r#""a\"\\\0a\ff""#), "{}", text);
+    }
+
+    // Eg. the values caught by two `catch`es with the same name
+    #[test]
+    fn suffixes_duplicate_names() {
+        let mut module =
+            parse_module("(module (func (param $a i32) (local $b i32) (local $c i32)))").unwrap();
+        module.names.locals[0].1 = vec![
+            (0, "n".to_string()),
+            (1, "n".to_string()),
+            (2, "n_1".to_string()),
+        ];
+        let text = print_module(&module, Style::Flat);
+        assert!(text.contains("(param $n i32)"), "{}", text);
+        assert!(
+            text.contains("(local $n_2 i32) (local $n_1 i32)"),
+            "{}",
+            text
+        );
+
+        let parsed = parse_module(&text).unwrap();
+        assert_eq!(
+            parsed.names.locals[0].1,
+            [
+                (0, "n".to_string()),
+                (1, "n_2".to_string()),
+                (2, "n_1".to_string())
+            ]
+        );
+    }
+}
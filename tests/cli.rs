@@ -0,0 +1,139 @@
+//! Runs the `compiler-rs` binary on the programs in this directory.
+
+use std::{
+    fs,
+    path::PathBuf,
+    process::{Command, Output},
+};
+
+// Runs the compiler from the crate's root, so paths in its output are relative to it
+fn compiler(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_compiler-rs"))
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .unwrap()
+}
+
+// The stdout of a successful run
+fn stdout(args: &[&str]) -> String {
+    let output = compiler(args);
+    assert!(
+        output.status.success(),
+        "{:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+// A path in a scratch directory for the test's output
+fn temp_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name)
+}
+
+#[test]
+fn emit_tokens() {
+    let tokens = stdout(&["tests/sample.jj", "--emit", "tokens"]);
+    let first = tokens.lines().take(3).collect::<Vec<_>>();
+    assert_eq!(
+        first,
+        [
+            "tests/sample.jj:1:1\tType",
+            "tests/sample.jj:1:6\tIdentifier(\"Person\")",
+            "tests/sample.jj:1:13\tAssign",
+        ]
+    );
+}
+
+#[test]
+fn emit_ast() {
+    let ast = stdout(&["tests/sample.jj", "--emit=ast"]);
+    assert!(ast.starts_with("// tests/sample.jj\n["), "{}", ast);
+    assert!(ast.contains("name: \"Person\""), "{}", ast);
+}
+
+#[test]
+fn emit_types() {
+    let types = stdout(&["tests/sample.jj", "--emit", "types"]);
+    let lines = types.lines().collect::<Vec<_>>();
+    assert!(lines.contains(&"type Adder = (int) => int;"), "{}", types);
+    assert!(lines.contains(&"let add_one: (int) => int;"), "{}", types);
+    assert!(lines.contains(&"let six: int;"), "{}", types);
+}
+
+#[test]
+fn emit_wat() {
+    let wat = stdout(&["tests/sample.jj", "--emit", "wat"]);
+    assert!(wat.starts_with("(module\n"), "{}", wat);
+    assert!(
+        wat.contains("(export \"add_one\" (func $add_one))"),
+        "{}",
+        wat
+    );
+
+    let folded = stdout(&["tests/sample.jj", "--emit", "wat-folded"]);
+    assert!(
+        folded.contains("(local.set $x (i32.add (local.get $x) (i32.const 1)))"),
+        "{}",
+        folded
+    );
+}
+
+#[test]
+fn emit_wasm() {
+    let path = temp_path("sample.wasm");
+    let output = stdout(&["tests/sample.jj", "-o", path.to_str().unwrap()]);
+    assert!(output.is_empty());
+    let wasm = fs::read(&path).unwrap();
+    assert_eq!(wasm[..8], *b"\0asm\x01\0\0\0");
+
+    // The module can be read back as text
+    let wat = stdout(&[path.to_str().unwrap(), "--emit", "wat"]);
+    assert!(
+        wat.contains("(export \"add_one\" (func $add_one))"),
+        "{}",
+        wat
+    );
+}
+
+#[test]
+fn run() {
+    assert_eq!(stdout(&["tests/sample.jj", "--run", "add_one 41"]), "42\n");
+    assert_eq!(
+        stdout(&["tests/program.jj", "--run", "add_two_int_32 1 2"]),
+        "3\n3\n"
+    );
+}
+
+#[test]
+fn reports_errors() {
+    let path = temp_path("mismatched.jj");
+    fs::write(&path, "let x: int = true;\n").unwrap();
+    let output = compiler(&[path.to_str().unwrap(), "--emit", "types"]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.starts_with("error: mismatched types\n"),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.ends_with("error: could not compile due to 1 previous error\n"),
+        "{}",
+        stderr
+    );
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn rejects_unknown_stages() {
+    let output = compiler(&["tests/sample.jj", "--emit", "typed-ast"]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.starts_with("error: unknown stage `typed-ast` for `--emit`\n"),
+        "{}",
+        stderr
+    );
+}
@@ -0,0 +1,42 @@
+(module
+  (type $binary (func (param i32 i32) (result i32)))
+  (import "env" "print_int" (func $print_int (param i32)))
+  (import "env" "limit" (global $limit i32))
+  (table 2 2 funcref)
+  (memory 1 4)
+  (tag $error (param i32))
+  (global $counter (mut i64) (i64.const -9223372036854775808))
+  (global $scale f64 (f64.const -0.5))
+  (export "memory" (memory 0))
+  (export "add" (func $add))
+  (start $init)
+  (elem (i32.const 0) $add $sub)
+  (data (i32.const 16) "hello\00\ff")
+  (func $init
+    (global.set $counter (i64.const 9223372036854775807)))
+  (func $add (type $binary)
+    (i32.add (local.get 0) (local.get 1)))
+  (func $sub (param $a i32) (param $b i32) (result i32)
+    (i32.sub (local.get $a) (local.get $b)))
+  (func $main (result i32)
+    (local $i i32)
+    (local $x f32)
+    (local.set $x (f32.const 1.5))
+    (local.set $i (i32.const -2147483648))
+    (block $done
+      (loop $next
+        (br_if $done (i32.ge_s (local.get $i) (global.get $limit)))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br_table $next $done (local.get $i))))
+    (i32.store offset=4 (i32.const 8) (local.get $i))
+    (drop (i64.load8_u (i32.const 16)))
+    (drop (memory.grow (i32.const 1)))
+    (try (result i32)
+      (do
+        (if (i32.eqz (local.get $i))
+          (then (throw $error (i32.const -64)))
+          (else (call $print_int (i32.const 63))))
+        (call_indirect (type $binary) (i32.const 1) (i32.const 2) (i32.const 0)))
+      (catch $error)
+      (catch_all
+        (i32.const -1)))))
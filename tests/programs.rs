@@ -0,0 +1,224 @@
+//! Compiles the programs in this directory and runs them with the interpreter.
+
+use std::sync::{Arc, Mutex};
+
+use compiler_rs::{
+    codegen::{declare_builtins, generate_module},
+    lexer::lexer::Lexer,
+    parser::{statements::parse_program, types::Type},
+    type_checker::{check_program, Scope, TypeError, TypeErrorKind},
+    wasm::{
+        encoder::EncodesToWasm,
+        interpreter::{Imports, Instance, RuntimeError, Value},
+        WasmModule,
+    },
+};
+
+// Compiles `tests/<name>.jj` to wasm, panicking with the errors if it doesn't compile
+fn compile(name: &str, gc: bool) -> Vec<u8> {
+    let path = format!("{}/tests/{}.jj", env!("CARGO_MANIFEST_DIR"), name);
+    let source = std::fs::read_to_string(&path).unwrap();
+
+    let (program, errors) = parse_program(Lexer::new(&source));
+    assert!(errors.is_empty(), "{} doesn't parse: {:?}", path, errors);
+    let mut scope = Scope::new();
+    declare_builtins(&mut scope);
+    if let Err(errors) = check_program(&program, &mut scope) {
+        panic!("{} doesn't type check: {:?}", path, errors);
+    }
+    let (module, _) = generate_module(&[program], gc)
+        .unwrap_or_else(|errors| panic!("{} doesn't compile: {:?}", path, errors));
+    if let Err(errors) = module.validate() {
+        panic!("{} compiles to invalid wasm: {:?}", path, errors);
+    }
+
+    let mut bytes = Vec::new();
+    module.encode_to_wasm(&mut bytes);
+    bytes
+}
+
+// Type checks `source`, returning the errors found
+fn type_errors(source: &str) -> Vec<TypeError> {
+    let (program, errors) = parse_program(Lexer::new(source));
+    assert!(errors.is_empty(), "doesn't parse: {:?}", errors);
+    let mut scope = Scope::new();
+    declare_builtins(&mut scope);
+    check_program(&program, &mut scope)
+        .err()
+        .unwrap_or_default()
+}
+
+type CallResult = Result<Vec<Value>, RuntimeError>;
+
+// Calls the function exported as `name`, returning its results and the ints it printed
+fn run(wasm: &[u8], name: &str, args: &[Value]) -> (CallResult, Vec<i32>) {
+    let (mut results, printed, _) = run_all(wasm, &[(name, args)]);
+    (results.remove(0), printed)
+}
+
+// Makes each call in turn on one instance of the module, returning the result of each, the
+// ints printed and how big the memory ended up
+fn run_all(wasm: &[u8], calls: &[(&str, &[Value])]) -> (Vec<CallResult>, Vec<i32>, usize) {
+    let module = WasmModule::decode(wasm).unwrap();
+    let printed = Arc::new(Mutex::new(Vec::new()));
+    let imports = Imports::default().with_function("env", "print_int", {
+        let printed = printed.clone();
+        move |args, _| {
+            let Value::I32(value) = args[0] else {
+                panic!("`print_int` called with {:?}", args);
+            };
+            printed.lock().unwrap().push(value);
+            Ok(Vec::new())
+        }
+    });
+    let (results, memory) = match Instance::new(&module, imports) {
+        Ok(mut instance) => {
+            let results = (calls.iter())
+                .map(|(name, args)| instance.call(name, args))
+                .collect();
+            (results, instance.memory().len())
+        }
+        Err(err) => (vec![Err(err)], 0),
+    };
+    let printed = printed.lock().unwrap().clone();
+    (results, printed, memory)
+}
+
+#[test]
+fn empty() {
+    let wasm = compile("empty", false);
+    let (result, _) = run(&wasm, "main", &[]);
+    assert_eq!(result, Err(RuntimeError::UnknownExport("main".to_string())));
+}
+
+#[test]
+fn program() {
+    let wasm = compile("program", false);
+    let (result, printed) = run(&wasm, "add_two_int_32", &[Value::I32(1), Value::I32(2)]);
+    assert_eq!(result, Ok(vec![Value::I32(3)]));
+    assert_eq!(printed, [3]);
+}
+
+#[test]
+fn sample() {
+    let wasm = compile("sample", false);
+    let (result, printed) = run(&wasm, "add_one", &[Value::I32(41)]);
+    assert_eq!(result, Ok(vec![Value::I32(42)]));
+    assert!(printed.is_empty());
+}
+
+#[test]
+fn structs() {
+    let wasm = compile("structs", false);
+    let (result, printed) = run(&wasm, "main", &[]);
+    assert_eq!(result, Ok(vec![Value::I32(2)]));
+    assert_eq!(printed, [7, 14, 3, 30, 37, -5, 3, 1, 4]);
+}
+
+#[test]
+fn pointers() {
+    let wasm = compile("pointers", false);
+    let (result, printed) = run(&wasm, "main", &[]);
+    assert_eq!(result, Ok(vec![Value::I32(2)]));
+    assert_eq!(printed, [5]);
+}
+
+#[test]
+fn functions() {
+    let wasm = compile("functions", false);
+    let (result, printed) = run(&wasm, "main", &[]);
+    assert_eq!(result, Ok(vec![Value::I32(42)]));
+    assert_eq!(printed, [4, 10, 6, 42, 7]);
+}
+
+#[test]
+fn closures() {
+    let wasm = compile("closures", false);
+    let (result, printed) = run(&wasm, "main", &[]);
+    assert_eq!(result, Ok(vec![Value::I32(6)]));
+    assert_eq!(printed, [15, 11, 13, 1, 7, 106, 42, 1]);
+}
+
+#[test]
+fn exceptions() {
+    let wasm = compile("exceptions", false);
+    let (result, printed) = run(&wasm, "main", &[]);
+    // `main` ends by yeeting a `bool` nothing catches
+    assert!(
+        matches!(result, Err(RuntimeError::Exception { ref values, .. }) if values == &[Value::I32(1)]),
+        "{:?}",
+        result
+    );
+    assert_eq!(printed, [10, -1003, 49, 18, 99, 3]);
+}
+
+#[test]
+fn lists_are_caught_whatever_their_length() {
+    let wasm = compile("exceptions", false);
+    let (result, _) = run(&wasm, "lists", &[]);
+    assert_eq!(result, Ok(vec![Value::I32(30)]));
+
+    let errors = type_errors(
+        "let f = (): int => { try { yeet [1, 2, 3]; } catch (xs: int[3]) { return xs[0]; } };",
+    );
+    let sized = Type::SizedArray {
+        element: Box::new(Type::Int),
+        len: 3,
+    };
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].node, TypeErrorKind::Invalid(sized));
+}
+
+#[test]
+fn caught_values_are_only_in_scope_in_their_catch() {
+    let errors = type_errors(
+        "let f = (): int => { try { yeet 1; } catch (n: int) { return n; } return n; };",
+    );
+    assert_eq!(errors.len(), 1);
+    assert_eq!(
+        errors[0].node,
+        TypeErrorKind::InvalidIdentifier("n".to_string())
+    );
+}
+
+#[test]
+fn garbage_collection() {
+    // `main` allocates about 300KiB. The collector reuses it, so the memory only has to hold
+    // the data, the shadow stack and a little heap, while without it the memory keeps growing
+    let bound = 3 * 65536;
+    let (results, printed, memory) = run_all(&compile("gc", true), &[("main", &[])]);
+    assert_eq!(results, [Ok(vec![Value::I32(37)])]);
+    assert_eq!(printed, [6, 70]);
+    assert!(memory <= bound, "grew to {} bytes", memory);
+
+    let (results, printed, memory) = run_all(&compile("gc", false), &[("main", &[])]);
+    assert_eq!(results, [Ok(vec![Value::I32(37)])]);
+    assert_eq!(printed, [6, 70]);
+    assert!(memory > bound, "only grew to {} bytes", memory);
+}
+
+#[test]
+fn unwinding_to_the_host_pops_frames() {
+    let wasm = compile("unwind", true);
+    // Each call unwinds 200 frames, which would fill the shadow stack if they were left on it
+    // and make `main` trap
+    let depth = [Value::I32(200)];
+    let mut calls = Vec::new();
+    for _ in 0..100 {
+        calls.extend([("fail", &depth[..]), ("crash", &depth[..])]);
+    }
+    calls.push(("main", &[]));
+
+    let (results, _, _) = run_all(&wasm, &calls);
+    for (result, (name, _)) in results.iter().zip(calls.iter()) {
+        match *name {
+            "fail" => assert!(
+                matches!(result, Err(RuntimeError::Exception { values, .. }) if values == &[Value::I32(0)]),
+                "{:?}",
+                result
+            ),
+            "crash" => assert!(result == &Err(RuntimeError::DivideByZero), "{:?}", result),
+            _ => assert_eq!(result, &Ok(vec![Value::I32(6)])),
+        }
+    }
+}